@@ -0,0 +1,448 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Documents are split into words by their content type, and every word then goes through the
+// database's analyzer to become the term it is indexed under. Search queries go through the same
+// analyzer, so that a query finds every word that analyzes to the same term. Analyzers are built
+// out of small stages, each doing one thing to a word, chained together by a `Pipeline`.
+
+/// Turns a word into the term it is indexed and searched under
+pub trait Analyzer: Send + Sync {
+    /// The term for `word`, or None if the word shouldn't be indexed at all, e.g. because nothing
+    /// is left of it
+    fn analyze(&self, word: String) -> Option<String>;
+
+    /// The start of the terms that words starting with `prefix` are indexed under, or None if no
+    /// term can start with it. Stages that change the end of a word, like stemming, can't know
+    /// how a prefix will end and leave it alone
+    fn analyze_prefix(&self, prefix: String) -> Option<String> {
+        self.analyze(prefix)
+    }
+
+    /// The name this stage is given in a pipeline spec, e.g. on the command line
+    fn name(&self) -> &'static str;
+}
+
+/// Lowercases words, so that a search matches however a word is capitalized
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lowercase;
+impl Analyzer for Lowercase {
+    fn analyze(&self, word: String) -> Option<String> {
+        Some(word.to_lowercase())
+    }
+
+    fn name(&self) -> &'static str {
+        "lowercase"
+    }
+}
+
+/// Strips punctuation from either end of words, so that "book," and "book" are the same term.
+/// Punctuation inside a word, as in "don't" or "e-mail", is kept. Words that are only punctuation
+/// are dropped
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StripPunctuation;
+impl Analyzer for StripPunctuation {
+    fn analyze(&self, word: String) -> Option<String> {
+        let stripped = word.trim_matches(|c: char| !c.is_alphanumeric());
+        match stripped.len() {
+            0 => None,
+            len if len == word.len() => Some(word),
+            _ => Some(stripped.to_string()),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "punctuation"
+    }
+}
+
+/// Removes diacritics from Latin letters, so that "café", "cafe\u{301}" and "cafe" are the same
+/// term. Combining marks are dropped, and precomposed letters are replaced by their base letter
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FoldDiacritics;
+impl Analyzer for FoldDiacritics {
+    fn analyze(&self, word: String) -> Option<String> {
+        if word.is_ascii() {
+            return Some(word);
+        }
+        let folded: String = word
+            .chars()
+            .filter(|c| !('\u{300}'..='\u{36f}').contains(c))
+            .map(fold_diacritic)
+            .collect();
+        (!folded.is_empty()).then_some(folded)
+    }
+
+    fn name(&self) -> &'static str {
+        "unicode"
+    }
+}
+
+// The base letter of `c` if it is a Latin letter with a diacritic, or `c` itself otherwise.
+fn fold_diacritic(c: char) -> char {
+    const FOLDS: &[(&str, char)] = &[
+        ("àáâãäåāăą", 'a'),
+        ("ÀÁÂÃÄÅĀĂĄ", 'A'),
+        ("çćĉċč", 'c'),
+        ("ÇĆĈĊČ", 'C'),
+        ("ďđ", 'd'),
+        ("ĎĐ", 'D'),
+        ("èéêëēĕėęě", 'e'),
+        ("ÈÉÊËĒĔĖĘĚ", 'E'),
+        ("ĝğġģ", 'g'),
+        ("ĜĞĠĢ", 'G'),
+        ("ĥħ", 'h'),
+        ("ĤĦ", 'H'),
+        ("ìíîïĩīĭįı", 'i'),
+        ("ÌÍÎÏĨĪĬĮİ", 'I'),
+        ("ĵ", 'j'),
+        ("Ĵ", 'J'),
+        ("ķ", 'k'),
+        ("Ķ", 'K'),
+        ("ĺļľŀł", 'l'),
+        ("ĹĻĽĿŁ", 'L'),
+        ("ñńņňŉ", 'n'),
+        ("ÑŃŅŇ", 'N'),
+        ("òóôõöøōŏő", 'o'),
+        ("ÒÓÔÕÖØŌŎŐ", 'O'),
+        ("ŕŗř", 'r'),
+        ("ŔŖŘ", 'R'),
+        ("śŝşš", 's'),
+        ("ŚŜŞŠ", 'S'),
+        ("ţťŧ", 't'),
+        ("ŢŤŦ", 'T'),
+        ("ùúûüũūŭůűų", 'u'),
+        ("ÙÚÛÜŨŪŬŮŰŲ", 'U'),
+        ("ŵ", 'w'),
+        ("Ŵ", 'W'),
+        ("ýÿŷ", 'y'),
+        ("ÝŸŶ", 'Y'),
+        ("źżž", 'z'),
+        ("ŹŻŽ", 'Z'),
+    ];
+    FOLDS
+        .iter()
+        .find(|(accented, _)| accented.contains(c))
+        .map_or(c, |(_, base)| *base)
+}
+
+/// Reduces English words to their stems with Porter's algorithm, so that "connected",
+/// "connecting" and "connection" are all the term "connect". Only lowercase ASCII words are
+/// stemmed, so this belongs after `Lowercase` in a pipeline. Irregular forms, like "ran" for
+/// "run", are left as they are
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PorterStem;
+impl Analyzer for PorterStem {
+    fn analyze(&self, word: String) -> Option<String> {
+        if word.len() <= 2 || !word.bytes().all(|b| b.is_ascii_lowercase()) {
+            return Some(word);
+        }
+        let mut stem = word.into_bytes();
+        porter::step1(&mut stem);
+        porter::step2(&mut stem);
+        porter::step3(&mut stem);
+        porter::step4(&mut stem);
+        porter::step5(&mut stem);
+        // Only ASCII letters go in, and only ASCII letters come out
+        Some(String::from_utf8(stem).unwrap())
+    }
+
+    fn analyze_prefix(&self, prefix: String) -> Option<String> {
+        Some(prefix)
+    }
+
+    fn name(&self) -> &'static str {
+        "stem"
+    }
+}
+
+// The steps of Porter's algorithm, as described in "An algorithm for suffix stripping" (1980).
+// Each works on a word of lowercase ASCII letters, removing or replacing a suffix when the stem
+// left behind is long enough. A stem's length is measured in consonant-vowel sequences: `m` in
+// `[C](VC)^m[V]`.
+mod porter {
+    // Whether the letter at `i` is a consonant. `y` is a consonant unless it follows one.
+    fn is_consonant(word: &[u8], i: usize) -> bool {
+        match word[i] {
+            b'a' | b'e' | b'i' | b'o' | b'u' => false,
+            b'y' => i == 0 || !is_consonant(word, i - 1),
+            _ => true,
+        }
+    }
+
+    // The number of vowel-consonant sequences in `stem`.
+    fn measure(stem: &[u8]) -> usize {
+        let mut m = 0;
+        let mut after_vowel = false;
+        for i in 0..stem.len() {
+            let consonant = is_consonant(stem, i);
+            if consonant && after_vowel {
+                m += 1;
+            }
+            after_vowel = !consonant;
+        }
+        m
+    }
+
+    fn has_vowel(stem: &[u8]) -> bool {
+        (0..stem.len()).any(|i| !is_consonant(stem, i))
+    }
+
+    fn ends_double_consonant(stem: &[u8]) -> bool {
+        let n = stem.len();
+        n >= 2 && stem[n - 1] == stem[n - 2] && is_consonant(stem, n - 1)
+    }
+
+    // Whether `stem` ends consonant-vowel-consonant, where the last consonant isn't w, x or y,
+    // as in "hop" but not "hoop" or "bow".
+    fn ends_cvc(stem: &[u8]) -> bool {
+        let n = stem.len();
+        n >= 3
+            && is_consonant(stem, n - 3)
+            && !is_consonant(stem, n - 2)
+            && is_consonant(stem, n - 1)
+            && !matches!(stem[n - 1], b'w' | b'x' | b'y')
+    }
+
+    // The longest of `rules`' suffixes that `word` ends with, along with its replacement and the
+    // stem before it.
+    fn longest_match<'a>(word: &[u8], rules: &[(&str, &'a str)]) -> Option<(usize, &'a str)> {
+        rules
+            .iter()
+            .filter(|(suffix, _)| word.ends_with(suffix.as_bytes()))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(suffix, replacement)| (word.len() - suffix.len(), *replacement))
+    }
+
+    // Replace the longest of `rules`' suffixes that `word` ends with, if the stem before it
+    // measures more than `min_measure`.
+    fn replace_suffix(word: &mut Vec<u8>, rules: &[(&str, &str)], min_measure: usize) {
+        if let Some((stem_len, replacement)) = longest_match(word, rules) {
+            if measure(&word[..stem_len]) > min_measure {
+                word.truncate(stem_len);
+                word.extend_from_slice(replacement.as_bytes());
+            }
+        }
+    }
+
+    // Plurals and past participles.
+    pub fn step1(word: &mut Vec<u8>) {
+        if let Some((stem_len, replacement)) = longest_match(
+            word,
+            &[("sses", "ss"), ("ies", "i"), ("ss", "ss"), ("s", "")],
+        ) {
+            word.truncate(stem_len);
+            word.extend_from_slice(replacement.as_bytes());
+        }
+        if word.ends_with(b"eed") {
+            if measure(&word[..word.len() - 3]) > 0 {
+                word.pop();
+            }
+        } else if let Some((stem_len, _)) = longest_match(word, &[("ed", ""), ("ing", "")]) {
+            if has_vowel(&word[..stem_len]) {
+                word.truncate(stem_len);
+                if word.ends_with(b"at") || word.ends_with(b"bl") || word.ends_with(b"iz") {
+                    word.push(b'e');
+                } else if ends_double_consonant(word)
+                    && !matches!(word.last(), Some(b'l' | b's' | b'z'))
+                {
+                    word.pop();
+                } else if measure(word) == 1 && ends_cvc(word) {
+                    word.push(b'e');
+                }
+            }
+        }
+        if word.ends_with(b"y") && has_vowel(&word[..word.len() - 1]) {
+            *word.last_mut().unwrap() = b'i';
+        }
+    }
+
+    // Double suffixes that map to single ones.
+    pub fn step2(word: &mut Vec<u8>) {
+        replace_suffix(
+            word,
+            &[
+                ("ational", "ate"),
+                ("tional", "tion"),
+                ("enci", "ence"),
+                ("anci", "ance"),
+                ("izer", "ize"),
+                ("abli", "able"),
+                ("alli", "al"),
+                ("entli", "ent"),
+                ("eli", "e"),
+                ("ousli", "ous"),
+                ("ization", "ize"),
+                ("ation", "ate"),
+                ("ator", "ate"),
+                ("alism", "al"),
+                ("iveness", "ive"),
+                ("fulness", "ful"),
+                ("ousness", "ous"),
+                ("aliti", "al"),
+                ("iviti", "ive"),
+                ("biliti", "ble"),
+            ],
+            0,
+        );
+    }
+
+    // -ic-, -full, -ness and the like.
+    pub fn step3(word: &mut Vec<u8>) {
+        replace_suffix(
+            word,
+            &[
+                ("icate", "ic"),
+                ("ative", ""),
+                ("alize", "al"),
+                ("iciti", "ic"),
+                ("ical", "ic"),
+                ("ful", ""),
+                ("ness", ""),
+            ],
+            0,
+        );
+    }
+
+    // Suffixes removed from long enough stems.
+    pub fn step4(word: &mut Vec<u8>) {
+        const SUFFIXES: &[(&str, &str)] = &[
+            ("al", ""),
+            ("ance", ""),
+            ("ence", ""),
+            ("er", ""),
+            ("ic", ""),
+            ("able", ""),
+            ("ible", ""),
+            ("ant", ""),
+            ("ement", ""),
+            ("ment", ""),
+            ("ent", ""),
+            ("ion", ""),
+            ("ou", ""),
+            ("ism", ""),
+            ("ate", ""),
+            ("iti", ""),
+            ("ous", ""),
+            ("ive", ""),
+            ("ize", ""),
+        ];
+        if let Some((stem_len, _)) = longest_match(word, SUFFIXES) {
+            let stem = &word[..stem_len];
+            // -ion only comes off after an s or a t
+            let is_ion = word.len() - stem_len == 3 && word.ends_with(b"ion");
+            if measure(stem) > 1 && (!is_ion || matches!(stem.last(), Some(b's' | b't'))) {
+                word.truncate(stem_len);
+            }
+        }
+    }
+
+    // A final -e, and a double l.
+    pub fn step5(word: &mut Vec<u8>) {
+        if word.ends_with(b"e") {
+            let stem = &word[..word.len() - 1];
+            let m = measure(stem);
+            if m > 1 || (m == 1 && !ends_cvc(stem)) {
+                word.pop();
+            }
+        }
+        if word.ends_with(b"ll") && measure(word) > 1 {
+            word.pop();
+        }
+    }
+}
+
+/// A chain of analyzers, each given the term the one before it produced
+#[derive(Clone)]
+pub struct Pipeline {
+    stages: Vec<Arc<dyn Analyzer>>,
+}
+
+impl Pipeline {
+    /// A pipeline running `stages` in order
+    pub fn new(stages: Vec<Arc<dyn Analyzer>>) -> Self {
+        Self { stages }
+    }
+
+    /// The pipeline that copes best with ordinary prose: diacritics are removed, words are
+    /// lowercased, and punctuation around them is stripped
+    pub fn standard() -> Self {
+        Self::new(vec![
+            Arc::new(FoldDiacritics),
+            Arc::new(Lowercase),
+            Arc::new(StripPunctuation),
+        ])
+    }
+
+    /// This pipeline, followed by stemming with `PorterStem`
+    pub fn with_stemming(mut self) -> Self {
+        self.stages.push(Arc::new(PorterStem));
+        self
+    }
+}
+
+/// A pipeline that only lowercases, which is how words were always indexed
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new(vec![Arc::new(Lowercase)])
+    }
+}
+
+impl Analyzer for Pipeline {
+    fn analyze(&self, word: String) -> Option<String> {
+        self.stages
+            .iter()
+            .try_fold(word, |word, stage| stage.analyze(word))
+    }
+
+    fn analyze_prefix(&self, prefix: String) -> Option<String> {
+        self.stages
+            .iter()
+            .try_fold(prefix, |prefix, stage| stage.analyze_prefix(prefix))
+    }
+
+    fn name(&self) -> &'static str {
+        "pipeline"
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.stages.iter().map(|stage| stage.name()).collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Pipeline({})", self)
+    }
+}
+
+// Parse a comma-separated list of stage names, e.g. `unicode,lowercase,punctuation`, into the
+// pipeline running them in that order. `standard` stands for `Pipeline::standard`'s stages.
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stages: Vec<Arc<dyn Analyzer>> = Vec::new();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "lowercase" => stages.push(Arc::new(Lowercase)),
+                "punctuation" => stages.push(Arc::new(StripPunctuation)),
+                "unicode" => stages.push(Arc::new(FoldDiacritics)),
+                "stem" => stages.push(Arc::new(PorterStem)),
+                "standard" => stages.extend(Pipeline::standard().stages),
+                _ => {
+                    return Err(format!(
+                        "unknown analyzer stage {:?}; expected lowercase, punctuation, unicode, \
+                         stem or standard",
+                        name
+                    ))
+                }
+            }
+        }
+        Ok(Self::new(stages))
+    }
+}
@@ -0,0 +1,128 @@
+use crate::message::{Request, Response};
+use crate::server::RequestContext;
+use crate::sync;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+// A server with an audit log appends a line of JSON to it for every request that would modify the
+// archive, saying who made it, from where, and what it did to which documents. Requests that were
+// refused are logged too, with why, so the log shows attempts as well as changes. Unlike the
+// request log, it never holds the documents themselves, only their sizes.
+
+/// One line of an audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the server accepted the connection, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// The address of the client, if it could be determined
+    pub peer: Option<SocketAddr>,
+    /// Who the client authenticated as, if anyone
+    pub principal: Option<String>,
+    /// The type of request, as `Request::kind` names it
+    pub action: String,
+    /// The collection the request named, or None for the server's default archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    /// The documents the request created or changed
+    pub doc_ids: Vec<usize>,
+    /// The size in bytes of each document the request sent
+    pub doc_sizes: Vec<usize>,
+    /// "ok", or why the request didn't succeed
+    pub outcome: String,
+}
+
+impl AuditEntry {
+    // An entry for `request`, received as `context` describes, before it is answered.
+    pub fn new(request: &Request, context: &RequestContext) -> Self {
+        let (doc_ids, doc_sizes) = match request {
+            Request::Publish { doc } | Request::PublishWith { doc, .. } => {
+                (vec![], vec![doc.len()])
+            }
+            Request::PublishBatch { docs, .. } => (vec![], docs.iter().map(String::len).collect()),
+            Request::Update { id, doc } => (vec![*id], vec![doc.len()]),
+            Request::Commit { id } => (vec![*id], vec![]),
+            _ => (vec![], vec![]),
+        };
+        Self {
+            timestamp_ms: context
+                .received_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            peer: context.peer,
+            principal: context.identity.clone(),
+            action: request.kind().to_string(),
+            collection: context.collection.clone(),
+            doc_ids,
+            doc_sizes,
+            outcome: String::new(),
+        }
+    }
+
+    // Complete the entry with the request's `response`, and its `outcome` as a short description.
+    pub fn answered(mut self, response: &Response, outcome: String) -> Self {
+        match response {
+            Response::PublishSuccess(id) => self.doc_ids = vec![*id],
+            Response::PublishBatchSuccess(ids) => self.doc_ids = ids.clone(),
+            _ => {}
+        }
+        self.outcome = outcome;
+        self
+    }
+}
+
+/// An append-only JSONL log of the changes clients make to the archive
+pub struct AuditLog {
+    path: String,
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl AuditLog {
+    // Open the log at `path` for appending, creating it if needed.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_string(),
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    // Close the log and open it again by its path, so that after it has been rotated, new lines
+    // go to a fresh file rather than the rotated one.
+    pub fn reopen(&self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut writer = sync::lock(&self.writer);
+        writer.flush()?;
+        *writer = BufWriter::new(file);
+        Ok(())
+    }
+
+    // Append `entry` to the log. Each line is flushed immediately so the log stays complete if the
+    // server is killed.
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let line = serde_json::to_string(entry)?;
+        let mut writer = sync::lock(&self.writer);
+        writeln!(writer, "{}", line)?;
+        writer.flush()
+    }
+
+    // Every entry in the log at `path`, oldest first.
+    pub fn read(path: &str) -> io::Result<Vec<AuditEntry>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// A server with API keys only lets clients that send one of them in their request header modify
+// the archive; searching and retrieving stay open to anyone. Each key belongs to a named client,
+// and requests made with it are logged as that client.
+//
+// Each key also has a role, which limits what it may do. A reader may only do what anyone can, a
+// publisher may also modify the archive, and an admin may also make administrative requests, such
+// as asking for the server's stats.
+//
+// A follower also accepts the key its primary sends changes with, which has the replicator role. A
+// replicator may only send changes to the follower, which no other role may do.
+//
+// A server may also isolate tenants, giving every client but an admin a collection of its own,
+// named after it. Its requests are then answered from that collection alone, and a client without
+// a key may do nothing at all.
+//
+// A key file has one key per line, as the client's name, its key and optionally its role, split by
+// whitespace. Keys without a role are publishers. Blank lines and lines starting with `#` are
+// ignored.
+
+/// What the holder of an API key may do, each role from `Reader` on allowing everything the ones
+/// before it do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// A follower's primary, which may only send it changes. Never read from a key file
+    Replicator,
+    /// May only search and retrieve
+    Reader,
+    /// May also publish and otherwise modify the archive
+    Publisher,
+    /// May also make administrative requests
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Replicator => "replicator",
+            Role::Reader => "reader",
+            Role::Publisher => "publisher",
+            Role::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Role {
+    // Whether this role may make requests that need `needed`. A replicator may only make those
+    // that need a replicator, and other roles those that need them or a role before them.
+    pub fn allows(self, needed: Role) -> bool {
+        match (self, needed) {
+            (Role::Replicator, _) | (_, Role::Replicator) => self == needed,
+            _ => self >= needed,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reader" => Ok(Role::Reader),
+            "publisher" => Ok(Role::Publisher),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!(
+                "unknown role {:?}; expected reader, publisher or admin",
+                s
+            )),
+        }
+    }
+}
+
+/// The API keys a server accepts, and who each belongs to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApiKeys {
+    /// Each key, along with the name of the client it belongs to and its role
+    keys: Vec<(String, String, Role)>,
+    /// The key file the keys were loaded from, if they were
+    source: Option<PathBuf>,
+}
+
+impl ApiKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Also accept `key`, as belonging to the client `name`, who may do what `role` allows.
+    pub fn with_key(mut self, name: impl Into<String>, key: impl Into<String>, role: Role) -> Self {
+        self.keys.push((name.into(), key.into(), role));
+        self
+    }
+
+    // Read the keys in the key file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut keys = Self {
+            source: Some(path.as_ref().to_path_buf()),
+            ..Self::new()
+        };
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, message),
+                )
+            };
+            keys = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, key] => keys.with_key(name, key, Role::Publisher),
+                [name, key, role] => keys.with_key(name, key, role.parse().map_err(invalid)?),
+                _ => return Err(invalid("expected a name, a key and a role".to_string())),
+            }
+        }
+        Ok(keys)
+    }
+
+    // The name of the client `key` belongs to and its role, if it is one of the keys. Every key is
+    // compared in full, so how long this takes doesn't give away how much of a key a guess got
+    // right.
+    pub fn identify(&self, key: &str) -> Option<(&str, Role)> {
+        let mut found = None;
+        for (name, candidate, role) in &self.keys {
+            if constant_time_eq(candidate.as_bytes(), key.as_bytes()) {
+                found = Some((name.as_str(), *role));
+            }
+        }
+        found
+    }
+
+    // The key file the keys were loaded from, if they were, so they can be loaded again.
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// Whether `a` and `b` are equal, looking at every byte whatever the answer.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,254 @@
+use crate::message::{Request, Response};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A load generator for measuring a running server. Each of a number of threads sends requests back
+// to back for a fixed time, choosing each one at random from a weighted mix of publishes, searches
+// and retrieves, and the time every request took is gathered into percentiles per kind.
+//
+// Documents are made of synthetic words drawn from a fixed vocabulary, skewed toward its first
+// words as natural text is, so searches find a realistic spread of matches. The random choices are
+// made with xorshift from a seed, so a run can be repeated.
+
+/// The requests a benchmark sends, and how hard
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    /// How often to publish, relative to the other weights
+    pub publish_weight: u32,
+    /// How often to search, relative to the other weights
+    pub search_weight: u32,
+    /// How often to retrieve, relative to the other weights
+    pub retrieve_weight: u32,
+    /// The number of threads sending requests at once
+    pub concurrency: usize,
+    /// How long to send requests for
+    pub duration: Duration,
+    /// The number of words in each published document
+    pub doc_words: usize,
+    /// The number of distinct words documents are made of
+    pub vocabulary: usize,
+    /// Seeds the random choices
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            publish_weight: 1,
+            search_weight: 8,
+            retrieve_weight: 1,
+            concurrency: 8,
+            duration: Duration::from_secs(10),
+            doc_words: 200,
+            vocabulary: 10_000,
+            seed: 1,
+        }
+    }
+}
+
+/// How long one kind of request took
+#[derive(Debug, Clone, PartialEq)]
+pub struct Latencies {
+    /// The kind of request, e.g. `search`
+    pub kind: &'static str,
+    /// How many were sent
+    pub count: usize,
+    /// How many got no response, or a failure
+    pub failed: usize,
+    /// The median latency
+    pub p50: Duration,
+    /// The latency 90% of requests took at most
+    pub p90: Duration,
+    /// The latency 99% of requests took at most
+    pub p99: Duration,
+    /// The longest any request took
+    pub max: Duration,
+}
+
+/// What a benchmark measured
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    /// How long requests were sent for
+    pub elapsed: Duration,
+    /// The latencies of each kind of request sent at least once
+    pub kinds: Vec<Latencies>,
+}
+
+impl BenchReport {
+    /// The number of requests sent
+    pub fn requests(&self) -> usize {
+        self.kinds.iter().map(|kind| kind.count).sum()
+    }
+
+    /// The number of requests that got no response, or a failure
+    pub fn failed(&self) -> usize {
+        self.kinds.iter().map(|kind| kind.failed).sum()
+    }
+
+    /// Requests answered per second
+    pub fn throughput(&self) -> f64 {
+        self.requests() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+// The kinds of request in a workload, in the order they are reported
+const KINDS: [&str; 3] = ["publish", "search", "retrieve"];
+
+// Send `workload` with `send`, which sends one request and returns the server's response, and
+// report how long the requests took. If the server holds no documents to retrieve, a few are
+// published first, outside the measurement.
+pub fn run<F>(send: F, workload: &Workload) -> BenchReport
+where
+    F: Fn(&Request) -> Option<Response> + Sync,
+{
+    let documents = AtomicUsize::new(match send(&Request::Ping) {
+        Some(Response::Pong { documents, .. }) => documents,
+        _ => 0,
+    });
+    if documents.load(Ordering::Relaxed) == 0 && workload.retrieve_weight > 0 {
+        let mut rng = Rng::new(workload.seed);
+        for _ in 0..workload.concurrency.max(1) {
+            let doc = rng.document(workload);
+            if let Some(Response::PublishSuccess(id)) = send(&Request::Publish { doc }) {
+                documents.fetch_max(id + 1, Ordering::Relaxed);
+            }
+        }
+    }
+    let start = Instant::now();
+    let deadline = start + workload.duration;
+    let samples = thread::scope(|scope| {
+        let workers = (0..workload.concurrency.max(1))
+            .map(|worker| {
+                let (send, documents) = (&send, &documents);
+                scope.spawn(move || {
+                    let mut rng = Rng::new(workload.seed.wrapping_add(worker as u64 + 1));
+                    let mut samples = Vec::new();
+                    while Instant::now() < deadline {
+                        let kind = rng.kind(workload);
+                        let request = match kind {
+                            0 => Request::Publish {
+                                doc: rng.document(workload),
+                            },
+                            1 => Request::Search {
+                                word: rng.word(workload),
+                            },
+                            _ => Request::Retrieve {
+                                id: rng.below(documents.load(Ordering::Relaxed).max(1)),
+                                offset: 0,
+                                length: None,
+                            },
+                        };
+                        let sent = Instant::now();
+                        let response = send(&request);
+                        let latency = sent.elapsed();
+                        if let Some(Response::PublishSuccess(id)) = response {
+                            documents.fetch_max(id + 1, Ordering::Relaxed);
+                        }
+                        let failed = matches!(
+                            response,
+                            None | Some(Response::Failure { .. } | Response::Busy)
+                        );
+                        samples.push((kind, latency, failed));
+                    }
+                    samples
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("a benchmark thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    let elapsed = start.elapsed();
+    let kinds = KINDS
+        .iter()
+        .enumerate()
+        .filter_map(|(index, &kind)| {
+            let mut latencies = samples
+                .iter()
+                .filter(|(sample_kind, _, _)| *sample_kind == index)
+                .map(|(_, latency, _)| *latency)
+                .collect::<Vec<_>>();
+            if latencies.is_empty() {
+                return None;
+            }
+            latencies.sort();
+            let failed = samples
+                .iter()
+                .filter(|(sample_kind, _, failed)| *sample_kind == index && *failed)
+                .count();
+            Some(Latencies {
+                kind,
+                count: latencies.len(),
+                failed,
+                p50: percentile(&latencies, 50),
+                p90: percentile(&latencies, 90),
+                p99: percentile(&latencies, 99),
+                max: latencies[latencies.len() - 1],
+            })
+        })
+        .collect();
+    BenchReport { elapsed, kinds }
+}
+
+// The latency that `percent` percent of the `sorted` latencies are at or under.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted[rank.saturating_sub(1)]
+}
+
+// A xorshift generator for a benchmark thread's random choices
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift gets stuck at zero
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // A number from 0 up to but not including `n`, which must be at least one.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // The index in `KINDS` of the kind of request to send next, chosen by the workload's weights.
+    fn kind(&mut self, workload: &Workload) -> usize {
+        let weights = [
+            workload.publish_weight,
+            workload.search_weight,
+            workload.retrieve_weight,
+        ];
+        let total = weights.iter().sum::<u32>().max(1);
+        let mut roll = self.below(total as usize) as u32;
+        for (kind, weight) in weights.into_iter().enumerate() {
+            if roll < weight {
+                return kind;
+            }
+            roll -= weight;
+        }
+        // Only reached if every weight is zero
+        1
+    }
+
+    // A word from the vocabulary, more likely the earlier it comes.
+    fn word(&mut self, workload: &Workload) -> String {
+        let bound = self.below(workload.vocabulary.max(1)) + 1;
+        format!("word{}", self.below(bound))
+    }
+
+    // A document of the workload's length.
+    fn document(&mut self, workload: &Workload) -> String {
+        (0..workload.doc_words.max(1))
+            .map(|_| self.word(workload))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
@@ -0,0 +1,139 @@
+use crate::database::Busy;
+use crate::sync;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{RwLock, RwLockReadGuard};
+
+// The BlobStore struct holds the values of an archive under consecutive ids, handed out in the
+// order the values are inserted. Rather than keeping every value behind one lock, it splits them
+// across shards by id, so that the value with id `id` is at index `id / shards` of shard
+// `id % shards`. Consecutive ids land in different shards, so writers inserting at the same time
+// and readers of recently inserted values rarely wait for each other.
+//
+// Ids are allocated with an atomic counter before the shard is locked, so a value is briefly
+// missing from its shard after its id has been handed out. Lookups treat it as not there yet.
+// Operations that visit every value take the `gate` exclusively, which waits for inserts in
+// progress to finish, so they never see a gap; inserts only hold it shared, so they don't hold
+// each other up.
+
+/// A sharded, append-only store of values under consecutive ids
+pub struct BlobStore<T> {
+    /// The values, each at index `id / shards.len()` of shard `id % shards.len()`
+    shards: Vec<RwLock<Vec<Option<T>>>>,
+    /// The next id to hand out
+    next_id: AtomicUsize,
+    /// The number of values inserted, which trails `next_id` while inserts are in progress
+    len: AtomicUsize,
+    /// Held shared by inserts and exclusively by operations that visit every value
+    gate: RwLock<()>,
+}
+
+impl<T> BlobStore<T> {
+    // Create an empty store split across `shard_count` shards. The store always has at least one
+    // shard.
+    pub fn new(shard_count: usize) -> Self {
+        Self {
+            shards: (0..shard_count.max(1))
+                .map(|_| RwLock::new(Vec::new()))
+                .collect(),
+            next_id: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            gate: RwLock::new(()),
+        }
+    }
+
+    // The number of values in the store. Values still being inserted aren't counted.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    // Whether nothing has been inserted into the store, or started being inserted.
+    pub fn is_empty(&self) -> bool {
+        self.next_id.load(Ordering::SeqCst) == 0
+    }
+
+    // The shard holding `id`, and the index of `id` within it.
+    fn locate(&self, id: usize) -> (&RwLock<Vec<Option<T>>>, usize) {
+        (&self.shards[id % self.shards.len()], id / self.shards.len())
+    }
+
+    // Put `value` in its place in `shard`, which holds `id`.
+    fn place(&self, shard: &mut Vec<Option<T>>, id: usize, value: T) {
+        let index = id / self.shards.len();
+        if shard.len() <= index {
+            shard.resize_with(index + 1, || None);
+        }
+        shard[index] = Some(value);
+    }
+
+    // Insert the value `make` builds under the next id, and return the id. `make` is given the
+    // id before the value is visible to anyone else, so anything that should be in place by the
+    // time the value can be found can be set up then; it shouldn't use the store itself.
+    pub fn insert_with<F: FnOnce(usize) -> T>(&self, make: F) -> usize {
+        let _gate = sync::read(&self.gate);
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let value = make(id);
+        let (shard, _) = self.locate(id);
+        self.place(&mut sync::write(shard), id, value);
+        self.len.fetch_add(1, Ordering::SeqCst);
+        id
+    }
+
+    // Insert every value in `values` under consecutive ids, all at once: no lookup sees some of
+    // them without the rest. `prepare` is given the ids before any of the values are visible, as
+    // `insert_with` gives its id to `make`. Return the ids.
+    pub fn insert_batch_with<F: FnOnce(Range<usize>)>(
+        &self,
+        values: Vec<T>,
+        prepare: F,
+    ) -> Range<usize> {
+        let _gate = sync::read(&self.gate);
+        let count = values.len();
+        let first_id = self.next_id.fetch_add(count, Ordering::SeqCst);
+        let ids = first_id..first_id + count;
+        prepare(ids.clone());
+        // Every shard is locked, in order, before any value goes in
+        let mut shards = self.shards.iter().map(sync::write).collect::<Vec<_>>();
+        for (id, value) in ids.clone().zip(values) {
+            self.place(&mut shards[id % self.shards.len()], id, value);
+        }
+        drop(shards);
+        self.len.fetch_add(count, Ordering::SeqCst);
+        ids
+    }
+
+    // Call `f` on the value with the given id, under a reader lock of its shard, and return what
+    // it returns. Return None if there is no value with that id yet.
+    pub fn get<R, F: FnOnce(&T) -> R>(&self, id: usize, f: F) -> Option<R> {
+        let (shard, index) = self.locate(id);
+        sync::read(shard).get(index)?.as_ref().map(f)
+    }
+
+    // Like `get`, but return `Busy` if the value's shard is locked for writing rather than waiting.
+    pub fn try_get<R, F: FnOnce(&T) -> R>(&self, id: usize, f: F) -> Result<Option<R>, Busy> {
+        let (shard, index) = self.locate(id);
+        let shard = sync::try_read(shard).ok_or(Busy)?;
+        Ok(shard.get(index).and_then(Option::as_ref).map(f))
+    }
+
+    // Call `f` on the value with the given id, under a writer lock of its shard, and return what
+    // it returns. Return None if there is no value with that id yet.
+    pub fn get_mut<R, F: FnOnce(&mut T) -> R>(&self, id: usize, f: F) -> Option<R> {
+        let (shard, index) = self.locate(id);
+        sync::write(shard).get_mut(index)?.as_mut().map(f)
+    }
+
+    // Call `f` on every value in the store, in id order, so that each value's id is its index,
+    // and return what it returns. Inserts wait until `f` returns, and there are no gaps: every
+    // id handed out so far has a value.
+    pub fn with_all<R, F: FnOnce(&[&T]) -> R>(&self, f: F) -> R {
+        let _gate = sync::write(&self.gate);
+        let shards: Vec<RwLockReadGuard<'_, Vec<Option<T>>>> =
+            self.shards.iter().map(sync::read).collect();
+        let values = (0..self.next_id.load(Ordering::SeqCst))
+            .map(|id| shards[id % shards.len()][id / shards.len()].as_ref())
+            .map(|value| value.expect("every id handed out is filled once inserts finish"))
+            .collect::<Vec<_>>();
+        f(&values)
+    }
+}
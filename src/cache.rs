@@ -0,0 +1,129 @@
+use crate::sync;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// A cache of the most recently used results, up to a fixed number of them. Each entry remembers
+// when it was last used, and an ordered map from those times back to the keys finds the least
+// recently used entry to evict when the cache is full.
+//
+// The cache is emptied whenever the data its results come from changes. A result worked out
+// while a change was being made might already be out of date, so callers note the cache's
+// generation before working a result out, and the result is only kept if no change has been
+// made since.
+
+/// A bounded cache that evicts the least recently used entry when full
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    /// The most entries the cache holds
+    capacity: usize,
+    entries: Mutex<Entries<K, V>>,
+    /// How many times the cache has been emptied
+    generation: AtomicU64,
+    /// How many lookups found an entry
+    hits: AtomicUsize,
+    /// How many lookups found nothing
+    misses: AtomicUsize,
+}
+
+// The entries of a cache, along with when each was last used
+#[derive(Debug)]
+struct Entries<K, V> {
+    values: HashMap<K, (V, u64)>,
+    by_last_use: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    // Create an empty cache that holds at most `capacity` entries, which must be at least one.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "a cache must hold at least one entry");
+        Self {
+            capacity,
+            entries: Mutex::new(Entries {
+                values: HashMap::new(),
+                by_last_use: BTreeMap::new(),
+                clock: 0,
+            }),
+            generation: AtomicU64::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    // The current generation, to pass to `insert` along with a result worked out from here on.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    // The value cached under `key`, if any, which becomes the most recently used.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = sync::lock(&self.entries);
+        let entries = &mut *entries;
+        entries.clock += 1;
+        let Some((value, last_use)) = entries.values.get_mut(key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let key = entries
+            .by_last_use
+            .remove(last_use)
+            .expect("every entry is ordered by its last use");
+        *last_use = entries.clock;
+        entries.by_last_use.insert(entries.clock, key);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(value.clone())
+    }
+
+    // Cache `value` under `key`, evicting the least recently used entry if the cache is full,
+    // unless the cache has been emptied since `generation`, when `value` was worked out.
+    pub fn insert(&self, key: K, value: V, generation: u64) {
+        let mut entries = sync::lock(&self.entries);
+        // Checked under the lock, so that `clear` can't empty the cache between the check and
+        // the insert
+        if self.generation() != generation {
+            return;
+        }
+        entries.clock += 1;
+        let clock = entries.clock;
+        if let Some((_, last_use)) = entries.values.insert(key.clone(), (value, clock)) {
+            entries.by_last_use.remove(&last_use);
+        } else if entries.values.len() > self.capacity {
+            let (_, oldest) = entries
+                .by_last_use
+                .pop_first()
+                .expect("a full cache has entries");
+            entries.values.remove(&oldest);
+        }
+        entries.by_last_use.insert(clock, key);
+    }
+
+    // Forget every entry, and any result worked out before now.
+    pub fn clear(&self) {
+        let mut entries = sync::lock(&self.entries);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.values.clear();
+        entries.by_last_use.clear();
+    }
+
+    /// The number of entries in the cache
+    pub fn len(&self) -> usize {
+        sync::lock(&self.entries).values.len()
+    }
+
+    /// Whether the cache has no entries
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many lookups have found an entry
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// How many lookups have found nothing
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
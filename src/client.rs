@@ -1,11 +1,136 @@
+use crate::database::{PublishOptions, SearchOptions};
 use crate::message::*;
+use crate::sync;
 use std::default::Default;
-use std::io::Write;
-use std::net::SocketAddr;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncClient;
+
+/// The most requests `Client::pipeline` sends ahead of the responses it has read, so that the
+/// server never waits to send responses the client isn't reading yet
+const PIPELINE_WINDOW: usize = 32;
+
+/// Called with the address the client was using and the address it switched to whenever it fails
+/// over between its primary and standby servers
+pub type FailoverCallback = Arc<dyn Fn(SocketAddr, SocketAddr) + Send + Sync>;
 
 /// A client for interacting with the server at address `address`
 pub struct Client {
     address: SocketAddr,
+    /// When set, requests are sent over the Unix domain socket at this path instead of to
+    /// `address`
+    #[cfg(unix)]
+    socket: Option<PathBuf>,
+    /// Sent with every request, e.g. to limit the size of responses
+    header: RequestHeader,
+    /// When set, requests are sent over TLS using this configuration
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ClientConfig>>,
+    /// A server to fall back to when the one in use fails
+    standby: Option<SocketAddr>,
+    /// Whether requests currently go to the standby rather than `address`
+    on_standby: AtomicBool,
+    /// Whether requests that modify the archive may also be sent to the other server on failure
+    failover_writes: bool,
+    /// Told about every failover. When unset, failovers are logged to stderr
+    on_failover: Option<FailoverCallback>,
+    /// The longest to wait for a connection to the server, or None to wait as long as the OS does
+    connect_timeout: Option<Duration>,
+    /// The longest to wait for the next bytes of a response, or None to wait forever
+    read_timeout: Option<Duration>,
+    /// How to retry requests that fail for reasons that may pass
+    retry: RetryPolicy,
+}
+
+/// How a client retries a request that failed in a way that may pass, e.g. because the server was
+/// restarting. A request that never reached the server is always safe to retry; one that did is
+/// only retried if it doesn't modify the archive, so it can't be applied twice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to retry a failed request before giving up
+    pub max_retries: usize,
+    /// How long to wait before the first retry. The wait doubles after every retry
+    pub initial_backoff: Duration,
+    /// The longest to wait between retries
+    pub max_backoff: Duration,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+impl RetryPolicy {
+    // How long to wait before retry number `retry`, counting from 0.
+    fn backoff(&self, retry: usize) -> Duration {
+        let factor = 1_u32.checked_shl(retry as u32).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Configures how a `Client` connects to its server before building it
+pub struct ClientBuilder {
+    address: SocketAddr,
+    #[cfg(unix)]
+    socket: Option<PathBuf>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    retry: RetryPolicy,
+}
+impl ClientBuilder {
+    // Give up connecting to the server after `timeout`, which must not be zero.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "the connect timeout must not be zero");
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    // Give up on a response when the server sends nothing for `timeout`, which must not be zero.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "the read timeout must not be zero");
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    // Retry failed requests as `policy` asks.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    // Build an `AsyncClient` with these settings. A Unix socket isn't supported, and the client
+    // doesn't retry failed requests.
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> AsyncClient {
+        AsyncClient::from_builder(self)
+    }
+
+    // Build a client with these settings. Other options can still be set on the client itself.
+    pub fn build(self) -> Client {
+        Client {
+            #[cfg(unix)]
+            socket: self.socket,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            retry: self.retry,
+            ..Client::at(self.address)
+        }
+    }
 }
 impl Default for Client {
     fn default() -> Self {
@@ -18,12 +143,152 @@ impl Client {
     // SocketAddr from an IpAddr and a port with `SocketAddr::new(addr, port)`.
     // You can create an IpAddr from a string with `address.parse().unwrap()`.
     pub fn new(address: &str, port: u16) -> Self {
-        let ip_address = address.parse().unwrap();
-        let socket_address = SocketAddr::new(ip_address, port);
-        Self { address : socket_address }
+        Self::builder(address, port).build()
     }
 
-    // Helper for next three functions
+    // Start configuring a client for the server at `address` and `port`, e.g. to give it timeouts
+    // or retry failed requests. `Client::new` is the same as building one without changing
+    // anything. The address may be an IPv4 or IPv6 address, with or without brackets, or a host
+    // name, which is resolved now. Panics if it can't be.
+    pub fn builder(address: &str, port: u16) -> ClientBuilder {
+        let host = address.trim_start_matches('[').trim_end_matches(']');
+        let address = match host.parse() {
+            Ok(ip_address) => SocketAddr::new(ip_address, port),
+            Err(_) => (host, port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addresses| addresses.next())
+                .unwrap_or_else(|| panic!("couldn't resolve the server address {}", address)),
+        };
+        ClientBuilder {
+            address,
+            #[cfg(unix)]
+            socket: None,
+            connect_timeout: None,
+            read_timeout: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    // Create a client that will connect to the server over the Unix domain socket at `path`.
+    #[cfg(unix)]
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self::unix_builder(path).build()
+    }
+
+    // Like `builder`, but for a server on the Unix domain socket at `path`. TLS and standby
+    // servers don't apply to such a client.
+    #[cfg(unix)]
+    pub fn unix_builder(path: impl Into<PathBuf>) -> ClientBuilder {
+        ClientBuilder {
+            socket: Some(path.into()),
+            ..Self::builder("127.0.0.1", 0)
+        }
+    }
+
+    // A client for the server at `address`, with every option unset.
+    fn at(address: SocketAddr) -> Self {
+        Self {
+            address,
+            #[cfg(unix)]
+            socket: None,
+            header: RequestHeader::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            standby: None,
+            on_standby: AtomicBool::new(false),
+            failover_writes: false,
+            on_failover: None,
+            connect_timeout: None,
+            read_timeout: None,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    // Fall back to the server at `standby` when the primary server fails, and back to the primary
+    // if the standby fails in turn. The client sticks with whichever server last answered. Only
+    // requests that don't modify the archive fail over, unless `with_failover_writes` is set.
+    pub fn with_standby(mut self, standby: SocketAddr) -> Self {
+        self.standby = Some(standby);
+        self
+    }
+
+    // Let requests that modify the archive fail over too. A write is only retried on the other
+    // server if it never reached the failed one, so it can't be applied twice.
+    pub fn with_failover_writes(mut self) -> Self {
+        self.failover_writes = true;
+        self
+    }
+
+    // Call `callback` with the old and new addresses whenever the client fails over, instead of
+    // logging the switch.
+    pub fn with_failover_callback(mut self, callback: FailoverCallback) -> Self {
+        self.on_failover = Some(callback);
+        self
+    }
+
+    /// The address of the server requests are currently sent to
+    pub fn active_address(&self) -> SocketAddr {
+        match (self.standby, self.on_standby.load(Ordering::SeqCst)) {
+            (Some(standby), true) => standby,
+            _ => self.address,
+        }
+    }
+
+    // Send every request over TLS using `config`, e.g. one built by `tls::client_config`. The
+    // server's certificate must be valid for the IP address the client connects to.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<rustls::ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    // Ask the server never to send a response of more than `max_len` bytes, counting its frame
+    // header. If `allow_truncation` is set, the server cuts down larger responses and marks them
+    // `Truncated`; otherwise it answers `ResponseTooLarge`. Responses over the limit are refused
+    // by the client either way.
+    pub fn with_max_response_len(mut self, max_len: usize, allow_truncation: bool) -> Self {
+        self.header.max_response_len = Some(max_len);
+        self.header.allow_truncation = allow_truncation;
+        self
+    }
+
+    // Ask the server to send the metadata of the documents in search, list and retrieve responses,
+    // which then come wrapped in `WithMetadata`.
+    pub fn with_metadata(mut self) -> Self {
+        self.header.include_metadata = true;
+        self
+    }
+
+    // Send `token` as the client's API key with every request, so that a server with API keys
+    // lets it modify the archive.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.header.token = Some(token.into());
+        self
+    }
+
+    // Send every request to the server's collection called `name` rather than its default archive.
+    pub fn with_collection(mut self, name: impl Into<String>) -> Self {
+        self.header.collection = Some(name.into());
+        self
+    }
+
+    // Compress large requests, and let the server compress large responses, with zstd.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self) -> Self {
+        self.header.compression = true;
+        self
+    }
+
+    // Read a response from `reader`, refusing it if it is over the limit the client declared.
+    fn read_response<R: std::io::Read>(&self, reader: R) -> Option<Response> {
+        match self.header.max_response_len {
+            Some(max_len) => Response::read_limited(reader, max_len.saturating_sub(4)),
+            None => Response::from_bytes(reader),
+        }
+    }
+
+    // Helper for the functions below, also usable directly to send any request
     // Convert the request to bytes, send it to the server, read the response to bytes, and convert
     // the response to a Response. If the response is invalid, return `None`.
     //
@@ -31,11 +296,168 @@ impl Client {
     // You can write to the stream with `stream.write_all(&bytes)`.
     // You can read from the stream by calling your `Response::from_bytes` function, since
     // `TcpStream` implements `Read`.
-    fn send(&self, request: &Request) -> Option<Response> {
-        let mut connection = std::net::TcpStream::connect(self.address).ok()?;
-        let bytes = request.to_bytes();
-        connection.write_all(&bytes).ok()?;
-        Response::from_bytes(connection)
+    //
+    // Failed requests are retried as the client's `RetryPolicy` asks. With a standby configured,
+    // a request the active server still fails to answer is retried on the other server, which
+    // becomes the active one if it answers.
+    pub fn send(&self, request: &Request) -> Option<Response> {
+        let bytes = request.to_bytes_with(&self.header);
+        let active = self.active_address();
+        let reached = match self.send_with_retries(active, &bytes, request.is_mutating()) {
+            Ok(Some(response)) => return Some(response),
+            Ok(None) => true,
+            Err(_) => false,
+        };
+        let standby = self.standby?;
+        // A write that reached the failed server may have been applied there already
+        if request.is_mutating() && (reached || !self.failover_writes) {
+            return None;
+        }
+        let other = if active == standby {
+            self.address
+        } else {
+            standby
+        };
+        let response = self
+            .send_with_retries(other, &bytes, request.is_mutating())
+            .ok()
+            .flatten()?;
+        self.on_standby.store(other == standby, Ordering::SeqCst);
+        match &self.on_failover {
+            Some(callback) => callback(active, other),
+            None => tracing::warn!(failed = %active, now = %other, "switched servers"),
+        }
+        Some(response)
+    }
+
+    // Like `send_to`, but retry as the client's `RetryPolicy` asks. A request that modifies the
+    // archive is only retried if it never reached the server. Fail if the last attempt couldn't
+    // reach the server at all.
+    fn send_with_retries(
+        &self,
+        address: SocketAddr,
+        bytes: &[u8],
+        is_mutating: bool,
+    ) -> io::Result<Option<Response>> {
+        let mut retry = 0;
+        loop {
+            let result = self.send_to(address, bytes);
+            let retriable = match &result {
+                Ok(Some(_)) => false,
+                Ok(None) => !is_mutating,
+                Err(_) => true,
+            };
+            if !retriable || retry >= self.retry.max_retries {
+                return result;
+            }
+            thread::sleep(self.retry.backoff(retry));
+            retry += 1;
+        }
+    }
+
+    // Send the encoded request `bytes` to the server at `address` and read its response. Fail if
+    // the server can't be reached, in which case nothing was sent; return None if it didn't answer
+    // with a valid response.
+    fn send_to(&self, address: SocketAddr, bytes: &[u8]) -> io::Result<Option<Response>> {
+        let mut connection = self.connect(address)?;
+        Ok(self.exchange(&mut connection, bytes))
+    }
+
+    // Open a connection to the server at `address`, or over the client's Unix socket if it has
+    // one, with the client's timeouts.
+    fn connect(&self, address: SocketAddr) -> io::Result<Connection> {
+        #[cfg(unix)]
+        if let Some(path) = &self.socket {
+            let connection = std::os::unix::net::UnixStream::connect(path)?;
+            connection.set_read_timeout(self.read_timeout)?;
+            return Ok(Connection::Unix(connection));
+        }
+        let connection = match self.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&address, timeout)?,
+            None => TcpStream::connect(address)?,
+        };
+        connection.set_read_timeout(self.read_timeout)?;
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let server_name = rustls::pki_types::ServerName::from(address.ip());
+            let session = rustls::ClientConnection::new(Arc::clone(config), server_name)
+                .map_err(io::Error::other)?;
+            let connection = rustls::StreamOwned::new(session, connection);
+            return Ok(Connection::Tls(Box::new(connection)));
+        }
+        Ok(Connection::Tcp(connection))
+    }
+
+    // Send every request in `requests` on one connection, without waiting for the response to
+    // each before sending the next, and return their responses in the same order, with None for
+    // any the server didn't answer. The server may answer them in any order, and an async server
+    // answers them concurrently, so requests that depend on each other, like publishing a document
+    // and searching for it, shouldn't be pipelined together. Failed requests aren't retried or
+    // sent to a standby.
+    pub fn pipeline(&self, requests: &[Request]) -> Vec<Option<Response>> {
+        let mut responses = iter::repeat_with(|| None)
+            .take(requests.len())
+            .collect::<Vec<_>>();
+        let Ok(mut connection) = self.connect(self.active_address()) else {
+            return responses;
+        };
+        let mut header = RequestHeader {
+            keep_alive: true,
+            ..self.header.clone()
+        };
+        for (sent, request) in requests.iter().enumerate() {
+            if sent >= PIPELINE_WINDOW && !self.receive(&mut connection, &mut responses) {
+                return responses;
+            }
+            header.request_id = Some(sent as u64);
+            let bytes = request.to_bytes_with(&header);
+            if connection
+                .write_all(&bytes)
+                .and_then(|_| connection.flush())
+                .is_err()
+            {
+                return responses;
+            }
+        }
+        for _ in 0..requests.len().min(PIPELINE_WINDOW) {
+            if !self.receive(&mut connection, &mut responses) {
+                break;
+            }
+        }
+        responses
+    }
+
+    // Read the response to one of the requests `pipeline` sent on `connection` into `responses`,
+    // at the position of the request's id. Return false if the server didn't answer with a valid
+    // response to a request still waiting for one.
+    fn receive(&self, connection: &mut Connection, responses: &mut [Option<Response>]) -> bool {
+        let Some(Response::Tagged {
+            request_id,
+            response,
+        }) = self.read_response(connection)
+        else {
+            return false;
+        };
+        match usize::try_from(request_id)
+            .ok()
+            .and_then(|id| responses.get_mut(id))
+        {
+            Some(waiting @ None) => {
+                *waiting = Some(*response);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Send the encoded request `bytes` on `connection` and read the response, or None if the
+    // server didn't answer with a valid one.
+    fn exchange(&self, connection: &mut Connection, bytes: &[u8]) -> Option<Response> {
+        connection
+            .write_all(bytes)
+            .and_then(|_| connection.flush())
+            .ok()?;
+        self.read_response(connection)
     }
 
     // Read the file at `path` and send a `Publish` request to the server with its contents.
@@ -44,19 +466,687 @@ impl Client {
     // You can read the contents of a file with `let s = std::fs::read_to_string(path)`.
     pub fn publish_from_path(&self, path: &str) -> Option<Response> {
         let doc = std::fs::read_to_string(path).ok()?;
-        let request = Request::Publish { doc : doc };
+        let request = Request::Publish { doc };
+        self.send(&request)
+    }
+    // Like `publish_from_path`, but send a `PublishWith` request so the document is published as
+    // `options` asks.
+    pub fn publish_from_path_with(&self, path: &str, options: PublishOptions) -> Option<Response> {
+        let doc = std::fs::read_to_string(path).ok()?;
+        let request = Request::PublishWith { doc, options };
+        self.send(&request)
+    }
+    // Publish the document read from `reader`, streaming it to the server in chunks so that it
+    // never has to be held in memory whole. Return the response from the server. A streamed
+    // request isn't retried and doesn't fail over, since the reader can't be read again.
+    pub fn publish_from_reader<R: Read>(&self, reader: R) -> Option<Response> {
+        self.publish_from_reader_with(reader, PublishOptions::default())
+    }
+    // Like `publish_from_reader`, but publish the document as `options` asks.
+    pub fn publish_from_reader_with<R: Read>(
+        &self,
+        mut reader: R,
+        options: PublishOptions,
+    ) -> Option<Response> {
+        let mut connection = self.connect(self.active_address()).ok()?;
+        let mut sent = connection.write_all(&publish_stream_start(&self.header, &options));
+        let mut chunk = vec![0_u8; STREAM_CHUNK_LEN];
+        while sent.is_ok() {
+            let len = match reader.read(&mut chunk) {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Hanging up without the empty chunk tells the server the document was cut off
+                Err(_) => return None,
+            };
+            sent = connection.write_all(&stream_chunk(&chunk[..len]));
+            if len == 0 {
+                break;
+            }
+        }
+        // Even if sending failed part way, the server may have said why
+        let _ = connection.flush();
+        self.read_response(&mut connection)
+    }
+    // Publish every text file under the directory at `path`, however deeply nested, in path
+    // order. Files that aren't valid UTF-8 are skipped. Return each file published with the id
+    // the server gave it, or None if the server didn't publish it, or an error if the directory
+    // couldn't be read.
+    pub fn publish_dir<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> io::Result<Vec<(PathBuf, Option<usize>)>> {
+        self.publish_dir_matching(path, "*")
+    }
+    // Like `publish_dir`, but only publish files whose names match the glob `pattern`, in which
+    // `*` stands for any run of characters and `?` for any one character, e.g. `*.txt`.
+    pub fn publish_dir_matching<P: AsRef<Path>>(
+        &self,
+        path: P,
+        pattern: &str,
+    ) -> io::Result<Vec<(PathBuf, Option<usize>)>> {
+        let mut files = Vec::new();
+        find_files(path.as_ref(), &mut files)?;
+        files.retain(|file| {
+            file.file_name()
+                .is_some_and(|name| glob_matches(pattern, &name.to_string_lossy()))
+        });
+        files.sort();
+        let mut published = Vec::new();
+        for file in files {
+            let Ok(doc) = std::fs::read_to_string(&file) else {
+                continue;
+            };
+            let id = match self.send(&Request::Publish { doc }) {
+                Some(Response::PublishSuccess(id)) => Some(id),
+                _ => None,
+            };
+            published.push((file, id));
+        }
+        Ok(published)
+    }
+    // Send a `Stats` request to the server. Return the response from the server.
+    pub fn stats(&self) -> Option<Response> {
+        self.send(&Request::Stats)
+    }
+    // Send a `Ping` request to the server. Return the response from the server.
+    pub fn ping(&self) -> Option<Response> {
+        self.send(&Request::Ping)
+    }
+    // Send a `Shutdown` request to the server. Return the response from the server.
+    pub fn shutdown(&self) -> Option<Response> {
+        self.send(&Request::Shutdown)
+    }
+    // Send a `Snapshot` request to the server. Return the response from the server.
+    pub fn snapshot(&self) -> Option<Response> {
+        self.send(&Request::Snapshot)
+    }
+    // Send an `Export` request to the server, asking it to dump the archive to the file called
+    // `path` in the server's data directory. Return the response from the server.
+    pub fn export(&self, path: &str) -> Option<Response> {
+        self.send(&Request::Export {
+            path: path.to_string(),
+        })
+    }
+    // Send an `Import` request to the server, asking it to publish every document in the dump
+    // called `path` in the server's data directory. Return the response from the server.
+    pub fn import(&self, path: &str) -> Option<Response> {
+        self.send(&Request::Import {
+            path: path.to_string(),
+        })
+    }
+    // Send a `CreateCollection` request to the server for an empty collection called `name`.
+    // Return the response from the server.
+    pub fn create_collection(&self, name: &str) -> Option<Response> {
+        self.send(&Request::CreateCollection {
+            name: name.to_string(),
+        })
+    }
+    // Send a `ListCollections` request to the server. Return the response from the server.
+    pub fn collections(&self) -> Option<Response> {
+        self.send(&Request::ListCollections)
+    }
+    // Send a `ResizePool` request to the server, asking for `workers` threads in its pool. Return
+    // the response from the server.
+    pub fn resize_pool(&self, workers: usize) -> Option<Response> {
+        self.send(&Request::ResizePool { workers })
+    }
+    // Send a `TermStats` request to the server for up to `limit` words after `after`. Return the
+    // response from the server.
+    pub fn term_stats(&self, after: Option<&str>, limit: usize) -> Option<Response> {
+        let request = Request::TermStats {
+            after: after.map(str::to_string),
+            limit,
+        };
+        self.send(&request)
+    }
+    // Fetch the statistics of every indexed word from the server, `page_size` words per request,
+    // and pass each word with its document frequency and total occurrences to `visit` as it
+    // arrives. Return the number of words visited, or an error if the server doesn't answer
+    // with term statistics.
+    pub fn export_term_stats<F: FnMut(&str, usize, usize)>(
+        &self,
+        page_size: usize,
+        mut visit: F,
+    ) -> io::Result<usize> {
+        let limit = page_size.max(1);
+        let mut after = None;
+        let mut count = 0;
+        loop {
+            let (mut terms, complete) = match self.term_stats(after.as_deref(), limit) {
+                Some(Response::TermStatsSuccess(terms)) => {
+                    let complete = terms.len() < limit;
+                    (terms, complete)
+                }
+                // A cut-down page is still good up to where it stops
+                Some(Response::Truncated(response)) => match *response {
+                    Response::TermStatsSuccess(terms) if !terms.is_empty() => (terms, false),
+                    _ => return Err(io::Error::other("term statistics don't fit in a response")),
+                },
+                Some(response) => return Err(unexpected(response)),
+                None => return Err(io::Error::other("failed to get response from server")),
+            };
+            for (term, doc_frequency, occurrences) in terms.iter() {
+                visit(term, *doc_frequency, *occurrences);
+            }
+            count += terms.len();
+            if complete {
+                return Ok(count);
+            }
+            after = terms.pop().map(|(term, _, _)| term);
+        }
+    }
+    // Read every file in `paths` and send a `PublishBatch` request to the server with their
+    // contents, so that they are published all together or not at all. Nothing is sent if any of
+    // the files can't be read. Return the response from the server.
+    pub fn publish_batch_from_paths(
+        &self,
+        paths: &[String],
+        options: PublishOptions,
+    ) -> Option<Response> {
+        let docs = paths
+            .iter()
+            .map(std::fs::read_to_string)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        let request = Request::PublishBatch { docs, options };
+        self.send(&request)
+    }
+    // Send a `Commit` request to the server for the pending document `id`. Return the response
+    // from the server.
+    pub fn commit(&self, id: usize) -> Option<Response> {
+        let request = Request::Commit { id };
+        self.send(&request)
+    }
+    // Read the file at `path` and send an `Update` request to the server replacing the document
+    // `id` with its contents. Return the response from the server.
+    pub fn update_from_path(&self, id: usize, path: &str) -> Option<Response> {
+        let doc = std::fs::read_to_string(path).ok()?;
+        let request = Request::Update { id, doc };
         self.send(&request)
     }
     // Send a `Search` request to the server with the given `word`. Return the response from the
     // server.
     pub fn search(&self, word: &str) -> Option<Response> {
-        let request = Request::Search { word : (*word).to_string() };
+        let request = Request::Search {
+            word: (*word).to_string(),
+        };
+        self.send(&request)
+    }
+    // Send a `Count` request to the server for the given `word`. Return the response from the
+    // server.
+    pub fn count(&self, word: &str) -> Option<Response> {
+        self.send(&Request::Count {
+            word: word.to_string(),
+        })
+    }
+    // Send a `SearchWith` request to the server for the given `word`, filtered and ordered as
+    // `options` asks. Return the response from the server.
+    pub fn search_with(&self, word: &str, options: SearchOptions) -> Option<Response> {
+        let request = Request::SearchWith {
+            word: word.to_string(),
+            options,
+        };
+        self.send(&request)
+    }
+    // Send a `SearchPrefix` request to the server for documents containing a word starting with
+    // `prefix`. Return the response from the server.
+    pub fn search_prefix(&self, prefix: &str) -> Option<Response> {
+        let request = Request::SearchPrefix {
+            prefix: prefix.to_string(),
+        };
+        self.send(&request)
+    }
+    // Send a `SearchSubstring` request to the server for documents containing `text` anywhere.
+    // Return the response from the server.
+    pub fn search_substring(&self, text: &str) -> Option<Response> {
+        let request = Request::SearchSubstring {
+            text: text.to_string(),
+        };
+        self.send(&request)
+    }
+    // Send a `Query` request to the server for documents matching the boolean query `expr`, e.g.
+    // `whale AND (ship OR boat) NOT harpoon`. Return the response from the server.
+    pub fn query(&self, expr: &str) -> Option<Response> {
+        let request = Request::Query {
+            expr: expr.to_string(),
+        };
+        self.send(&request)
+    }
+    // Send a `SearchRanked` request to the server for the `k` documents most relevant to `word`.
+    // Return the response from the server.
+    pub fn search_ranked(&self, word: &str, k: usize) -> Option<Response> {
+        let request = Request::SearchRanked {
+            word: word.to_string(),
+            k,
+        };
         self.send(&request)
     }
     // Send a `Retrieve` request to the server with the given `id`. Return the response from the
     // server.
     pub fn retrieve(&self, id: usize) -> Option<Response> {
-        let request = Request::Retrieve { id : id };
+        self.retrieve_range(id, 0, None)
+    }
+    // Like `retrieve`, but only fetch the `length` bytes of the document from `offset` on, or
+    // everything from `offset` on if `length` is None, e.g. the first few kilobytes for a
+    // preview. The server cuts the range short at the end of the document, so that it ends on a
+    // whole character, and answers `OutOfRange` if `offset` is past the end or inside a
+    // character.
+    pub fn retrieve_range(
+        &self,
+        id: usize,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Option<Response> {
+        let request = Request::Retrieve { id, offset, length };
         self.send(&request)
     }
+    // Like `retrieve`, but have the server stream the document, and write it to `writer` as it
+    // arrives rather than holding it in memory. Return how many bytes were written, or the
+    // response from the server if it didn't send the document, e.g. because there is no document
+    // with that id. Fails if the server can't be reached, doesn't answer with a valid response,
+    // or `writer` fails. The request isn't retried and doesn't fail over, since part of the
+    // document may already have been written.
+    pub fn retrieve_to_writer<W: Write>(
+        &self,
+        id: usize,
+        writer: W,
+    ) -> io::Result<Result<u64, Response>> {
+        self.retrieve_range_to_writer(id, 0, None, writer)
+    }
+    // Like `retrieve_to_writer`, but only write the part of the document that `retrieve_range`
+    // would return.
+    pub fn retrieve_range_to_writer<W: Write>(
+        &self,
+        id: usize,
+        offset: usize,
+        length: Option<usize>,
+        writer: W,
+    ) -> io::Result<Result<u64, Response>> {
+        let header = RequestHeader {
+            stream_documents: true,
+            ..self.header.clone()
+        };
+        let mut connection = self.connect(self.active_address())?;
+        let request = Request::Retrieve { id, offset, length };
+        connection.write_all(&request.to_bytes_with(&header))?;
+        connection.flush()?;
+        let max_len = self
+            .header
+            .max_response_len
+            .map_or(MAX_FRAME_LEN, |max_len| max_len.saturating_sub(4));
+        Response::read_streamed(&mut connection, writer, max_len)
+    }
+    // Search for `word` and retrieve the first `limit` matching documents, in the order the
+    // search returns them, or just a snippet of about `snippet_words` words of context around the
+    // word in each if `snippet_words` is given. Return each document's id with its text or
+    // snippet, or an error if the server doesn't answer with results. Documents removed between
+    // the search and their retrieval are left out.
+    pub fn search_and_retrieve(
+        &self,
+        word: &str,
+        limit: usize,
+        snippet_words: Option<usize>,
+    ) -> io::Result<Vec<(usize, String)>> {
+        let options = SearchOptions {
+            snippet_words,
+            limit: Some(limit),
+            ..SearchOptions::default()
+        };
+        let ids = match self.search_with(word, options).map(unwrap_results) {
+            Some(Response::SearchSuccess(ids)) => ids,
+            Some(Response::SearchSnippetsSuccess(results)) => return Ok(results),
+            Some(response) => return Err(unexpected(response)),
+            None => return Err(io::Error::other("failed to get response from server")),
+        };
+        let mut documents = Vec::with_capacity(ids.len());
+        for id in ids {
+            match self.retrieve(id).map(unwrap_results) {
+                Some(Response::RetrieveSuccess(doc)) => documents.push((id, doc)),
+                Some(Response::Failure {
+                    code: ErrorCode::NotFound,
+                    ..
+                }) => {}
+                Some(response) => return Err(unexpected(response)),
+                None => return Err(io::Error::other("failed to get response from server")),
+            }
+        }
+        Ok(documents)
+    }
+    // Send a `List` request to the server, asking for a preview of the first `preview_chars`
+    // characters of each document. Return the response from the server.
+    pub fn list(&self, preview_chars: usize) -> Option<Response> {
+        let request = Request::List { preview_chars };
+        self.send(&request)
+    }
+    // Send a `SuggestTerms` request to the server for up to `limit` words starting with `prefix`.
+    // Return the response from the server.
+    pub fn suggest(&self, prefix: &str, limit: usize) -> Option<Response> {
+        let request = Request::SuggestTerms {
+            prefix: prefix.to_string(),
+            limit,
+        };
+        self.send(&request)
+    }
+    // Send a `Subscribe` request to the server for documents containing `word`, on a connection of
+    // its own. Return the subscription if the server accepted it; otherwise fail with the server's
+    // response, or None if it didn't answer.
+    //
+    // The subscription yields each matching document as it is published, committed, or updated.
+    // It ends when the server stops, or, if the client has a read timeout, once nothing matches
+    // for that long.
+    pub fn subscribe(&self, word: &str) -> Result<Subscription, Option<Response>> {
+        let request = Request::Subscribe {
+            word: word.to_string(),
+        };
+        let mut connection = self.connect(self.active_address()).map_err(|_| None)?;
+        match self.exchange(&mut connection, &request.to_bytes_with(&self.header)) {
+            Some(Response::Subscribed(_)) => Ok(Subscription {
+                connection,
+                max_len: self.header.max_response_len,
+            }),
+            response => Err(response),
+        }
+    }
+}
+
+/// Documents containing a word, pushed by the server as they are published. Iterating yields the
+/// id of each document and a preview of its start
+pub struct Subscription {
+    connection: Connection,
+    /// The longest notification the client accepts, as declared to the server
+    max_len: Option<usize>,
+}
+
+impl Iterator for Subscription {
+    type Item = (usize, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let response = match self.max_len {
+            Some(max_len) => {
+                Response::read_limited(&mut self.connection, max_len.saturating_sub(4))
+            }
+            None => Response::from_bytes(&mut self.connection),
+        };
+        match response? {
+            Response::Notification { id, preview } => Some((id, preview)),
+            _ => None,
+        }
+    }
+}
+
+/// A fixed number of connections to one server, kept open and shared by concurrent callers so
+/// that each request doesn't pay for a new connection. Connections are opened as they are first
+/// needed; a caller that finds them all in use waits for one to be returned
+pub struct ClientPool {
+    /// Opens the pool's connections and encodes its requests
+    client: Client,
+    /// The most connections the pool keeps open at once
+    size: usize,
+    connections: Mutex<Connections>,
+    /// Notified whenever a connection is returned to the pool or closed
+    returned: Condvar,
+}
+
+// The connections of a pool that are waiting to be used, and how many it has open in all.
+struct Connections {
+    idle: Vec<Connection>,
+    open: usize,
+}
+
+impl ClientPool {
+    // Create a pool of up to `size` connections, which must be at least one, opened as `client`
+    // would open them. Requests are sent with `client`'s header, but the pool doesn't fail over
+    // to a standby or retry failed requests, except to resend a request that doesn't modify the
+    // archive when a connection turns out to have been closed by the server while idle.
+    //
+    // Each connection ties up one of a blocking server's worker threads for as long as it is
+    // open, so the pool should be smaller than the server's thread pool. The server closes
+    // connections that sit idle for longer than its read timeout.
+    pub fn new(mut client: Client, size: usize) -> Self {
+        assert!(size > 0, "a pool must hold at least one connection");
+        client.header.keep_alive = true;
+        Self {
+            client,
+            size,
+            connections: Mutex::new(Connections {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// The most connections the pool keeps open at once
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Send `request` on one of the pool's connections and read the response, waiting for a
+    // connection to be free if they are all in use. Return None if a connection couldn't be
+    // opened or the server didn't answer with a valid response.
+    pub fn send(&self, request: &Request) -> Option<Response> {
+        let bytes = request.to_bytes_with(&self.client.header);
+        loop {
+            let (mut connection, reused) = self.check_out()?;
+            if let Some(response) = self.client.exchange(&mut connection, &bytes) {
+                self.check_in(connection);
+                return Some(response);
+            }
+            self.close();
+            // A write that failed on a kept connection may still have been applied
+            if !reused || request.is_mutating() {
+                return None;
+            }
+        }
+    }
+
+    // Like `Client::publish_from_path`, but on one of the pool's connections.
+    pub fn publish_from_path(&self, path: &str) -> Option<Response> {
+        let doc = std::fs::read_to_string(path).ok()?;
+        self.send(&Request::Publish { doc })
+    }
+
+    // Like `Client::search`, but on one of the pool's connections.
+    pub fn search(&self, word: &str) -> Option<Response> {
+        self.send(&Request::Search {
+            word: word.to_string(),
+        })
+    }
+
+    // Like `Client::search_with`, but on one of the pool's connections.
+    pub fn search_with(&self, word: &str, options: SearchOptions) -> Option<Response> {
+        self.send(&Request::SearchWith {
+            word: word.to_string(),
+            options,
+        })
+    }
+
+    // Like `Client::retrieve`, but on one of the pool's connections.
+    pub fn retrieve(&self, id: usize) -> Option<Response> {
+        self.send(&Request::Retrieve {
+            id,
+            offset: 0,
+            length: None,
+        })
+    }
+
+    // Take a connection for one request: an idle one if there is one that the server hasn't
+    // closed, or else a new one if the pool has room for it, or else the next one returned. Also
+    // return whether the connection was used before. Return None if a new connection couldn't be
+    // opened.
+    fn check_out(&self) -> Option<(Connection, bool)> {
+        let mut connections = sync::lock(&self.connections);
+        loop {
+            while let Some(connection) = connections.idle.pop() {
+                if connection.is_quiet() {
+                    return Some((connection, true));
+                }
+                connections.open -= 1;
+            }
+            if connections.open < self.size {
+                connections.open += 1;
+                drop(connections);
+                let address = self.client.active_address();
+                return match self.client.connect(address) {
+                    Ok(connection) => Some((connection, false)),
+                    Err(_) => {
+                        self.close();
+                        None
+                    }
+                };
+            }
+            connections = sync::wait(&self.returned, connections);
+        }
+    }
+
+    // Return `connection` to the pool after a request was answered on it.
+    fn check_in(&self, connection: Connection) {
+        sync::lock(&self.connections).idle.push(connection);
+        self.returned.notify_one();
+    }
+
+    // Count a checked out connection as closed, making room for another.
+    fn close(&self) {
+        sync::lock(&self.connections).open -= 1;
+        self.returned.notify_one();
+    }
+}
+
+// An open connection to a server, over whichever transport the client uses.
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    // Whether nothing is waiting to be read from the connection, as should be the case between
+    // requests. A server never sends anything unasked, so a connection with bytes to read, or
+    // that the server has closed, can't be used for another request.
+    fn is_quiet(&self) -> bool {
+        match self {
+            Connection::Tcp(stream) => is_quiet(stream, TcpStream::set_nonblocking),
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                is_quiet(stream, std::os::unix::net::UnixStream::set_nonblocking)
+            }
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => is_quiet(&stream.sock, TcpStream::set_nonblocking),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// Whether a read of `socket` would block, switching it to nonblocking mode with `set_nonblocking`
+// to find out without waiting.
+fn is_quiet<S>(socket: &S, set_nonblocking: fn(&S, bool) -> io::Result<()>) -> bool
+where
+    for<'a> &'a S: Read,
+{
+    if set_nonblocking(socket, true).is_err() {
+        return false;
+    }
+    let mut reader = socket;
+    let quiet = matches!(
+        reader.read(&mut [0_u8; 1]),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    );
+    set_nonblocking(socket, false).is_ok() && quiet
+}
+
+// The results in `response`, without the page or the metadata they came with.
+fn unwrap_results(response: Response) -> Response {
+    match response {
+        Response::Paged { response, .. } | Response::WithMetadata { response, .. } => {
+            unwrap_results(*response)
+        }
+        response => response,
+    }
+}
+
+// An error for a response that isn't the kind asked for.
+fn unexpected(response: Response) -> io::Error {
+    io::Error::other(format!("unexpected response {:?}", response))
+}
+
+// Add the path of every file under the directory `dir` to `files`. Symbolic links to directories
+// aren't followed, so a link back up the tree can't loop forever.
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Whether `name` matches the glob `pattern`, where `*` matches any run of characters and `?` any
+// one character. After a mismatch, only the most recent `*` needs to be tried with one more
+// character, so this never backtracks further than that.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` seen, and of the name when it was
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
 }
@@ -0,0 +1,155 @@
+use super::*;
+use crate::message::read_frame_async;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+// A client that sends requests over tokio's `TcpStream`, so that an async program can wait on the
+// server without tying up a thread. It speaks the same protocol as the blocking `Client`, one
+// connection per request, and retries the same way, but doesn't support Unix sockets, TLS or
+// standby servers yet.
+
+/// A client for the server at `address` whose requests can be awaited
+pub struct AsyncClient {
+    address: SocketAddr,
+    /// Sent with every request, e.g. to limit the size of responses
+    header: RequestHeader,
+    /// The longest to wait for a connection to the server, or None to wait as long as the OS does
+    connect_timeout: Option<Duration>,
+    /// The longest to wait for a whole response, or None to wait forever
+    read_timeout: Option<Duration>,
+    /// How to retry requests that fail for reasons that may pass
+    retry: RetryPolicy,
+}
+
+impl AsyncClient {
+    // Create a client that will connect to the server at `address` and `port`, which are read as
+    // `Client::new` reads them. Use `Client::builder(address, port).build_async()` to give it
+    // timeouts or retry failed requests.
+    pub fn new(address: &str, port: u16) -> Self {
+        Client::builder(address, port).build_async()
+    }
+
+    // Build a client with the settings in `builder`, which must not be for a Unix socket.
+    pub(super) fn from_builder(builder: ClientBuilder) -> Self {
+        #[cfg(unix)]
+        assert!(
+            builder.socket.is_none(),
+            "an async client can't connect over a Unix socket"
+        );
+        Self {
+            address: builder.address,
+            header: RequestHeader::default(),
+            connect_timeout: builder.connect_timeout,
+            read_timeout: builder.read_timeout,
+            retry: builder.retry,
+        }
+    }
+
+    // Send `token` as the client's API key with every request, as `Client::with_token` does.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.header.token = Some(token.into());
+        self
+    }
+
+    // Ask the server never to send a response of more than `max_len` bytes, as
+    // `Client::with_max_response_len` does.
+    pub fn with_max_response_len(mut self, max_len: usize, allow_truncation: bool) -> Self {
+        self.header.max_response_len = Some(max_len);
+        self.header.allow_truncation = allow_truncation;
+        self
+    }
+
+    // Send `request` to the server and await its response, retrying as the client's
+    // `RetryPolicy` asks. Return None if the server couldn't be reached or didn't answer with a
+    // valid response.
+    pub async fn send(&self, request: &Request) -> Option<Response> {
+        let bytes = request.to_bytes_with(&self.header);
+        let mut retry = 0;
+        loop {
+            let result = self.send_once(&bytes).await;
+            let retriable = match &result {
+                Ok(Some(_)) => false,
+                Ok(None) => !request.is_mutating(),
+                Err(_) => true,
+            };
+            if !retriable || retry >= self.retry.max_retries {
+                return result.ok().flatten();
+            }
+            tokio::time::sleep(self.retry.backoff(retry)).await;
+            retry += 1;
+        }
+    }
+
+    // Send the encoded request `bytes` to the server and read its response. Fail if the server
+    // can't be reached, in which case nothing was sent; return None if it didn't answer with a
+    // valid response.
+    async fn send_once(&self, bytes: &[u8]) -> io::Result<Option<Response>> {
+        let connect = TcpStream::connect(self.address);
+        let mut stream = match self.connect_timeout {
+            Some(limit) => timeout(limit, connect)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+            None => connect.await?,
+        };
+        if stream.write_all(bytes).await.is_err() {
+            return Ok(None);
+        }
+        let max_len = self
+            .header
+            .max_response_len
+            .map_or(MAX_FRAME_LEN, |max_len| max_len.saturating_sub(4));
+        let read = read_frame_async(&mut stream, max_len);
+        let frame = match self.read_timeout {
+            Some(limit) => timeout(limit, read).await.ok().and_then(Result::ok),
+            None => read.await.ok(),
+        };
+        Ok(frame.and_then(|frame| Response::read_limited(&frame[..], max_len)))
+    }
+
+    // Publish `doc` and return the response from the server.
+    pub async fn publish(&self, doc: impl Into<String>) -> Option<Response> {
+        self.send(&Request::Publish { doc: doc.into() }).await
+    }
+
+    // Publish `doc` as `options` asks and return the response from the server.
+    pub async fn publish_with(
+        &self,
+        doc: impl Into<String>,
+        options: PublishOptions,
+    ) -> Option<Response> {
+        let request = Request::PublishWith {
+            doc: doc.into(),
+            options,
+        };
+        self.send(&request).await
+    }
+
+    // Search for `word` and return the response from the server.
+    pub async fn search(&self, word: &str) -> Option<Response> {
+        let request = Request::Search {
+            word: word.to_string(),
+        };
+        self.send(&request).await
+    }
+
+    // Search for `word`, filtering and ordering the results as `options` asks, and return the
+    // response from the server.
+    pub async fn search_with(&self, word: &str, options: SearchOptions) -> Option<Response> {
+        let request = Request::SearchWith {
+            word: word.to_string(),
+            options,
+        };
+        self.send(&request).await
+    }
+
+    // Retrieve the document with the index `id` and return the response from the server.
+    pub async fn retrieve(&self, id: usize) -> Option<Response> {
+        let request = Request::Retrieve {
+            id,
+            offset: 0,
+            length: None,
+        };
+        self.send(&request).await
+    }
+}
@@ -1,59 +1,1737 @@
-use crate::multimap::ConcurrentMultiMap;
-use std::sync::Mutex;
+use crate::analyzer::{Analyzer, Pipeline};
+use crate::blob_store::BlobStore;
+use crate::index::SegmentedIndex;
+use crate::query::Query;
+use crate::sync;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, Read, Write};
+use std::mem::size_of;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-// The archive struct contains two data structures: a ConcurrentMultiMap for storing the
-// reverse index that maps words to the documents they appear in, and a Mutex<Vec<String>> for
-// storing the documents themselves. Since the documents themselves aren't accessed as often, it's
-// ok to keep them behind a single mutex.
+// The archive struct contains two data structures: a SegmentedIndex for storing the reverse index
+// that maps words to the documents they appear in, and a BlobStore for storing the documents
+// themselves. The blob store is sharded, so publishes and retrieves of different documents don't
+// wait for each other, and documents are split into words and compressed before it is touched at
+// all. Metadata is kept in a table of its own, since most documents have none. Substring search
+// can optionally be sped up by a second SegmentedIndex, mapping character n-grams rather than
+// words to documents.
 
 /// A document database that allows clients to publish documents and
 /// search for documents containing specific words.
 pub struct Database {
     /// A map from words to the set of documents that contain them
-    reverse_index: ConcurrentMultiMap<String, usize>,
+    reverse_index: SegmentedIndex,
     /// A store of all documents in the database
-    blob_store: Mutex<Vec<String>>,
+    blob_store: BlobStore<Document>,
+    /// The metadata of each document that was published with any. Lock after `blob_store`
+    metadata: Mutex<HashMap<usize, Metadata>>,
+    /// A map from character n-grams to the documents that contain them, if substring search is
+    /// indexed
+    ngram_index: Option<NgramIndex>,
+    /// Words too common to be worth indexing, in lowercase. They are left out of the reverse index
+    /// and searching for one finds nothing
+    stop_words: HashSet<String>,
+    /// Turns the words of documents and queries into the terms they are indexed and searched under
+    analyzer: Box<dyn Analyzer>,
+    /// The zstd level to compress documents at as they are stored, or None to store them as they
+    /// are
+    compression_level: Option<i32>,
+    /// What publishing a copy of a stored document does
+    duplicates: Duplicates,
+    /// The ids of the documents with each content hash, kept unless duplicates are allowed. Lock
+    /// before `blob_store`
+    content_hashes: Mutex<HashMap<u64, Vec<usize>>>,
+    /// Roughly how many bytes the documents in the blob store take up
+    blob_store_memory: AtomicUsize,
+    /// Roughly how many bytes the postings in the reverse index and n-gram index take up
+    index_memory: AtomicUsize,
 }
 
-const BUCKETS: usize = 128;
+/// Common English words that the server leaves out of its index unless told otherwise
+pub const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "has", "have", "he",
+    "her", "his", "i", "if", "in", "into", "is", "it", "its", "of", "on", "or", "she", "so",
+    "that", "the", "their", "then", "there", "these", "they", "this", "to", "was", "were", "will",
+    "with",
+];
+
+/// An index of the character n-grams in each searchable document
+struct NgramIndex {
+    /// The number of characters in each n-gram
+    n: usize,
+    /// A map from n-grams to the set of documents that contain them
+    index: SegmentedIndex,
+}
+
+impl NgramIndex {
+    // Get the n-grams of the lowercased `text`, along with its last n - 1 suffixes, which are
+    // shorter. Every substring of the text shorter than n is then a prefix of one of them.
+    fn ngrams(&self, text: &str) -> HashSet<String> {
+        let chars: Vec<char> = text.to_lowercase().chars().collect();
+        (0..chars.len())
+            .map(|i| chars[i..chars.len().min(i + self.n)].iter().collect())
+            .collect()
+    }
+
+    // Get the documents that might contain the lowercased `query`: all of those that do, and
+    // possibly some that have its n-grams in other places.
+    fn candidates(&self, query: &str) -> Vec<usize> {
+        let chars: Vec<char> = query.chars().collect();
+        if chars.len() < self.n {
+            return self.index.get_prefix(query);
+        }
+        let mut ids = self.index.get(&chars[..self.n].iter().collect::<String>());
+        for gram in chars.windows(self.n).skip(1) {
+            if ids.is_empty() {
+                break;
+            }
+            let found = self.index.get(&gram.iter().collect::<String>());
+            ids.retain(|id| found.binary_search(id).is_ok());
+        }
+        ids
+    }
+}
+
+/// The text of a document, as it is kept in the blob store
+#[derive(Clone)]
+enum StoredText {
+    /// The text as it was published
+    Plain(String),
+    /// The text compressed with zstd
+    #[cfg(feature = "compression")]
+    Compressed(Vec<u8>),
+}
+
+impl StoredText {
+    // Store `text`, compressed at `compression_level` if there is one and it makes the text any
+    // smaller.
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn new(text: String, compression_level: Option<i32>) -> Self {
+        #[cfg(feature = "compression")]
+        if let Some(level) = compression_level {
+            match zstd::bulk::compress(text.as_bytes(), level) {
+                Ok(compressed) if compressed.len() < text.len() => {
+                    return StoredText::Compressed(compressed)
+                }
+                _ => {}
+            }
+        }
+        StoredText::Plain(text)
+    }
+
+    // The text, decompressed if need be.
+    fn get(&self) -> Cow<'_, str> {
+        match self {
+            StoredText::Plain(text) => Cow::Borrowed(text),
+            #[cfg(feature = "compression")]
+            StoredText::Compressed(compressed) => {
+                let bytes = zstd::stream::decode_all(&compressed[..])
+                    .expect("documents are compressed when they are stored");
+                Cow::Owned(String::from_utf8(bytes).expect("documents are valid UTF-8"))
+            }
+        }
+    }
+
+    fn into_string(self) -> String {
+        match self {
+            StoredText::Plain(text) => text,
+            #[cfg(feature = "compression")]
+            StoredText::Compressed(_) => self.get().into_owned(),
+        }
+    }
+
+    // How many bytes the text takes up in the blob store.
+    fn stored_len(&self) -> usize {
+        match self {
+            StoredText::Plain(text) => text.len(),
+            #[cfg(feature = "compression")]
+            StoredText::Compressed(compressed) => compressed.len(),
+        }
+    }
+}
+
+/// A document in the blob store
+struct Document {
+    /// The full text of the document, compressed if the database compresses documents
+    text: StoredText,
+    /// When the document was published, in seconds since the Unix epoch
+    published_at: u64,
+    /// How many times each indexed word appears in the document
+    term_counts: HashMap<String, usize>,
+    /// The total number of indexed words in the document
+    word_count: usize,
+    /// Whether the document has been stored but not yet committed, and so can't be found by
+    /// searching
+    pending: bool,
+    /// The kind of text the document holds
+    content_type: ContentType,
+}
+
+impl Document {
+    // Count `terms`, the terms `text` is indexed under, for a document published at
+    // `published_at` as `options` asks.
+    fn new(
+        text: StoredText,
+        terms: Vec<String>,
+        published_at: u64,
+        options: &PublishOptions,
+    ) -> Self {
+        let (term_counts, word_count) = count_terms(terms);
+        Self {
+            text,
+            published_at,
+            term_counts,
+            word_count,
+            pending: options.pending,
+            content_type: options.content_type,
+        }
+    }
+
+    // Roughly how many bytes the document takes up in the blob store: its text as stored, its
+    // word counts, and its own slot.
+    fn memory_size(&self) -> usize {
+        let term_counts = self
+            .term_counts
+            .keys()
+            .map(|term| term.len() + size_of::<(String, usize)>())
+            .sum::<usize>();
+        size_of::<Option<Document>>() + self.text.stored_len() + term_counts
+    }
+}
+
+/// Filters and ordering for a search
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchOptions {
+    /// Only match documents published at or after this time, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    /// Only match documents published at or before this time, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<u64>,
+    /// Order results from most to least recently published, instead of by id
+    #[serde(default)]
+    pub newest_first: bool,
+    /// Leave out documents containing any of these words
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Only match documents of this type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<ContentType>,
+    /// Return a snippet of about this many words of context around the word in each document,
+    /// instead of just the document's id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snippet_words: Option<usize>,
+    /// Skip this many matches, e.g. those already shown on earlier pages
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: usize,
+    /// Return at most this many matches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl SearchOptions {
+    /// Whether these options ask for one page of the matches rather than all of them
+    pub fn is_paged(&self) -> bool {
+        self.offset > 0 || self.limit.is_some()
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// How a document should be published
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishOptions {
+    /// Store the document without making it searchable until it is committed with
+    /// `Database::commit`
+    #[serde(default)]
+    pub pending: bool,
+    /// Descriptive information to store with the document
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+    /// The kind of text the document holds, which decides how it is split into words
+    #[serde(default)]
+    pub content_type: ContentType,
+}
+
+/// The kind of text a document holds. Each kind is split into words its own way, so that e.g.
+/// identifiers in code can be found by the words they are made of.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    /// Prose, split on whitespace
+    #[default]
+    Plain,
+    /// Markdown, split on whitespace with formatting characters removed from around words
+    Markdown,
+    /// Source code, split into identifiers, each also indexed by its `snake_case` or `camelCase`
+    /// parts
+    Code,
+}
+
+/// Characters that Markdown uses for formatting around words
+const MARKDOWN_SYNTAX: &[char] = &[
+    '#', '*', '_', '`', '>', '[', ']', '(', ')', '!', '~', '|', '-', '+', ':',
+];
+
+impl ContentType {
+    /// Every content type, in the order of their codes
+    pub const ALL: [ContentType; 3] =
+        [ContentType::Plain, ContentType::Markdown, ContentType::Code];
+
+    /// The number that stands for this type when it is saved or sent over the network
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// The content type with the given code, if there is one
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.get(code as usize).copied()
+    }
+
+    // Split `text` into the lowercase words it should be indexed under, in order. Words may
+    // repeat.
+    pub fn tokenize(self, text: &str) -> Vec<String> {
+        self.split(text)
+            .into_iter()
+            .map(|word| word.to_lowercase())
+            .collect()
+    }
+
+    // Split `text` into words as they are written, in order, for an analyzer to turn into terms.
+    // Words may repeat.
+    pub fn split(self, text: &str) -> Vec<String> {
+        match self {
+            ContentType::Plain => text.split_whitespace().map(str::to_string).collect(),
+            ContentType::Markdown => text
+                .split_whitespace()
+                // Link text and its URL are separate words
+                .flat_map(|word| word.split("]("))
+                .map(|word| word.trim_matches(MARKDOWN_SYNTAX))
+                .filter(|cleaned_word| !cleaned_word.is_empty())
+                .map(str::to_string)
+                .collect(),
+            ContentType::Code => {
+                let mut words = Vec::new();
+                let identifiers = text
+                    .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .filter(|identifier| !identifier.trim_matches('_').is_empty());
+                for identifier in identifiers {
+                    words.push(identifier.to_string());
+                    let parts = identifier_parts(identifier);
+                    if parts.len() > 1 {
+                        words.extend(parts);
+                    }
+                }
+                words
+            }
+        }
+    }
+}
+
+// Split an identifier into its parts, at underscores and at camelCase boundaries. A run
+// of capitals is one part, except for its last letter if a lowercase letter follows, so
+// `parseHTTPRequest` splits into `parse`, `http` and `request`.
+fn identifier_parts(identifier: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    for word in identifier.split('_').filter(|word| !word.is_empty()) {
+        let chars: Vec<char> = word.chars().collect();
+        let mut start = 0;
+        for i in 1..chars.len() {
+            let (prev, c) = (chars[i - 1], chars[i]);
+            let next_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            if c.is_uppercase() && (!prev.is_uppercase() || next_lower) {
+                parts.push(chars[start..i].iter().collect());
+                start = i;
+            }
+        }
+        parts.push(chars[start..].iter().collect());
+    }
+    parts
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ContentType::Plain => "plain",
+            ContentType::Markdown => "markdown",
+            ContentType::Code => "code",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" | "text" => Ok(ContentType::Plain),
+            "markdown" | "md" => Ok(ContentType::Markdown),
+            "code" => Ok(ContentType::Code),
+            _ => Err(format!(
+                "unknown content type {:?}; expected plain, markdown or code",
+                s
+            )),
+        }
+    }
+}
+
+/// What publishing a document that is a copy of one already in the archive, with the same text
+/// and content type, does
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Store the copy under a new id, like any other document
+    #[default]
+    Allow,
+    /// Store nothing, and return the id of the document already there
+    Reuse,
+    /// Store nothing, and fail with the id of the document already there
+    Reject,
+}
+
+impl Duplicates {
+    // The policy for callers that have no way to report a rejected copy, which reuse it instead.
+    fn without_rejecting(self) -> Self {
+        match self {
+            Duplicates::Reject => Duplicates::Reuse,
+            duplicates => duplicates,
+        }
+    }
+
+    // What publishing a copy of the document with id `id` returns.
+    fn copy_of(self, id: usize) -> Result<usize, Duplicate> {
+        match self {
+            Duplicates::Reject => Err(Duplicate(id)),
+            _ => Ok(id),
+        }
+    }
+}
+
+impl fmt::Display for Duplicates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Duplicates::Allow => "allow",
+            Duplicates::Reuse => "reuse",
+            Duplicates::Reject => "reject",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Duplicates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "allow" => Ok(Duplicates::Allow),
+            "reuse" => Ok(Duplicates::Reuse),
+            "reject" => Ok(Duplicates::Reject),
+            _ => Err(format!(
+                "unknown duplicate policy {:?}; expected allow, reuse or reject",
+                s
+            )),
+        }
+    }
+}
+
+// Roughly how many bytes postings of a document under each of `terms` take up in an index.
+fn postings_size<'a, I: IntoIterator<Item = &'a String>>(terms: I) -> usize {
+    terms
+        .into_iter()
+        .map(|term| term.len() + size_of::<(String, usize)>())
+        .sum()
+}
+
+// A hash of a document's text and content type, to find copies of it by.
+fn content_hash(text: &str, content_type: ContentType) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (text, content_type).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Descriptive information about a document, given when it is published
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The document's title
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Who wrote the document
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// When the document was written, in whatever form it was given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+}
+
+impl Metadata {
+    /// Whether no metadata is set at all
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.author.is_none() && self.date.is_none()
+    }
+}
+
+// A snippet of `context_words` words of `doc` around the first whitespace-separated word that
+// `terms` says contains the term `term`, which is wrapped in `**` to highlight it. The context is
+// split evenly before and after the match where the document allows, and `...` marks where the
+// document goes on. A document without the term gets a snippet from its start.
+fn snippet<F: Fn(&str) -> Vec<String>>(
+    doc: &Document,
+    term: &str,
+    context_words: usize,
+    terms: F,
+) -> String {
+    let text = doc.text.get();
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let hit = words
+        .iter()
+        .position(|candidate| terms(candidate).iter().any(|found| found == term));
+    let len = context_words.saturating_add(1).min(words.len());
+    let start = hit
+        .unwrap_or(0)
+        .saturating_sub(context_words / 2)
+        .min(words.len() - len);
+    let end = start + len;
+    let mut snippet: Vec<String> = words[start..end].iter().map(|w| w.to_string()).collect();
+    if let Some(hit) = hit {
+        snippet[hit - start] = format!("**{}**", words[hit]);
+    }
+    if start > 0 {
+        snippet.insert(0, "...".to_string());
+    }
+    if end < words.len() {
+        snippet.push("...".to_string());
+    }
+    snippet.join(" ")
+}
+
+// The ids in both of the sorted lists `a` and `b`.
+fn intersection(mut a: Vec<usize>, b: &[usize]) -> Vec<usize> {
+    a.retain(|id| b.binary_search(id).is_ok());
+    a
+}
+
+// The ids in the sorted list `a` but not in the sorted list `b`.
+fn difference(mut a: Vec<usize>, b: &[usize]) -> Vec<usize> {
+    a.retain(|id| b.binary_search(id).is_err());
+    a
+}
+
+// The ids in either of the sorted lists `a` and `b`, sorted.
+fn union(a: Vec<usize>, b: Vec<usize>) -> Vec<usize> {
+    let mut ids = Vec::with_capacity(a.len() + b.len());
+    let (mut a, mut b) = (a.into_iter().peekable(), b.into_iter().peekable());
+    loop {
+        let next = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) if x < y => a.next(),
+            (Some(x), Some(y)) if x > y => b.next(),
+            (Some(_), Some(_)) => {
+                b.next();
+                a.next()
+            }
+            (Some(_), None) => a.next(),
+            (None, _) => b.next(),
+        };
+        match next {
+            Some(id) => ids.push(id),
+            None => return ids,
+        }
+    }
+}
+
+// Count how many times each of `words` appears, and how many words there are in total.
+fn count_terms(words: Vec<String>) -> (HashMap<String, usize>, usize) {
+    let word_count = words.len();
+    let mut term_counts: HashMap<String, usize> = HashMap::new();
+    for word in words {
+        *term_counts.entry(word).or_default() += 1;
+    }
+    (term_counts, word_count)
+}
+
+// The current time, in seconds since the Unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A short description of a document in the archive
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocumentSummary {
+    /// The document's id
+    pub id: usize,
+    /// The length of the document in bytes
+    pub length: usize,
+    /// The beginning of the document
+    pub preview: String,
+    /// When the document was published, in seconds since the Unix epoch
+    pub published_at: u64,
+}
+
+/// The differences between two versions of an archive, e.g. two saved snapshots
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct SnapshotDiff {
+    /// Ids of documents only in the newer archive
+    pub added: Vec<usize>,
+    /// Ids of documents only in the older archive
+    pub removed: Vec<usize>,
+    /// Ids of documents in both archives whose text differs
+    pub changed: Vec<usize>,
+    /// Every word found in a different number of documents, in alphabetical order
+    pub terms: Vec<TermDelta>,
+}
+
+/// A change in how many documents contain a word
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TermDelta {
+    pub term: String,
+    /// The number of documents containing the word in the older archive
+    pub before: usize,
+    /// The number of documents containing the word in the newer archive
+    pub after: usize,
+}
+
+/// The blob store stayed locked for longer than the caller was willing to wait
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Busy;
+
+/// Roughly how much memory an archive takes up, in bytes. Only the documents and the postings
+/// that find them are counted, not the space collections set aside to grow into
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    /// The documents, with their word counts
+    pub blob_store: usize,
+    /// The postings of the reverse index, and of the n-gram index if there is one
+    pub index: usize,
+}
+
+impl MemoryUsage {
+    /// The memory the whole archive takes up
+    pub fn total(&self) -> usize {
+        self.blob_store + self.index
+    }
+}
+
+/// A document was rejected for being a copy of the stored document with this id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Duplicate(pub usize);
+
+/// The number of buckets in the reverse index's write buffer unless told otherwise
+pub const BUCKETS: usize = 128;
+
+/// The number of shards the blob store splits documents across
+pub const BLOB_STORE_SHARDS: usize = 16;
+
+/// The longest a caller of `try_retrieve` sleeps between attempts to take the blob store lock
+const MAX_BACKOFF: Duration = Duration::from_millis(32);
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Database {
-    // Create a new empty archive. The map should have `BUCKETS` buckets.
+    // Create a new empty archive. The index's write buffer should have `BUCKETS` buckets.
     pub fn new() -> Self {
+        Self::with_buckets(BUCKETS)
+    }
+
+    // Create a new empty archive whose index's write buffer has `buckets` buckets.
+    pub fn with_buckets(buckets: usize) -> Self {
         Self {
-            reverse_index: ConcurrentMultiMap::new(BUCKETS),
-            blob_store: Mutex::new(Vec::new()),
+            reverse_index: SegmentedIndex::new(buckets),
+            blob_store: BlobStore::new(BLOB_STORE_SHARDS),
+            metadata: Mutex::new(HashMap::new()),
+            ngram_index: None,
+            stop_words: HashSet::new(),
+            analyzer: Box::new(Pipeline::default()),
+            compression_level: None,
+            duplicates: Duplicates::Allow,
+            content_hashes: Mutex::new(HashMap::new()),
+            blob_store_memory: AtomicUsize::new(0),
+            index_memory: AtomicUsize::new(0),
+        }
+    }
+
+    // Keep documents published from now on compressed with zstd at `level`, trading some time
+    // on every publish and retrieve for memory. Levels run from 1, the fastest, to 22, the
+    // smallest.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, level: i32) -> Self {
+        assert!(
+            zstd::compression_level_range().contains(&level),
+            "compression level {} is out of range",
+            level
+        );
+        self.compression_level = Some(level);
+        self
+    }
+
+    // How many bytes the text of every document takes up in the blob store, after compression.
+    pub fn stored_bytes(&self) -> usize {
+        self.blob_store
+            .with_all(|docs| docs.iter().map(|doc| doc.text.stored_len()).sum())
+    }
+
+    // Turn words into terms with `analyzer` rather than just lowercasing them, both when indexing
+    // documents and when searching. It must be set before anything is published.
+    pub fn with_analyzer<A: Analyzer + 'static>(mut self, analyzer: A) -> Self {
+        assert!(
+            self.blob_store.is_empty(),
+            "the analyzer must be set before documents are published"
+        );
+        self.analyzer = Box::new(analyzer);
+        self
+    }
+
+    // The terms a document of type `content_type` holding `text` is indexed under, in order.
+    fn terms(&self, content_type: ContentType, text: &str) -> Vec<String> {
+        content_type
+            .split(text)
+            .into_iter()
+            .filter_map(|word| self.analyzer.analyze(word))
+            .filter(|term| !self.stop_words.contains(term))
+            .collect()
+    }
+
+    // The term to look up when searching for `word`, or None if no document can contain it.
+    fn query_term(&self, word: &str) -> Option<String> {
+        self.analyzer
+            .analyze(word.to_string())
+            .filter(|term| !self.stop_words.contains(term))
+    }
+
+    // The start of the terms to look up when searching for words starting with `prefix`, or
+    // None if no term can start with it. Every term starts with the empty prefix.
+    fn query_prefix(&self, prefix: &str) -> Option<String> {
+        match prefix.is_empty() {
+            true => Some(String::new()),
+            false => self.analyzer.analyze_prefix(prefix.to_string()),
         }
     }
 
+    // Leave `stop_words` out of the index, ignoring case, so that words found in nearly every
+    // document don't bloat it. They must be set before anything is published.
+    pub fn with_stop_words<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        mut self,
+        stop_words: I,
+    ) -> Self {
+        assert!(
+            self.blob_store.is_empty(),
+            "stop words must be set before documents are published"
+        );
+        self.stop_words = stop_words
+            .into_iter()
+            .map(|word| word.as_ref().to_lowercase())
+            .collect();
+        self
+    }
+
+    // Index the character n-grams of every document, with `n` characters each, so that
+    // `search_substring` needn't scan every document. Documents already in the archive are
+    // indexed now.
+    pub fn with_ngram_index(mut self, n: usize) -> Self {
+        assert!(n > 0, "n-grams must have at least one character");
+        let ngram_index = NgramIndex {
+            n,
+            index: SegmentedIndex::new(BUCKETS),
+        };
+        self.blob_store.with_all(|docs| {
+            for (id, doc) in docs.iter().enumerate() {
+                if !doc.pending {
+                    let ngrams = ngram_index.ngrams(&doc.text.get());
+                    *self.index_memory.get_mut() += postings_size(&ngrams);
+                    ngram_index.index.insert(ngrams, id);
+                }
+            }
+        });
+        self.ngram_index = Some(ngram_index);
+        self
+    }
+
+    // Treat documents published from now on that are copies of stored ones, with the same text
+    // and content type, as `duplicates` says. Documents already in the archive are hashed so that
+    // copies of them are found too.
+    pub fn with_duplicates(mut self, duplicates: Duplicates) -> Self {
+        let mut content_hashes: HashMap<u64, Vec<usize>> = HashMap::new();
+        if duplicates != Duplicates::Allow {
+            self.blob_store.with_all(|docs| {
+                for (id, doc) in docs.iter().enumerate() {
+                    let hash = content_hash(&doc.text.get(), doc.content_type);
+                    content_hashes.entry(hash).or_default().push(id);
+                }
+            });
+        }
+        self.duplicates = duplicates;
+        *self
+            .content_hashes
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = content_hashes;
+        self
+    }
+
+    // The id of the document among `candidates`, the stored documents with the right content
+    // hash, that has text `text` and type `content_type`, if there is one.
+    fn find_copy(
+        &self,
+        candidates: &[usize],
+        text: &str,
+        content_type: ContentType,
+    ) -> Option<usize> {
+        candidates.iter().copied().find(|id| {
+            self.blob_store
+                .get(*id, |doc| {
+                    doc.content_type == content_type && doc.text.get() == text
+                })
+                .unwrap_or(false)
+        })
+    }
+
     // Publish a document to the archive in three steps:
-    // 1. Make a new unique identifier for the document
-    // 2. Split the document into words and map each word to the document's identifier in the
-    //    reverse index. For our purposes, using built-in String functionality to split on
-    //    whitespace is sufficient. It is up to you whether to also perform transformations like
-    //    converting to lowercase or removing numerals.
-    // 3. Add the document to the blob store
+    // 1. Split the document into words. For our purposes, using built-in String functionality to
+    //    split on whitespace is sufficient. It is up to you whether to also perform
+    //    transformations like converting to lowercase or removing numerals. No lock is held while
+    //    this happens, so publishing a large document doesn't hold up anyone else.
+    // 2. Add the document to the blob store under a new unique identifier, which is taken from an
+    //    atomic counter and only briefly locks the one shard the document goes in
+    // 3. Map each word to the document's identifier in the reverse index
     pub fn publish(&self, doc: String) -> usize {
-        let mut blob_store = self.blob_store.lock().unwrap();
-        let next_id = blob_store.len();
-        for word in doc.split_whitespace() {
-            let cleaned_word = word.to_lowercase(); //My transformation is just to lowercase, could add more
-            if !cleaned_word.is_empty() {
-                self.reverse_index.set(cleaned_word, next_id);
+        self.publish_at(doc, now())
+    }
+
+    // Like `publish`, but record the document as published at `published_at` (in seconds since
+    // the Unix epoch) rather than now, e.g. when restoring an archive.
+    pub fn publish_at(&self, doc: String, published_at: u64) -> usize {
+        self.publish_with_at(doc, &PublishOptions::default(), published_at)
+    }
+
+    // Like `publish`, but publish the document as `options` asks.
+    pub fn publish_with(&self, doc: String, options: &PublishOptions) -> usize {
+        self.publish_with_at(doc, options, now())
+    }
+
+    // Like `publish_with`, but record the document as published at `published_at`.
+    pub fn publish_with_at(
+        &self,
+        doc: String,
+        options: &PublishOptions,
+        published_at: u64,
+    ) -> usize {
+        let duplicates = self.duplicates.without_rejecting();
+        let stored = self.store(doc, published_at, options, duplicates);
+        stored.unwrap_or_else(|Duplicate(id)| id)
+    }
+
+    // Like `publish_with_at`, but fail with the id of the stored document if the archive rejects
+    // duplicates and `doc` is a copy of one.
+    pub fn try_publish_with_at(
+        &self,
+        doc: String,
+        options: &PublishOptions,
+        published_at: u64,
+    ) -> Result<usize, Duplicate> {
+        self.store(doc, published_at, options, self.duplicates)
+    }
+
+    // Add a document to the blob store and, unless it is pending, to the reverse index, unless it
+    // is a copy of a stored document and `duplicates` says to reuse or reject it. Its metadata,
+    // if any, goes in the metadata table before the document can be retrieved. The document is
+    // split into words and compressed before the blob store is touched, and it is only indexed
+    // once it is stored, so a search never finds a document that can't be retrieved yet.
+    fn store(
+        &self,
+        doc: String,
+        published_at: u64,
+        options: &PublishOptions,
+        duplicates: Duplicates,
+    ) -> Result<usize, Duplicate> {
+        // Content hashes are kept whenever the archive looks for copies, even while restoring
+        // one with `duplicates` allowed so that ids are kept
+        let hash = (self.duplicates != Duplicates::Allow)
+            .then(|| content_hash(&doc, options.content_type));
+        // Looking for a copy before the document is split up keeps retried publishes cheap
+        if let (Some(hash), false) = (hash, duplicates == Duplicates::Allow) {
+            let hashes = sync::lock(&self.content_hashes);
+            let copy = hashes
+                .get(&hash)
+                .and_then(|candidates| self.find_copy(candidates, &doc, options.content_type));
+            if let Some(id) = copy {
+                return duplicates.copy_of(id);
+            }
+        }
+        let terms = self.terms(options.content_type, &doc);
+        let ngrams = match (&self.ngram_index, options.pending) {
+            (Some(ngram_index), false) => Some(ngram_index.ngrams(&doc)),
+            _ => None,
+        };
+        let text = StoredText::new(doc, self.compression_level);
+        let document = Document::new(text, terms, published_at, options);
+        let terms = match options.pending {
+            true => Vec::new(),
+            false => document.term_counts.keys().cloned().collect(),
+        };
+        // The table of hashes is held until the document is stored, so that a copy published in
+        // the meantime is found now and one published later finds this one
+        let mut hashes = hash.map(|_| sync::lock(&self.content_hashes));
+        if let (Some(hashes), Some(hash), false) = (&hashes, hash, duplicates == Duplicates::Allow)
+        {
+            let copy = hashes.get(&hash).and_then(|candidates| {
+                self.find_copy(candidates, &document.text.get(), options.content_type)
+            });
+            if let Some(id) = copy {
+                return duplicates.copy_of(id);
             }
         }
-        blob_store.push(doc);
-        next_id
+        let memory_size = document.memory_size();
+        let id = self.blob_store.insert_with(|id| {
+            if !options.metadata.is_empty() {
+                sync::lock(&self.metadata).insert(id, options.metadata.clone());
+            }
+            document
+        });
+        if let (Some(hashes), Some(hash)) = (&mut hashes, hash) {
+            hashes.entry(hash).or_default().push(id);
+        }
+        drop(hashes);
+        self.blob_store_memory
+            .fetch_add(memory_size, Ordering::Relaxed);
+        if let (Some(ngram_index), Some(ngrams)) = (&self.ngram_index, ngrams) {
+            self.index_memory
+                .fetch_add(postings_size(&ngrams), Ordering::Relaxed);
+            ngram_index.index.insert(ngrams, id);
+        }
+        self.index_memory
+            .fetch_add(postings_size(&terms), Ordering::Relaxed);
+        self.reverse_index.insert(terms, id);
+        Ok(id)
+    }
+
+    // Publish every document in `docs` as `options` asks, all or nothing: no search, retrieval or
+    // listing sees some of them without the rest. Return their ids, which are consecutive, in
+    // order, unless the archive reuses duplicates: a copy of a stored document, or of one earlier
+    // in the batch, gets that document's id instead.
+    pub fn publish_batch(&self, docs: Vec<String>, options: &PublishOptions) -> Vec<usize> {
+        self.publish_batch_at(docs, options, now())
+    }
+
+    // Like `publish_batch`, but record the documents as published at `published_at`.
+    pub fn publish_batch_at(
+        &self,
+        docs: Vec<String>,
+        options: &PublishOptions,
+        published_at: u64,
+    ) -> Vec<usize> {
+        let duplicates = self.duplicates.without_rejecting();
+        self.store_batch(docs, published_at, options, duplicates)
+            .expect("only rejecting duplicates fails")
+    }
+
+    // Like `publish_batch_at`, but if the archive rejects duplicates and any of `docs` is a copy
+    // of a stored document, publish none of them and fail with the id of the stored document.
+    // Copies within the batch are stored once, as if duplicates were reused.
+    pub fn try_publish_batch_at(
+        &self,
+        docs: Vec<String>,
+        options: &PublishOptions,
+        published_at: u64,
+    ) -> Result<Vec<usize>, Duplicate> {
+        self.store_batch(docs, published_at, options, self.duplicates)
+    }
+
+    // Store every document in `docs` as `store` does, but all at once.
+    fn store_batch(
+        &self,
+        docs: Vec<String>,
+        published_at: u64,
+        options: &PublishOptions,
+        duplicates: Duplicates,
+    ) -> Result<Vec<usize>, Duplicate> {
+        // Where each document in the batch ends up
+        enum Place {
+            /// The stored document with this id, which it is a copy of
+            Stored(usize),
+            /// This place among the documents the batch stores
+            New(usize),
+        }
+        let mut prepared = Vec::with_capacity(docs.len());
+        for doc in docs {
+            let hash = (self.duplicates != Duplicates::Allow)
+                .then(|| content_hash(&doc, options.content_type));
+            let terms = self.terms(options.content_type, &doc);
+            let ngrams = match (&self.ngram_index, options.pending) {
+                (Some(ngram_index), false) => Some(ngram_index.ngrams(&doc)),
+                _ => None,
+            };
+            let text = StoredText::new(doc, self.compression_level);
+            let document = Document::new(text, terms, published_at, options);
+            prepared.push((hash, document, ngrams));
+        }
+        // The documents are stored all at once, so they can't be seen one at a time, and the
+        // index gets all their postings in one segment. Postings are collected by each document's
+        // place in the batch until the batch has its ids.
+        let mut hashes =
+            (self.duplicates != Duplicates::Allow).then(|| sync::lock(&self.content_hashes));
+        let mut places = Vec::with_capacity(prepared.len());
+        let mut documents: Vec<Document> = Vec::new();
+        let mut new_hashes: Vec<(u64, usize)> = Vec::new();
+        let mut postings = Vec::new();
+        let mut ngram_postings = Vec::new();
+        for (hash, document, ngrams) in prepared {
+            if let (Some(hashes), Some(hash), false) =
+                (&hashes, hash, duplicates == Duplicates::Allow)
+            {
+                let text = document.text.get();
+                let copy = hashes
+                    .get(&hash)
+                    .and_then(|candidates| self.find_copy(candidates, &text, options.content_type));
+                if let Some(id) = copy {
+                    places.push(Place::Stored(duplicates.copy_of(id)?));
+                    continue;
+                }
+                let earlier = new_hashes.iter().find(|(earlier_hash, offset)| {
+                    *earlier_hash == hash && documents[*offset].text.get() == text
+                });
+                if let Some((_, offset)) = earlier {
+                    places.push(Place::New(*offset));
+                    continue;
+                }
+            }
+            let offset = documents.len();
+            if let Some(hash) = hash {
+                new_hashes.push((hash, offset));
+            }
+            if let Some(ngrams) = ngrams {
+                ngram_postings.extend(ngrams.into_iter().map(|gram| (gram, offset)));
+            }
+            if !options.pending {
+                postings.extend(
+                    document
+                        .term_counts
+                        .keys()
+                        .map(|term| (term.clone(), offset)),
+                );
+            }
+            places.push(Place::New(offset));
+            documents.push(document);
+        }
+        let memory_size = documents.iter().map(Document::memory_size).sum();
+        let ids = self.blob_store.insert_batch_with(documents, |ids| {
+            if !options.metadata.is_empty() {
+                let mut metadata = sync::lock(&self.metadata);
+                metadata.extend(ids.map(|id| (id, options.metadata.clone())));
+            }
+        });
+        let first_id = ids.start;
+        if let Some(hashes) = &mut hashes {
+            for (hash, offset) in new_hashes {
+                hashes.entry(hash).or_default().push(first_id + offset);
+            }
+        }
+        drop(hashes);
+        self.blob_store_memory
+            .fetch_add(memory_size, Ordering::Relaxed);
+        let postings_memory =
+            postings_size(postings.iter().chain(&ngram_postings).map(|(term, _)| term));
+        self.index_memory
+            .fetch_add(postings_memory, Ordering::Relaxed);
+        let postings = postings
+            .into_iter()
+            .map(|(term, offset)| (term, first_id + offset))
+            .collect::<Vec<_>>();
+        let ngram_postings = ngram_postings
+            .into_iter()
+            .map(|(gram, offset)| (gram, first_id + offset))
+            .collect::<Vec<_>>();
+        let ids = places
+            .into_iter()
+            .map(|place| match place {
+                Place::Stored(id) => id,
+                Place::New(offset) => first_id + offset,
+            })
+            .collect();
+        // The n-grams go in first, so that a substring search can't find a document before a
+        // word search can
+        if let (Some(ngram_index), false) = (&self.ngram_index, ngram_postings.is_empty()) {
+            ngram_index.index.insert_atomically(ngram_postings);
+        }
+        if !postings.is_empty() {
+            self.reverse_index.insert_atomically(postings);
+        }
+        Ok(ids)
+    }
+
+    // Make a document published with `PublishOptions::pending` searchable. Committing a document
+    // that is already searchable does nothing. Return false if there is no document with the
+    // given id.
+    pub fn commit(&self, id: usize) -> bool {
+        // The n-grams are worked out under a reader lock, so retrieves of the document carry on
+        let ngrams = self
+            .blob_store
+            .get(id, |doc| match (&self.ngram_index, doc.pending) {
+                (Some(ngram_index), true) => Some(ngram_index.ngrams(&doc.text.get())),
+                _ => None,
+            });
+        let Some(ngrams) = ngrams else {
+            return false;
+        };
+        self.blob_store.get_mut(id, |doc| {
+            if doc.pending {
+                self.index_memory
+                    .fetch_add(postings_size(doc.term_counts.keys()), Ordering::Relaxed);
+                self.reverse_index
+                    .insert(doc.term_counts.keys().cloned(), id);
+                if let (Some(ngram_index), Some(ngrams)) = (&self.ngram_index, ngrams) {
+                    self.index_memory
+                        .fetch_add(postings_size(&ngrams), Ordering::Relaxed);
+                    ngram_index.index.insert(ngrams, id);
+                }
+                doc.pending = false;
+            }
+        });
+        true
+    }
+    // Replace the text of the document with the given id with `doc`, reindexing it. The document
+    // keeps its publish time, metadata and content type, and stays pending if it was. Words in
+    // both the old and new text stay searchable throughout. Return false if there is no document
+    // with the given id.
+    pub fn update(&self, id: usize, doc: String) -> bool {
+        // A document's content type never changes, so the new text can be split into words and
+        // compressed before its shard is locked for writing
+        let Some(content_type) = self.blob_store.get(id, |existing| existing.content_type) else {
+            return false;
+        };
+        let (term_counts, word_count) = count_terms(self.terms(content_type, &doc));
+        let hash = (self.duplicates != Duplicates::Allow).then(|| content_hash(&doc, content_type));
+        let new_ngrams = self
+            .ngram_index
+            .as_ref()
+            .map(|ngram_index| ngram_index.ngrams(&doc));
+        let text = StoredText::new(doc, self.compression_level);
+        self.blob_store.get_mut(id, |existing| {
+            self.replace(id, existing, text, term_counts, word_count, new_ngrams)
+        });
+        // The document stays under its old hash too, but it no longer matches it, and a copy is
+        // only ever found by its text
+        if let Some(hash) = hash {
+            let mut hashes = sync::lock(&self.content_hashes);
+            let ids = hashes.entry(hash).or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        true
+    }
+    // Replace the text of `existing`, the document with the given id, with `text`, as `update`
+    // does. Reindexing happens under the document's writer lock, so that updates of the same
+    // document can't interleave in the index.
+    fn replace(
+        &self,
+        id: usize,
+        existing: &mut Document,
+        text: StoredText,
+        term_counts: HashMap<String, usize>,
+        word_count: usize,
+        new_ngrams: Option<HashSet<String>>,
+    ) {
+        let old_memory_size = existing.memory_size();
+        if !existing.pending {
+            // Words in both versions cancel out, so the index changes by the difference
+            self.index_memory
+                .fetch_add(postings_size(term_counts.keys()), Ordering::Relaxed);
+            self.index_memory.fetch_sub(
+                postings_size(existing.term_counts.keys()),
+                Ordering::Relaxed,
+            );
+            // Add the new words before removing the old ones, so that no search sees a word that
+            // is in both versions go missing
+            let added = term_counts
+                .keys()
+                .filter(|term| !existing.term_counts.contains_key(*term));
+            self.reverse_index.insert(added.cloned(), id);
+            let removed = existing
+                .term_counts
+                .keys()
+                .filter(|term| !term_counts.contains_key(*term));
+            self.reverse_index.remove(removed.cloned(), id);
+            if let (Some(ngram_index), Some(new)) = (&self.ngram_index, new_ngrams) {
+                let old = ngram_index.ngrams(&existing.text.get());
+                self.index_memory
+                    .fetch_add(postings_size(&new), Ordering::Relaxed);
+                self.index_memory
+                    .fetch_sub(postings_size(&old), Ordering::Relaxed);
+                ngram_index.index.insert(new.difference(&old).cloned(), id);
+                ngram_index.index.remove(old.difference(&new).cloned(), id);
+            }
+        }
+        existing.text = text;
+        existing.term_counts = term_counts;
+        existing.word_count = word_count;
+        self.blob_store_memory
+            .fetch_add(existing.memory_size(), Ordering::Relaxed);
+        self.blob_store_memory
+            .fetch_sub(old_memory_size, Ordering::Relaxed);
+    }
+
+    // The term `search` looks up for `word`, or None if searching for it finds nothing. Words
+    // that search for the same term find the same documents.
+    pub fn search_term(&self, word: &str) -> Option<String> {
+        self.query_term(word)
     }
     // Use the reverse index to get the set of documents that contain the given word.
     pub fn search(&self, word: &str) -> Vec<usize> {
-        let cleaned_word = word.to_lowercase();
-        self.reverse_index.get(&cleaned_word)
+        match self.query_term(word) {
+            Some(term) => self.reverse_index.get(&term),
+            None => Vec::new(),
+        }
+    }
+    // The number of documents that contain the given word, as `search` would find, without
+    // collecting their ids.
+    pub fn count(&self, word: &str) -> usize {
+        match self.query_term(word) {
+            Some(term) => self.reverse_index.count(&term),
+            None => 0,
+        }
+    }
+
+    // Get the set of documents containing a word that starts with `prefix`, so that e.g. "astro"
+    // finds documents containing "astronomy" or "astronaut".
+    pub fn search_prefix(&self, prefix: &str) -> Vec<usize> {
+        match self.query_prefix(prefix) {
+            Some(prefix) => self.reverse_index.get_prefix(&prefix),
+            None => Vec::new(),
+        }
+    }
+    // Get the set of documents matching the boolean `query`. `a AND NOT b` is worked out by
+    // removing b's documents from a's; only a NOT on its own is taken from every searchable
+    // document.
+    pub fn search_query(&self, query: &Query) -> Vec<usize> {
+        match query {
+            Query::Word(word) => self.search(word),
+            Query::And(a, b) => match (&**a, &**b) {
+                (a, Query::Not(b)) | (Query::Not(b), a) => {
+                    difference(self.search_query(a), &self.search_query(b))
+                }
+                (a, b) => intersection(self.search_query(a), &self.search_query(b)),
+            },
+            Query::Or(a, b) => union(self.search_query(a), self.search_query(b)),
+            Query::Not(a) => {
+                let searchable = self
+                    .blob_store
+                    .with_all(|docs| (0..docs.len()).filter(|id| !docs[*id].pending).collect());
+                difference(searchable, &self.search_query(a))
+            }
+        }
+    }
+    // Get the set of documents containing `text` anywhere, ignoring case, even in the middle of a
+    // word or across several. Without an n-gram index this reads every document.
+    pub fn search_substring(&self, text: &str) -> Vec<usize> {
+        let query = text.to_lowercase();
+        // Every document contains the empty string, even an empty one with no n-grams
+        let candidates = match &self.ngram_index {
+            Some(ngram_index) if !query.is_empty() => Some(ngram_index.candidates(&query)),
+            _ => None,
+        };
+        let contains =
+            |doc: &Document| !doc.pending && doc.text.get().to_lowercase().contains(&query);
+        match candidates {
+            Some(candidates) => candidates
+                .into_iter()
+                .filter(|id| self.blob_store.get(*id, contains).unwrap_or(false))
+                .collect(),
+            None => self
+                .blob_store
+                .with_all(|docs| (0..docs.len()).filter(|id| contains(docs[*id])).collect()),
+        }
+    }
+    // Like `search`, but only keep documents published within the time range in `options`, of the
+    // type it asks for, that contain none of its excluded words, order them as it asks, and only
+    // return the page of them it asks for.
+    pub fn search_with(&self, word: &str, options: &SearchOptions) -> Vec<usize> {
+        self.search_page(word, options).0
+    }
+    // Like `search_with`, but also return how many documents match in total, across all pages.
+    pub fn search_page(&self, word: &str, options: &SearchOptions) -> (Vec<usize>, usize) {
+        let mut ids = self.search(word);
+        for excluded in options.exclude.iter() {
+            // Both lists are sorted, so this could be a merge, but exclusions are rare and short
+            let excluded = self.search(excluded);
+            ids.retain(|id| excluded.binary_search(id).is_err());
+        }
+        let mut hits = ids
+            .into_iter()
+            .filter_map(|id| {
+                let doc = self
+                    .blob_store
+                    .get(id, |doc| (doc.published_at, doc.content_type));
+                Some((id, doc?))
+            })
+            .filter(|(_, (published_at, content_type))| {
+                options.since.is_none_or(|since| *published_at >= since)
+                    && options.until.is_none_or(|until| *published_at <= until)
+                    && options
+                        .content_type
+                        .is_none_or(|wanted| *content_type == wanted)
+            })
+            .map(|(id, (published_at, _))| (id, published_at))
+            .collect::<Vec<_>>();
+        if options.newest_first {
+            hits.sort_by(|(a, a_time), (b, b_time)| b_time.cmp(a_time).then(b.cmp(a)));
+        }
+        let total_hits = hits.len();
+        let page = hits
+            .into_iter()
+            .skip(options.offset)
+            .take(options.limit.unwrap_or(usize::MAX))
+            .map(|(id, _)| id)
+            .collect();
+        (page, total_hits)
+    }
+    // Cut a snippet out of each document in `ids`, with about `context_words` words of context
+    // around the first place `word` appears, and return it along with the document's id.
+    // Documents that don't exist are left out.
+    pub fn snippets(
+        &self,
+        ids: &[usize],
+        word: &str,
+        context_words: usize,
+    ) -> Vec<(usize, String)> {
+        let term = self.query_term(word).unwrap_or_default();
+        ids.iter()
+            .filter_map(|id| {
+                let snippet = self.blob_store.get(*id, |doc| {
+                    let terms = |word: &str| self.terms(doc.content_type, word);
+                    snippet(doc, &term, context_words, terms)
+                });
+                Some((*id, snippet?))
+            })
+            .collect()
+    }
+    // Find the `k` documents most relevant to `word`, best first, each with its TF-IDF score. A
+    // document's term frequency is the share of its words that are `word`, and the inverse document
+    // frequency is smoothed so that a word found in every document still scores above zero. Ties
+    // are broken by id.
+    pub fn search_ranked(&self, word: &str, k: usize) -> Vec<(usize, f32)> {
+        let Some(cleaned_word) = self.query_term(word) else {
+            return Vec::new();
+        };
+        let ids = self.reverse_index.get(&cleaned_word);
+        let total = self.blob_store.len() as f32;
+        let idf = ((total + 1.0) / (ids.len() as f32 + 1.0)).ln() + 1.0;
+        let mut scores = ids
+            .into_iter()
+            .filter_map(|id| {
+                let score = self.blob_store.get(id, |doc| {
+                    let count = *doc.term_counts.get(&cleaned_word)?;
+                    Some(count as f32 / doc.word_count as f32 * idf)
+                });
+                Some((id, score??))
+            })
+            .collect::<Vec<_>>();
+        scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then(a.cmp(b)));
+        scores.truncate(k);
+        scores
+    }
+    // Suggest up to `limit` indexed words starting with `prefix`, for autocompletion. Each word is
+    // returned with the number of documents containing it, most common first.
+    pub fn suggest(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        let Some(cleaned_prefix) = self.query_prefix(prefix) else {
+            return Vec::new();
+        };
+        let mut terms = self.reverse_index.terms_with_prefix(&cleaned_prefix);
+        terms.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+        terms.truncate(limit);
+        terms
+    }
+    // Count, for up to `limit` indexed words in alphabetical order after `after` (or from the
+    // first, if it is None), the searchable documents containing each and how many times it
+    // appears in them all. Calling this again after the last word returned continues the listing.
+    pub fn term_stats(&self, after: Option<&str>, limit: usize) -> Vec<(String, usize, usize)> {
+        self.blob_store.with_all(|docs| {
+            let mut stats: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+            for doc in docs.iter().filter(|doc| !doc.pending) {
+                for (term, count) in doc.term_counts.iter() {
+                    if after.is_some_and(|after| term.as_str() <= after) {
+                        continue;
+                    }
+                    let (doc_frequency, occurrences) = stats.entry(term).or_default();
+                    *doc_frequency += 1;
+                    *occurrences += count;
+                }
+            }
+            stats
+                .into_iter()
+                .take(limit)
+                .map(|(term, (doc_frequency, occurrences))| {
+                    (term.to_string(), doc_frequency, occurrences)
+                })
+                .collect()
+        })
     }
     // Retrieve the document with the given id from the blob store.
     // Return None if the given id is invalid.
     pub fn retrieve(&self, id: usize) -> Option<String> {
-        let blob_store = self.blob_store.lock().unwrap();
-        blob_store.get(id).cloned() //cloned returns option with clone of doc within
+        let text = self.blob_store.get(id, |doc| doc.text.clone());
+        // Decompress without holding up other users of the blob store
+        text.map(StoredText::into_string)
+    }
+
+    // Like `retrieve`, but give up with `Busy` if the document's shard of the blob store can't be
+    // locked within `deadline` (e.g. because a large batch is being published) instead of blocking
+    // indefinitely. The lock is retried with exponential backoff until the deadline passes.
+    pub fn try_retrieve(&self, id: usize, deadline: Duration) -> Result<Option<String>, Busy> {
+        let start = Instant::now();
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            if let Ok(text) = self.blob_store.try_get(id, |doc| doc.text.clone()) {
+                return Ok(text.map(StoredText::into_string));
+            }
+            let remaining = deadline.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Err(Busy);
+            }
+            std::thread::sleep(backoff.min(remaining));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    // Whether the document with id `id` is indexed under `term`, as `search_term` gives it, and can
+    // be found by searching for it. Pending documents can't.
+    pub fn has_term(&self, id: usize, term: &str) -> bool {
+        self.blob_store
+            .get(id, |doc| !doc.pending && doc.term_counts.contains_key(term))
+            .unwrap_or(false)
+    }
+
+    // The metadata the document with the given id was published with. Return None if the id is
+    // invalid or the document has no metadata.
+    pub fn metadata(&self, id: usize) -> Option<Metadata> {
+        sync::lock(&self.metadata).get(&id).cloned()
+    }
+
+    // The metadata of each of `ids` that has any, in the order given.
+    pub fn metadata_for(&self, ids: &[usize]) -> Vec<(usize, Metadata)> {
+        let metadata = sync::lock(&self.metadata);
+        ids.iter()
+            .filter_map(|id| Some((*id, metadata.get(id)?.clone())))
+            .collect()
+    }
+
+    /// The number of documents in the archive, including pending ones
+    pub fn document_count(&self) -> usize {
+        self.blob_store.len()
+    }
+
+    /// Roughly how much memory the documents and indexes of the archive take up
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            blob_store: self.blob_store_memory.load(Ordering::Relaxed),
+            index: self.index_memory.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The number of segments in the reverse index
+    pub fn segment_count(&self) -> usize {
+        self.reverse_index.segment_count()
+    }
+
+    // Summarize every document in the archive, in id order. Each preview holds the first
+    // `preview_chars` characters of its document.
+    pub fn list(&self, preview_chars: usize) -> Vec<DocumentSummary> {
+        self.blob_store.with_all(|docs| {
+            docs.iter()
+                .enumerate()
+                .map(|(id, doc)| (id, doc, doc.text.get()))
+                .map(|(id, doc, text)| DocumentSummary {
+                    id,
+                    length: text.len(),
+                    preview: text.chars().take(preview_chars).collect(),
+                    published_at: doc.published_at,
+                })
+                .collect()
+        })
+    }
+
+    // Compare this archive with `newer`, a later version of it. Documents are matched up by id.
+    pub fn diff(&self, newer: &Database) -> SnapshotDiff {
+        // Each archive is summarized under its own lock, so diffing an archive with itself is fine
+        let (old_hashes, old_terms) = self.fingerprint();
+        let (new_hashes, new_terms) = newer.fingerprint();
+        let mut diff = SnapshotDiff::default();
+        for id in 0..old_hashes.len().max(new_hashes.len()) {
+            match (old_hashes.get(id), new_hashes.get(id)) {
+                (None, Some(_)) => diff.added.push(id),
+                (Some(_), None) => diff.removed.push(id),
+                (Some(old), Some(new)) if old != new => diff.changed.push(id),
+                _ => {}
+            }
+        }
+        let mut terms: BTreeMap<&String, (usize, usize)> = BTreeMap::new();
+        for (term, count) in old_terms.iter() {
+            terms.entry(term).or_default().0 = *count;
+        }
+        for (term, count) in new_terms.iter() {
+            terms.entry(term).or_default().1 = *count;
+        }
+        diff.terms = terms
+            .into_iter()
+            .filter(|(_, (before, after))| before != after)
+            .map(|(term, (before, after))| TermDelta {
+                term: term.clone(),
+                before,
+                after,
+            })
+            .collect();
+        diff
+    }
+
+    // A hash of each document's text, in id order, and the number of documents containing each
+    // word.
+    fn fingerprint(&self) -> (Vec<u64>, HashMap<String, usize>) {
+        let mut doc_counts: HashMap<String, usize> = HashMap::new();
+        let hashes = self.blob_store.with_all(|docs| {
+            docs.iter()
+                .map(|doc| {
+                    for term in doc.term_counts.keys() {
+                        *doc_counts.entry(term.clone()).or_default() += 1;
+                    }
+                    let mut hasher = DefaultHasher::new();
+                    doc.text.get().hash(&mut hasher);
+                    hasher.finish()
+                })
+                .collect()
+        });
+        (hashes, doc_counts)
+    }
+
+    // Write every document in the archive to `writer`, so that `load` can rebuild it later. Only
+    // the documents are written: the reverse index is derived from them, so it is rebuilt on load
+    // rather than stored. The format is the number of documents followed by each document's
+    // publish time, a flags byte, and then its text as a length-prefixed UTF-8 string, with all
+    // integers encoded as big-endian u64s. Bit 0 of the flags is set if the document is pending,
+    // and bit 1 if its title, author and date follow its text, each as a 0 byte if missing or a 1
+    // byte and a length-prefixed string otherwise. Bits 2 and 3 hold its content type's code.
+    pub fn save<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        self.blob_store
+            .with_all(|docs| self.write_documents(docs, &mut writer))?;
+        writer.flush()
+    }
+    // Write `docs`, every document in the archive, to `writer` in the format `save` describes.
+    fn write_documents<W: Write>(&self, docs: &[&Document], mut writer: W) -> io::Result<()> {
+        fn write_str<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+            writer.write_all(&(s.len() as u64).to_be_bytes())?;
+            writer.write_all(s.as_bytes())
+        }
+        let metadata = sync::lock(&self.metadata);
+        writer.write_all(&(docs.len() as u64).to_be_bytes())?;
+        for (id, doc) in docs.iter().enumerate() {
+            let doc_metadata = metadata.get(&id);
+            let flags = doc.pending as u8
+                | (doc_metadata.is_some() as u8) << 1
+                | doc.content_type.code() << 2;
+            writer.write_all(&doc.published_at.to_be_bytes())?;
+            writer.write_all(&[flags])?;
+            write_str(&mut writer, &doc.text.get())?;
+            if let Some(doc_metadata) = doc_metadata {
+                for field in [
+                    &doc_metadata.title,
+                    &doc_metadata.author,
+                    &doc_metadata.date,
+                ] {
+                    match field {
+                        Some(value) => {
+                            writer.write_all(&[1])?;
+                            write_str(&mut writer, value)?;
+                        }
+                        None => writer.write_all(&[0])?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Read an archive written by `save` from `reader`, republishing every document so that each
+    // keeps its original id and metadata, and stays pending if it was.
+    pub fn load<R: Read>(reader: R) -> io::Result<Self> {
+        let database = Self::new();
+        database.restore(reader)?;
+        Ok(database)
+    }
+
+    // Like `load`, but republish the documents into this archive, keeping however its index is
+    // set up. The documents only keep their ids if the archive is empty. Return how many
+    // documents were read.
+    pub fn restore<R: Read>(&self, mut reader: R) -> io::Result<usize> {
+        fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+            let mut buffer = [0_u8; 8];
+            reader.read_exact(&mut buffer)?;
+            Ok(u64::from_be_bytes(buffer))
+        }
+        fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+            let mut buffer = [0_u8; 1];
+            reader.read_exact(&mut buffer)?;
+            Ok(buffer[0])
+        }
+        fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+            let len = read_u64(reader)?;
+            let mut bytes = Vec::new();
+            reader.take(len).read_to_end(&mut bytes)?;
+            if bytes.len() as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        fn read_optional_string<R: Read>(reader: &mut R) -> io::Result<Option<String>> {
+            match read_u8(reader)? {
+                0 => Ok(None),
+                1 => read_string(reader).map(Some),
+                _ => Err(io::ErrorKind::InvalidData.into()),
+            }
+        }
+        let count = read_u64(&mut reader)?;
+        for _ in 0..count {
+            let published_at = read_u64(&mut reader)?;
+            let flags = read_u8(&mut reader)?;
+            let content_type = ContentType::from_code(flags >> 2 & 0b11);
+            let (Some(content_type), 0) = (content_type, flags >> 4) else {
+                return Err(io::ErrorKind::InvalidData.into());
+            };
+            let doc = read_string(&mut reader)?;
+            let mut options = PublishOptions {
+                pending: flags & 1 != 0,
+                content_type,
+                ..PublishOptions::default()
+            };
+            if flags & 2 != 0 {
+                options.metadata = Metadata {
+                    title: read_optional_string(&mut reader)?,
+                    author: read_optional_string(&mut reader)?,
+                    date: read_optional_string(&mut reader)?,
+                };
+            }
+            // Every document is stored, even a copy, so that each keeps its id
+            let _ = self.store(doc, published_at, &options, Duplicates::Allow);
+        }
+        Ok(count as usize)
+    }
+
+    // Write every document in the archive to `writer` as a portable dump: one line of JSON per
+    // document, in order of id, holding a `DumpedDocument`. Unlike `save`, which only this crate
+    // reads, a dump can be read by other tools, and by `read_dump` in any later version. Return
+    // how many documents were written.
+    pub fn export<W: Write>(&self, mut writer: W) -> io::Result<usize> {
+        let count = self.blob_store.with_all(|docs| {
+            // Locked after the documents, as publishing locks them
+            let metadata = sync::lock(&self.metadata);
+            for (id, doc) in docs.iter().enumerate() {
+                let dumped = DumpedDocument {
+                    id,
+                    published_at: doc.published_at,
+                    text: doc.text.get().into_owned(),
+                    options: PublishOptions {
+                        pending: doc.pending,
+                        metadata: metadata.get(&id).cloned().unwrap_or_default(),
+                        content_type: doc.content_type,
+                    },
+                };
+                serde_json::to_writer(&mut writer, &dumped)?;
+                writer.write_all(b"\n")?;
+            }
+            Ok::<_, io::Error>(docs.len())
+        })?;
+        writer.flush()?;
+        Ok(count)
+    }
+}
+
+/// One document in a dump written by `Database::export`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpedDocument {
+    /// The document's id in the archive it was exported from
+    pub id: usize,
+    /// When the document was published, in seconds since the Unix epoch
+    pub published_at: u64,
+    /// The full text of the document
+    pub text: String,
+    /// Whether the document is pending, its metadata, and its content type
+    #[serde(flatten)]
+    pub options: PublishOptions,
+}
+
+// Read the documents in a dump written by `Database::export` from `reader`, one at a time, so that
+// a dump needn't fit in memory. Blank lines are skipped.
+pub fn read_dump<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<DumpedDocument>> {
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+}
+
+/// A document as `Database` serializes it with serde
+#[derive(Serialize, Deserialize)]
+struct SerializedDocument {
+    /// When the document was published, in seconds since the Unix epoch
+    published_at: u64,
+    /// The full text of the document
+    text: String,
+    /// Whether the document is pending, its metadata, and its content type
+    #[serde(flatten)]
+    options: PublishOptions,
+}
+
+/// An archive as `Database` serializes it with serde
+#[derive(Serialize, Deserialize)]
+struct SerializedDatabase {
+    /// Every document in the archive, in order of id
+    documents: Vec<SerializedDocument>,
+}
+
+// Like `save`, an archive serializes as just its documents, in order of id, each with its publish
+// time and the options needed to publish it again as it was. The reverse index is rebuilt from the
+// documents when the archive is deserialized.
+impl Serialize for Database {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let documents = self.blob_store.with_all(|docs| {
+            // Locked after the documents, as publishing locks them
+            let metadata = sync::lock(&self.metadata);
+            docs.iter()
+                .enumerate()
+                .map(|(id, doc)| SerializedDocument {
+                    published_at: doc.published_at,
+                    text: doc.text.get().into_owned(),
+                    options: PublishOptions {
+                        pending: doc.pending,
+                        metadata: metadata.get(&id).cloned().unwrap_or_default(),
+                        content_type: doc.content_type,
+                    },
+                })
+                .collect()
+        });
+        SerializedDatabase { documents }.serialize(serializer)
+    }
+}
+
+// Like `load`, republish every document into a new archive, so that each keeps its original id
+// and metadata, and stays pending if it was.
+impl<'de> Deserialize<'de> for Database {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let serialized = SerializedDatabase::deserialize(deserializer)?;
+        let database = Self::new();
+        for doc in serialized.documents {
+            let _ = database.store(doc.text, doc.published_at, &doc.options, Duplicates::Allow);
+        }
+        Ok(database)
     }
 }
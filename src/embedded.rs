@@ -0,0 +1,152 @@
+use crate::database::{Database, Duplicates};
+use crate::snapshot;
+use std::io;
+use std::path::Path;
+
+pub use crate::analyzer::{Analyzer, Pipeline};
+pub use crate::database::{ContentType, DocumentSummary, Metadata, PublishOptions, SearchOptions};
+pub use crate::query::Query;
+
+// An `Archive` indexes and searches documents in-process, for applications that want the archive
+// without running a server or talking to one. It is the same database a server keeps, so it can
+// be shared between threads with an `Arc`, and what it saves a server can load as a snapshot,
+// and the other way round.
+
+/// An archive of documents kept in memory, searchable without any networking
+#[derive(Default)]
+pub struct Archive {
+    database: Database,
+}
+
+impl Archive {
+    // An empty archive that splits and lowercases words, and drops no stop words.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Turn words into terms with `analyzer` when indexing documents and searching. The archive
+    // must still be empty.
+    pub fn with_analyzer<A: Analyzer + 'static>(mut self, analyzer: A) -> Self {
+        self.database = self.database.with_analyzer(analyzer);
+        self
+    }
+
+    // Leave `stop_words` out of the index; searching for one finds nothing. The archive must
+    // still be empty.
+    pub fn with_stop_words<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        mut self,
+        stop_words: I,
+    ) -> Self {
+        self.database = self.database.with_stop_words(stop_words);
+        self
+    }
+
+    // Keep documents compressed with zstd at `level`, from 1, the fastest, to 22, the smallest.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, level: i32) -> Self {
+        self.database = self.database.with_compression(level);
+        self
+    }
+
+    // Index the character n-grams of documents, `n` characters each, to speed up substring
+    // searches.
+    pub fn with_ngram_index(mut self, n: usize) -> Self {
+        self.database = self.database.with_ngram_index(n);
+        self
+    }
+
+    // Treat publishes of copies of stored documents as `duplicates` says. Since `publish` can't
+    // fail, rejected copies are reused.
+    pub fn with_duplicates(mut self, duplicates: Duplicates) -> Self {
+        self.database = self.database.with_duplicates(duplicates);
+        self
+    }
+
+    // Add `doc` to the archive, returning its id.
+    pub fn publish(&self, doc: impl Into<String>) -> usize {
+        self.database.publish(doc.into())
+    }
+
+    // Like `publish`, but publish the document as `options` asks.
+    pub fn publish_with(&self, doc: impl Into<String>, options: &PublishOptions) -> usize {
+        self.database.publish_with(doc.into(), options)
+    }
+
+    // Make the pending document `id` searchable, returning false if there is no such document.
+    pub fn commit(&self, id: usize) -> bool {
+        self.database.commit(id)
+    }
+
+    // Replace the text of document `id` with `doc`, returning false if there is no such document.
+    pub fn update(&self, id: usize, doc: impl Into<String>) -> bool {
+        self.database.update(id, doc.into())
+    }
+
+    // The ids of the documents containing `word`.
+    pub fn search(&self, word: &str) -> Vec<usize> {
+        self.database.search(word)
+    }
+
+    // Like `search`, but filter, order and page the results as `options` asks.
+    pub fn search_with(&self, word: &str, options: &SearchOptions) -> Vec<usize> {
+        self.database.search_with(word, options)
+    }
+
+    // The `k` documents most relevant to `word`, best first, each with its TF-IDF score.
+    pub fn search_ranked(&self, word: &str, k: usize) -> Vec<(usize, f32)> {
+        self.database.search_ranked(word, k)
+    }
+
+    // The ids of the documents matching the boolean query `expr`, e.g. `whale AND NOT ship`, or
+    // what is wrong with it if it can't be parsed.
+    pub fn query(&self, expr: &str) -> Result<Vec<usize>, String> {
+        Ok(self.database.search_query(&expr.parse()?))
+    }
+
+    // Up to `limit` indexed words starting with `prefix`, each with the number of documents
+    // containing it, most common first.
+    pub fn suggest(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        self.database.suggest(prefix, limit)
+    }
+
+    // The text of document `id`, if there is one.
+    pub fn retrieve(&self, id: usize) -> Option<String> {
+        self.database.retrieve(id)
+    }
+
+    // The title, author and date of document `id`, if it has any.
+    pub fn metadata(&self, id: usize) -> Option<Metadata> {
+        self.database.metadata(id)
+    }
+
+    // A summary of every document, with a preview of its first `preview_chars` characters.
+    pub fn list(&self, preview_chars: usize) -> Vec<DocumentSummary> {
+        self.database.list(preview_chars)
+    }
+
+    // The number of documents in the archive, pending or not.
+    pub fn len(&self) -> usize {
+        self.database.document_count()
+    }
+
+    // Whether the archive has no documents at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The database behind the archive, for anything the archive doesn't offer directly.
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    // Save the whole archive to `path`, replacing whatever was there only once it is complete.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        snapshot::save(&self.database, 0, path.as_ref())
+    }
+
+    // Load the archive saved at `path` into this one, which should be empty, keeping this one's
+    // analyzer and stop words. Return false if nothing has been saved there yet.
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<bool> {
+        Ok(snapshot::load(&self.database, path.as_ref())?.is_some())
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Fault injection for testing clients against an unreliable server. A server built with the
+// `fault-injection` feature can be given a `FaultConfig`, and before sending each response it rolls
+// for a fault: the response may be delayed, the connection dropped without a response, or the
+// response corrupted so it no longer decodes. None of this belongs in a production build, which is
+// why the module only exists behind the feature flag.
+
+/// How often, and how badly, the server should misbehave. Rates are probabilities between 0 and 1,
+/// rolled independently for each response.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FaultConfig {
+    /// How long to stall before sending a delayed response
+    pub delay: Duration,
+    /// The chance that a response is delayed by `delay`
+    pub delay_rate: f64,
+    /// The chance that the connection is closed without sending a response
+    pub drop_rate: f64,
+    /// The chance that the response is corrupted before it is sent
+    pub corrupt_rate: f64,
+}
+
+/// What to do to one response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Stall for this long, then send the response as usual
+    Delay(Duration),
+    /// Close the connection without responding
+    Drop,
+    /// Send a mangled response
+    Corrupt,
+}
+
+/// Rolls for faults according to a `FaultConfig`
+#[derive(Debug)]
+pub struct FaultInjector {
+    config: FaultConfig,
+    /// State of the xorshift generator used for rolls
+    state: AtomicU64,
+}
+
+impl FaultInjector {
+    // Create an injector seeded from the current time.
+    pub fn new(config: FaultConfig) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self::with_seed(config, seed)
+    }
+
+    // Create an injector whose rolls are determined by `seed`, so a run can be reproduced.
+    pub fn with_seed(config: FaultConfig, seed: u64) -> Self {
+        Self {
+            config,
+            // Xorshift gets stuck at zero
+            state: AtomicU64::new(seed.max(1)),
+        }
+    }
+
+    /// The configuration this injector rolls against
+    pub fn config(&self) -> &FaultConfig {
+        &self.config
+    }
+
+    // Decide which faults, if any, to inject into the next response. Drops take precedence over
+    // corruption, since there's nothing left to corrupt once the connection is gone; a delay can
+    // come with either.
+    pub fn roll(&self) -> Vec<Fault> {
+        let mut faults = Vec::new();
+        if self.chance(self.config.delay_rate) {
+            faults.push(Fault::Delay(self.config.delay));
+        }
+        if self.chance(self.config.drop_rate) {
+            faults.push(Fault::Drop);
+        } else if self.chance(self.config.corrupt_rate) {
+            faults.push(Fault::Corrupt);
+        }
+        faults
+    }
+
+    // Return true with probability `rate`.
+    fn chance(&self, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+        let mut next = 0;
+        let _ = self
+            .state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |mut x| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                next = x;
+                Some(x)
+            });
+        // Use the top 53 bits to get a uniform float in [0, 1)
+        ((next >> 11) as f64 / (1_u64 << 53) as f64) < rate
+    }
+}
+
+// Mangle an encoded response so that it no longer decodes to what was sent: the frame is cut
+// short of the length its header promises.
+pub fn corrupt(bytes: &mut Vec<u8>) {
+    bytes.truncate(bytes.len() / 2);
+}
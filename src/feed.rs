@@ -0,0 +1,68 @@
+use crate::database::Database;
+use crate::sync;
+use std::sync::{mpsc, Mutex};
+
+// A client can subscribe to a word, and is then sent a notification on the same connection
+// whenever a document containing it becomes searchable in the server's default archive: when it
+// is published, or committed, or updated to contain it. Each subscription holds its connection
+// open until the client hangs up or the server stops, tying up a worker of a blocking server for
+// as long.
+
+/// How many characters of each document its notifications preview
+pub const PREVIEW_CHARS: usize = 100;
+
+/// A document's id, and the start of it, sent to a subscriber
+pub type Notification = (usize, String);
+
+/// The subscriptions to the documents published to an archive
+#[derive(Default)]
+pub struct Feed {
+    /// The term each subscriber asked for, and where to send the documents indexed under it
+    subscribers: Mutex<Vec<(String, mpsc::Sender<Notification>)>>,
+}
+
+impl Feed {
+    // Subscribe to the documents indexed under `term`, as `Database::search_term` gives it.
+    // Subscribing stops once the returned receiver is dropped.
+    pub fn subscribe(&self, term: String) -> mpsc::Receiver<Notification> {
+        let (sender, receiver) = mpsc::channel();
+        sync::lock(&self.subscribers).push((term, sender));
+        receiver
+    }
+
+    // Notify each subscriber of those of the documents `ids` in `database` that it subscribed to,
+    // and forget the subscribers that have gone.
+    pub fn notify(&self, database: &Database, ids: &[usize]) {
+        let mut subscribers = sync::lock(&self.subscribers);
+        if subscribers.is_empty() {
+            return;
+        }
+        for &id in ids {
+            let mut preview = None;
+            subscribers.retain(|(term, sender)| {
+                if !database.has_term(id, term) {
+                    return true;
+                }
+                let preview = preview.get_or_insert_with(|| {
+                    database
+                        .retrieve(id)
+                        .unwrap_or_default()
+                        .chars()
+                        .take(PREVIEW_CHARS)
+                        .collect::<String>()
+                });
+                sender.send((id, preview.clone())).is_ok()
+            });
+        }
+    }
+
+    /// How many subscriptions are open
+    pub fn len(&self) -> usize {
+        sync::lock(&self.subscribers).len()
+    }
+
+    /// Whether no subscriptions are open
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,202 @@
+use crate::message::{DecodeError, ErrorCode, MessageLimits, Request, Response};
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+// The HTTP gateway lets browsers and tools like curl use the archive without speaking the binary
+// protocol. It answers a small REST API over HTTP/1.1, one request per connection:
+//
+//     POST /documents       publish the request body as a document
+//     GET  /search?q=WORD   search for WORD
+//     GET  /count?q=WORD    count the documents containing WORD
+//     GET  /documents/ID    retrieve the document with id ID
+//     GET  /health          check that the server is up
+//
+// Requests are turned into the same `Request`s the binary protocol sends and answered the same
+// way. A client with an API key sends it as `Authorization: Bearer KEY`. Every answer is the
+// response as `Response::to_json` gives it, with a status code to match.
+
+/// The most bytes of request line and headers the gateway reads before giving up on a request
+pub const MAX_HEAD_LEN: usize = 8 << 10;
+
+/// An HTTP request, as much of it as the gateway uses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    /// The path, without the query string
+    pub path: String,
+    /// The query string, without the leading `?`, if there was one
+    pub query: Option<String>,
+    /// The API key from the `Authorization` header, if there was one
+    pub token: Option<String>,
+    pub body: Vec<u8>,
+}
+
+// Read one HTTP request from `reader`, refusing bodies that are over the document size in
+// `limits`.
+pub fn read_request<R: Read>(
+    reader: R,
+    limits: &MessageLimits,
+) -> Result<HttpRequest, DecodeError> {
+    let mut reader = BufReader::new(reader).take(MAX_HEAD_LEN as u64);
+    let request_line = read_line(&mut reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(DecodeError::Malformed);
+    };
+    let mut content_length = 0;
+    let mut token = None;
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or(DecodeError::Malformed)?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().map_err(|_| DecodeError::Malformed)?;
+        } else if name.trim().eq_ignore_ascii_case("authorization") {
+            token = value.trim().strip_prefix("Bearer ").map(str::to_string);
+        }
+    }
+    if content_length > limits.max_document_len.min(limits.max_message_len) {
+        return Err(DecodeError::TooLarge);
+    }
+    let mut reader = reader.into_inner();
+    let mut body = vec![0_u8; content_length];
+    reader.read_exact(&mut body).map_err(read_error)?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    Ok(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        token,
+        body,
+    })
+}
+
+// Read one line of the request head, without its line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, DecodeError> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Err(DecodeError::Malformed),
+        Ok(_) if !line.ends_with('\n') => Err(DecodeError::TooLarge),
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(e) => Err(read_error(e)),
+    }
+}
+
+fn read_error(error: io::Error) -> DecodeError {
+    match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => DecodeError::TimedOut,
+        _ => DecodeError::Malformed,
+    }
+}
+
+// Turn `request` into the request it maps onto, or the response to send if it maps onto none.
+pub fn route(request: &HttpRequest) -> Result<Request, Response> {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), &segments[..]) {
+        ("POST", ["documents"]) => match String::from_utf8(request.body.clone()) {
+            Ok(doc) => Ok(Request::Publish { doc }),
+            Err(_) => Err(Response::failure(
+                ErrorCode::Malformed,
+                "the document is not valid UTF-8",
+            )),
+        },
+        ("GET", [endpoint @ ("search" | "count")]) => {
+            let word = request
+                .query
+                .iter()
+                .flat_map(|query| query.split('&'))
+                .find_map(|pair| pair.strip_prefix("q="))
+                .and_then(percent_decode);
+            match (word, *endpoint) {
+                (Some(word), "search") => Ok(Request::Search { word }),
+                (Some(word), _) => Ok(Request::Count { word }),
+                (None, _) => Err(Response::failure(
+                    ErrorCode::Malformed,
+                    "expected a word as ?q=WORD",
+                )),
+            }
+        }
+        ("GET", ["documents", id]) => match id.parse() {
+            Ok(id) => Ok(Request::Retrieve {
+                id,
+                offset: 0,
+                length: None,
+            }),
+            Err(_) => Err(Response::failure(
+                ErrorCode::Malformed,
+                format!("{:?} is not a document id", id),
+            )),
+        },
+        ("GET", ["health"]) => Ok(Request::Ping),
+        _ => Err(Response::failure(
+            ErrorCode::NotFound,
+            format!("no such endpoint {} {}", request.method, request.path),
+        )),
+    }
+}
+
+// Decode a query string component, where `+` is a space and `%XX` the byte with hex value XX.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// The HTTP status code and reason phrase to send `response` with.
+pub fn status(response: &Response) -> (u16, &'static str) {
+    match response {
+        Response::PublishSuccess(_) => (201, "Created"),
+        Response::Failure { code, .. } => match code {
+            ErrorCode::Malformed => (400, "Bad Request"),
+            ErrorCode::NotFound => (404, "Not Found"),
+            ErrorCode::ReadOnly => (403, "Forbidden"),
+            ErrorCode::TimedOut => (408, "Request Timeout"),
+            ErrorCode::Overloaded => (503, "Service Unavailable"),
+            ErrorCode::Internal => (500, "Internal Server Error"),
+            ErrorCode::Unauthorized => (401, "Unauthorized"),
+            ErrorCode::Forbidden => (403, "Forbidden"),
+            ErrorCode::RateLimited => (429, "Too Many Requests"),
+            ErrorCode::Duplicate => (409, "Conflict"),
+            ErrorCode::MemoryLimit => (507, "Insufficient Storage"),
+            ErrorCode::OutOfRange => (416, "Range Not Satisfiable"),
+            ErrorCode::Unsupported => (501, "Not Implemented"),
+        },
+        Response::Busy => (503, "Service Unavailable"),
+        Response::TooLarge => (413, "Payload Too Large"),
+        Response::UnsupportedVersion { .. } => (400, "Bad Request"),
+        _ => (200, "OK"),
+    }
+}
+
+// Send `response` to `writer` as JSON, with the status code that goes with it.
+pub fn write_response<W: Write>(mut writer: W, response: &Response) -> io::Result<()> {
+    let (code, reason) = status(response);
+    let body = response.to_json().to_string();
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        code,
+        reason,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
@@ -0,0 +1,437 @@
+use crate::multimap::ConcurrentMultiMap;
+use crate::sync;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc, Arc, Mutex, RwLock,
+};
+use std::thread;
+
+// The reverse index is split Lucene-style into immutable, sorted segments plus an in-memory write
+// buffer. New postings go into the buffer, which is a ConcurrentMultiMap. Once the buffer holds
+// enough postings, a background thread freezes it, replaces it with an empty one, and turns the
+// frozen buffer into a new segment. The same thread merges segments together once there are too
+// many of them.
+//
+// Readers never wait for writers: all of the index's parts are reachable from a single `View`,
+// which readers clone out of an `RwLock` that is only ever write-locked to swap in a new view.
+//
+// Postings can be removed, e.g. when a document is updated. They are taken out of the write buffer
+// directly, but segments are immutable, so each segment instead carries a set of its postings
+// that have been deleted. Readers skip those, and merging drops them for good.
+
+/// The number of buffered postings that triggers a flush into a new segment
+pub const FLUSH_THRESHOLD: usize = 1 << 16;
+
+/// The number of segments that triggers merging them into one
+pub const MERGE_THRESHOLD: usize = 8;
+
+/// An immutable run of postings, sorted by term
+pub struct Segment {
+    /// Each term with the sorted ids of the documents that contain it
+    terms: Vec<(String, Vec<usize>)>,
+}
+
+impl Segment {
+    // Build a segment from (term, id) pairs in any order, possibly with duplicates.
+    fn from_postings(mut postings: Vec<(String, usize)>) -> Self {
+        postings.sort_unstable();
+        postings.dedup();
+        let mut terms: Vec<(String, Vec<usize>)> = Vec::new();
+        for (term, id) in postings {
+            match terms.last_mut() {
+                Some((last, ids)) if *last == term => ids.push(id),
+                _ => terms.push((term, vec![id])),
+            }
+        }
+        Self { terms }
+    }
+
+    // Combine several segments into one, leaving out their deleted postings.
+    fn merge(segments: &[LiveSegment]) -> Self {
+        let mut merged: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for live in segments {
+            for (term, ids) in live.segment.terms.iter() {
+                let ids = ids.iter().filter(|id| !live.is_deleted(term, **id));
+                merged.entry(term).or_default().extend(ids);
+            }
+        }
+        let terms = merged
+            .into_iter()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(term, mut ids)| {
+                ids.sort_unstable();
+                ids.dedup();
+                (term.to_string(), ids)
+            })
+            .collect();
+        Self { terms }
+    }
+
+    /// The ids of the documents in this segment that contain `term`
+    pub fn get(&self, term: &str) -> &[usize] {
+        match self
+            .terms
+            .binary_search_by(|(candidate, _)| candidate.as_str().cmp(term))
+        {
+            Ok(i) => &self.terms[i].1,
+            Err(_) => &[],
+        }
+    }
+
+    /// Each term in this segment starting with `prefix`, in order, with its posting list
+    pub fn terms_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a [usize])> + 'a {
+        let start = self
+            .terms
+            .partition_point(|(term, _)| term.as_str() < prefix);
+        self.terms[start..]
+            .iter()
+            .take_while(move |(term, _)| term.starts_with(prefix))
+            .map(|(term, ids)| (term.as_str(), ids.as_slice()))
+    }
+
+    /// The number of distinct terms in this segment
+    pub fn term_count(&self) -> usize {
+        self.terms.len()
+    }
+}
+
+// A segment along with the postings that have been deleted from it since it was made.
+#[derive(Clone)]
+struct LiveSegment {
+    segment: Arc<Segment>,
+    /// The ids deleted from each term's postings
+    deleted: Arc<HashMap<String, HashSet<usize>>>,
+}
+
+impl LiveSegment {
+    fn new(segment: Segment) -> Self {
+        Self {
+            segment: Arc::new(segment),
+            deleted: Arc::new(HashMap::new()),
+        }
+    }
+
+    fn is_deleted(&self, term: &str, id: usize) -> bool {
+        self.deleted.get(term).is_some_and(|ids| ids.contains(&id))
+    }
+
+    // The ids of the documents in this segment that still contain `term`.
+    fn get<'a>(&'a self, term: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.segment
+            .get(term)
+            .iter()
+            .copied()
+            .filter(move |id| !self.is_deleted(term, *id))
+    }
+}
+
+// A write buffer, along with the bookkeeping needed to freeze it safely. Writers register in
+// `active_writers` before inserting and back off if the buffer has been sealed, so once a sealed
+// buffer has no active writers its contents are final.
+struct Buffer {
+    postings: ConcurrentMultiMap<String, usize>,
+    active_writers: AtomicUsize,
+    sealed: AtomicBool,
+}
+
+impl Buffer {
+    fn new(bucket_count: usize) -> Self {
+        Self {
+            postings: ConcurrentMultiMap::new(bucket_count),
+            active_writers: AtomicUsize::new(0),
+            sealed: AtomicBool::new(false),
+        }
+    }
+}
+
+// Everything a reader needs to answer a query, as of one moment.
+#[derive(Clone)]
+struct View {
+    /// The buffer new postings are written to
+    buffer: Arc<Buffer>,
+    /// Buffers that have been frozen but not yet turned into segments
+    frozen: Vec<Arc<Buffer>>,
+    /// Segments, oldest first
+    segments: Vec<LiveSegment>,
+}
+
+struct Inner {
+    view: RwLock<Arc<View>>,
+    /// The number of postings written to the current buffer
+    buffered: AtomicUsize,
+    /// Serializes flushes and merges
+    maintenance: Mutex<()>,
+    bucket_count: usize,
+}
+
+impl Inner {
+    fn view(&self) -> Arc<View> {
+        Arc::clone(&sync::read(&self.view))
+    }
+
+    // Freeze the current buffer, if it holds anything, and turn it into a new segment.
+    fn flush(&self) {
+        let _maintenance = sync::lock(&self.maintenance);
+        let frozen = {
+            let mut view = sync::write(&self.view);
+            if self.buffered.swap(0, Ordering::SeqCst) == 0 {
+                return;
+            }
+            let frozen = Arc::clone(&view.buffer);
+            let mut next = View::clone(&view);
+            next.buffer = Arc::new(Buffer::new(self.bucket_count));
+            next.frozen.push(Arc::clone(&frozen));
+            *view = Arc::new(next);
+            frozen
+        };
+        // Wait for writers that started before the swap to finish
+        frozen.sealed.store(true, Ordering::SeqCst);
+        while frozen.active_writers.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+        let segment = LiveSegment::new(Segment::from_postings(frozen.postings.iter().collect()));
+        let mut view = sync::write(&self.view);
+        let mut next = View::clone(&view);
+        next.frozen.retain(|buffer| !Arc::ptr_eq(buffer, &frozen));
+        next.segments.push(segment);
+        *view = Arc::new(next);
+    }
+
+    // Merge every segment into one, if there are at least `threshold` of them.
+    fn merge(&self, threshold: usize) {
+        let _maintenance = sync::lock(&self.maintenance);
+        let segments = self.view().segments.clone();
+        if segments.len() < threshold.max(2) {
+            return;
+        }
+        let merged = LiveSegment::new(Segment::merge(&segments));
+        // Flushes and removals can't run concurrently, so the segments are unchanged since the
+        // snapshot
+        let mut view = sync::write(&self.view);
+        let mut next = View::clone(&view);
+        next.segments = vec![merged];
+        *view = Arc::new(next);
+    }
+}
+
+/// A reverse index from terms to the ids of the documents that contain them
+pub struct SegmentedIndex {
+    inner: Arc<Inner>,
+    /// Wakes the maintenance thread; dropping it stops the thread
+    wakeup: Option<mpsc::Sender<()>>,
+    maintenance_thread: Option<thread::JoinHandle<()>>,
+    flush_threshold: usize,
+}
+
+impl SegmentedIndex {
+    // Create an empty index whose write buffers have `bucket_count` buckets, and start its
+    // maintenance thread.
+    pub fn new(bucket_count: usize) -> Self {
+        Self::with_flush_threshold(bucket_count, FLUSH_THRESHOLD)
+    }
+
+    // Like `new`, but flush the write buffer into a segment once it holds `flush_threshold`
+    // postings.
+    pub fn with_flush_threshold(bucket_count: usize, flush_threshold: usize) -> Self {
+        let inner = Arc::new(Inner {
+            view: RwLock::new(Arc::new(View {
+                buffer: Arc::new(Buffer::new(bucket_count)),
+                frozen: Vec::new(),
+                segments: Vec::new(),
+            })),
+            buffered: AtomicUsize::new(0),
+            maintenance: Mutex::new(()),
+            bucket_count,
+        });
+        let (wakeup, receiver) = mpsc::channel::<()>();
+        let maintenance_thread = thread::spawn({
+            let inner = Arc::clone(&inner);
+            move || {
+                while receiver.recv().is_ok() {
+                    inner.flush();
+                    inner.merge(MERGE_THRESHOLD);
+                }
+            }
+        });
+        Self {
+            inner,
+            wakeup: Some(wakeup),
+            maintenance_thread: Some(maintenance_thread),
+            flush_threshold,
+        }
+    }
+
+    // Record that every term in `terms` appears in the document `id`. None of them should be
+    // recorded for `id` already.
+    pub fn insert<I: IntoIterator<Item = String>>(&self, terms: I, id: usize) {
+        let buffer = loop {
+            let buffer = Arc::clone(&self.inner.view().buffer);
+            buffer.active_writers.fetch_add(1, Ordering::SeqCst);
+            if !buffer.sealed.load(Ordering::SeqCst) {
+                break buffer;
+            }
+            // The buffer was frozen between reading the view and registering; use the new one
+            buffer.active_writers.fetch_sub(1, Ordering::SeqCst);
+        };
+        let mut count = 0;
+        for term in terms {
+            buffer.postings.set(term, id);
+            count += 1;
+        }
+        buffer.active_writers.fetch_sub(1, Ordering::SeqCst);
+
+        let buffered = self.inner.buffered.fetch_add(count, Ordering::SeqCst) + count;
+        if buffered >= self.flush_threshold {
+            if let Some(wakeup) = &self.wakeup {
+                let _ = wakeup.send(());
+            }
+        }
+    }
+
+    // Record every (term, id) posting in `postings` at once: readers see either none of them or
+    // all of them. The postings become a segment of their own, which is merged with the rest
+    // later. As with `insert`, none of them should be in the index already.
+    pub fn insert_atomically(&self, postings: Vec<(String, usize)>) {
+        let segment = LiveSegment::new(Segment::from_postings(postings));
+        {
+            // A merge running concurrently would drop the new segment when it swaps its result in
+            let _maintenance = sync::lock(&self.inner.maintenance);
+            let mut view = sync::write(&self.inner.view);
+            let mut next = View::clone(&view);
+            next.segments.push(segment);
+            *view = Arc::new(next);
+        }
+        // Let the maintenance thread merge segments if there are now too many
+        if let Some(wakeup) = &self.wakeup {
+            let _ = wakeup.send(());
+        }
+    }
+
+    // Record that no term in `terms` appears in the document `id` any more. Removing a posting
+    // that isn't in the index does nothing.
+    pub fn remove<I: IntoIterator<Item = String>>(&self, terms: I, id: usize) {
+        // Hold off flushes, so that every posting is either in the current buffer or a segment
+        let _maintenance = sync::lock(&self.inner.maintenance);
+        let mut view = sync::write(&self.inner.view);
+        let mut next = View::clone(&view);
+        for term in terms {
+            next.buffer.postings.remove(&term, &id);
+            for live in next.segments.iter_mut() {
+                if live.segment.get(&term).binary_search(&id).is_ok() {
+                    let deleted = Arc::make_mut(&mut live.deleted);
+                    deleted.entry(term.clone()).or_default().insert(id);
+                }
+            }
+        }
+        *view = Arc::new(next);
+    }
+
+    // Get the ids of every document that contains `term`, in ascending order.
+    pub fn get(&self, term: &str) -> Vec<usize> {
+        let view = self.inner.view();
+        let mut ids = Vec::new();
+        for segment in view.segments.iter() {
+            ids.extend(segment.get(term));
+        }
+        for buffer in view.frozen.iter().chain(std::iter::once(&view.buffer)) {
+            ids.extend(buffer.postings.get(term));
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    // The number of documents that contain `term`, as `get` would find, without collecting their
+    // ids. Each posting is only ever in one segment or buffer, so the counts can simply be added.
+    pub fn count(&self, term: &str) -> usize {
+        let view = self.inner.view();
+        let mut count = 0;
+        for live in view.segments.iter() {
+            count += match live.deleted.get(term) {
+                Some(_) => live.get(term).count(),
+                None => live.segment.get(term).len(),
+            };
+        }
+        for buffer in view.frozen.iter().chain(std::iter::once(&view.buffer)) {
+            count += buffer.postings.get_count(term);
+        }
+        count
+    }
+
+    // Get the ids of every document containing a term that starts with `prefix`, in ascending
+    // order. Segments keep their terms sorted, so the matching terms are found by binary search;
+    // only the write buffers, which are small, have to be scanned.
+    pub fn get_prefix(&self, prefix: &str) -> Vec<usize> {
+        let view = self.inner.view();
+        let mut ids = Vec::new();
+        for live in view.segments.iter() {
+            for (term, term_ids) in live.segment.terms_with_prefix(prefix) {
+                ids.extend(term_ids.iter().filter(|id| !live.is_deleted(term, **id)));
+            }
+        }
+        for buffer in view.frozen.iter().chain(std::iter::once(&view.buffer)) {
+            buffer.postings.for_each(|term, id| {
+                if term.starts_with(prefix) {
+                    ids.push(*id);
+                }
+            });
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    // Get every term starting with `prefix`, in order, with the number of documents containing it.
+    pub fn terms_with_prefix(&self, prefix: &str) -> Vec<(String, usize)> {
+        let view = self.inner.view();
+        // A posting is only ever inserted while it isn't in the index, and deleted postings are
+        // skipped, so each live posting is in exactly one part of the index and the counts of the
+        // parts can simply be added up
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for live in view.segments.iter() {
+            for (term, ids) in live.segment.terms_with_prefix(prefix) {
+                let count = ids.iter().filter(|id| !live.is_deleted(term, **id)).count();
+                if count > 0 {
+                    *counts.entry(term.to_string()).or_default() += count;
+                }
+            }
+        }
+        for buffer in view.frozen.iter().chain(std::iter::once(&view.buffer)) {
+            buffer.postings.for_each(|term, _| {
+                if term.starts_with(prefix) {
+                    *counts.entry(term.clone()).or_default() += 1;
+                }
+            });
+        }
+        counts.into_iter().collect()
+    }
+
+    // Synchronously turn the write buffer into a segment, rather than waiting for the maintenance
+    // thread to do it.
+    pub fn flush(&self) {
+        self.inner.flush();
+    }
+
+    // Synchronously merge every segment into one.
+    pub fn merge(&self) {
+        self.inner.merge(2);
+    }
+
+    /// The number of segments currently in the index
+    pub fn segment_count(&self) -> usize {
+        self.inner.view().segments.len()
+    }
+}
+
+impl Drop for SegmentedIndex {
+    fn drop(&mut self) {
+        drop(self.wakeup.take());
+        if let Some(thread) = self.maintenance_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -1,6 +1,30 @@
+pub mod analyzer;
+pub mod audit;
+pub mod auth;
+pub mod bench;
+pub mod blob_store;
+pub mod cache;
 pub mod client;
 pub mod database;
+pub mod embedded;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+pub mod feed;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod index;
 pub mod message;
+pub mod metrics;
 pub mod multimap;
 pub mod pool;
+pub mod query;
+pub mod rate_limit;
+pub mod record;
+pub mod replication;
 pub mod server;
+pub mod shard;
+pub mod snapshot;
+mod sync;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod wal;
@@ -1,6 +1,34 @@
-use clap::{Parser, Subcommand};
-use ngram::client::Client;
-use ngram::server::Server;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use ngram::analyzer::Pipeline;
+use ngram::audit::AuditLog;
+use ngram::auth::ApiKeys;
+use ngram::bench::{self, Workload};
+use ngram::client::{Client, ClientPool, RetryPolicy};
+use ngram::database::{
+    read_dump, ContentType, Database, Duplicates, Metadata, PublishOptions, SearchOptions, BUCKETS,
+    STOP_WORDS,
+};
+use ngram::message::{MessageLimits, Response, MAX_FRAME_LEN};
+use ngram::rate_limit::RateLimit;
+use ngram::record::{self, RequestLog};
+use ngram::replication::Replicator;
+use ngram::server::{
+    ConnectionTimeouts, ListenerConfig, Server, DEFAULT_BIND, MAX_WORKERS, WORKERS,
+};
+use ngram::shard::Shards;
+use ngram::snapshot::{self, SnapshotPolicy};
+use ngram::wal::WriteAheadLog;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
 // Fill out the `Args` struct to parse the command line arguments. You may find clap "subcommands"
 // helpful.
@@ -10,84 +38,2108 @@ use ngram::server::Server;
 struct Args {
     #[command(subcommand)]
     mode: Mode,
+    /// The least severe log events to print, or a filter such as `ngram=debug`
+    #[arg(long, global = true, default_value = "info", value_name = "LEVEL")]
+    log_level: String,
+    /// Print log events as lines of JSON
+    #[arg(long, global = true)]
+    log_json: bool,
 }
 
 // First need to determine if command is client or server
 #[derive(Subcommand, Debug)]
 enum Mode {
-    Client(ClientArgs),
-    Server(ServerArgs),
+    Client(Box<ClientArgs>),
+    Server(Box<ServerArgs>),
+    Local(LocalArgs),
+    Replay(ReplayArgs),
+    Bench(BenchArgs),
+    Export(DumpArgs),
+    Import(DumpArgs),
+    Diff(DiffArgs),
+    Admin(AdminArgs),
 }
 
-// If client need an address, port, and one of the three requests below
+// If client need an address, port, and one of the three requests below. The address and port
+// may be left out if they are set in the environment or the config file.
 #[derive(Parser, Debug)]
 struct ClientArgs {
-    address: String,
-    port: u16,
+    /// The server's address [default: $NGRAM_ADDR, or the config file's address]
+    address: Option<String>,
+    /// The server's port [default: $NGRAM_PORT, or the config file's port]
+    port: Option<u16>,
+    /// The file of client settings to use where no option is given
+    /// [default: ~/.config/ngram/config.toml]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Connect to the server over the Unix domain socket at this path instead of TCP
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["address", "port"])]
+    socket: Option<String>,
+    /// Print the response as a line of JSON instead of debug-formatted
+    #[arg(long)]
+    json: bool,
+    /// Connect to the server over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "ca")]
+    tls: bool,
+    /// PEM bundle of certificate authorities to trust when connecting over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls")]
+    ca: Option<String>,
+    /// Ask the server not to send a response larger than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_response_bytes: Option<usize>,
+    /// Let the server cut down a response that is too large instead of refusing it
+    #[arg(long, requires = "max_response_bytes")]
+    truncate: bool,
+    /// Ask for the metadata of the documents in search, list and retrieve responses
+    #[arg(long)]
+    metadata: bool,
+    /// API key to send with every request, for servers that require one to modify the archive
+    #[arg(long, value_name = "KEY")]
+    token: Option<String>,
+    /// Use the server's collection with this name rather than its default archive
+    #[arg(long, value_name = "NAME")]
+    collection: Option<String>,
+    /// Compress large requests and responses with zstd
+    #[cfg(feature = "compression")]
+    #[arg(long)]
+    compress: bool,
+    /// A standby server to fail over to if the server doesn't answer
+    #[arg(long, value_name = "ADDRESS:PORT")]
+    standby: Option<SocketAddr>,
+    /// Also fail over requests that modify the archive
+    #[arg(long, requires = "standby")]
+    failover_writes: bool,
+    /// Seconds to wait for a connection to the server, or 0 to wait as long as the OS does
+    /// [default: 10]
+    #[arg(long, value_name = "SECS")]
+    connect_timeout: Option<u64>,
+    /// Seconds to wait for the server to respond, or 0 to wait forever [default: 60]
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+    /// How many times to retry a request that fails for reasons that may pass
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    retries: usize,
     #[command(subcommand)]
     request: Request,
 }
 
+/// How to publish documents
+#[derive(Parser, Debug)]
+struct PublishArgs {
+    /// Store the documents without making them searchable until they are committed
+    #[arg(long)]
+    pending: bool,
+    /// The documents' title
+    #[arg(long)]
+    title: Option<String>,
+    /// Who wrote the documents
+    #[arg(long)]
+    author: Option<String>,
+    /// When the documents were written
+    #[arg(long)]
+    date: Option<String>,
+    /// The kind of text in the documents: plain, markdown or code
+    #[arg(long = "type", value_name = "TYPE", default_value_t = ContentType::Plain)]
+    content_type: ContentType,
+}
+
+impl From<PublishArgs> for PublishOptions {
+    fn from(args: PublishArgs) -> Self {
+        PublishOptions {
+            pending: args.pending,
+            metadata: Metadata {
+                title: args.title,
+                author: args.author,
+                date: args.date,
+            },
+            content_type: args.content_type,
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Request {
     Publish {
+        /// The file to publish, or - to read the document from standard input
         path: String,
+        #[command(flatten)]
+        options: PublishArgs,
     },
+    /// Publish several documents together, so that either all of them appear or none do
+    PublishBatch {
+        #[arg(required = true)]
+        paths: Vec<String>,
+        #[command(flatten)]
+        options: PublishArgs,
+    },
+    /// Publish every text file under a directory, one document each
+    PublishDir {
+        path: String,
+        /// Only publish files whose names match this pattern, e.g. '*.txt'
+        #[arg(long, value_name = "PATTERN", default_value = "*")]
+        glob: String,
+    },
+    /// Make a pending document searchable
+    Commit { doc_id: usize },
+    /// Replace a document's text with the contents of a file
+    Update { doc_id: usize, path: String },
     Search {
         word: String,
+        /// Only match documents published at or after this Unix time, in seconds
+        #[arg(long, value_name = "SECS")]
+        since: Option<u64>,
+        /// Only match documents published at or before this Unix time, in seconds
+        #[arg(long, value_name = "SECS")]
+        until: Option<u64>,
+        /// Order matches from most to least recently published
+        #[arg(long)]
+        newest_first: bool,
+        /// Leave out documents containing this word; may be repeated
+        #[arg(long = "not", value_name = "WORD")]
+        exclude: Vec<String>,
+        /// Only match documents of this type: plain, markdown or code
+        #[arg(long = "type", value_name = "TYPE")]
+        content_type: Option<ContentType>,
+        /// Show this many words of context around the word in each match
+        #[arg(long, value_name = "WORDS")]
+        snippets: Option<usize>,
+        /// Skip this many matches
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Show at most this many matches, along with how many there are in all
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Count the documents containing a word, without listing them
+    Count { word: String },
+    /// Find documents containing a word that starts with a prefix
+    Prefix { prefix: String },
+    /// Find documents containing some text anywhere, even inside a word
+    Substring { text: String },
+    /// Find documents matching a boolean query, e.g. "whale AND (ship OR boat) NOT harpoon"
+    Query { expr: String },
+    /// Find the documents most relevant to a word
+    Rank {
+        word: String,
+        /// Number of documents to return
+        #[arg(long, default_value_t = 10)]
+        top: usize,
     },
     Retrieve {
         doc_id: usize,
+        /// Only fetch the document from this byte on
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Only fetch at most this many bytes of the document
+        #[arg(long)]
+        length: Option<usize>,
+        /// Write the document to this file as it arrives, rather than printing it
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<String>,
+    },
+    /// List the documents in the archive
+    List {
+        /// Number of characters of each document to preview
+        #[arg(long, default_value_t = 40)]
+        preview: usize,
+    },
+    /// Show the server's metrics
+    Stats,
+    /// Check that the server is up, exiting with an error if it doesn't answer
+    Ping,
+    /// List the server's collections
+    Collections,
+    /// Export every indexed word with its document frequency and total occurrences, one per line
+    TermStats {
+        /// Number of words to fetch per request
+        #[arg(long, default_value_t = 1000, value_parser = positive)]
+        page_size: usize,
+    },
+    /// Search for a word and show the first matching documents, each with its id
+    SearchFetch {
+        word: String,
+        /// Number of documents to show
+        #[arg(long, default_value_t = 5)]
+        limit: usize,
+        /// Only show this many words of context around the word in each document
+        #[arg(long, value_name = "WORDS")]
+        snippets: Option<usize>,
     },
+    /// Send requests typed at a prompt, over one connection kept open between them
+    Shell,
+    /// Suggest indexed words starting with a prefix
+    Suggest {
+        prefix: String,
+        /// Maximum number of words to suggest
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Print each document containing a word as it is published, until the server stops or nothing
+    /// matches for the read timeout; pass --timeout 0 to wait for matches indefinitely
+    Subscribe { word: String },
 }
 
 // Else, just need port, only one server command
 #[derive(Parser, Debug)]
 struct ServerArgs {
+    /// The port to accept requests on
+    #[arg(required_unless_present = "config")]
+    port: Option<u16>,
+    /// Read settings from this TOML file; options given on the command line override it
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Keep the write-ahead log and snapshots in this directory, unless `--wal` or `--snapshot`
+    /// put them elsewhere
+    #[arg(long, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+    /// Number of worker threads handling requests
+    #[arg(long, default_value_t = WORKERS, value_parser = positive)]
+    workers: usize,
+    /// Most worker threads an admin may resize the pool to
+    #[arg(long, value_name = "N", default_value_t = MAX_WORKERS, value_parser = positive)]
+    max_workers: usize,
+    /// Number of buckets in the reverse index's hash map
+    #[arg(long, default_value_t = BUCKETS, value_parser = positive)]
+    buckets: usize,
+    /// Local address to listen on, e.g. 0.0.0.0 or ::1; may be repeated, e.g. with 0.0.0.0 and ::
+    /// to serve both IPv4 and IPv6
+    #[arg(long, value_name = "ADDR", default_values_t = [DEFAULT_BIND])]
+    bind: Vec<IpAddr>,
+    /// Additional ports to accept any request on
+    #[arg(long = "extra-port", value_name = "PORT")]
+    extra_ports: Vec<u16>,
+    /// Additional ports that refuse requests which modify the archive
+    #[arg(long = "read-only-port", value_name = "PORT")]
+    read_only_ports: Vec<u16>,
+    /// Record every request received to this JSONL file
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+    /// Include the full text of published documents in the request log
+    #[arg(long, requires = "record")]
+    record_payloads: bool,
+    /// Record who made every change to the archive, and what it changed, to this JSONL file
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<String>,
+    /// Log every change to the archive to this file before acknowledging it, and replay the
+    /// changes already in it on startup
+    #[arg(long, value_name = "FILE")]
+    wal: Option<String>,
+    /// Save the archive to this file in the background, and load it from there on startup
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<String>,
+    /// Seconds between snapshots, or 0 to only take them after `--snapshot-writes` changes
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 300,
+        requires = "snapshot"
+    )]
+    snapshot_interval: u64,
+    /// Also take a snapshot after this many changes to the archive
+    #[arg(long, value_name = "N", value_parser = positive, requires = "snapshot")]
+    snapshot_writes: Option<usize>,
+    /// Handle connections on async tasks instead of a thread pool; `--workers` is ignored
+    #[cfg(feature = "async")]
+    #[arg(long = "async")]
+    r#async: bool,
+    /// Also serve metrics over HTTP at /metrics on this port
+    #[arg(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+    /// Also serve the archive over HTTP on this port
+    #[cfg(feature = "http")]
+    #[arg(long, value_name = "PORT")]
+    http_port: Option<u16>,
+    /// Also accept requests on a Unix domain socket at this path
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    socket: Option<String>,
+    /// Largest request to accept, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = MAX_FRAME_LEN)]
+    max_message_bytes: usize,
+    /// Largest document to accept for publishing, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = MAX_FRAME_LEN)]
+    max_document_bytes: usize,
+    /// Most requests to queue while every worker is busy; more are refused as overloaded
+    #[arg(long, value_name = "N")]
+    queue_capacity: Option<usize>,
+    /// Most connections to hold open at once; more are refused as overloaded
+    #[arg(long, value_name = "N", value_parser = positive)]
+    max_connections: Option<usize>,
+    /// Most requests each client address may make per second, over time
+    #[arg(long, value_name = "RPS", value_parser = positive_rate)]
+    rate_limit: Option<f64>,
+    /// Most requests each client address may make at once; defaults to one second's worth
+    #[arg(long, value_name = "N", value_parser = positive, requires = "rate_limit")]
+    rate_burst: Option<usize>,
+    /// Seconds to wait for a client to send its request before closing the connection, or 0 to
+    /// wait forever
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    read_timeout: u64,
+    /// Seconds to wait for a client to take its response before closing the connection, or 0 to
+    /// wait forever
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    write_timeout: u64,
+    /// Stages to turn each word into the term it is indexed under, in order, separated by commas:
+    /// lowercase, punctuation, unicode, standard for those three, and stem to reduce English
+    /// words to their stems
+    #[arg(long, value_name = "STAGES", default_value = "lowercase")]
+    analyzer: Pipeline,
+    /// File of words to leave out of the index, separated by whitespace, instead of the built-in
+    /// list of common English words. An empty file indexes every word
+    #[arg(long, value_name = "FILE")]
+    stopwords: Option<String>,
+    /// File of `NAME KEY [ROLE]` lines, giving the API keys that may modify the archive; admin
+    /// requests are refused without one
+    #[arg(long, value_name = "FILE")]
+    api_keys: Option<String>,
+    /// Give each API key but admins' its own collection, and keep it from seeing any other
+    #[arg(long)]
+    tenant_isolation: bool,
+    /// Send every change to the archive to the follower server at this address; may be repeated
+    #[arg(long, value_name = "ADDR:PORT")]
+    replicate_to: Vec<SocketAddr>,
+    /// Key the primary sends changes to followers with; a follower only takes changes sent with
+    /// the key it was given
+    #[arg(long, value_name = "KEY")]
+    replication_key: Option<String>,
+    /// Take changes only from a primary server replicating to this one, and refuse any others
+    #[arg(long)]
+    follower: bool,
+    /// Run as a coordinator, splitting the archive across the backend server at this address; may
+    /// be repeated, and the backends must always be given in the same order
+    #[arg(long = "shard", value_name = "ADDR:PORT")]
+    shards: Vec<SocketAddr>,
+    /// API key to send requests to the backends with
+    #[arg(long, value_name = "KEY")]
+    shard_key: Option<String>,
+    /// Keep documents compressed with zstd at this level, from 1 (fastest) to 22 (smallest)
+    #[cfg(feature = "compression")]
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1..=22))]
+    compression_level: Option<i32>,
+    /// Index character n-grams of this many characters, to speed up substring searches
+    #[arg(long, value_name = "N", value_parser = positive)]
+    ngram: Option<usize>,
+    /// Cache the results of this many recent searches, emptied whenever the archive changes
+    #[arg(long, value_name = "N", value_parser = positive)]
+    search_cache: Option<usize>,
+    /// Refuse publishes once the archive takes up roughly this many bytes of memory
+    #[arg(long, value_name = "BYTES", value_parser = positive)]
+    memory_limit: Option<usize>,
+    /// What publishing a copy of a stored document does: allow stores it again, reuse returns
+    /// the stored document's id, and reject fails with it
+    #[arg(long, value_name = "POLICY", default_value_t = Duplicates::Allow)]
+    duplicates: Duplicates,
+    /// PEM certificate chain to serve TLS with
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// PEM private key matching `--tls-cert`
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// Chance, from 0 to 1, of delaying each response by `--fault-delay-ms`
+    #[cfg(feature = "fault-injection")]
+    #[arg(long, value_name = "RATE", default_value_t = 0.0, value_parser = rate)]
+    fault_delay_rate: f64,
+    /// How long a delayed response stalls, in milliseconds
+    #[cfg(feature = "fault-injection")]
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    fault_delay_ms: u64,
+    /// Chance, from 0 to 1, of closing the connection instead of responding
+    #[cfg(feature = "fault-injection")]
+    #[arg(long, value_name = "RATE", default_value_t = 0.0, value_parser = rate)]
+    fault_drop_rate: f64,
+    /// Chance, from 0 to 1, of sending a corrupted response
+    #[cfg(feature = "fault-injection")]
+    #[arg(long, value_name = "RATE", default_value_t = 0.0, value_parser = rate)]
+    fault_corrupt_rate: f64,
+}
+
+/// Server settings read from a TOML file, each used where the command line doesn't give one.
+/// They are named as the options are, with the limits and auth settings in their own tables.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ServerConfig {
+    port: Option<u16>,
+    bind: Option<Vec<IpAddr>>,
+    extra_ports: Vec<u16>,
+    read_only_ports: Vec<u16>,
+    workers: Option<usize>,
+    max_workers: Option<usize>,
+    buckets: Option<usize>,
+    data_dir: Option<PathBuf>,
+    limits: LimitsConfig,
+    auth: AuthConfig,
+    replication: ReplicationConfig,
+    sharding: ShardingConfig,
+}
+
+/// The `[limits]` table of a server config file
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsConfig {
+    max_message_bytes: Option<usize>,
+    max_document_bytes: Option<usize>,
+    queue_capacity: Option<usize>,
+    max_connections: Option<usize>,
+    rate_limit: Option<f64>,
+    rate_burst: Option<usize>,
+    read_timeout: Option<u64>,
+    write_timeout: Option<u64>,
+    memory_limit: Option<usize>,
+}
+
+/// The `[auth]` table of a server config file
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AuthConfig {
+    api_keys: Option<String>,
+    tenant_isolation: bool,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+}
+
+/// The `[replication]` table of a server config file
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ReplicationConfig {
+    replicate_to: Vec<SocketAddr>,
+    key: Option<String>,
+    follower: bool,
+}
+
+/// The `[sharding]` table of a server config file
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ShardingConfig {
+    backends: Vec<SocketAddr>,
+    key: Option<String>,
+}
+
+impl ServerConfig {
+    // Check the settings the command line would have refused.
+    fn validate(&self) -> Result<(), String> {
+        let counts = [
+            ("workers", self.workers),
+            ("max_workers", self.max_workers),
+            ("buckets", self.buckets),
+            ("limits.max_connections", self.limits.max_connections),
+            ("limits.rate_burst", self.limits.rate_burst),
+            ("limits.memory_limit", self.limits.memory_limit),
+        ];
+        if let Some((name, _)) = counts.iter().find(|(_, count)| *count == Some(0)) {
+            return Err(format!("{} must be at least 1", name));
+        }
+        if let Some(rate) = self.limits.rate_limit {
+            positive_rate(&rate.to_string()).map_err(|e| format!("limits.rate_limit {}", e))?;
+        }
+        #[cfg(not(feature = "tls"))]
+        if self.auth.tls_cert.is_some() || self.auth.tls_key.is_some() {
+            return Err("TLS settings need the tls feature".to_string());
+        }
+        Ok(())
+    }
+}
+
+// The files a server keeps in its `--data-dir`
+const WAL_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot";
+
+// Fill in the settings the command line left out of `server_args` from the `--config` file, if
+// there is one, and create the `--data-dir` directory. `matches` are the server's parsed
+// arguments, which tell which settings were given on the command line. Return the port to serve.
+fn configure_server(server_args: &mut ServerArgs, matches: &ArgMatches) -> Result<u16, String> {
+    if let Some(path) = &server_args.config {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let config: ServerConfig = toml::from_str(&text)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        let (limits, auth, replication) = (config.limits, config.auth, config.replication);
+        let sharding = config.sharding;
+        // Settings without a default were given on the command line if they are set at all
+        server_args.port = server_args.port.or(config.port);
+        if server_args.extra_ports.is_empty() {
+            server_args.extra_ports = config.extra_ports;
+        }
+        if server_args.read_only_ports.is_empty() {
+            server_args.read_only_ports = config.read_only_ports;
+        }
+        server_args.data_dir = server_args.data_dir.take().or(config.data_dir);
+        server_args.queue_capacity = server_args.queue_capacity.or(limits.queue_capacity);
+        server_args.max_connections = server_args.max_connections.or(limits.max_connections);
+        server_args.rate_limit = server_args.rate_limit.or(limits.rate_limit);
+        server_args.rate_burst = server_args.rate_burst.or(limits.rate_burst);
+        server_args.memory_limit = server_args.memory_limit.or(limits.memory_limit);
+        server_args.api_keys = server_args.api_keys.take().or(auth.api_keys);
+        server_args.tenant_isolation |= auth.tenant_isolation;
+        if server_args.replicate_to.is_empty() {
+            server_args.replicate_to = replication.replicate_to;
+        }
+        server_args.replication_key = server_args.replication_key.take().or(replication.key);
+        server_args.follower |= replication.follower;
+        if server_args.shards.is_empty() {
+            server_args.shards = sharding.backends;
+        }
+        server_args.shard_key = server_args.shard_key.take().or(sharding.key);
+        #[cfg(feature = "tls")]
+        {
+            server_args.tls_cert = server_args.tls_cert.take().or(auth.tls_cert);
+            server_args.tls_key = server_args.tls_key.take().or(auth.tls_key);
+        }
+        // The rest need the matches to tell
+        configure(&mut server_args.bind, config.bind, matches, "bind");
+        configure(&mut server_args.workers, config.workers, matches, "workers");
+        configure(
+            &mut server_args.max_workers,
+            config.max_workers,
+            matches,
+            "max_workers",
+        );
+        configure(&mut server_args.buckets, config.buckets, matches, "buckets");
+        configure(
+            &mut server_args.max_message_bytes,
+            limits.max_message_bytes,
+            matches,
+            "max_message_bytes",
+        );
+        configure(
+            &mut server_args.max_document_bytes,
+            limits.max_document_bytes,
+            matches,
+            "max_document_bytes",
+        );
+        configure(
+            &mut server_args.read_timeout,
+            limits.read_timeout,
+            matches,
+            "read_timeout",
+        );
+        configure(
+            &mut server_args.write_timeout,
+            limits.write_timeout,
+            matches,
+            "write_timeout",
+        );
+    }
+    if server_args.tenant_isolation && server_args.api_keys.is_none() {
+        return Err("Tenant isolation needs API keys".to_string());
+    }
+    if (server_args.follower || !server_args.replicate_to.is_empty())
+        && server_args.replication_key.is_none()
+    {
+        return Err("Replication needs a key; pass --replication-key".to_string());
+    }
+    #[cfg(feature = "tls")]
+    if server_args.tls_cert.is_some() != server_args.tls_key.is_some() {
+        return Err("TLS needs both a certificate and a key".to_string());
+    }
+    if let Some(dir) = &server_args.data_dir {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create data directory {}: {}", dir.display(), e))?;
+        let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        server_args.wal.get_or_insert_with(|| file(WAL_FILE));
+        // The async server takes no snapshots
+        #[cfg(feature = "async")]
+        let snapshots = !server_args.r#async;
+        #[cfg(not(feature = "async"))]
+        let snapshots = true;
+        if snapshots {
+            server_args
+                .snapshot
+                .get_or_insert_with(|| file(SNAPSHOT_FILE));
+        }
+    }
+    server_args
+        .port
+        .ok_or_else(|| "No port given; pass one, or set port in the config file".to_string())
+}
+
+// The archive kept in the data directory `dir`: the snapshot there, if any, with the changes in
+// the write-ahead log since made to it. Also return the log, to append further changes to.
+fn open_data_dir(dir: &Path) -> Result<(Database, WriteAheadLog), String> {
+    let database = Database::new();
+    let snapshot = dir.join(SNAPSHOT_FILE);
+    let seq = snapshot::load(&database, &snapshot)
+        .map_err(|e| format!("Failed to load snapshot {}: {}", snapshot.display(), e))?;
+    let path = dir.join(WAL_FILE);
+    let wal = WriteAheadLog::open(&path)
+        .and_then(|wal| wal.replay(&database, seq.unwrap_or(0)).map(|_| wal))
+        .map_err(|e| format!("Failed to replay write-ahead log {}: {}", path.display(), e))?;
+    Ok((database, wal))
+}
+
+// Dump the archive in the data directory `dump_args` names to its file.
+fn run_export(dump_args: DumpArgs) -> Result<(), String> {
+    let (database, _) = open_data_dir(&dump_args.data_dir)?;
+    let exported = File::create(&dump_args.file)
+        .and_then(|file| database.export(BufWriter::new(file)))
+        .map_err(|e| format!("Failed to write {}: {}", dump_args.file, e))?;
+    println!("Exported {} documents to {}", exported, dump_args.file);
+    Ok(())
+}
+
+// Publish every document in the dump `dump_args` names into its data directory, creating the
+// directory if need be. The documents are appended to the write-ahead log, so the server finds
+// them when it next starts. As with the Import request, documents keep their ids, so the
+// directory's archive must be empty, and a document that can't keep its id or is refused stops
+// the import.
+fn run_import(dump_args: DumpArgs) -> Result<(), String> {
+    let dir = &dump_args.data_dir;
+    std::fs::create_dir_all(dir)
+        .map_err(|e| format!("Failed to create data directory {}: {}", dir.display(), e))?;
+    let (database, wal) = open_data_dir(dir)?;
+    if database.document_count() > 0 {
+        return Err(format!(
+            "{} already holds documents; dumps can only be imported into an empty archive, so \
+             documents keep their ids",
+            dir.display()
+        ));
+    }
+    let file = File::open(&dump_args.file)
+        .map_err(|e| format!("Failed to open {}: {}", dump_args.file, e))?;
+    let mut imported = 0;
+    for dumped in read_dump(BufReader::new(file)) {
+        let dumped = dumped.map_err(|e| {
+            format!(
+                "{} isn't a valid dump, after {} documents: {}",
+                dump_args.file, imported, e
+            )
+        })?;
+        let id = dumped.id;
+        if database.document_count() != id {
+            return Err(format!(
+                "Document {} of {} can't keep its id, after {} documents",
+                id, dump_args.file, imported
+            ));
+        }
+        match wal.append(dumped.into(), &database) {
+            Ok(Response::PublishSuccess(published)) if published == id => imported += 1,
+            Ok(Response::Failure { message, .. }) => {
+                return Err(format!(
+                    "Document {} of {} was refused, after {} documents: {}",
+                    id, dump_args.file, imported, message
+                ))
+            }
+            Ok(_) => {
+                return Err(format!(
+                    "Document {} of {} didn't keep its id, after {} documents",
+                    id, dump_args.file, imported
+                ))
+            }
+            Err(e) => return Err(format!("Failed to log document: {}", e)),
+        }
+    }
+    println!("Imported {} documents into {}", imported, dir.display());
+    Ok(())
+}
+
+// Set `setting` to `value` from a config file, unless the argument `id` was given on the
+// command line.
+fn configure<T>(setting: &mut T, value: Option<T>, matches: &ArgMatches, id: &str) {
+    let given = matches.value_source(id) == Some(ValueSource::CommandLine);
+    if let (false, Some(value)) = (given, value) {
+        *setting = value;
+    }
+}
+
+// The words the server should leave out of its index: those in the `--stopwords` file, or the
+// built-in list if there isn't one.
+fn stop_words(server_args: &ServerArgs) -> Result<Vec<String>, String> {
+    match &server_args.stopwords {
+        Some(path) => std::fs::read_to_string(path)
+            .map(|text| text.split_whitespace().map(str::to_string).collect())
+            .map_err(|e| format!("Failed to read stop words {}: {}", path, e)),
+        None => Ok(STOP_WORDS.iter().map(|word| word.to_string()).collect()),
+    }
+}
+
+// When the server was asked to save snapshots, if at all
+fn snapshot_policy(server_args: &ServerArgs) -> Option<SnapshotPolicy> {
+    let path = server_args.snapshot.as_ref()?;
+    Some(SnapshotPolicy {
+        path: path.into(),
+        interval: (server_args.snapshot_interval > 0)
+            .then(|| Duration::from_secs(server_args.snapshot_interval)),
+        writes: server_args.snapshot_writes,
+    })
+}
+
+// The faults the server was asked to inject, if any
+#[cfg(feature = "fault-injection")]
+fn fault_config(server_args: &ServerArgs) -> Option<ngram::faults::FaultConfig> {
+    let injecting = server_args.fault_delay_rate > 0.0
+        || server_args.fault_drop_rate > 0.0
+        || server_args.fault_corrupt_rate > 0.0;
+    injecting.then(|| ngram::faults::FaultConfig {
+        delay: std::time::Duration::from_millis(server_args.fault_delay_ms),
+        delay_rate: server_args.fault_delay_rate,
+        drop_rate: server_args.fault_drop_rate,
+        corrupt_rate: server_args.fault_corrupt_rate,
+    })
+}
+
+// Run the async server on `listeners` with the options in `server_args`, rejecting the options it
+// doesn't support.
+#[cfg(feature = "async")]
+fn run_async_server(server_args: &ServerArgs, listeners: &[ListenerConfig]) -> Result<(), String> {
+    if server_args.metrics_port.is_some() {
+        return Err("--metrics-port is not supported with --async".to_string());
+    }
+    if server_args.queue_capacity.is_some() {
+        return Err("--queue-capacity is not supported with --async".to_string());
+    }
+    #[cfg(feature = "http")]
+    if server_args.http_port.is_some() {
+        return Err("--http-port is not supported with --async".to_string());
+    }
+    if server_args.snapshot.is_some() {
+        return Err("--snapshot is not supported with --async".to_string());
+    }
+    #[cfg(unix)]
+    if server_args.socket.is_some() {
+        return Err("--socket is not supported with --async".to_string());
+    }
+    #[cfg(feature = "tls")]
+    if server_args.tls_cert.is_some() {
+        return Err("TLS is not supported with --async".to_string());
+    }
+    let server = ngram::server::AsyncServer::with_buckets(server_args.buckets)
+        .with_limits(MessageLimits {
+            max_message_len: server_args.max_message_bytes,
+            max_document_len: server_args.max_document_bytes,
+        })
+        .with_timeouts(connection_timeouts(server_args));
+    let server = match rate_limit(server_args) {
+        Some(limit) => server.with_rate_limit(limit),
+        None => server,
+    };
+    let server = match server_args.max_connections {
+        Some(max) => server.with_max_connections(max),
+        None => server,
+    };
+    let server = server
+        .with_analyzer(server_args.analyzer.clone())
+        .with_stop_words(stop_words(server_args)?);
+    let server = match server_args.ngram {
+        Some(n) => server.with_ngram_index(n),
+        None => server,
+    };
+    let server = server.with_duplicates(server_args.duplicates);
+    let server = match server_args.search_cache {
+        Some(capacity) => server.with_search_cache(capacity),
+        None => server,
+    };
+    let server = match server_args.memory_limit {
+        Some(bytes) => server.with_memory_limit(bytes),
+        None => server,
+    };
+    #[cfg(feature = "compression")]
+    let server = match server_args.compression_level {
+        Some(level) => server.with_compression(level),
+        None => server,
+    };
+    let server = match &server_args.record {
+        Some(path) => match RequestLog::open(path, server_args.record_payloads) {
+            Ok(log) => server.with_request_log(log),
+            Err(e) => return Err(format!("Failed to open request log {}: {}", path, e)),
+        },
+        None => server,
+    };
+    let server = match &server_args.audit_log {
+        Some(path) => match AuditLog::open(path) {
+            Ok(log) => server.with_audit_log(log),
+            Err(e) => return Err(format!("Failed to open audit log {}: {}", path, e)),
+        },
+        None => server,
+    };
+    let server = match &server_args.api_keys {
+        Some(path) => server.with_api_keys(
+            ApiKeys::load(path).map_err(|e| format!("Failed to read API keys {}: {}", path, e))?,
+        ),
+        None => server,
+    };
+    let server = match server_args.tenant_isolation {
+        true => server.with_tenant_isolation(),
+        false => server,
+    };
+    let server = match &server_args.wal {
+        Some(path) => WriteAheadLog::open(path)
+            .and_then(|wal| server.with_write_ahead_log(wal))
+            .map_err(|e| format!("Failed to replay write-ahead log {}: {}", path, e))?,
+        None => server,
+    };
+    let server = match replicator(server_args) {
+        Some(replicator) => server.with_replication(replicator),
+        None => server,
+    };
+    let server = match (server_args.follower, &server_args.replication_key) {
+        (true, Some(key)) => server.as_follower(key),
+        _ => server,
+    };
+    let server = match &server_args.data_dir {
+        Some(dir) => server.with_data_dir(dir),
+        None => server,
+    };
+    let server = match shards(server_args) {
+        Some(shards) => server.with_shards(shards),
+        None => server,
+    };
+    #[cfg(feature = "fault-injection")]
+    let server = match fault_config(server_args) {
+        Some(config) => server.with_faults(config),
+        None => server,
+    };
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    runtime
+        .block_on(server.run_listeners(listeners))
+        .map_err(|e| format!("Failed to start listeners: {}", e))
+}
+
+// Something to send changes to the followers `server_args` names, if it names any.
+fn replicator(server_args: &ServerArgs) -> Option<Replicator> {
+    (!server_args.replicate_to.is_empty()).then(|| {
+        Replicator::new(
+            &server_args.replicate_to,
+            server_args.replication_key.as_deref(),
+        )
+    })
+}
+
+// The backends `server_args` splits the archive across, if it names any.
+fn shards(server_args: &ServerArgs) -> Option<Shards> {
+    (!server_args.shards.is_empty())
+        .then(|| Shards::new(&server_args.shards, server_args.shard_key.as_deref()))
+}
+
+// Parse a probability between 0 and 1
+#[cfg(feature = "fault-injection")]
+fn rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if (0.0..=1.0).contains(&r) => Ok(r),
+        Ok(_) => Err("must be between 0 and 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// The connection timeouts asked for in `server_args`, where 0 seconds means no timeout.
+fn connection_timeouts(server_args: &ServerArgs) -> ConnectionTimeouts {
+    let timeout = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+    ConnectionTimeouts {
+        read: timeout(server_args.read_timeout),
+        write: timeout(server_args.write_timeout),
+    }
+}
+
+// The rate limit asked for in `server_args`, if any.
+fn rate_limit(server_args: &ServerArgs) -> Option<RateLimit> {
+    let per_second = server_args.rate_limit?;
+    let burst = server_args.rate_burst.unwrap_or(per_second.ceil() as usize);
+    Some(RateLimit {
+        per_second,
+        burst: u32::try_from(burst).unwrap_or(u32::MAX),
+    })
+}
+
+// Parse a rate that must be above zero
+fn positive_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if r > 0.0 && r.is_finite() => Ok(r),
+        Ok(_) => Err("must be above 0".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Parse a count that must be at least one
+fn positive(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Replay mode re-sends the requests in a log recorded with `server --record` to another server
+#[derive(Parser, Debug)]
+struct ReplayArgs {
+    log: String,
+    address: String,
     port: u16,
+    /// Reproduce the original gaps between requests instead of sending them back to back
+    #[arg(long)]
+    timed: bool,
 }
 
+// Bench mode sends a random mix of requests to a server as fast as it answers them, and reports
+// how many it answered and how long they took
+#[derive(Parser, Debug)]
+struct BenchArgs {
+    address: String,
+    port: u16,
+    /// How often to publish, relative to the other kinds of request
+    #[arg(long, value_name = "WEIGHT", default_value_t = 1)]
+    publish: u32,
+    /// How often to search, relative to the other kinds of request
+    #[arg(long, value_name = "WEIGHT", default_value_t = 8)]
+    search: u32,
+    /// How often to retrieve, relative to the other kinds of request
+    #[arg(long, value_name = "WEIGHT", default_value_t = 1)]
+    retrieve: u32,
+    /// Number of requests to have in flight at once
+    #[arg(long, value_name = "N", default_value_t = 8, value_parser = positive)]
+    concurrency: usize,
+    /// Seconds to send requests for
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = positive)]
+    duration: usize,
+    /// Number of words in each published document
+    #[arg(long, value_name = "N", default_value_t = 200, value_parser = positive)]
+    doc_words: usize,
+    /// Number of distinct words documents are made of
+    #[arg(long, value_name = "N", default_value_t = 10_000, value_parser = positive)]
+    vocabulary: usize,
+    /// Seeds the random choices, so a run can be repeated
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+    /// Send requests over kept-alive connections, one per request in flight, instead of opening
+    /// a connection for each request
+    #[arg(long)]
+    keep_alive: bool,
+    /// Print the report as a line of JSON
+    #[arg(long)]
+    json: bool,
+}
+
+// Admin mode asks a running server about itself or tells it what to do, with an admin key
+#[derive(Parser, Debug)]
+struct AdminArgs {
+    address: String,
+    port: u16,
+    /// Admin API key, for servers that require one
+    #[arg(long, value_name = "KEY")]
+    token: Option<String>,
+    /// Export or import the server's collection with this name rather than its default archive
+    #[arg(long, value_name = "NAME")]
+    collection: Option<String>,
+    /// Print the response as a line of JSON instead of debug-formatted
+    #[arg(long)]
+    json: bool,
+    /// Connect to the server over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "ca")]
+    tls: bool,
+    /// PEM bundle of certificate authorities to trust when connecting over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE", requires = "tls")]
+    ca: Option<String>,
+    #[command(subcommand)]
+    command: AdminCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Print the server's metrics
+    Stats,
+    /// Stop the server once it has answered the requests it already accepted
+    Shutdown,
+    /// Save the archive to the server's snapshot file now
+    Snapshot,
+    /// Dump every document in the archive to a file in the server's data directory
+    Export { path: String },
+    /// Publish every document in a dump in the server's data directory into its empty archive,
+    /// keeping their ids
+    Import { path: String },
+    /// Create an empty collection, which clients can use with `--collection`
+    CreateCollection { name: String },
+    /// Grow or shrink the server's thread pool without restarting it
+    ResizePool {
+        /// The number of worker threads to answer requests with
+        #[arg(value_parser = positive)]
+        workers: usize,
+    },
+}
+
+// Export and import modes dump the archive in a server's data directory to a file, or publish the
+// documents in a dump into one, while the server isn't running
+#[derive(Parser, Debug)]
+struct DumpArgs {
+    /// The data directory the server keeps with `--data-dir`
+    #[arg(long, value_name = "DIR")]
+    data_dir: PathBuf,
+    /// The dump to write or read
+    file: String,
+}
+
+// Diff mode compares two index files saved by local mode, e.g. a backup and the live index
+#[derive(Parser, Debug)]
+struct DiffArgs {
+    /// The older index file
+    old: String,
+    /// The newer index file
+    new: String,
+    /// Print the differences as a line of JSON
+    #[arg(long)]
+    json: bool,
+}
+
+// Load the index files named in `diff_args` and print how they differ.
+fn run_diff(diff_args: DiffArgs) -> Result<(), String> {
+    let load = |path: &String| -> Result<Database, String> {
+        let file =
+            std::fs::File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        Database::load(std::io::BufReader::new(file))
+            .map_err(|e| format!("Failed to load index {}: {}", path, e))
+    };
+    let diff = load(&diff_args.old)?.diff(&load(&diff_args.new)?);
+    if diff_args.json {
+        println!("{}", serde_json::json!(diff));
+        return Ok(());
+    }
+    for id in diff.added.iter() {
+        println!("+ document {}", id);
+    }
+    for id in diff.removed.iter() {
+        println!("- document {}", id);
+    }
+    for id in diff.changed.iter() {
+        println!("~ document {}", id);
+    }
+    for delta in diff.terms.iter() {
+        println!(
+            "{}\t{} -> {} ({:+})",
+            delta.term,
+            delta.before,
+            delta.after,
+            delta.after as i64 - delta.before as i64
+        );
+    }
+    Ok(())
+}
+
+// Local mode runs the same search engine in-process, without a server. Documents can come from
+// `--file` arguments, from a saved index, or both.
+#[derive(Parser, Debug)]
+struct LocalArgs {
+    /// Index file to load before and save after running the command
+    #[arg(long, value_name = "FILE")]
+    index: Option<String>,
+    /// Documents to publish before running the command
+    #[arg(long = "file", value_name = "PATH")]
+    files: Vec<String>,
+    #[command(subcommand)]
+    request: LocalRequest,
+}
+
+#[derive(Subcommand, Debug)]
+enum LocalRequest {
+    Publish {
+        paths: Vec<String>,
+    },
+    Search {
+        word: String,
+    },
+    /// Count the documents containing a word, without listing them
+    Count {
+        word: String,
+    },
+    /// Find documents containing a word that starts with a prefix
+    Prefix {
+        prefix: String,
+    },
+    /// Find documents containing some text anywhere, even inside a word
+    Substring {
+        text: String,
+    },
+    /// Find documents matching a boolean query, e.g. "whale AND (ship OR boat) NOT harpoon"
+    Query {
+        expr: String,
+    },
+    /// Find the documents most relevant to a word
+    Rank {
+        word: String,
+        /// Number of documents to return
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+    Retrieve {
+        doc_id: usize,
+    },
+    /// List the documents in the archive
+    List {
+        /// Number of characters of each document to preview
+        #[arg(long, default_value_t = 40)]
+        preview: usize,
+    },
+    /// Print every indexed word with its document frequency and total occurrences
+    TermStats,
+}
+
+// Run a local request against an in-process database, loading and saving the index file if one
+// was given.
+fn run_local(local_args: LocalArgs) -> Result<(), String> {
+    let database = match &local_args.index {
+        Some(index) if std::path::Path::new(index).exists() => {
+            let file = std::fs::File::open(index).map_err(|e| e.to_string())?;
+            Database::load(std::io::BufReader::new(file))
+                .map_err(|e| format!("Failed to load index {}: {}", index, e))?
+        }
+        _ => Database::new(),
+    };
+    let publish = |path: &String| -> Result<usize, String> {
+        let doc =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Ok(database.publish(doc))
+    };
+    for path in local_args.files.iter() {
+        publish(path)?;
+    }
+    match local_args.request {
+        LocalRequest::Publish { paths } => {
+            for path in paths.iter() {
+                println!("Published {} as document {}", path, publish(path)?);
+            }
+        }
+        LocalRequest::Search { word } => println!("{:?}", database.search(&word)),
+        LocalRequest::Count { word } => println!("{}", database.count(&word)),
+        LocalRequest::Prefix { prefix } => println!("{:?}", database.search_prefix(&prefix)),
+        LocalRequest::Substring { text } => {
+            println!("{:?}", database.search_substring(&text))
+        }
+        LocalRequest::Query { expr } => match expr.parse() {
+            Ok(query) => println!("{:?}", database.search_query(&query)),
+            Err(e) => return Err(e),
+        },
+        LocalRequest::Rank { word, top } => {
+            for (id, score) in database.search_ranked(&word, top) {
+                println!("{}\t{:.6}", id, score);
+            }
+        }
+        LocalRequest::Retrieve { doc_id } => match database.retrieve(doc_id) {
+            Some(doc) => println!("{}", doc),
+            None => return Err(format!("No document with id {}", doc_id)),
+        },
+        LocalRequest::List { preview } => {
+            for summary in database.list(preview) {
+                println!("{:?}", summary);
+            }
+        }
+        LocalRequest::TermStats => {
+            for (term, doc_frequency, occurrences) in database.term_stats(None, usize::MAX) {
+                println!("{}\t{}\t{}", term, doc_frequency, occurrences);
+            }
+        }
+    }
+    if let Some(index) = &local_args.index {
+        let file = std::fs::File::create(index).map_err(|e| e.to_string())?;
+        database
+            .save(std::io::BufWriter::new(file))
+            .map_err(|e| format!("Failed to save index {}: {}", index, e))?;
+    }
+    Ok(())
+}
+
+// Print a response from the server, either debug-formatted or as a line of JSON.
+fn print_response(response: Option<Response>, json: bool) {
+    match (response, json) {
+        (Some(Response::StatsSuccess(text)), false) => print!("{}", text),
+        (
+            Some(Response::Paged {
+                response,
+                total_hits,
+            }),
+            false,
+        ) => {
+            println!("{} matches in all", total_hits);
+            print_response(Some(*response), false);
+        }
+        (Some(Response::SearchSnippetsSuccess(results)), false) => {
+            for (id, snippet) in results {
+                println!("{}: {}", id, snippet);
+            }
+        }
+        (Some(response), false) => println!("Server response: {:?}", response),
+        (Some(response), true) => println!("{}", response.to_json()),
+        (None, false) => eprintln!("Error: Failed to get response from server."),
+        (None, true) => println!(
+            "{}",
+            serde_json::json!({
+                "type": "error",
+                "message": "Failed to get response from server.",
+            })
+        ),
+    }
+}
+
+// Connect to the server and send the administrative request the user asked for.
+fn run_admin(admin_args: AdminArgs) {
+    let client = Client::new(&admin_args.address, admin_args.port);
+    #[cfg(feature = "tls")]
+    let client = match (admin_args.tls, &admin_args.ca) {
+        (true, Some(ca)) => match ngram::tls::client_config(ca) {
+            Ok(config) => client.with_tls(config),
+            Err(e) => {
+                eprintln!("Error: Failed to load CA bundle {}: {}", ca, e);
+                return;
+            }
+        },
+        _ => client,
+    };
+    let client = match &admin_args.token {
+        Some(token) => client.with_token(token),
+        None => client,
+    };
+    let client = match &admin_args.collection {
+        Some(name) => client.with_collection(name),
+        None => client,
+    };
+    let response = match admin_args.command {
+        AdminCommand::Stats => client.stats(),
+        AdminCommand::Shutdown => client.shutdown(),
+        AdminCommand::Snapshot => client.snapshot(),
+        AdminCommand::Export { path } => client.export(&path),
+        AdminCommand::Import { path } => client.import(&path),
+        AdminCommand::CreateCollection { name } => client.create_collection(&name),
+        AdminCommand::ResizePool { workers } => client.resize_pool(workers),
+    };
+    print_response(response, admin_args.json);
+}
+
+/// Client settings read from a TOML file, each used where the command line doesn't give one
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ClientConfig {
+    address: Option<String>,
+    port: Option<u16>,
+    /// In seconds, as for `--connect-timeout`
+    connect_timeout: Option<u64>,
+    /// In seconds, as for `--timeout`
+    timeout: Option<u64>,
+    output: Option<OutputFormat>,
+}
+
+/// How the client prints responses
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+// Read the client settings in the file at `path`, or in the default config file if no path is
+// given. It's fine for the default file not to exist, but not for one that was asked for.
+fn load_client_config(path: Option<&Path>) -> Result<ClientConfig, String> {
+    let (path, required) = match (path, default_config_path()) {
+        (Some(path), _) => (path.to_path_buf(), true),
+        (None, Some(path)) => (path, false),
+        (None, None) => return Ok(ClientConfig::default()),
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if !required && e.kind() == ErrorKind::NotFound => {
+            return Ok(ClientConfig::default())
+        }
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    toml::from_str(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+// Where the client looks for its settings when `--config` isn't given:
+// `$XDG_CONFIG_HOME/ngram/config.toml`, or `~/.config/ngram/config.toml` if that isn't set.
+fn default_config_path() -> Option<PathBuf> {
+    let config_dir = match env_var("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env_var("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("ngram").join("config.toml"))
+}
+
+// The value of the environment variable `name`, unless it is unset or empty.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+// The address and port of the server to connect to. Each is taken from the command line if it's
+// given there, or else from `NGRAM_ADDR` or `NGRAM_PORT`, or else from `config`.
+fn server_address(
+    client_args: &ClientArgs,
+    config: &ClientConfig,
+) -> Result<(String, u16), String> {
+    let address = client_args
+        .address
+        .clone()
+        .or_else(|| env_var("NGRAM_ADDR"))
+        .or_else(|| config.address.clone())
+        .ok_or(
+            "No server address given; pass one, or set NGRAM_ADDR or the config file's address",
+        )?;
+    let port = match (client_args.port, env_var("NGRAM_PORT")) {
+        (Some(port), _) => port,
+        (None, Some(port)) => port
+            .parse()
+            .map_err(|_| format!("NGRAM_PORT is not a port number: {:?}", port))?,
+        (None, None) => config
+            .port
+            .ok_or("No server port given; pass one, or set NGRAM_PORT or the config file's port")?,
+    };
+    Ok((address, port))
+}
+
+// Send the workload the user asked for to the server, and print how it kept up.
+fn run_bench(bench_args: BenchArgs) {
+    if bench_args.publish + bench_args.search + bench_args.retrieve == 0 {
+        eprintln!("Error: At least one kind of request must have a weight above 0");
+        return;
+    }
+    let workload = Workload {
+        publish_weight: bench_args.publish,
+        search_weight: bench_args.search,
+        retrieve_weight: bench_args.retrieve,
+        concurrency: bench_args.concurrency,
+        duration: Duration::from_secs(bench_args.duration as u64),
+        doc_words: bench_args.doc_words,
+        vocabulary: bench_args.vocabulary,
+        seed: bench_args.seed,
+    };
+    let client = Client::new(&bench_args.address, bench_args.port);
+    let report = if bench_args.keep_alive {
+        let pool = ClientPool::new(client, bench_args.concurrency);
+        bench::run(|request| pool.send(request), &workload)
+    } else {
+        bench::run(|request| client.send(request), &workload)
+    };
+    if bench_args.json {
+        let kinds = report
+            .kinds
+            .iter()
+            .map(|latencies| {
+                serde_json::json!({
+                    "kind": latencies.kind,
+                    "count": latencies.count,
+                    "failed": latencies.failed,
+                    "p50_ms": latencies.p50.as_secs_f64() * 1000.0,
+                    "p90_ms": latencies.p90.as_secs_f64() * 1000.0,
+                    "p99_ms": latencies.p99.as_secs_f64() * 1000.0,
+                    "max_ms": latencies.max.as_secs_f64() * 1000.0,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "{}",
+            serde_json::json!({
+                "type": "bench",
+                "requests": report.requests(),
+                "failed": report.failed(),
+                "elapsed_secs": report.elapsed.as_secs_f64(),
+                "throughput": report.throughput(),
+                "kinds": kinds,
+            })
+        );
+        return;
+    }
+    println!(
+        "Sent {} requests ({} failed) in {:.1?}: {:.1} requests/s",
+        report.requests(),
+        report.failed(),
+        report.elapsed,
+        report.throughput()
+    );
+    println!(
+        "{:<10} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "kind", "count", "failed", "p50", "p90", "p99", "max"
+    );
+    for latencies in &report.kinds {
+        println!(
+            "{:<10} {:>8} {:>8} {:>10.2?} {:>10.2?} {:>10.2?} {:>10.2?}",
+            latencies.kind,
+            latencies.count,
+            latencies.failed,
+            latencies.p50,
+            latencies.p90,
+            latencies.p99,
+            latencies.max
+        );
+    }
+}
+
+// Connect to the server and send the request the user asked for. In JSON mode, only the response
+// is printed so the output can be piped straight into tools like `jq`.
+fn run_client(client_args: ClientArgs) {
+    let config = match load_client_config(client_args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    let json = client_args.json || config.output == Some(OutputFormat::Json);
+    let say = |message: String| {
+        if !json {
+            println!("{}", message);
+        }
+    };
+    let builder = match server_address(&client_args, &config) {
+        #[cfg(unix)]
+        _ if client_args.socket.is_some() => {
+            let path = client_args.socket.as_deref().unwrap_or_default();
+            say(format!("Connecting to server at {}...", path));
+            Client::unix_builder(path)
+        }
+        Ok((address, port)) => {
+            say(format!("Connecting to server at {}:{}...", address, port));
+            Client::builder(&address, port)
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+    let builder = builder.with_retry(RetryPolicy {
+        max_retries: client_args.retries,
+        ..RetryPolicy::default()
+    });
+    let connect_timeout = client_args.connect_timeout.or(config.connect_timeout);
+    let builder = match connect_timeout.unwrap_or(10) {
+        0 => builder,
+        secs => builder.with_connect_timeout(Duration::from_secs(secs)),
+    };
+    let client = match client_args.timeout.or(config.timeout).unwrap_or(60) {
+        0 => builder.build(),
+        secs => builder.with_read_timeout(Duration::from_secs(secs)).build(),
+    };
+    #[cfg(feature = "tls")]
+    let client = match (client_args.tls, &client_args.ca) {
+        (true, Some(ca)) => match ngram::tls::client_config(ca) {
+            Ok(config) => client.with_tls(config),
+            Err(e) => {
+                eprintln!("Error: Failed to load CA bundle {}: {}", ca, e);
+                return;
+            }
+        },
+        _ => client,
+    };
+    let client = match client_args.max_response_bytes {
+        Some(max_len) => client.with_max_response_len(max_len, client_args.truncate),
+        None => client,
+    };
+    let client = match client_args.metadata {
+        true => client.with_metadata(),
+        false => client,
+    };
+    let client = match &client_args.token {
+        Some(token) => client.with_token(token),
+        None => client,
+    };
+    let client = match &client_args.collection {
+        Some(name) => client.with_collection(name),
+        None => client,
+    };
+    #[cfg(feature = "compression")]
+    let client = match client_args.compress {
+        true => client.with_compression(),
+        false => client,
+    };
+    let client = match (client_args.standby, client_args.failover_writes) {
+        (Some(standby), true) => client.with_standby(standby).with_failover_writes(),
+        (Some(standby), false) => client.with_standby(standby),
+        (None, _) => client,
+    };
+    let probe = matches!(client_args.request, Request::Ping);
+    let response = match client_args.request {
+        Request::Publish { path, options } => {
+            say(format!("Sending PUBLISH request for: {}", path));
+            let options = PublishOptions::from(options);
+            if path == "-" {
+                // Streamed, so a document piped in needn't be held in memory before sending
+                client.publish_from_reader_with(std::io::stdin().lock(), options)
+            } else if options == PublishOptions::default() {
+                client.publish_from_path(&path)
+            } else {
+                client.publish_from_path_with(&path, options)
+            }
+        }
+        Request::PublishBatch { paths, options } => {
+            say(format!(
+                "Sending PUBLISH_BATCH request for: {}",
+                paths.join(", ")
+            ));
+            client.publish_batch_from_paths(&paths, options.into())
+        }
+        Request::PublishDir { path, glob } => {
+            say(format!("Publishing files matching {} under {}", glob, path));
+            let published = match client.publish_dir_matching(&path, &glob) {
+                Ok(published) => published,
+                Err(e) => {
+                    eprintln!("Error: Failed to read directory {}: {}", path, e);
+                    return;
+                }
+            };
+            if json {
+                let documents = published
+                    .iter()
+                    .map(|(file, id)| serde_json::json!({ "path": file, "doc_id": id }))
+                    .collect::<Vec<_>>();
+                println!(
+                    "{}",
+                    serde_json::json!({ "type": "publish_dir", "documents": documents })
+                );
+                return;
+            }
+            for (file, id) in published {
+                match id {
+                    Some(id) => println!("{} -> {}", file.display(), id),
+                    None => println!("{} -> failed", file.display()),
+                }
+            }
+            return;
+        }
+        Request::TermStats { page_size } => {
+            // Words are written out as each page arrives rather than gathered into one response
+            let mut stdout = std::io::stdout().lock();
+            let exported =
+                client.export_term_stats(page_size, |term, doc_frequency, occurrences| {
+                    let _ = if json {
+                        writeln!(
+                            stdout,
+                            "{}",
+                            serde_json::json!({
+                                "term": term,
+                                "doc_frequency": doc_frequency,
+                                "occurrences": occurrences,
+                            })
+                        )
+                    } else {
+                        writeln!(stdout, "{}\t{}\t{}", term, doc_frequency, occurrences)
+                    };
+                });
+            if let Err(e) = exported {
+                eprintln!("Error: Failed to export term statistics: {}", e);
+            }
+            return;
+        }
+        Request::Subscribe { word } => {
+            say(format!("Sending SUBSCRIBE request for: {}", word));
+            let subscription = match client.subscribe(&word) {
+                Ok(subscription) => subscription,
+                Err(response) => {
+                    print_response(response, json);
+                    return;
+                }
+            };
+            for (id, preview) in subscription {
+                if json {
+                    println!("{}", Response::Notification { id, preview }.to_json());
+                } else {
+                    println!("{}: {}", id, preview);
+                }
+            }
+            return;
+        }
+        Request::SearchFetch {
+            word,
+            limit,
+            snippets,
+        } => {
+            say(format!("Searching for {} and retrieving the matches", word));
+            let documents = match client.search_and_retrieve(&word, limit, snippets) {
+                Ok(documents) => documents,
+                Err(e) => {
+                    eprintln!("Error: Failed to search for {}: {}", word, e);
+                    return;
+                }
+            };
+            if json {
+                let documents = documents
+                    .iter()
+                    .map(|(id, text)| serde_json::json!({ "doc_id": id, "text": text }))
+                    .collect::<Vec<_>>();
+                println!(
+                    "{}",
+                    serde_json::json!({ "type": "search_fetch", "documents": documents })
+                );
+                return;
+            }
+            for (id, text) in documents {
+                match snippets {
+                    Some(_) => println!("{}: {}", id, text),
+                    None => println!("=== Document {} ===\n{}", id, text.trim_end()),
+                }
+            }
+            return;
+        }
+        Request::Shell => {
+            run_shell(&ClientPool::new(client, 1), json);
+            return;
+        }
+        Request::Stats => {
+            say("Sending STATS request".to_string());
+            client.stats()
+        }
+        Request::Ping => {
+            say("Sending PING request".to_string());
+            client.ping()
+        }
+        Request::Collections => {
+            say("Sending LIST_COLLECTIONS request".to_string());
+            client.collections()
+        }
+        Request::Commit { doc_id } => {
+            say(format!("Sending COMMIT request for: {}", doc_id));
+            client.commit(doc_id)
+        }
+        Request::Update { doc_id, path } => {
+            say(format!(
+                "Sending UPDATE request for {} from: {}",
+                doc_id, path
+            ));
+            client.update_from_path(doc_id, &path)
+        }
+        Request::Search {
+            word,
+            since,
+            until,
+            newest_first,
+            exclude,
+            content_type,
+            snippets,
+            offset,
+            limit,
+        } => {
+            say(format!("Sending SEARCH request for: {}", word));
+            let options = SearchOptions {
+                since,
+                until,
+                newest_first,
+                exclude,
+                content_type,
+                snippet_words: snippets,
+                offset,
+                limit,
+            };
+            if options == SearchOptions::default() {
+                client.search(&word)
+            } else {
+                client.search_with(&word, options)
+            }
+        }
+        Request::Count { word } => {
+            say(format!("Sending COUNT request for: {}", word));
+            client.count(&word)
+        }
+        Request::Prefix { prefix } => {
+            say(format!("Sending PREFIX SEARCH request for: {}", prefix));
+            client.search_prefix(&prefix)
+        }
+        Request::Substring { text } => {
+            say(format!("Sending SUBSTRING SEARCH request for: {}", text));
+            client.search_substring(&text)
+        }
+        Request::Query { expr } => {
+            say(format!("Sending QUERY request for: {}", expr));
+            client.query(&expr)
+        }
+        Request::Rank { word, top } => {
+            say(format!("Sending RANKED SEARCH request for: {}", word));
+            client.search_ranked(&word, top)
+        }
+        Request::Retrieve {
+            doc_id,
+            offset,
+            length,
+            output: Some(path),
+        } => {
+            say(format!("Sending RETRIEVE request for: {}", doc_id));
+            let file = match File::create(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Error: Failed to create {}: {}", path, e);
+                    return;
+                }
+            };
+            let written =
+                client.retrieve_range_to_writer(doc_id, offset, length, BufWriter::new(file));
+            match written {
+                Ok(Ok(bytes)) if json => println!(
+                    "{}",
+                    serde_json::json!({ "type": "retrieve_to_file", "path": path, "bytes": bytes })
+                ),
+                Ok(Ok(bytes)) => println!("Wrote {} bytes to {}", bytes, path),
+                // Nothing was retrieved, so don't leave an empty file behind
+                Ok(Err(response)) => {
+                    let _ = std::fs::remove_file(&path);
+                    print_response(Some(response), json);
+                }
+                Err(e) => {
+                    let _ = std::fs::remove_file(&path);
+                    eprintln!("Error: Failed to retrieve document {}: {}", doc_id, e);
+                }
+            }
+            return;
+        }
+        Request::Retrieve {
+            doc_id,
+            offset,
+            length,
+            output: None,
+        } => {
+            say(format!("Sending RETRIEVE request for: {}", doc_id));
+            client.retrieve_range(doc_id, offset, length)
+        }
+        Request::List { preview } => {
+            say("Sending LIST request".to_string());
+            client.list(preview)
+        }
+        Request::Suggest { prefix, limit } => {
+            say(format!("Sending SUGGEST request for: {}", prefix));
+            client.suggest(&prefix, limit)
+        }
+    };
+    let healthy = matches!(response, Some(Response::Pong { .. }));
+    print_response(response, json);
+    // A probe fails when the server doesn't answer, so orchestrators can tell from the exit code
+    if probe && !healthy {
+        std::process::exit(1);
+    }
+}
+
+const SHELL_HELP: &str = "\
+Commands:
+  publish <path>   Publish the document in a file
+  search <word>    Find the documents containing a word
+  retrieve <id>    Show the document with an id
+  help             Show this list
+  quit             Leave the shell
+";
+
+// Read commands at a prompt, with the lines already entered available through the arrow keys,
+// and send each one to the server through `pool` until the user quits or ends the input. The
+// pool keeps its connection open between commands, reopening it if the server closes it.
+fn run_shell(pool: &ClientPool, json: bool) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Error: Failed to open the shell: {}", e);
+            return;
+        }
+    };
+    if !json {
+        println!("Type help for a list of commands.");
+    }
+    loop {
+        let line = match editor.readline("ngram> ") {
+            Ok(line) => line,
+            // Ctrl-C abandons the line being typed, as in other shells
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Error: Failed to read command: {}", e);
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        let response = match (command, argument) {
+            ("quit" | "exit", "") => break,
+            ("help", "") => {
+                print!("{}", SHELL_HELP);
+                continue;
+            }
+            ("publish", path) if !path.is_empty() => pool.publish_from_path(path),
+            ("search", word) if !word.is_empty() => pool.search(word),
+            ("retrieve", id) => match id.parse() {
+                Ok(id) => pool.retrieve(id),
+                Err(_) => {
+                    eprintln!("Error: Expected a document id, not {:?}", id);
+                    continue;
+                }
+            },
+            _ => {
+                eprintln!("Error: Unknown command {:?}; type help for a list", line);
+                continue;
+            }
+        };
+        print_response(response, json);
+    }
+}
+
+// Send log events at `--log-level` and above to stderr, as lines of JSON if `--log-json` is given.
+fn init_logging(args: &Args) -> Result<(), String> {
+    let filter = EnvFilter::try_new(&args.log_level)
+        .map_err(|e| format!("Invalid log level {:?}: {}", args.log_level, e))?;
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    if args.log_json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+    Ok(())
+}
 
 // Inspect the contents of the `args` struct that has been created from the command line arguments
 // the user passed. Depending on the arguments, either start a server or make a client and send the
 // appropriate request. You may find it helpful to print the request response.
 fn main() {
-    let args = Args::parse();
+    // Parsed in two steps to keep the matches, which tell the server's settings given on the
+    // command line from those its config file may fill in
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Err(e) = init_logging(&args) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
     match args.mode {
         // Client mode
-        Mode::Client(client_args) => { 
-            println!("Connecting to server at {}:{}...", client_args.address, client_args.port);
-            let client = Client::new(&client_args.address, client_args.port);
-            match client_args.request {
-                Request::Publish { path }=> {
-                    println!("Sending PUBLISH request for: {}", path);
-                    match client.publish_from_path(&path) {
-                        Some(response) => println!("Server response: {:?}", response),
-                        None => eprintln!("Error: Failed to get response from server."),
-                    }
+        Mode::Client(client_args) => run_client(*client_args),
+        // Server mode
+        Mode::Server(mut server_args) => {
+            let server_matches = matches
+                .subcommand_matches("server")
+                .expect("the server subcommand was parsed");
+            let port = match configure_server(&mut server_args, server_matches) {
+                Ok(port) => port,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
                 }
-                Request::Search { word }=> {
-                    println!("Sending SEARCH request for: {}", word);
-                    match client.search(&word) {
-                        Some(response) => println!("Server response: {:?}", response),
-                        None => eprintln!("Error: Failed to get response from server."),
-                    }
+            };
+            info!(bind = ?server_args.bind, port, "starting server");
+            let mut listeners = vec![ListenerConfig::new(port)];
+            listeners.extend(
+                server_args
+                    .extra_ports
+                    .iter()
+                    .copied()
+                    .map(ListenerConfig::new),
+            );
+            listeners.extend(
+                server_args
+                    .read_only_ports
+                    .iter()
+                    .copied()
+                    .map(ListenerConfig::read_only),
+            );
+            // Every port is served on every address
+            let listeners = server_args
+                .bind
+                .iter()
+                .flat_map(|&address| listeners.iter().map(move |listener| listener.bind(address)))
+                .collect::<Vec<_>>();
+            #[cfg(feature = "async")]
+            if server_args.r#async {
+                if let Err(e) = run_async_server(&server_args, &listeners) {
+                    error!("{}", e);
+                    std::process::exit(1);
                 }
-                Request::Retrieve { doc_id }=> {
-                    println!("Sending RETRIEVE request for: {}", doc_id);
-                    match client.retrieve(doc_id) {
-                        Some(response) => println!("Server response: {:?}", response),
-                        None => eprintln!("Error: Failed to get response from server."),
+                return;
+            }
+            let server = Server::with_capacity(server_args.workers, server_args.buckets)
+                .with_limits(MessageLimits {
+                    max_message_len: server_args.max_message_bytes,
+                    max_document_len: server_args.max_document_bytes,
+                })
+                .with_timeouts(connection_timeouts(&server_args));
+            let server = match server_args.queue_capacity {
+                Some(capacity) => server.with_queue_capacity(capacity),
+                None => server,
+            };
+            let server = server.with_max_workers(server_args.max_workers);
+            let server = match rate_limit(&server_args) {
+                Some(limit) => server.with_rate_limit(limit),
+                None => server,
+            };
+            let server = match server_args.max_connections {
+                Some(max) => server.with_max_connections(max),
+                None => server,
+            };
+            let server = server.with_analyzer(server_args.analyzer.clone());
+            let server = match stop_words(&server_args) {
+                Ok(stop_words) => server.with_stop_words(stop_words),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let server = match server_args.ngram {
+                Some(n) => server.with_ngram_index(n),
+                None => server,
+            };
+            let server = server.with_duplicates(server_args.duplicates);
+            let server = match server_args.search_cache {
+                Some(capacity) => server.with_search_cache(capacity),
+                None => server,
+            };
+            let server = match server_args.memory_limit {
+                Some(bytes) => server.with_memory_limit(bytes),
+                None => server,
+            };
+            #[cfg(feature = "compression")]
+            let server = match server_args.compression_level {
+                Some(level) => server.with_compression(level),
+                None => server,
+            };
+            let server = match &server_args.record {
+                Some(path) => match RequestLog::open(path, server_args.record_payloads) {
+                    Ok(log) => server.with_request_log(log),
+                    Err(e) => {
+                        error!("failed to open request log {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                },
+                None => server,
+            };
+            let server = match &server_args.audit_log {
+                Some(path) => match AuditLog::open(path) {
+                    Ok(log) => server.with_audit_log(log),
+                    Err(e) => {
+                        error!("failed to open audit log {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                },
+                None => server,
+            };
+            let server = match &server_args.api_keys {
+                Some(path) => match ApiKeys::load(path) {
+                    Ok(keys) => server.with_api_keys(keys),
+                    Err(e) => {
+                        error!("failed to read API keys {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                },
+                None => server,
+            };
+            let server = match server_args.tenant_isolation {
+                true => server.with_tenant_isolation(),
+                false => server,
+            };
+            let server = match snapshot_policy(&server_args) {
+                Some(policy) => match server.with_snapshots(policy) {
+                    Ok(server) => server,
+                    Err(e) => {
+                        let path = server_args.snapshot.as_deref().unwrap_or_default();
+                        error!("failed to load snapshot {}: {}", path, e);
+                        std::process::exit(1);
                     }
+                },
+                None => server,
+            };
+            let server = match &server_args.wal {
+                Some(path) => match WriteAheadLog::open(path)
+                    .and_then(|wal| server.with_write_ahead_log(wal))
+                {
+                    Ok(server) => server,
+                    Err(e) => {
+                        error!("failed to replay write-ahead log {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                },
+                None => server,
+            };
+            let server = match replicator(&server_args) {
+                Some(replicator) => server.with_replication(replicator),
+                None => server,
+            };
+            let server = match (server_args.follower, &server_args.replication_key) {
+                (true, Some(key)) => server.as_follower(key),
+                _ => server,
+            };
+            let server = match &server_args.data_dir {
+                Some(dir) => server.with_data_dir(dir),
+                None => server,
+            };
+            let server = match shards(&server_args) {
+                Some(shards) => server.with_shards(shards),
+                None => server,
+            };
+            let server = match server_args.metrics_port {
+                Some(port) => server.with_metrics_listener((server_args.bind[0], port).into()),
+                None => server,
+            };
+            #[cfg(feature = "http")]
+            let server = match server_args.http_port {
+                Some(port) => server.with_http_listener((server_args.bind[0], port).into()),
+                None => server,
+            };
+            #[cfg(unix)]
+            let server = match &server_args.socket {
+                Some(path) => server.with_unix_socket(path),
+                None => server,
+            };
+            #[cfg(feature = "tls")]
+            let server = match (&server_args.tls_cert, &server_args.tls_key) {
+                (Some(cert), Some(key)) => match ngram::tls::server_config(cert, key) {
+                    Ok(config) => server.with_tls(config),
+                    Err(e) => {
+                        error!("failed to load TLS certificate or key: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                _ => server,
+            };
+            #[cfg(feature = "fault-injection")]
+            let server = match fault_config(&server_args) {
+                Some(config) => server.with_faults(config),
+                None => server,
+            };
+            server.run_listeners(&listeners);
+        }
+        // Replay mode
+        Mode::Replay(replay_args) => {
+            let client = Client::new(&replay_args.address, replay_args.port);
+            match record::replay(&replay_args.log, &client, replay_args.timed) {
+                Ok(summary) => println!(
+                    "Replayed {} requests ({} failed) in {:?}",
+                    summary.sent + summary.failed,
+                    summary.failed,
+                    summary.elapsed
+                ),
+                Err(e) => {
+                    eprintln!("Error: Failed to replay {}: {}", replay_args.log, e);
+                    std::process::exit(1);
                 }
             }
         }
-        // Server mode
-        Mode::Server(server_args) => {
-            println!("Starting server on port {}...", server_args.port);
-            let server = Server::new();
-            server.run(server_args.port);
+        // Bench mode
+        Mode::Bench(bench_args) => run_bench(bench_args),
+        // Export and import modes
+        Mode::Export(dump_args) => {
+            if let Err(e) = run_export(dump_args) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Mode::Import(dump_args) => {
+            if let Err(e) = run_import(dump_args) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        // Admin mode
+        Mode::Admin(admin_args) => run_admin(admin_args),
+        // Diff mode
+        Mode::Diff(diff_args) => {
+            if let Err(e) = run_diff(diff_args) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        // Local mode
+        Mode::Local(local_args) => {
+            if let Err(e) = run_local(local_args) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
-
     }
 }
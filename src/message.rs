@@ -1,3 +1,107 @@
+use crate::database::{ContentType, DocumentSummary, Metadata, PublishOptions, SearchOptions};
+use serde_json::json;
+use std::io::{self, Read, Write};
+use std::iter;
+
+// Every message is sent as a frame: its length as a big-endian u32, followed by that many bytes
+// of body. The reader takes in the whole frame before decoding it, so a message that is cut off
+// or that has trailing bytes is rejected as a whole instead of being half-read.
+//
+// Ids, counts and lengths are `usize` in memory but always sent as big-endian u64s, so machines
+// with different pointer widths agree on the format. A value too big for the reader's `usize`
+// makes the message malformed.
+//
+// A request body starts with the two bytes of `MAGIC` and the version of the protocol the client
+// speaks, then a `RequestHeader` of options that apply to any request, then the one-byte tag
+// saying which request it is. A server answers a request in a version it doesn't speak with
+// `UnsupportedVersion`, giving the versions it does, rather than guessing at what the rest of the
+// request means. A response body starts directly with its tag. Version 2 added
+// `RequestHeader::keep_alive`, version 3 `RequestHeader::stream_documents`, version 4
+// `RequestHeader::collection`, and version 5 `RequestHeader::request_id`; a request in an older
+// version is read as if they were unset.
+//
+// A client that gives its requests ids and keeps the connection alive may pipeline them: send the
+// next before the last is answered. The response to a request with an id is wrapped in `Tagged`,
+// and may arrive before the responses to requests sent earlier, so the client matches them up by
+// id. A request that can't be read has no id to answer with, so the failure sent for it isn't
+// tagged, and the server hangs up after sending it.
+//
+// A document too large to send in one frame can be streamed instead: a request frame tagged
+// `PUBLISH_STREAM_TAG` carries the publish options, and the document follows in raw chunk frames,
+// ending with an empty one. The reader puts the chunks back together and hands over an ordinary
+// `PublishWith` request. A client that sets `RequestHeader::stream_documents` has retrieved
+// documents streamed back to it the same way, after a response frame holding only
+// `DOCUMENT_STREAM_TAG`.
+//
+// A client that sets `RequestHeader::compression` may compress the part of a large request after
+// its header, and lets the server compress large responses. A compressed part is sent as the tag
+// `COMPRESSED_TAG` followed by the zstd-compressed tag and fields, and is decompressed before it
+// is decoded, so compression never shows in the decoded message. Compression needs the
+// `compression` feature; without it nothing is compressed and compressed messages are malformed.
+
+/// The bytes every request starts with, so that anything else sent to a server is rejected early
+pub const MAGIC: [u8; 2] = *b"NG";
+
+/// The version of the protocol this crate speaks
+pub const PROTOCOL_VERSION: u8 = 5;
+
+/// The oldest version of the protocol a server still answers
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// The largest frame body that will be read unless told otherwise, in bytes
+pub const MAX_FRAME_LEN: usize = 256 << 20;
+
+/// The tag that marks the rest of a message body as compressed
+pub const COMPRESSED_TAG: u8 = 255;
+
+/// The tag of a request that starts streaming a document to publish in chunks
+pub const PUBLISH_STREAM_TAG: u8 = 21;
+
+/// The tag of a response that starts streaming a retrieved document in chunks
+pub const DOCUMENT_STREAM_TAG: u8 = 25;
+
+/// The most bytes of a streamed document sent in one chunk
+pub const STREAM_CHUNK_LEN: usize = 64 << 10;
+
+/// The smallest message body worth compressing, in bytes
+pub const COMPRESSION_THRESHOLD: usize = 4096;
+
+/// The zstd level messages are compressed at
+#[cfg(feature = "compression")]
+const WIRE_COMPRESSION_LEVEL: i32 = 3;
+
+/// Limits on the size of the requests a server accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// The largest frame body that will be read, in bytes
+    pub max_message_len: usize,
+    /// The longest document that may be published, in bytes
+    pub max_document_len: usize,
+}
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            max_message_len: MAX_FRAME_LEN,
+            max_document_len: MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// Why a message could not be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The message was cut off, or isn't a valid encoding of any message
+    Malformed,
+    /// The message, or a document in it, is over the size limit
+    TooLarge,
+    /// The message didn't arrive before the reader's timeout
+    TimedOut,
+    /// The connection was closed before any of the message arrived
+    Closed,
+    /// The request was in the given version of the protocol, which the reader doesn't speak
+    UnsupportedVersion(u8),
+}
+
 /// A request from the client to the server
 #[derive(Debug, PartialEq)]
 pub enum Request {
@@ -5,75 +109,600 @@ pub enum Request {
     Publish { doc: String },
     /// Search for the word `word` in the archive
     Search { word: String },
-    /// Retrieve the document with the index `id` from the archive
-    Retrieve { id: usize },
+    /// Retrieve the document with the index `id` from the archive, or only the `length` bytes of
+    /// it from `offset` on. A range running past the end of the document is cut short there, and
+    /// so that it ends on a whole character
+    Retrieve {
+        id: usize,
+        offset: usize,
+        length: Option<usize>,
+    },
+    /// List every document in the archive, with a preview of its first `preview_chars`
+    /// characters
+    List { preview_chars: usize },
+    /// Suggest up to `limit` indexed words starting with `prefix`
+    SuggestTerms { prefix: String, limit: usize },
+    /// Search for the word `word` in the archive, filtering and ordering the results as `options`
+    /// asks
+    SearchWith {
+        word: String,
+        options: SearchOptions,
+    },
+    /// Find the `k` documents most relevant to the word `word`, ranked by TF-IDF
+    SearchRanked { word: String, k: usize },
+    /// Add the document `doc` to the archive as `options` asks
+    PublishWith {
+        doc: String,
+        options: PublishOptions,
+    },
+    /// Make the pending document with the index `id` searchable
+    Commit { id: usize },
+    /// Report the server's metrics
+    Stats,
+    /// Replace the text of the document with the index `id` with `doc`
+    Update { id: usize, doc: String },
+    /// Add every document in `docs` to the archive as `options` asks, all or nothing
+    PublishBatch {
+        docs: Vec<String>,
+        options: PublishOptions,
+    },
+    /// Search for documents containing a word starting with `prefix`
+    SearchPrefix { prefix: String },
+    /// Search for documents containing `text` anywhere, not just as a whole word
+    SearchSubstring { text: String },
+    /// Report, for up to `limit` indexed words in alphabetical order after `after`, how many
+    /// documents contain each and how many times it appears in all of them
+    TermStats { after: Option<String>, limit: usize },
+    /// Search for documents matching a boolean query over words, e.g.
+    /// `whale AND (ship OR boat) NOT harpoon`, as `query::Query` parses it
+    Query { expr: String },
+    /// Stop the server, once the requests it has already accepted are answered
+    Shutdown,
+    /// Save the archive to the server's snapshot file now
+    Snapshot,
+    /// Check that the server is up, cheaply
+    Ping,
+    /// Count the documents containing the word `word`, without listing them
+    Count { word: String },
+    /// Write every document in the archive to the file called `path` in the server's data
+    /// directory, as a dump that `Database::export` writes
+    Export { path: String },
+    /// Publish every document in the dump called `path` in the server's data directory, each
+    /// under the id it was dumped with; only an empty archive can be imported into
+    Import { path: String },
+    /// Create an empty collection called `name`, which requests name in their header to use it
+    /// rather than the server's default archive
+    CreateCollection { name: String },
+    /// List the names of the server's collections
+    ListCollections,
+    /// Make a change its primary made, numbered `seq` in the primary's `session`, to a follower's
+    /// archive. `entry` is the change as a JSON write-ahead log entry
+    Replicate {
+        session: u64,
+        seq: u64,
+        entry: String,
+    },
+    /// Be sent a `Notification` on this connection whenever a document containing the word `word`
+    /// becomes searchable, until hanging up
+    Subscribe { word: String },
+    /// Grow or shrink the server's thread pool to `workers` threads
+    ResizePool { workers: usize },
 }
 impl Request {
-    // Convert the request `self` into a byte vector. 
-    // One byte tag at beginning encodes which of the three requests is sent
+    /// Whether handling this request modifies the archive
+    pub fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Request::Publish { .. }
+                | Request::PublishWith { .. }
+                | Request::Commit { .. }
+                | Request::Update { .. }
+                | Request::PublishBatch { .. }
+                | Request::Import { .. }
+                | Request::Replicate { .. }
+        )
+    }
+
+    /// Whether this request asks about or changes how the server is running, rather than the
+    /// archive
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Request::Stats
+                | Request::Shutdown
+                | Request::Snapshot
+                | Request::Export { .. }
+                | Request::Import { .. }
+                | Request::CreateCollection { .. }
+                | Request::Replicate { .. }
+                | Request::ResizePool { .. }
+        )
+    }
+
+    /// A short name for this type of request, e.g. for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Publish { .. } => "publish",
+            Request::Search { .. } => "search",
+            Request::Retrieve { .. } => "retrieve",
+            Request::List { .. } => "list",
+            Request::SuggestTerms { .. } => "suggest_terms",
+            Request::SearchWith { .. } => "search_with",
+            Request::SearchRanked { .. } => "search_ranked",
+            Request::PublishWith { .. } => "publish_with",
+            Request::Commit { .. } => "commit",
+            Request::Stats => "stats",
+            Request::Update { .. } => "update",
+            Request::PublishBatch { .. } => "publish_batch",
+            Request::SearchPrefix { .. } => "search_prefix",
+            Request::SearchSubstring { .. } => "search_substring",
+            Request::TermStats { .. } => "term_stats",
+            Request::Query { .. } => "query",
+            Request::Shutdown => "shutdown",
+            Request::Snapshot => "snapshot",
+            Request::Ping => "ping",
+            Request::Count { .. } => "count",
+            Request::Export { .. } => "export",
+            Request::Import { .. } => "import",
+            Request::CreateCollection { .. } => "create_collection",
+            Request::ListCollections => "list_collections",
+            Request::Replicate { .. } => "replicate",
+            Request::Subscribe { .. } => "subscribe",
+            Request::ResizePool { .. } => "resize_pool",
+        }
+    }
+
+    // Convert the request `self` into a byte vector.
+    // One byte tag at beginning encodes which kind of request is sent
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
+        self.to_bytes_with(&RequestHeader::default())
+    }
+
+    // Like `to_bytes`, but send `header` along with the request. The header comes first in the
+    // frame, before the tag.
+    pub fn to_bytes_with(&self, header: &RequestHeader) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(PROTOCOL_VERSION);
+        write_request_header(&mut bytes, header);
+        let header_len = bytes.len();
         match self {
             // To publish, encode tag of 1, length of input doc, and then input doc
             Request::Publish { doc } => {
-                bytes.push(1 as u8); 
-                let len = doc.len() as usize; 
-                bytes.extend(len.to_be_bytes().iter());
-                bytes.extend(doc.as_bytes().iter())
+                bytes.push(1_u8);
+                write_str(&mut bytes, doc);
             }
             // To search, encode tag of 2, length of query word, and then query word
             Request::Search { word } => {
-                bytes.push(2 as u8); 
-                let len = word.len() as usize;
-                bytes.extend(len.to_be_bytes().iter());
-                bytes.extend(word.as_bytes().iter())
+                bytes.push(2_u8);
+                write_str(&mut bytes, word);
             }
             // To retrieve, encode tag of 3 and id
-            Request::Retrieve { id } => {
-                bytes.push(3 as u8);
-                bytes.extend(id.to_be_bytes().iter());
+            Request::Retrieve {
+                id,
+                offset: 0,
+                length: None,
+            } => {
+                bytes.push(3_u8);
+                write_usize(&mut bytes, *id);
+            }
+            // To retrieve part of a document, encode tag of 22, id, offset and length
+            Request::Retrieve { id, offset, length } => {
+                bytes.push(22_u8);
+                write_usize(&mut bytes, *id);
+                write_usize(&mut bytes, *offset);
+                write_optional_usize(&mut bytes, *length);
+            }
+            // To list, encode tag of 4 and the preview length
+            Request::List { preview_chars } => {
+                bytes.push(4_u8);
+                write_usize(&mut bytes, *preview_chars);
+            }
+            // To suggest terms, encode tag of 5, the prefix, and then the limit
+            Request::SuggestTerms { prefix, limit } => {
+                bytes.push(5_u8);
+                write_str(&mut bytes, prefix);
+                write_usize(&mut bytes, *limit);
+            }
+            // To search with options, encode tag of 6, the word, and then the options
+            Request::SearchWith { word, options } => {
+                bytes.push(6_u8);
+                write_str(&mut bytes, word);
+                write_search_options(&mut bytes, options);
+            }
+            // To search ranked, encode tag of 7, the word, and then the number of results
+            Request::SearchRanked { word, k } => {
+                bytes.push(7_u8);
+                write_str(&mut bytes, word);
+                write_usize(&mut bytes, *k);
+            }
+            // To publish with options, encode tag of 8, the doc, and then the options
+            Request::PublishWith { doc, options } => {
+                bytes.push(8_u8);
+                write_str(&mut bytes, doc);
+                write_publish_options(&mut bytes, options);
+            }
+            // To commit, encode tag of 9 and id
+            Request::Commit { id } => {
+                bytes.push(9_u8);
+                write_usize(&mut bytes, *id);
+            }
+            // To get stats, encode tag of 10
+            Request::Stats => {
+                bytes.push(10_u8);
+            }
+            // To update, encode tag of 11, id, length of new doc, and then new doc
+            Request::Update { id, doc } => {
+                bytes.push(11_u8);
+                write_usize(&mut bytes, *id);
+                write_str(&mut bytes, doc);
+            }
+            // To publish a batch, encode tag of 12, the number of docs, each doc, and then options
+            Request::PublishBatch { docs, options } => {
+                bytes.push(12_u8);
+                write_usize(&mut bytes, docs.len());
+                for doc in docs {
+                    write_str(&mut bytes, doc);
+                }
+                write_publish_options(&mut bytes, options);
+            }
+            // To search by prefix, encode tag of 13, length of prefix, and then prefix
+            Request::SearchPrefix { prefix } => {
+                bytes.push(13_u8);
+                write_str(&mut bytes, prefix);
+            }
+            // To search by substring, encode tag of 14, length of text, and then text
+            Request::SearchSubstring { text } => {
+                bytes.push(14_u8);
+                write_str(&mut bytes, text);
+            }
+            // To get term statistics, encode tag of 15, the word to start after if any, and then
+            // the limit
+            Request::TermStats { after, limit } => {
+                bytes.push(15_u8);
+                write_optional_str(&mut bytes, after.as_deref());
+                write_usize(&mut bytes, *limit);
+            }
+            // To search with a boolean query, encode tag of 16, length of the query, and then the
+            // query
+            Request::Query { expr } => {
+                bytes.push(16_u8);
+                write_str(&mut bytes, expr);
+            }
+            // To shut down the server, encode tag of 17
+            Request::Shutdown => {
+                bytes.push(17_u8);
+            }
+            // To save a snapshot, encode tag of 18
+            Request::Snapshot => {
+                bytes.push(18_u8);
+            }
+            // To ping, encode tag of 19
+            Request::Ping => {
+                bytes.push(19_u8);
+            }
+            // To count, encode tag of 20, length of the word, and then the word
+            Request::Count { word } => {
+                bytes.push(20_u8);
+                write_str(&mut bytes, word);
+            }
+            // To export, encode tag of 23, length of the path, and then the path
+            Request::Export { path } => {
+                bytes.push(23_u8);
+                write_str(&mut bytes, path);
+            }
+            // To import, encode tag of 24, length of the path, and then the path
+            Request::Import { path } => {
+                bytes.push(24_u8);
+                write_str(&mut bytes, path);
+            }
+            // To create a collection, encode tag of 25, length of the name, and then the name
+            Request::CreateCollection { name } => {
+                bytes.push(25_u8);
+                write_str(&mut bytes, name);
+            }
+            // To list collections, encode tag of 26
+            Request::ListCollections => {
+                bytes.push(26_u8);
+            }
+            // To replicate a change, encode tag of 27, the session, the change's number, and then
+            // the length of the entry and the entry
+            Request::Replicate {
+                session,
+                seq,
+                entry,
+            } => {
+                bytes.push(27_u8);
+                write_u64(&mut bytes, *session);
+                write_u64(&mut bytes, *seq);
+                write_str(&mut bytes, entry);
+            }
+            // To subscribe, encode tag of 28, length of the word, and then the word
+            Request::Subscribe { word } => {
+                bytes.push(28_u8);
+                write_str(&mut bytes, word);
+            }
+            // To resize the thread pool, encode tag of 29 and the number of workers
+            Request::ResizePool { workers } => {
+                bytes.push(29_u8);
+                write_usize(&mut bytes, *workers);
             }
         }
-        bytes
+        if header.compression {
+            compress_tail(&mut bytes, header_len);
+        }
+        frame(bytes)
     }
     // Read a request from `reader` and return it. Calling `to_bytes` from above and then calling
     // `from_bytes` should return the original request. If the request is invalid, return `None`.
+    pub fn from_bytes<R: Read>(reader: R) -> Option<Self> {
+        Self::read_limited(reader, &MessageLimits::default()).ok()
+    }
+
+    // Like `from_bytes`, but reject requests over the sizes in `limits` with `TooLarge`. Oversized
+    // frames are rejected from their header alone, without reading or allocating space for them.
+    pub fn read_limited<R: Read>(reader: R, limits: &MessageLimits) -> Result<Self, DecodeError> {
+        Self::read_with_header(reader, limits).map(|(request, _)| request)
+    }
+
+    // Like `read_limited`, but also return the header the client sent with the request. A
+    // document streamed in chunks is read in full, and returned as a `PublishWith` request.
+    pub fn read_with_header<R: Read>(
+        mut reader: R,
+        limits: &MessageLimits,
+    ) -> Result<(Self, RequestHeader), DecodeError> {
+        let body = read_frame(&mut reader, limits.max_message_len)?;
+        let (mut request, header, streamed) = Self::decode_frame(&body, limits)?;
+        if let (true, Request::PublishWith { doc, .. }) = (streamed, &mut request) {
+            *doc = read_stream(&mut reader, limits)?;
+        }
+        request.check_sizes(limits)?;
+        Ok((request, header))
+    }
+
+    // Like `read_with_header`, but without blocking.
+    #[cfg(feature = "async")]
+    pub(crate) async fn read_with_header_async<R: tokio::io::AsyncRead + Unpin>(
+        reader: &mut R,
+        limits: &MessageLimits,
+    ) -> Result<(Self, RequestHeader), DecodeError> {
+        let frame = read_frame_async(reader, limits.max_message_len).await?;
+        let (mut request, header, streamed) = Self::decode_frame(&frame[4..], limits)?;
+        if let (true, Request::PublishWith { doc, .. }) = (streamed, &mut request) {
+            let mut bytes = Some(Vec::new());
+            loop {
+                let chunk = match read_frame_async(reader, limits.max_message_len).await {
+                    Err(DecodeError::Closed) => return Err(DecodeError::Malformed),
+                    chunk => chunk?,
+                };
+                if chunk[4..].is_empty() {
+                    break;
+                }
+                add_chunk(&mut bytes, &chunk[4..], limits);
+            }
+            *doc = finish_stream(bytes)?;
+        }
+        request.check_sizes(limits)?;
+        Ok((request, header))
+    }
+
+    // Decode the frame `body` of a request, checking its magic and version. Also return whether
+    // it starts a streamed document, which is returned as a `PublishWith` request with no text.
+    fn decode_frame(
+        body: &[u8],
+        limits: &MessageLimits,
+    ) -> Result<(Self, RequestHeader, bool), DecodeError> {
+        let (version, body) = match body.split_first_chunk::<3>() {
+            Some(([m0, m1, version], body)) if [*m0, *m1] == MAGIC => {
+                match (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(version) {
+                    true => (*version, body),
+                    false => return Err(DecodeError::UnsupportedVersion(*version)),
+                }
+            }
+            _ => return Err(DecodeError::Malformed),
+        };
+        Self::decode(body, version, limits.max_message_len).ok_or(DecodeError::Malformed)
+    }
+
+    // Refuse the request if a document in it is over the limit in `limits`.
+    fn check_sizes(&self, limits: &MessageLimits) -> Result<(), DecodeError> {
+        match self {
+            Request::Publish { doc }
+            | Request::PublishWith { doc, .. }
+            | Request::Update { doc, .. }
+                if doc.len() > limits.max_document_len =>
+            {
+                Err(DecodeError::TooLarge)
+            }
+            Request::PublishBatch { docs, .. }
+                if docs.iter().any(|doc| doc.len() > limits.max_document_len) =>
+            {
+                Err(DecodeError::TooLarge)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Decode the body of a request frame in protocol `version` after its magic and version, which
+    // decompresses to at most `max_len` bytes. Also return whether it starts a streamed document.
     // Convert back using convention set above
-    pub fn from_bytes<R: std::io::Read>(mut reader: R) -> Option<Self> {
-        let mut tag_buffer = [0 as u8; 1];
-        reader.read_exact(&mut tag_buffer).ok()?;
-        let tag = tag_buffer[0];
-        match tag {
+    fn decode(body: &[u8], version: u8, max_len: usize) -> Option<(Self, RequestHeader, bool)> {
+        let decompressed;
+        let mut reader = body;
+        let header = read_request_header(&mut reader, version)?;
+        if reader.first() == Some(&COMPRESSED_TAG) {
+            decompressed = decompress(&reader[1..], max_len)?;
+            reader = &decompressed[..];
+        }
+        let tag = read_u8(&mut reader)?;
+        let request = match tag {
             1 => {
-                let mut len_buffer = [0 as u8; std::mem::size_of::<usize>()];
-                reader.read_exact(&mut len_buffer).ok()?;
-                let len = u64::from_be_bytes(len_buffer) as usize;
-                let mut doc_buffer = vec![0 as u8; len];
-                reader.read_exact(&mut doc_buffer).ok()?;
-                let doc = String::from_utf8(doc_buffer).ok()?;
-                return Some (Request::Publish { doc })
+                let doc = read_string(&mut reader)?;
+                Some(Request::Publish { doc })
             }
             2 => {
-                let mut len_buffer = [0 as u8; std::mem::size_of::<usize>()];
-                reader.read_exact(&mut len_buffer).ok()?;
-                let len = u64::from_be_bytes(len_buffer) as usize;
-                let mut word_buffer = vec![0 as u8; len];
-                reader.read_exact(&mut word_buffer).ok()?;
-                let word = String::from_utf8(word_buffer).ok()?;
-                return Some (Request::Search { word })
+                let word = read_string(&mut reader)?;
+                Some(Request::Search { word })
             }
             3 => {
-                let mut id_buffer = [0 as u8; std::mem::size_of::<usize>()];
-                reader.read_exact(&mut id_buffer).ok()?;
-                let id = u64::from_be_bytes(id_buffer) as usize;
-                return Some (Request::Retrieve { id })
+                let id = read_usize(&mut reader)?;
+                Some(Request::Retrieve {
+                    id,
+                    offset: 0,
+                    length: None,
+                })
+            }
+            4 => {
+                let preview_chars = read_usize(&mut reader)?;
+                Some(Request::List { preview_chars })
+            }
+            5 => {
+                let prefix = read_string(&mut reader)?;
+                let limit = read_usize(&mut reader)?;
+                Some(Request::SuggestTerms { prefix, limit })
+            }
+            6 => {
+                let word = read_string(&mut reader)?;
+                let options = read_search_options(&mut reader)?;
+                Some(Request::SearchWith { word, options })
+            }
+            7 => {
+                let word = read_string(&mut reader)?;
+                let k = read_usize(&mut reader)?;
+                Some(Request::SearchRanked { word, k })
+            }
+            8 => {
+                let doc = read_string(&mut reader)?;
+                let options = read_publish_options(&mut reader)?;
+                Some(Request::PublishWith { doc, options })
+            }
+            9 => {
+                let id = read_usize(&mut reader)?;
+                Some(Request::Commit { id })
+            }
+            10 => Some(Request::Stats),
+            11 => {
+                let id = read_usize(&mut reader)?;
+                let doc = read_string(&mut reader)?;
+                Some(Request::Update { id, doc })
+            }
+            12 => {
+                let len = read_usize(&mut reader)?;
+                let mut docs = Vec::new();
+                for _ in 0..len {
+                    docs.push(read_string(&mut reader)?);
+                }
+                let options = read_publish_options(&mut reader)?;
+                Some(Request::PublishBatch { docs, options })
+            }
+            13 => {
+                let prefix = read_string(&mut reader)?;
+                Some(Request::SearchPrefix { prefix })
+            }
+            14 => {
+                let text = read_string(&mut reader)?;
+                Some(Request::SearchSubstring { text })
+            }
+            15 => {
+                let after = read_optional_string(&mut reader)?;
+                let limit = read_usize(&mut reader)?;
+                Some(Request::TermStats { after, limit })
+            }
+            16 => {
+                let expr = read_string(&mut reader)?;
+                Some(Request::Query { expr })
+            }
+            17 => Some(Request::Shutdown),
+            18 => Some(Request::Snapshot),
+            19 => Some(Request::Ping),
+            20 => {
+                let word = read_string(&mut reader)?;
+                Some(Request::Count { word })
+            }
+            22 => {
+                let id = read_usize(&mut reader)?;
+                let offset = read_usize(&mut reader)?;
+                let length = read_optional_usize(&mut reader)?;
+                Some(Request::Retrieve { id, offset, length })
+            }
+            23 => {
+                let path = read_string(&mut reader)?;
+                Some(Request::Export { path })
+            }
+            24 => {
+                let path = read_string(&mut reader)?;
+                Some(Request::Import { path })
+            }
+            25 => {
+                let name = read_string(&mut reader)?;
+                Some(Request::CreateCollection { name })
+            }
+            26 => Some(Request::ListCollections),
+            27 => {
+                let session = read_u64(&mut reader)?;
+                let seq = read_u64(&mut reader)?;
+                let entry = read_string(&mut reader)?;
+                Some(Request::Replicate {
+                    session,
+                    seq,
+                    entry,
+                })
+            }
+            28 => {
+                let word = read_string(&mut reader)?;
+                Some(Request::Subscribe { word })
+            }
+            29 => {
+                let workers = read_usize(&mut reader)?;
+                Some(Request::ResizePool { workers })
+            }
+            // The document follows in chunk frames, which the caller reads
+            PUBLISH_STREAM_TAG => {
+                let options = read_publish_options(&mut reader)?;
+                Some(Request::PublishWith {
+                    doc: String::new(),
+                    options,
+                })
             }
             // If doesn't matc any of the tags, return none for invalid request
-            _ => return None,
-        }
+            _ => None,
+        }?;
+        // The whole frame should have been used up
+        reader
+            .is_empty()
+            .then_some((request, header, tag == PUBLISH_STREAM_TAG))
     }
 }
 
+/// Options sent along with every request
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RequestHeader {
+    /// The largest response the client can accept, in bytes, counting its frame header
+    pub max_response_len: Option<usize>,
+    /// Whether the server may cut a response that is too large down to size, rather than
+    /// refusing it with `ResponseTooLarge`
+    pub allow_truncation: bool,
+    /// Whether the server should send the metadata of the documents in search, list and retrieve
+    /// responses, wrapping them in `WithMetadata`
+    pub include_metadata: bool,
+    /// Whether the client can read compressed responses. A client that sets this compresses its
+    /// own large requests, and the server compresses large responses if it can
+    pub compression: bool,
+    /// The API key the client authenticates with, if any. A server with API keys only accepts
+    /// requests that modify the archive from clients that send one of them
+    pub token: Option<String>,
+    /// Whether the server should keep the connection open for another request once it has
+    /// answered this one, rather than closing it
+    pub keep_alive: bool,
+    /// Whether the server should stream a retrieved document in chunks, as `document_stream`
+    /// encodes it, rather than sending it in one frame
+    pub stream_documents: bool,
+    /// The collection the request is about, or None for the server's default archive
+    pub collection: Option<String>,
+    /// Chosen by the client to match the response to the request, which the server wraps in
+    /// `Tagged`. A retrieved document isn't streamed in answer to a request with an id
+    pub request_id: Option<u64>,
+}
+
 /// A response from the server to the client
 #[derive(Debug, PartialEq)]
 pub enum Response {
@@ -84,83 +713,1269 @@ pub enum Response {
     SearchSuccess(Vec<usize>),
     /// The retrieval of the document was successful, and the document is returned
     RetrieveSuccess(String),
-    /// The request failed
-    Failure,
+    /// The request failed, for the reason `code` gives and as `message` explains
+    Failure { code: ErrorCode, message: String },
+    /// The listing was successful, and a summary of every document is returned
+    ListSuccess(Vec<DocumentSummary>),
+    /// The server was too busy to handle the request in time; it may succeed if retried
+    Busy,
+    /// The suggestion was successful, and matching words are returned with the number of
+    /// documents containing each
+    SuggestSuccess(Vec<(String, usize)>),
+    /// The ranked search was successful, and the ids of the best matching documents are returned
+    /// with their scores, best first
+    SearchRankedSuccess(Vec<(usize, f32)>),
+    /// The request was larger than the server accepts
+    TooLarge,
+    /// The pending document with the given index was committed, and is now searchable
+    CommitSuccess(usize),
+    /// The server's metrics, in the Prometheus text format
+    StatsSuccess(String),
+    /// The response was too large for the client, so only the part of it that fit is returned
+    Truncated(Box<Response>),
+    /// The response, of the given size in bytes, was too large for the client
+    ResponseTooLarge(usize),
+    /// The document with the given index was updated
+    UpdateSuccess(usize),
+    /// Every document in a batch was published, with the given indices in order
+    PublishBatchSuccess(Vec<usize>),
+    /// The term statistics were gathered, and each word is returned with the number of documents
+    /// containing it and its total number of occurrences, in alphabetical order
+    TermStatsSuccess(Vec<(String, usize, usize)>),
+    /// The search for the word was successful, and the ids of the documents containing it are
+    /// returned, each with a snippet of the text around the word
+    SearchSnippetsSuccess(Vec<(usize, String)>),
+    /// One page of a search's results, out of `total_hits` matches in all
+    Paged {
+        response: Box<Response>,
+        total_hits: usize,
+    },
+    /// A response along with the metadata of the documents in it, for those that have any
+    WithMetadata {
+        response: Box<Response>,
+        metadata: Vec<(usize, Metadata)>,
+    },
+    /// The request was in a version of the protocol the server doesn't speak; it speaks versions
+    /// `min` to `max`
+    UnsupportedVersion { min: u8, max: u8 },
+    /// The server is stopping, and will answer no more requests once those it has accepted are
+    ShuttingDown,
+    /// The archive was saved to the server's snapshot file, with the given number of documents
+    SnapshotSuccess(usize),
+    /// The server is up: it has been running for `uptime_secs` seconds, holds `documents`
+    /// documents, and is the given version of the crate
+    Pong {
+        uptime_secs: u64,
+        documents: usize,
+        version: String,
+    },
+    /// The count was successful, and the number of documents containing the word is returned
+    CountSuccess(usize),
+    /// The archive was exported, with the given number of documents
+    ExportSuccess(usize),
+    /// The given number of documents were imported
+    ImportSuccess(usize),
+    /// The collection with the given name was created
+    CollectionCreated(String),
+    /// The names of the server's collections, in alphabetical order
+    CollectionsSuccess(Vec<String>),
+    /// The follower has made the change with the given number
+    Replicated(u64),
+    /// The client is subscribed to the documents indexed under the given term, and will be sent a
+    /// `Notification` for each
+    Subscribed(String),
+    /// A document the client subscribed to, with the given id, became searchable; `preview` is
+    /// the start of it
+    Notification { id: usize, preview: String },
+    /// The response to the request the client gave the id `request_id`
+    Tagged {
+        request_id: u64,
+        response: Box<Response>,
+    },
+    /// The server's thread pool was resized to the given number of workers
+    PoolResized(usize),
+}
+/// Why a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request couldn't be read, or isn't a valid encoding of any request
+    Malformed,
+    /// No document has the id the request gave
+    NotFound,
+    /// The request would modify the archive, but arrived on a read-only listener, or at a follower
+    ReadOnly,
+    /// The client took too long to send the request
+    TimedOut,
+    /// The server has too much work queued to take the request; it may succeed if retried
+    Overloaded,
+    /// The server failed while handling the request
+    Internal,
+    /// The request would modify the archive, but didn't come with a valid API key
+    Unauthorized,
+    /// The request came with a valid API key, but its role doesn't allow the request
+    Forbidden,
+    /// The client has made more requests than the server's rate limit allows; it may succeed if
+    /// retried later
+    RateLimited,
+    /// The request would publish a copy of a stored document, which the server rejects, or
+    /// create a collection that already exists
+    Duplicate,
+    /// The archive has grown past the server's memory limit, so it takes no more documents
+    MemoryLimit,
+    /// The request asked for part of a document that it doesn't have
+    OutOfRange,
+    /// The server can't handle this kind of request, e.g. a coordinator asked to save a snapshot
+    /// of archives its backends hold
+    Unsupported,
+}
+impl ErrorCode {
+    /// Every error code, in order of their codes
+    pub const ALL: [ErrorCode; 13] = [
+        ErrorCode::Malformed,
+        ErrorCode::NotFound,
+        ErrorCode::ReadOnly,
+        ErrorCode::TimedOut,
+        ErrorCode::Overloaded,
+        ErrorCode::Internal,
+        ErrorCode::Unauthorized,
+        ErrorCode::Forbidden,
+        ErrorCode::RateLimited,
+        ErrorCode::Duplicate,
+        ErrorCode::MemoryLimit,
+        ErrorCode::OutOfRange,
+        ErrorCode::Unsupported,
+    ];
+
+    /// The byte this error code is encoded as
+    pub fn code(self) -> u8 {
+        ErrorCode::ALL
+            .iter()
+            .position(|code| *code == self)
+            .unwrap() as u8
+            + 1
+    }
+
+    /// The error code encoded as `code`, if any
+    pub fn from_code(code: u8) -> Option<Self> {
+        ErrorCode::ALL.get((code as usize).checked_sub(1)?).copied()
+    }
 }
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ErrorCode::Malformed => "malformed",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::ReadOnly => "read_only",
+            ErrorCode::TimedOut => "timed_out",
+            ErrorCode::Overloaded => "overloaded",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::Duplicate => "duplicate",
+            ErrorCode::MemoryLimit => "memory_limit",
+            ErrorCode::OutOfRange => "out_of_range",
+            ErrorCode::Unsupported => "unsupported",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl Response {
-    // Convert the request `self` into a byte vector. 
-    // One byte tag at beginning encodes which of the three requests is sent
+    /// A failure for the reason `code` gives, explained by `message`
+    pub fn failure(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Failure {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The failure for a request about the document with id `id` when there is no such document
+    pub fn not_found(id: usize) -> Self {
+        Response::failure(ErrorCode::NotFound, format!("no document with id {}", id))
+    }
+
+    /// The failure for a publish rejected for being a copy of the document with id `id`
+    pub fn duplicate(id: usize) -> Self {
+        Response::failure(
+            ErrorCode::Duplicate,
+            format!("a copy of document {} is already stored", id),
+        )
+    }
+
+    // Convert the response `self` into a byte vector.
+    // One byte tag at beginning encodes which kind of response is sent
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
+        self.encode(&mut bytes);
+        frame(bytes)
+    }
+
+    // Like `to_bytes`, but compress the response if it is large, for a client that said it can
+    // read compressed responses.
+    pub fn to_bytes_compressed(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.encode(&mut bytes);
+        compress_tail(&mut bytes, 0);
+        frame(bytes)
+    }
+
+    // Append the body of this response's frame to `bytes`.
+    fn encode(&self, bytes: &mut Vec<u8>) {
         match self {
             Response::PublishSuccess(index) => {
-                bytes.push(1 as u8);
-                bytes.extend(index.to_be_bytes().iter());
+                bytes.push(1_u8);
+                write_usize(bytes, *index);
             }
             Response::SearchSuccess(indices) => {
-                bytes.push(2 as u8);
-                let len = indices.len() as usize;
-                bytes.extend(len.to_be_bytes().iter());
+                bytes.push(2_u8);
+                write_usize(bytes, indices.len());
                 for index in indices {
-                    bytes.extend(index.to_be_bytes().iter());
+                    write_usize(bytes, *index);
                 }
             }
             Response::RetrieveSuccess(doc) => {
-                bytes.push(3 as u8);
-                let len = doc.len() as usize;
-                bytes.extend(len.to_be_bytes().iter());
-                bytes.extend(doc.as_bytes().iter())
+                bytes.push(3_u8);
+                write_str(bytes, doc);
+            }
+            // For a failure, encode tag of 4, the error code, and then the message
+            Response::Failure { code, message } => {
+                bytes.push(4_u8);
+                bytes.push(code.code());
+                write_str(bytes, message);
+            }
+            Response::ListSuccess(summaries) => {
+                bytes.push(5_u8);
+                write_usize(bytes, summaries.len());
+                for summary in summaries {
+                    write_usize(bytes, summary.id);
+                    write_usize(bytes, summary.length);
+                    write_str(bytes, &summary.preview);
+                    write_u64(bytes, summary.published_at);
+                }
+            }
+            Response::Busy => {
+                bytes.push(6_u8);
             }
-            Response::Failure => {
-                bytes.push(4 as u8);
+            Response::SuggestSuccess(terms) => {
+                bytes.push(7_u8);
+                write_usize(bytes, terms.len());
+                for (term, count) in terms {
+                    write_str(bytes, term);
+                    write_usize(bytes, *count);
+                }
+            }
+            Response::SearchRankedSuccess(results) => {
+                bytes.push(8_u8);
+                write_usize(bytes, results.len());
+                for (id, score) in results {
+                    write_usize(bytes, *id);
+                    write_f32(bytes, *score);
+                }
+            }
+            Response::TooLarge => {
+                bytes.push(9_u8);
+            }
+            Response::CommitSuccess(id) => {
+                bytes.push(10_u8);
+                write_usize(bytes, *id);
+            }
+            Response::StatsSuccess(text) => {
+                bytes.push(11_u8);
+                write_str(bytes, text);
+            }
+            Response::Truncated(response) => {
+                bytes.push(12_u8);
+                response.encode(bytes);
+            }
+            Response::ResponseTooLarge(size) => {
+                bytes.push(13_u8);
+                write_usize(bytes, *size);
+            }
+            Response::UpdateSuccess(id) => {
+                bytes.push(15_u8);
+                write_usize(bytes, *id);
+            }
+            Response::PublishBatchSuccess(indices) => {
+                bytes.push(16_u8);
+                write_usize(bytes, indices.len());
+                for index in indices {
+                    write_usize(bytes, *index);
+                }
+            }
+            // For term statistics, encode tag of 17, the number of words, and then each word
+            // followed by its document frequency and total occurrences
+            Response::TermStatsSuccess(terms) => {
+                bytes.push(17_u8);
+                write_usize(bytes, terms.len());
+                for (term, doc_frequency, occurrences) in terms {
+                    write_str(bytes, term);
+                    write_usize(bytes, *doc_frequency);
+                    write_usize(bytes, *occurrences);
+                }
+            }
+            // For a page of results, encode tag of 19, the total number of matches, and then the
+            // page itself
+            Response::Paged {
+                response,
+                total_hits,
+            } => {
+                bytes.push(19_u8);
+                write_usize(bytes, *total_hits);
+                response.encode(bytes);
+            }
+            // For an unsupported version, encode tag of 20, then the oldest and newest versions
+            // the server speaks
+            Response::UnsupportedVersion { min, max } => {
+                bytes.push(20_u8);
+                bytes.push(*min);
+                bytes.push(*max);
+            }
+            Response::ShuttingDown => {
+                bytes.push(21_u8);
+            }
+            Response::SnapshotSuccess(documents) => {
+                bytes.push(22_u8);
+                write_usize(bytes, *documents);
+            }
+            // For a pong, encode tag of 23, the uptime, the document count, and then the version
+            Response::Pong {
+                uptime_secs,
+                documents,
+                version,
+            } => {
+                bytes.push(23_u8);
+                write_u64(bytes, *uptime_secs);
+                write_usize(bytes, *documents);
+                write_str(bytes, version);
+            }
+            Response::CountSuccess(count) => {
+                bytes.push(24_u8);
+                write_usize(bytes, *count);
+            }
+            Response::ExportSuccess(documents) => {
+                bytes.push(26_u8);
+                write_usize(bytes, *documents);
+            }
+            Response::ImportSuccess(documents) => {
+                bytes.push(27_u8);
+                write_usize(bytes, *documents);
+            }
+            Response::CollectionCreated(name) => {
+                bytes.push(28_u8);
+                write_str(bytes, name);
+            }
+            // For a list of collections, encode tag of 29, the number of collections, and then
+            // each name
+            Response::CollectionsSuccess(names) => {
+                bytes.push(29_u8);
+                write_usize(bytes, names.len());
+                for name in names {
+                    write_str(bytes, name);
+                }
+            }
+            Response::Replicated(seq) => {
+                bytes.push(30_u8);
+                write_u64(bytes, *seq);
+            }
+            Response::Subscribed(term) => {
+                bytes.push(31_u8);
+                write_str(bytes, term);
+            }
+            // For a notification, encode tag of 32, the document id, and then the length of the
+            // preview and the preview
+            Response::Notification { id, preview } => {
+                bytes.push(32_u8);
+                write_usize(bytes, *id);
+                write_str(bytes, preview);
+            }
+            // For the response to a request with an id, encode tag of 33, the id, and then the
+            // response itself
+            Response::Tagged {
+                request_id,
+                response,
+            } => {
+                bytes.push(33_u8);
+                write_u64(bytes, *request_id);
+                response.encode(bytes);
+            }
+            Response::PoolResized(workers) => {
+                bytes.push(34_u8);
+                write_usize(bytes, *workers);
+            }
+            // For a search with snippets, encode tag of 18, the number of results, and then each
+            // document id followed by its snippet
+            Response::SearchSnippetsSuccess(results) => {
+                bytes.push(18_u8);
+                write_usize(bytes, results.len());
+                for (id, snippet) in results {
+                    write_usize(bytes, *id);
+                    write_str(bytes, snippet);
+                }
+            }
+            // For a response with metadata, encode tag of 14, the response, the number of
+            // documents with metadata, and then each document's id and metadata
+            Response::WithMetadata { response, metadata } => {
+                bytes.push(14_u8);
+                response.encode(bytes);
+                write_usize(bytes, metadata.len());
+                for (id, doc_metadata) in metadata {
+                    write_usize(bytes, *id);
+                    write_metadata(bytes, doc_metadata);
+                }
             }
         }
-        bytes
     }
 
-    // Read a request from `reader` and return it. Calling `to_bytes` from above and then calling
-    // `from_bytes` should return the original request. If the request is invalid, return `None`.
-    pub fn from_bytes<R: std::io::Read>(mut reader: R) -> Option<Self> {
-        let mut tag_buffer = [0 as u8; 1];
-        reader.read_exact(&mut tag_buffer).ok()?; //should not panic here
-        let tag = tag_buffer[0];
+    // Read a response from `reader` and return it. Calling `to_bytes` from above and then calling
+    // `from_bytes` should return the original response. If the response is invalid, return `None`.
+    pub fn from_bytes<R: Read>(reader: R) -> Option<Self> {
+        Self::read_limited(reader, MAX_FRAME_LEN)
+    }
+
+    // Like `from_bytes`, but refuse a response whose frame body is over `max_len` bytes without
+    // buffering it. A streamed document is read in full, as long as it is no longer than
+    // `max_len` either, and returned as `RetrieveSuccess`.
+    pub fn read_limited<R: Read>(mut reader: R, max_len: usize) -> Option<Self> {
+        let body = read_frame(&mut reader, max_len).ok()?; //should not panic here
+        if body == [DOCUMENT_STREAM_TAG] {
+            let limits = MessageLimits {
+                max_message_len: max_len,
+                max_document_len: max_len,
+            };
+            return read_stream(reader, &limits)
+                .ok()
+                .map(Response::RetrieveSuccess);
+        }
+        Self::from_body(body, max_len)
+    }
+
+    // Like `read_limited`, but write a streamed document to `writer` as it arrives rather than
+    // holding it in memory, and return how many bytes were written. Any other response is
+    // returned as it is. Fails with `InvalidData` if the response isn't valid.
+    pub fn read_streamed<R: Read, W: Write>(
+        mut reader: R,
+        mut writer: W,
+        max_len: usize,
+    ) -> io::Result<Result<u64, Response>> {
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+        let body = read_frame(&mut reader, max_len).map_err(|_| invalid())?;
+        if body != [DOCUMENT_STREAM_TAG] {
+            return Self::from_body(body, max_len).map(Err).ok_or_else(invalid);
+        }
+        let mut written = 0;
+        loop {
+            let chunk = read_frame(&mut reader, STREAM_CHUNK_LEN).map_err(|_| invalid())?;
+            if chunk.is_empty() {
+                writer.flush()?;
+                return Ok(Ok(written));
+            }
+            writer.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+    }
+
+    // Decode the frame `body` of a response that isn't streamed, which decompresses to at most
+    // `max_len` bytes.
+    fn from_body(mut body: Vec<u8>, max_len: usize) -> Option<Self> {
+        if body.first() == Some(&COMPRESSED_TAG) {
+            body = decompress(&body[1..], max_len)?;
+        }
+        let mut reader = &body[..];
+        let response = Self::decode(&mut reader)?;
+        // The whole frame should have been used up
+        reader.is_empty().then_some(response)
+    }
+
+    // Decode a response body from the front of `reader`.
+    fn decode(reader: &mut &[u8]) -> Option<Self> {
+        let tag = read_u8(reader)?;
         match tag {
             // For publish response, encode tag of 1 and index of newly published doc
             1 => {
-                let mut id_buffer = [0 as u8; std::mem::size_of::<usize>()];
-                reader.read_exact(&mut id_buffer).unwrap();
-                let id = u64::from_be_bytes(id_buffer) as usize;
-                return Some (Response::PublishSuccess(id))
+                let id = read_usize(reader)?;
+                Some(Response::PublishSuccess(id))
             }
             // For search response, encode tag of 2 and index of docs that contain word
             2 => {
-                let mut len_buffer = [0 as u8; std::mem::size_of::<usize>()];
-                reader.read_exact(&mut len_buffer).unwrap();
-                let len = u64::from_be_bytes(len_buffer) as usize;
-                let mut indices = Vec::with_capacity(len);
+                let len = read_usize(reader)?;
+                let mut indices = Vec::with_capacity(len.min(reader.len()));
                 for _ in 0..len {
-                    let mut index_buffer = [0 as u8; std::mem::size_of::<usize>()];
-                    reader.read_exact(&mut index_buffer).unwrap();
-                    let index = u64::from_be_bytes(index_buffer) as usize;
-                    indices.push(index);
+                    indices.push(read_usize(reader)?);
                 }
-                return Some (Response::SearchSuccess(indices))
+                Some(Response::SearchSuccess(indices))
             }
             // For retrieve response, encode tag of 3, length of docm and then output doc
             3 => {
-                let mut len_buffer = [0 as u8; std::mem::size_of::<usize>()];
-                reader.read_exact(&mut len_buffer).unwrap();
-                let len = u64::from_be_bytes(len_buffer) as usize;
-                let mut doc_buffer = vec![0 as u8; len];
-                reader.read_exact(&mut doc_buffer).unwrap();
-                let doc = String::from_utf8(doc_buffer).ok()?;
-                return Some (Response::RetrieveSuccess(doc))
+                let doc = read_string(reader)?;
+                Some(Response::RetrieveSuccess(doc))
             }
             4 => {
-                return Some (Response::Failure)
+                let code = ErrorCode::from_code(read_u8(reader)?)?;
+                let message = read_string(reader)?;
+                Some(Response::Failure { code, message })
+            }
+            // For list response, encode tag of 5, the number of documents, and then the id,
+            // length, and preview of each
+            5 => {
+                let len = read_usize(reader)?;
+                let mut summaries = Vec::with_capacity(len.min(reader.len()));
+                for _ in 0..len {
+                    summaries.push(DocumentSummary {
+                        id: read_usize(reader)?,
+                        length: read_usize(reader)?,
+                        preview: read_string(reader)?,
+                        published_at: read_u64(reader)?,
+                    });
+                }
+                Some(Response::ListSuccess(summaries))
+            }
+            6 => Some(Response::Busy),
+            // For suggest response, encode tag of 7, the number of words, and then each word
+            // followed by its document count
+            7 => {
+                let len = read_usize(reader)?;
+                let mut terms = Vec::with_capacity(len.min(reader.len()));
+                for _ in 0..len {
+                    terms.push((read_string(reader)?, read_usize(reader)?));
+                }
+                Some(Response::SuggestSuccess(terms))
+            }
+            // For ranked search response, encode tag of 8, the number of results, and then each
+            // document id followed by its score
+            8 => {
+                let len = read_usize(reader)?;
+                let mut results = Vec::with_capacity(len.min(reader.len()));
+                for _ in 0..len {
+                    results.push((read_usize(reader)?, read_f32(reader)?));
+                }
+                Some(Response::SearchRankedSuccess(results))
+            }
+            9 => Some(Response::TooLarge),
+            10 => {
+                let id = read_usize(reader)?;
+                Some(Response::CommitSuccess(id))
+            }
+            11 => {
+                let text = read_string(reader)?;
+                Some(Response::StatsSuccess(text))
+            }
+            // For a truncated response, encode tag of 12 and then the cut-down response
+            12 => Some(Response::Truncated(Box::new(Self::decode(reader)?))),
+            13 => Some(Response::ResponseTooLarge(read_usize(reader)?)),
+            14 => {
+                let response = Box::new(Self::decode(reader)?);
+                let len = read_usize(reader)?;
+                let mut metadata = Vec::new();
+                for _ in 0..len {
+                    metadata.push((read_usize(reader)?, read_metadata(reader)?));
+                }
+                Some(Response::WithMetadata { response, metadata })
+            }
+            15 => Some(Response::UpdateSuccess(read_usize(reader)?)),
+            16 => {
+                let len = read_usize(reader)?;
+                let mut indices = Vec::new();
+                for _ in 0..len {
+                    indices.push(read_usize(reader)?);
+                }
+                Some(Response::PublishBatchSuccess(indices))
+            }
+            17 => {
+                let len = read_usize(reader)?;
+                let mut terms = Vec::with_capacity(len.min(reader.len()));
+                for _ in 0..len {
+                    let term = read_string(reader)?;
+                    terms.push((term, read_usize(reader)?, read_usize(reader)?));
+                }
+                Some(Response::TermStatsSuccess(terms))
+            }
+            18 => {
+                let len = read_usize(reader)?;
+                let mut results = Vec::with_capacity(len.min(reader.len()));
+                for _ in 0..len {
+                    results.push((read_usize(reader)?, read_string(reader)?));
+                }
+                Some(Response::SearchSnippetsSuccess(results))
+            }
+            19 => {
+                let total_hits = read_usize(reader)?;
+                let response = Box::new(Self::decode(reader)?);
+                Some(Response::Paged {
+                    response,
+                    total_hits,
+                })
+            }
+            20 => Some(Response::UnsupportedVersion {
+                min: read_u8(reader)?,
+                max: read_u8(reader)?,
+            }),
+            21 => Some(Response::ShuttingDown),
+            22 => Some(Response::SnapshotSuccess(read_usize(reader)?)),
+            23 => Some(Response::Pong {
+                uptime_secs: read_u64(reader)?,
+                documents: read_usize(reader)?,
+                version: read_string(reader)?,
+            }),
+            24 => Some(Response::CountSuccess(read_usize(reader)?)),
+            26 => Some(Response::ExportSuccess(read_usize(reader)?)),
+            27 => Some(Response::ImportSuccess(read_usize(reader)?)),
+            28 => Some(Response::CollectionCreated(read_string(reader)?)),
+            29 => {
+                let len = read_usize(reader)?;
+                let mut names = Vec::with_capacity(len.min(reader.len()));
+                for _ in 0..len {
+                    names.push(read_string(reader)?);
+                }
+                Some(Response::CollectionsSuccess(names))
+            }
+            30 => Some(Response::Replicated(read_u64(reader)?)),
+            31 => Some(Response::Subscribed(read_string(reader)?)),
+            32 => Some(Response::Notification {
+                id: read_usize(reader)?,
+                preview: read_string(reader)?,
+            }),
+            33 => {
+                let request_id = read_u64(reader)?;
+                let response = Box::new(Self::decode(reader)?);
+                Some(Response::Tagged {
+                    request_id,
+                    response,
+                })
+            }
+            34 => Some(Response::PoolResized(read_usize(reader)?)),
+            _ => None,
+        }
+    }
+}
+
+impl Response {
+    /// This response as a JSON object, tagged with its `type`
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Response::PublishSuccess(id) => json!({ "type": "publish", "doc_id": id }),
+            Response::SearchSuccess(ids) => json!({ "type": "search", "doc_ids": ids }),
+            Response::RetrieveSuccess(doc) => json!({ "type": "retrieve", "doc": doc }),
+            Response::Failure { code, message } => {
+                json!({ "type": "failure", "code": code.to_string(), "message": message })
+            }
+            Response::ListSuccess(summaries) => json!({ "type": "list", "documents": summaries }),
+            Response::Busy => json!({ "type": "busy" }),
+            Response::TooLarge => json!({ "type": "too_large" }),
+            Response::UnsupportedVersion { min, max } => {
+                json!({ "type": "unsupported_version", "min": min, "max": max })
+            }
+            Response::CommitSuccess(id) => json!({ "type": "commit", "doc_id": id }),
+            Response::UpdateSuccess(id) => json!({ "type": "update", "doc_id": id }),
+            Response::PublishBatchSuccess(ids) => {
+                json!({ "type": "publish_batch", "doc_ids": ids })
+            }
+            Response::StatsSuccess(text) => json!({ "type": "stats", "metrics": text }),
+            Response::ShuttingDown => json!({ "type": "shutting_down" }),
+            Response::Pong {
+                uptime_secs,
+                documents,
+                version,
+            } => json!({
+                "type": "pong",
+                "uptime_secs": uptime_secs,
+                "documents": documents,
+                "version": version,
+            }),
+            Response::SnapshotSuccess(documents) => {
+                json!({ "type": "snapshot", "documents": documents })
+            }
+            Response::CountSuccess(count) => json!({ "type": "count", "count": count }),
+            Response::ExportSuccess(documents) => {
+                json!({ "type": "export", "documents": documents })
             }
-            _ => return None,
+            Response::ImportSuccess(documents) => {
+                json!({ "type": "import", "documents": documents })
+            }
+            Response::CollectionCreated(name) => {
+                json!({ "type": "collection_created", "name": name })
+            }
+            Response::CollectionsSuccess(names) => {
+                json!({ "type": "collections", "names": names })
+            }
+            Response::Replicated(seq) => json!({ "type": "replicated", "seq": seq }),
+            Response::Subscribed(term) => json!({ "type": "subscribed", "term": term }),
+            Response::PoolResized(workers) => json!({ "type": "pool_resized", "workers": workers }),
+            Response::Notification { id, preview } => {
+                json!({ "type": "notification", "id": id, "preview": preview })
+            }
+            Response::Truncated(response) => {
+                let mut json = response.to_json();
+                json["truncated"] = json!(true);
+                json
+            }
+            Response::Paged {
+                response,
+                total_hits,
+            } => {
+                let mut json = response.to_json();
+                json["total_hits"] = json!(total_hits);
+                json
+            }
+            Response::Tagged {
+                request_id,
+                response,
+            } => {
+                let mut json = response.to_json();
+                json["request_id"] = json!(request_id);
+                json
+            }
+            Response::WithMetadata { response, metadata } => {
+                let mut json = response.to_json();
+                json["metadata"] = metadata
+                    .iter()
+                    .map(|(id, doc_metadata)| {
+                        let mut entry = json!(doc_metadata);
+                        entry["doc_id"] = json!(id);
+                        entry
+                    })
+                    .collect();
+                json
+            }
+            Response::ResponseTooLarge(size) => {
+                json!({ "type": "response_too_large", "size": size })
+            }
+            Response::SuggestSuccess(terms) => json!({
+                "type": "suggest",
+                "terms": terms
+                    .iter()
+                    .map(|(term, doc_count)| json!({ "term": term, "doc_count": doc_count }))
+                    .collect::<Vec<_>>(),
+            }),
+            Response::SearchSnippetsSuccess(results) => json!({
+                "type": "search_snippets",
+                "results": results
+                    .iter()
+                    .map(|(id, snippet)| json!({ "doc_id": id, "snippet": snippet }))
+                    .collect::<Vec<_>>(),
+            }),
+            Response::TermStatsSuccess(terms) => json!({
+                "type": "term_stats",
+                "terms": terms
+                    .iter()
+                    .map(|(term, doc_frequency, occurrences)| json!({
+                        "term": term,
+                        "doc_frequency": doc_frequency,
+                        "occurrences": occurrences,
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            Response::SearchRankedSuccess(results) => json!({
+                "type": "search_ranked",
+                "results": results
+                    .iter()
+                    .map(|(id, score)| json!({ "doc_id": id, "score": score }))
+                    .collect::<Vec<_>>(),
+            }),
+        }
+    }
+}
+
+impl Response {
+    // Make this response fit in the size the client asked for in `header`: cut it down if the
+    // client allows that, or refuse it with `ResponseTooLarge` otherwise.
+    pub fn fit(self, header: &RequestHeader) -> Response {
+        let Some(max_len) = header.max_response_len else {
+            return self;
+        };
+        // Leave room to tag the response with the request's id
+        let max_len = match header.request_id {
+            Some(_) => max_len.saturating_sub(1 + std::mem::size_of::<u64>()),
+            None => max_len,
+        };
+        let size = self.to_bytes().len();
+        if size <= max_len {
+            return self;
+        }
+        let truncated = header
+            .allow_truncation
+            .then(|| self.truncate_to(max_len))
+            .flatten();
+        truncated.unwrap_or(Response::ResponseTooLarge(size))
+    }
+
+    // Wrap this response in `Tagged` if the client gave the request it answers an id in `header`.
+    pub fn tagged(self, header: &RequestHeader) -> Response {
+        match header.request_id {
+            Some(request_id) => Response::Tagged {
+                request_id,
+                response: Box::new(self),
+            },
+            None => self,
+        }
+    }
+
+    // Cut this response down to at most `max_len` encoded bytes, by dropping results from the end
+    // of a list or the end of a document, and wrap it in `Truncated`. Return None if this kind of
+    // response can't be cut down (responses with metadata can't), or can't be cut down far enough.
+    pub fn truncate_to(self, max_len: usize) -> Option<Response> {
+        let fits = |response: Response| {
+            let truncated = Response::Truncated(Box::new(response));
+            (truncated.to_bytes().len() <= max_len).then_some(truncated)
+        };
+        match self {
+            Response::SearchSuccess(ids) => {
+                fit_prefix(&ids, |ids| fits(Response::SearchSuccess(ids.to_vec())))
+            }
+            Response::ListSuccess(summaries) => fit_prefix(&summaries, |summaries| {
+                fits(Response::ListSuccess(summaries.to_vec()))
+            }),
+            Response::SuggestSuccess(terms) => fit_prefix(&terms, |terms| {
+                fits(Response::SuggestSuccess(terms.to_vec()))
+            }),
+            Response::SearchRankedSuccess(results) => fit_prefix(&results, |results| {
+                fits(Response::SearchRankedSuccess(results.to_vec()))
+            }),
+            // The page is cut down to fit in what the wrapper leaves, and then marked as truncated
+            // as a whole
+            Response::Paged {
+                response,
+                total_hits,
+            } => {
+                let overhead = 1 + std::mem::size_of::<u64>();
+                let Response::Truncated(page) =
+                    response.truncate_to(max_len.checked_sub(overhead)?)?
+                else {
+                    return None;
+                };
+                fits(Response::Paged {
+                    response: page,
+                    total_hits,
+                })
+            }
+            Response::SearchSnippetsSuccess(results) => fit_prefix(&results, |results| {
+                fits(Response::SearchSnippetsSuccess(results.to_vec()))
+            }),
+            Response::TermStatsSuccess(terms) => fit_prefix(&terms, |terms| {
+                fits(Response::TermStatsSuccess(terms.to_vec()))
+            }),
+            Response::RetrieveSuccess(doc) => {
+                let end = fit_str(&doc, max_len, Response::RetrieveSuccess)?;
+                fits(Response::RetrieveSuccess(doc[..end].to_string()))
+            }
+            Response::StatsSuccess(text) => {
+                let end = fit_str(&text, max_len, Response::StatsSuccess)?;
+                fits(Response::StatsSuccess(text[..end].to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Build a response from the longest prefix of `items` that `build` accepts. Responses only grow
+// as items are added, so the longest prefix can be binary searched for.
+fn fit_prefix<T>(items: &[T], build: impl Fn(&[T]) -> Option<Response>) -> Option<Response> {
+    let mut best = build(&[])?;
+    let (mut low, mut high) = (0, items.len());
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        match build(&items[..mid]) {
+            Some(response) => {
+                best = response;
+                low = mid;
+            }
+            None => high = mid - 1,
+        }
+    }
+    Some(best)
+}
+
+// The length of the longest prefix of `text` that, wrapped by `variant` and then `Truncated`,
+// encodes to at most `max_len` bytes, or None if not even an empty string does.
+fn fit_str(text: &str, max_len: usize, variant: fn(String) -> Response) -> Option<usize> {
+    let overhead = Response::Truncated(Box::new(variant(String::new())))
+        .to_bytes()
+        .len();
+    let mut end = max_len.checked_sub(overhead)?.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(end)
+}
+
+// Helpers shared by the request and response encodings. Integers are written as big-endian `u64`s,
+// floats as big-endian IEEE 754 values, and strings as their length followed by their UTF-8 bytes.
+// Optional values are a one byte flag, followed by the value if the flag is 1.
+
+// Wrap an encoded message body in a frame.
+fn frame(body: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + body.len());
+    bytes.extend((body.len() as u32).to_be_bytes().iter());
+    bytes.extend(body);
+    bytes
+}
+
+// Compress everything in the message body `bytes` after the first `start` bytes, if there is
+// enough of it to be worth it and compressing makes it smaller.
+#[cfg_attr(not(feature = "compression"), allow(unused_variables, clippy::ptr_arg))]
+fn compress_tail(bytes: &mut Vec<u8>, start: usize) {
+    #[cfg(feature = "compression")]
+    if bytes.len() - start >= COMPRESSION_THRESHOLD {
+        let compressed = match zstd::bulk::compress(&bytes[start..], WIRE_COMPRESSION_LEVEL) {
+            Ok(compressed) if compressed.len() + 1 < bytes.len() - start => compressed,
+            _ => return,
+        };
+        bytes.truncate(start);
+        bytes.push(COMPRESSED_TAG);
+        bytes.extend(compressed);
+    }
+}
+
+// Decompress the compressed part of a message body, refusing it if it would come to more than
+// `max_len` bytes.
+#[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+fn decompress(compressed: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    #[cfg(feature = "compression")]
+    return zstd::bulk::decompress(compressed, max_len).ok();
+    #[cfg(not(feature = "compression"))]
+    None
+}
+
+// Read one frame of at most `max_len` bytes from `reader` and return its body.
+fn read_frame<R: Read>(mut reader: R, max_len: usize) -> Result<Vec<u8>, DecodeError> {
+    let mut header = [0_u8; 4];
+    // A connection closed between frames is told apart from one that cut a frame off
+    let first = loop {
+        match reader.read(&mut header[..1]) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            read => break read.map_err(read_error)?,
+        }
+    };
+    if first == 0 {
+        return Err(DecodeError::Closed);
+    }
+    reader.read_exact(&mut header[1..]).map_err(read_error)?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_len {
+        return Err(DecodeError::TooLarge);
+    }
+    let mut body = vec![0_u8; len];
+    reader.read_exact(&mut body).map_err(read_error)?;
+    Ok(body)
+}
+
+// Encode the frame that starts streaming a document to publish as `options` asks, sending `header`
+// with it. The document follows in frames of at most `STREAM_CHUNK_LEN` bytes each, encoded by
+// `stream_chunk`, and ends with an empty one. The server answers once it has the whole document,
+// as it would a `PublishWith` request.
+pub fn publish_stream_start(header: &RequestHeader, options: &PublishOptions) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(PROTOCOL_VERSION);
+    write_request_header(&mut bytes, header);
+    bytes.push(PUBLISH_STREAM_TAG);
+    write_publish_options(&mut bytes, options);
+    frame(bytes)
+}
+
+// Encode `chunk` of a streamed document as a frame. An empty chunk ends the document.
+pub fn stream_chunk(chunk: &[u8]) -> Vec<u8> {
+    frame(chunk.to_vec())
+}
+
+// Encode `doc` as a streamed document: a response frame holding only `DOCUMENT_STREAM_TAG`, then
+// frames of at most `STREAM_CHUNK_LEN` bytes of it, then an empty frame. The frames are encoded
+// one at a time, as they are asked for.
+pub fn document_stream(doc: &str) -> impl Iterator<Item = Vec<u8>> + '_ {
+    iter::once(frame(vec![DOCUMENT_STREAM_TAG]))
+        .chain(doc.as_bytes().chunks(STREAM_CHUNK_LEN).map(stream_chunk))
+        .chain(iter::once(stream_chunk(&[])))
+}
+
+// Read the chunk frames of a streamed document from `reader`, up to the empty one that ends it.
+fn read_stream<R: Read>(mut reader: R, limits: &MessageLimits) -> Result<String, DecodeError> {
+    let mut bytes = Some(Vec::new());
+    loop {
+        let chunk = match read_frame(&mut reader, limits.max_message_len) {
+            // A stream that ends without its empty chunk was cut off
+            Err(DecodeError::Closed) => return Err(DecodeError::Malformed),
+            chunk => chunk?,
+        };
+        if chunk.is_empty() {
+            return finish_stream(bytes);
+        }
+        add_chunk(&mut bytes, &chunk, limits);
+    }
+}
+
+// Add `chunk` to the streamed document `bytes`, or drop the document, leaving None, once it grows
+// over the limit in `limits`. The rest of the stream is still read, so that the client hears why
+// the document was refused rather than having its connection reset mid-stream.
+fn add_chunk(bytes: &mut Option<Vec<u8>>, chunk: &[u8], limits: &MessageLimits) {
+    if let Some(doc) = bytes {
+        match doc.len() + chunk.len() > limits.max_document_len {
+            true => *bytes = None,
+            false => doc.extend_from_slice(chunk),
+        }
+    }
+}
+
+// The text of a streamed document once all of its chunks are in.
+fn finish_stream(bytes: Option<Vec<u8>>) -> Result<String, DecodeError> {
+    let bytes = bytes.ok_or(DecodeError::TooLarge)?;
+    String::from_utf8(bytes).map_err(|_| DecodeError::Malformed)
+}
+
+// Read one whole frame from `reader`, header included, so it can be decoded without blocking.
+// Frames with a body over `max_len` bytes are refused from their header alone.
+#[cfg(feature = "async")]
+pub(crate) async fn read_frame_async<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    use tokio::io::AsyncReadExt;
+    let mut header = [0_u8; 4];
+    // A connection closed between frames is told apart from one that cut a frame off
+    match reader.read(&mut header[..1]).await {
+        Ok(0) => return Err(DecodeError::Closed),
+        Ok(_) => {}
+        Err(_) => return Err(DecodeError::Malformed),
+    }
+    reader
+        .read_exact(&mut header[1..])
+        .await
+        .map_err(|_| DecodeError::Malformed)?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_len {
+        return Err(DecodeError::TooLarge);
+    }
+    let mut frame = vec![0_u8; 4 + len];
+    frame[..4].copy_from_slice(&header);
+    reader
+        .read_exact(&mut frame[4..])
+        .await
+        .map_err(|_| DecodeError::Malformed)?;
+    Ok(frame)
+}
+
+// Why a read of a message failed with `error`. A reader with a timeout fails with `WouldBlock` or
+// `TimedOut`, depending on the platform, when it runs out of time.
+fn read_error(error: io::Error) -> DecodeError {
+    match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => DecodeError::TimedOut,
+        _ => DecodeError::Malformed,
+    }
+}
+
+fn write_usize(bytes: &mut Vec<u8>, n: usize) {
+    write_u64(bytes, u64::try_from(n).expect("usize wider than u64"));
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    write_usize(bytes, s.len());
+    bytes.extend(s.as_bytes().iter());
+}
+
+fn write_u64(bytes: &mut Vec<u8>, n: u64) {
+    bytes.extend(n.to_be_bytes().iter());
+}
+
+fn write_f32(bytes: &mut Vec<u8>, n: f32) {
+    bytes.extend(n.to_be_bytes().iter());
+}
+
+fn write_optional_u64(bytes: &mut Vec<u8>, n: Option<u64>) {
+    match n {
+        Some(n) => {
+            bytes.push(1_u8);
+            write_u64(bytes, n);
+        }
+        None => bytes.push(0_u8),
+    }
+}
+
+fn write_optional_usize(bytes: &mut Vec<u8>, n: Option<usize>) {
+    match n {
+        Some(n) => {
+            bytes.push(1_u8);
+            write_usize(bytes, n);
+        }
+        None => bytes.push(0_u8),
+    }
+}
+
+fn write_bool(bytes: &mut Vec<u8>, b: bool) {
+    bytes.push(b as u8);
+}
+
+fn write_search_options(bytes: &mut Vec<u8>, options: &SearchOptions) {
+    write_optional_u64(bytes, options.since);
+    write_optional_u64(bytes, options.until);
+    write_bool(bytes, options.newest_first);
+    write_usize(bytes, options.exclude.len());
+    for word in options.exclude.iter() {
+        write_str(bytes, word);
+    }
+    // 0 for any type, otherwise one more than the type's code
+    bytes.push(
+        options
+            .content_type
+            .map_or(0, |content_type| content_type.code() + 1),
+    );
+    write_optional_usize(bytes, options.snippet_words);
+    write_usize(bytes, options.offset);
+    write_optional_usize(bytes, options.limit);
+}
+
+fn write_optional_str(bytes: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            bytes.push(1_u8);
+            write_str(bytes, s);
         }
+        None => bytes.push(0_u8),
+    }
+}
+
+fn write_metadata(bytes: &mut Vec<u8>, metadata: &Metadata) {
+    write_optional_str(bytes, metadata.title.as_deref());
+    write_optional_str(bytes, metadata.author.as_deref());
+    write_optional_str(bytes, metadata.date.as_deref());
+}
+
+fn write_publish_options(bytes: &mut Vec<u8>, options: &PublishOptions) {
+    write_bool(bytes, options.pending);
+    write_metadata(bytes, &options.metadata);
+    bytes.push(options.content_type.code());
+}
+
+fn write_request_header(bytes: &mut Vec<u8>, header: &RequestHeader) {
+    write_optional_usize(bytes, header.max_response_len);
+    write_bool(bytes, header.allow_truncation);
+    write_bool(bytes, header.include_metadata);
+    write_bool(bytes, header.compression);
+    write_optional_str(bytes, header.token.as_deref());
+    write_bool(bytes, header.keep_alive);
+    write_bool(bytes, header.stream_documents);
+    write_optional_str(bytes, header.collection.as_deref());
+    write_optional_u64(bytes, header.request_id);
+}
+
+// Read a request header sent in protocol `version`.
+fn read_request_header<R: Read>(reader: &mut R, version: u8) -> Option<RequestHeader> {
+    Some(RequestHeader {
+        max_response_len: read_optional_usize(reader)?,
+        allow_truncation: read_bool(reader)?,
+        include_metadata: read_bool(reader)?,
+        compression: read_bool(reader)?,
+        token: read_optional_string(reader)?,
+        keep_alive: version >= 2 && read_bool(reader)?,
+        stream_documents: version >= 3 && read_bool(reader)?,
+        collection: match version >= 4 {
+            true => read_optional_string(reader)?,
+            false => None,
+        },
+        request_id: match version >= 5 {
+            true => read_optional_u64(reader)?,
+            false => None,
+        },
+    })
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Option<u8> {
+    let mut buffer = [0_u8; 1];
+    reader.read_exact(&mut buffer).ok()?;
+    Some(buffer[0])
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Option<u64> {
+    let mut buffer = [0_u8; 8];
+    reader.read_exact(&mut buffer).ok()?;
+    Some(u64::from_be_bytes(buffer))
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> Option<f32> {
+    let mut buffer = [0_u8; 4];
+    reader.read_exact(&mut buffer).ok()?;
+    Some(f32::from_be_bytes(buffer))
+}
+
+fn read_optional_u64<R: Read>(reader: &mut R) -> Option<Option<u64>> {
+    match read_u8(reader)? {
+        0 => Some(None),
+        1 => Some(Some(read_u64(reader)?)),
+        _ => None,
+    }
+}
+
+fn read_bool<R: Read>(reader: &mut R) -> Option<bool> {
+    match read_u8(reader)? {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+fn read_search_options<R: Read>(reader: &mut R) -> Option<SearchOptions> {
+    Some(SearchOptions {
+        since: read_optional_u64(reader)?,
+        until: read_optional_u64(reader)?,
+        newest_first: read_bool(reader)?,
+        exclude: {
+            let len = read_usize(reader)?;
+            let mut exclude = Vec::new();
+            for _ in 0..len {
+                exclude.push(read_string(reader)?);
+            }
+            exclude
+        },
+        content_type: match read_u8(reader)? {
+            0 => None,
+            code => Some(ContentType::from_code(code - 1)?),
+        },
+        snippet_words: read_optional_usize(reader)?,
+        offset: read_usize(reader)?,
+        limit: read_optional_usize(reader)?,
+    })
+}
+
+// Read a usize written by `write_optional_usize`, failing if it doesn't fit.
+fn read_optional_usize<R: Read>(reader: &mut R) -> Option<Option<usize>> {
+    match read_optional_u64(reader)? {
+        Some(n) => Some(Some(usize::try_from(n).ok()?)),
+        None => Some(None),
     }
 }
 
+fn read_optional_string<R: Read>(reader: &mut R) -> Option<Option<String>> {
+    match read_u8(reader)? {
+        0 => Some(None),
+        1 => Some(Some(read_string(reader)?)),
+        _ => None,
+    }
+}
+
+fn read_metadata<R: Read>(reader: &mut R) -> Option<Metadata> {
+    Some(Metadata {
+        title: read_optional_string(reader)?,
+        author: read_optional_string(reader)?,
+        date: read_optional_string(reader)?,
+    })
+}
+
+fn read_publish_options<R: Read>(reader: &mut R) -> Option<PublishOptions> {
+    Some(PublishOptions {
+        pending: read_bool(reader)?,
+        metadata: read_metadata(reader)?,
+        content_type: ContentType::from_code(read_u8(reader)?)?,
+    })
+}
+
+fn read_usize<R: Read>(reader: &mut R) -> Option<usize> {
+    usize::try_from(read_u64(reader)?).ok()
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Option<String> {
+    let len = read_usize(reader)?;
+    // Don't trust the length enough to allocate for it up front
+    let mut buffer = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buffer).ok()?;
+    if buffer.len() != len {
+        return None;
+    }
+    String::from_utf8(buffer).ok()
+}
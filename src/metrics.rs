@@ -0,0 +1,221 @@
+use crate::sync;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Counters and histograms describing the work a server has done, rendered in the Prometheus text
+// exposition format. Requests are counted by type, with a histogram of how long each type took to
+// answer; gauges that are cheaper to read on demand than to track (queue depth, index size, ...)
+// are passed in when rendering, as are histograms kept elsewhere, like the thread pool's.
+
+/// The upper bounds of the latency histograms' buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// The most bytes of request line and headers `serve_http` reads from a metrics request
+pub const MAX_HEAD_LEN: usize = 8 << 10;
+
+/// How long `serve_http` waits on a metrics client, to read its request or send the response,
+/// before hanging up on it, so that a slow client can't keep others from being answered
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many things took how long, in buckets bounded by `LATENCY_BUCKETS`
+#[derive(Debug, Default, Clone)]
+pub struct Histogram {
+    /// The number of things in each latency bucket, plus one for slower things
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    /// The total time the things took, in seconds
+    total_seconds: f64,
+}
+
+impl Histogram {
+    // Count one more thing, which took `latency`.
+    pub fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.total_seconds += seconds;
+    }
+
+    /// The number of things counted
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The total time the things counted took, in seconds
+    pub fn total_seconds(&self) -> f64 {
+        self.total_seconds
+    }
+
+    // Write the histogram's series to `out` as the histogram `name`, with `labels`, e.g.
+    // `type="search"`, on each series, or none if empty.
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name,
+            labels,
+            separator,
+            self.count()
+        );
+        let labels = match labels.is_empty() {
+            true => String::new(),
+            false => format!("{{{}}}", labels),
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.total_seconds);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count());
+    }
+}
+
+/// Counters and histograms for a server
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// How long each type of request took to answer, by name
+    requests: Mutex<BTreeMap<&'static str, Histogram>>,
+    /// The number of connections currently being handled
+    active_connections: AtomicUsize,
+}
+
+/// Counts a connection as active for as long as it is alive
+pub struct ConnectionGuard<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Record that a request of type `kind` was answered in `latency`.
+    pub fn record(&self, kind: &'static str, latency: Duration) {
+        let mut requests = sync::lock(&self.requests);
+        requests.entry(kind).or_default().observe(latency);
+    }
+
+    // Count a connection as active until the returned guard is dropped.
+    pub fn connection(&self) -> ConnectionGuard<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { metrics: self }
+    }
+
+    /// The number of requests of type `kind` answered so far
+    pub fn request_count(&self, kind: &str) -> u64 {
+        let requests = sync::lock(&self.requests);
+        requests.get(kind).map_or(0, Histogram::count)
+    }
+
+    /// The number of connections currently being handled
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    // Render every metric in the Prometheus text format, followed by each of `gauges` as a
+    // `ngram_`-prefixed gauge.
+    pub fn render(&self, gauges: &[(&str, usize)]) -> String {
+        self.render_with(gauges, &[])
+    }
+
+    // Like `render`, but also render each of `histograms`, given with its name and help text, as a
+    // `ngram_`-prefixed histogram.
+    pub fn render_with(
+        &self,
+        gauges: &[(&str, usize)],
+        histograms: &[(&str, &str, &Histogram)],
+    ) -> String {
+        let requests = sync::lock(&self.requests).clone();
+        let mut out = String::new();
+        out.push_str("# HELP ngram_requests_total Requests answered, by type\n");
+        out.push_str("# TYPE ngram_requests_total counter\n");
+        for (kind, stats) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "ngram_requests_total{{type=\"{}\"}} {}",
+                kind,
+                stats.count()
+            );
+        }
+        out.push_str("# HELP ngram_request_duration_seconds Time taken to answer requests\n");
+        out.push_str("# TYPE ngram_request_duration_seconds histogram\n");
+        for (kind, stats) in requests.iter() {
+            let labels = format!("type=\"{}\"", kind);
+            stats.write(&mut out, "ngram_request_duration_seconds", &labels);
+        }
+        for (name, help, histogram) in histograms {
+            let _ = writeln!(out, "# HELP ngram_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE ngram_{} histogram", name);
+            histogram.write(&mut out, &format!("ngram_{}", name), "");
+        }
+        let gauges = std::iter::once(("active_connections", self.active_connections()))
+            .chain(gauges.iter().copied());
+        for (name, value) in gauges {
+            let _ = writeln!(out, "# TYPE ngram_{} gauge", name);
+            let _ = writeln!(out, "ngram_{} {}", name, value);
+        }
+        out
+    }
+}
+
+// Answer one HTTP request on `stream`: `GET /metrics` gets `body`, and anything else a 404. Only
+// the first `MAX_HEAD_LEN` bytes of the request are read, and a client slower than `HTTP_TIMEOUT`
+// is given up on.
+pub fn serve_http(mut stream: TcpStream, body: impl FnOnce() -> String) {
+    if stream
+        .set_read_timeout(Some(HTTP_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(HTTP_TIMEOUT)))
+        .is_err()
+    {
+        return;
+    }
+    let mut reader = BufReader::new(&stream).take(MAX_HEAD_LEN as u64);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    // Read the headers too, so the connection isn't reset for closing with them unread
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|n| n > 0) && header.trim() != "" {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = body();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    let _ = stream
+        .write_all(response.as_bytes())
+        .and_then(|_| stream.flush());
+}
@@ -1,71 +1,357 @@
+use crate::sync;
+use rustc_hash::FxBuildHasher;
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeMap, Serializer};
 use std::borrow::Borrow;
-use std::collections::{hash_map::DefaultHasher, LinkedList};
-use std::hash::{Hash, Hasher};
-use std::sync::RwLock;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{PoisonError, RwLock};
+
+/// The average number of keys per bucket above which the map doubles its bucket count
+pub const MAX_LOAD_FACTOR: usize = 4;
 
 // The ConcurrentMultiMap struct is a concurrent hash map that allows multiple values to be
 // associated with a single key. It is implemented using a vector of RwLocks, where each lock
-// protects a linked list of key-value pairs.
-pub struct ConcurrentMultiMap<K: Hash + Eq, V> {
-    buckets: Vec<RwLock<LinkedList<(K, V)>>>,
+// protects a vector of entries. Each entry holds one key along with all of the values associated
+// with it, so a lookup only compares against each distinct key in the bucket once. A key's values
+// are kept sorted, so checking for a duplicate value is a binary search of them, and values set in
+// increasing order, as document ids are, are simply appended.
+//
+// The map counts its keys, and once there are more than `MAX_LOAD_FACTOR` per bucket on average,
+// it doubles its bucket count and rehashes every entry into the new buckets, so buckets stay short
+// as the map grows. The vector of buckets is itself behind a RwLock: every operation holds a reader
+// lock of it, and a resize takes the writer lock, briefly pausing the whole map while it moves the
+// entries.
+//
+// Keys are hashed with `S`, which defaults to FxHash. The index hashes every token of every
+// document it's sent, and the map is only ever used in-process, so a fast hash matters more than
+// the resistance to crafted collisions that the standard library's SipHash offers. Use
+// `with_hasher` to pick another one.
+pub struct ConcurrentMultiMap<K: Hash + Eq, V, S = FxBuildHasher> {
+    buckets: RwLock<Vec<RwLock<Bucket<K, V>>>>,
+    /// The number of distinct keys in the map
+    keys: AtomicUsize,
+    /// The number of key-value pairs in the map
+    pairs: AtomicUsize,
+    /// Builds the hashers that pick a key's bucket
+    hasher: S,
 }
 
+// The entries of one bucket, each a key and the values associated with it.
+type Bucket<K, V> = Vec<(K, Vec<V>)>;
+
 impl<K: Hash + Eq, V> ConcurrentMultiMap<K, V> {
-    // Create a new empty ConcurrentMultiMap with the given number of buckets.
+    // Create a new empty ConcurrentMultiMap with the given number of buckets. The map always has
+    // at least one bucket.
     pub fn new(bucket_count: usize) -> Self {
-        let mut buckets = Vec::with_capacity(bucket_count); 
+        Self::with_hasher(bucket_count, FxBuildHasher)
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ConcurrentMultiMap<K, V, S> {
+    // Create a new empty ConcurrentMultiMap with the given number of buckets, whose keys are
+    // hashed by hashers built by `hasher`.
+    pub fn with_hasher(bucket_count: usize, hasher: S) -> Self {
+        Self {
+            buckets: RwLock::new(Self::empty_buckets(bucket_count.max(1))),
+            keys: AtomicUsize::new(0),
+            pairs: AtomicUsize::new(0),
+            hasher,
+        }
+    }
+
+    fn empty_buckets(bucket_count: usize) -> Vec<RwLock<Bucket<K, V>>> {
+        let mut buckets = Vec::with_capacity(bucket_count);
         for _ in 0..bucket_count {
-            let list = LinkedList::new();
-            let lock = RwLock::new(list);
-            buckets.push(lock);
+            buckets.push(RwLock::new(Vec::new()));
+        }
+        buckets
+    }
+
+    // Find the index of the bucket that `key` belongs in among `bucket_count` buckets, by hashing
+    // it and modulo-ing the hash by the number of buckets.
+    fn bucket_index<Q>(&self, key: &Q, bucket_count: usize) -> usize
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash_value = self.hasher.hash_one(key);
+        (hash_value as usize) % bucket_count
+    }
+
+    // Find the bucket that `key` belongs in among `buckets`.
+    fn bucket<'a, Q>(
+        &self,
+        buckets: &'a [RwLock<Bucket<K, V>>],
+        key: &Q,
+    ) -> &'a RwLock<Bucket<K, V>>
+    where
+        Q: Hash + ?Sized,
+    {
+        &buckets[self.bucket_index(key, buckets.len())]
+    }
+
+    // Double the bucket count if the map holds more than `MAX_LOAD_FACTOR` keys per bucket. To do
+    // so, take the writer lock of the bucket vector, so that no other operation is in progress,
+    // and move every entry into the bucket it belongs in among twice as many buckets. The load is
+    // checked again once the lock is held, since another thread may have resized the map first.
+    fn grow_if_overloaded(&self) {
+        let overloaded = |bucket_count: usize| self.key_count() > bucket_count * MAX_LOAD_FACTOR;
+        if !overloaded(self.bucket_count()) {
+            return;
         }
-        Self {buckets}
+        let mut buckets = sync::write(&self.buckets);
+        if !overloaded(buckets.len()) {
+            return;
+        }
+        let mut resized = Self::empty_buckets(buckets.len() * 2);
+        for bucket_lock in buckets.iter_mut() {
+            for (key, values) in bucket_lock
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .drain(..)
+            {
+                let index = self.bucket_index(&key, resized.len());
+                resized[index]
+                    .get_mut()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push((key, values));
+            }
+        }
+        *buckets = resized;
     }
 }
 
-impl<K: Hash + Eq, V: Clone + Eq> ConcurrentMultiMap<K, V> {
+impl<K: Hash + Eq, V: Clone + Ord, S: BuildHasher> ConcurrentMultiMap<K, V, S> {
     // Associate the given value with the given key. To do so, hash the key, and find the
     // corresponding bucket in the vector by modulo-ing the hash by the number of buckets. Then,
-    // take a writer lock of the bucket and iterate over the linked list, checking if the
-    // key-values pair already exists. If it does, return early. Otherwise, add the key-value pair
-    // to the linked list.
+    // take a writer lock of the bucket and look for the key's entry. If it already holds the
+    // value, return early. Otherwise, insert the value into the entry where it keeps the values
+    // sorted, creating the entry if the key has none. If a new entry was created, the map may now
+    // be overloaded, so grow it if needed.
     pub fn set(&self, key: K, value: V) {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash_value = hasher.finish();
-        let bucket_ind = (hash_value as usize) % self.buckets.len();
-        let bucket_lock = &self.buckets[bucket_ind];
-        let mut write = bucket_lock.write().unwrap();
-        for (existing_key, existing_value) in write.iter() {
-            if *existing_key == key && *existing_value == value {
-                return;
+        {
+            let buckets = sync::read(&self.buckets);
+            let mut write = sync::write(self.bucket(&buckets, &key));
+            match write
+                .iter_mut()
+                .find(|(existing_key, _)| *existing_key == key)
+            {
+                Some((_, values)) => {
+                    if let Err(position) = values.binary_search(&value) {
+                        values.insert(position, value);
+                        self.pairs.fetch_add(1, Ordering::SeqCst);
+                    }
+                    return;
+                }
+                None => write.push((key, vec![value])),
             }
+            self.keys.fetch_add(1, Ordering::SeqCst);
+            self.pairs.fetch_add(1, Ordering::SeqCst);
         }
-        write.push_back((key, value))
+        self.grow_if_overloaded();
     }
 
     // Retrieve all values associated with `key`. To do so, hash the key, and find the
     // corresponding bucket in the vector by modulo-ing the hash by the number of buckets. Then,
-    // take a reader lock of the bucker and iterate over the linked list, collecting all values
-    // associated with the key by `clone`-ing them. Return the collected values.
+    // take a reader lock of the bucket, find the key's entry, and `clone` its values.
     pub fn get<Q>(&self, key: &Q) -> Vec<V>
     where
         K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash_value = hasher.finish();
-        let bucket_ind = (hash_value as usize) % self.buckets.len();
-        let bucket_lock = &self.buckets[bucket_ind];
-        let read = bucket_lock.read().unwrap();
-        let mut to_return = Vec::new();
-        for (existing_key, existing_value) in read.iter() {
-            if existing_key.borrow() == key {
-                to_return.push(existing_value.clone());
+        let buckets = sync::read(&self.buckets);
+        let read = sync::read(self.bucket(&buckets, key));
+        read.iter()
+            .find(|(existing_key, _)| existing_key.borrow() == key)
+            .map(|(_, values)| values.clone())
+            .unwrap_or_default()
+    }
+
+    // The number of values associated with `key`, without cloning them as `get` does.
+    pub fn get_count<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let buckets = sync::read(&self.buckets);
+        let read = sync::read(self.bucket(&buckets, key));
+        read.iter()
+            .find(|(existing_key, _)| existing_key.borrow() == key)
+            .map_or(0, |(_, values)| values.len())
+    }
+
+    // Whether `value` is associated with `key`, taking a reader lock of the key's bucket.
+    pub fn contains<Q>(&self, key: &Q, value: &V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let buckets = sync::read(&self.buckets);
+        let read = sync::read(self.bucket(&buckets, key));
+        read.iter()
+            .find(|(existing_key, _)| existing_key.borrow() == key)
+            .is_some_and(|(_, values)| values.binary_search(value).is_ok())
+    }
+
+    // Disassociate `value` from `key`. To do so, take a writer lock of the key's bucket and drop
+    // the value from the key's entry, keeping the other values in order. An entry left with no
+    // values is dropped too. Return whether the value was present.
+    pub fn remove<Q>(&self, key: &Q, value: &V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let buckets = sync::read(&self.buckets);
+        let mut write = sync::write(self.bucket(&buckets, key));
+        let Some(index) = write
+            .iter()
+            .position(|(existing_key, _)| existing_key.borrow() == key)
+        else {
+            return false;
+        };
+        let values = &mut write[index].1;
+        let Ok(position) = values.binary_search(value) else {
+            return false;
+        };
+        values.remove(position);
+        self.pairs.fetch_sub(1, Ordering::SeqCst);
+        if values.is_empty() {
+            write.swap_remove(index);
+            self.keys.fetch_sub(1, Ordering::SeqCst);
+        }
+        true
+    }
+
+    // Disassociate every value from `key`, taking a writer lock of the key's bucket and dropping
+    // the key's entry. Return the values that were removed.
+    pub fn remove_all<Q>(&self, key: &Q) -> Vec<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let buckets = sync::read(&self.buckets);
+        let mut write = sync::write(self.bucket(&buckets, key));
+        match write
+            .iter()
+            .position(|(existing_key, _)| existing_key.borrow() == key)
+        {
+            Some(index) => {
+                let (_, values) = write.swap_remove(index);
+                self.keys.fetch_sub(1, Ordering::SeqCst);
+                self.pairs.fetch_sub(values.len(), Ordering::SeqCst);
+                values
             }
+            None => Vec::new(),
         }
-        to_return
     }
 }
 
+impl<K: Hash + Eq, V, S> ConcurrentMultiMap<K, V, S> {
+    // The number of buckets the map currently has.
+    pub fn bucket_count(&self) -> usize {
+        sync::read(&self.buckets).len()
+    }
+
+    // The number of distinct keys with at least one value in the map.
+    pub fn key_count(&self) -> usize {
+        self.keys.load(Ordering::SeqCst)
+    }
+
+    // The number of key-value pairs in the map.
+    pub fn len(&self) -> usize {
+        self.pairs.load(Ordering::SeqCst)
+    }
+
+    // Whether the map has no values at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Call `f` on every key-value pair in the map, taking a reader lock of one bucket at a time.
+    // Pairs set concurrently may or may not be visited. The map can't be resized until every pair
+    // has been visited.
+    pub(crate) fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
+        let buckets = sync::read(&self.buckets);
+        for bucket_lock in buckets.iter() {
+            let read = sync::read(bucket_lock);
+            for (key, values) in read.iter() {
+                for value in values.iter() {
+                    f(key, value);
+                }
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, S> ConcurrentMultiMap<K, V, S> {
+    // Iterate over a snapshot of every key-value pair in the map, cloned out as visited by
+    // `for_each`, so no lock is held while the caller iterates. Pairs come in no particular order.
+    pub fn iter(&self) -> std::vec::IntoIter<(K, V)> {
+        let mut entries = Vec::with_capacity(self.len());
+        self.for_each(|key, value| entries.push((key.clone(), value.clone())));
+        entries.into_iter()
+    }
+
+    // A snapshot of every key with at least one value in the map, in no particular order.
+    pub fn keys(&self) -> Vec<K> {
+        let buckets = sync::read(&self.buckets);
+        let mut keys = Vec::with_capacity(self.key_count());
+        for bucket_lock in buckets.iter() {
+            let read = sync::read(bucket_lock);
+            keys.extend(read.iter().map(|(key, _)| key.clone()));
+        }
+        keys
+    }
+}
+
+// A map serializes as a map from each key to the list of its values. Every bucket is read-locked
+// at once while serializing, so the result is a consistent snapshot of the map.
+impl<K: Hash + Eq + Serialize, V: Serialize, S> Serialize for ConcurrentMultiMap<K, V, S> {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let buckets = sync::read(&self.buckets);
+        let reads = buckets
+            .iter()
+            .map(|bucket_lock| sync::read(bucket_lock))
+            .collect::<Vec<_>>();
+        let mut map = serializer.serialize_map(Some(reads.iter().map(|read| read.len()).sum()))?;
+        for read in reads.iter() {
+            for (key, values) in read.iter() {
+                map.serialize_entry(key, values)?;
+            }
+        }
+        map.end()
+    }
+}
+
+// Deserialize a map from each key to the list of its values, into a map with just enough buckets
+// to hold its keys without resizing. Values are sorted, repeated values are only kept once, and
+// keys without any values are left out.
+impl<'de, K, V, S> Deserialize<'de> for ConcurrentMultiMap<K, V, S>
+where
+    K: Hash + Eq + Deserialize<'de>,
+    V: Ord + Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = HashMap::<K, Vec<V>>::deserialize(deserializer)?;
+        let mut map = Self::with_hasher(entries.len().div_ceil(MAX_LOAD_FACTOR), S::default());
+        for (key, mut unique) in entries {
+            unique.sort();
+            unique.dedup();
+            if unique.is_empty() {
+                continue;
+            }
+            let index = map.bucket_index(&key, map.bucket_count());
+            *map.keys.get_mut() += 1;
+            *map.pairs.get_mut() += unique.len();
+            map.buckets
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)[index]
+                .get_mut()
+                .unwrap()
+                .push((key, unique));
+        }
+        Ok(map)
+    }
+}
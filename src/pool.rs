@@ -1,13 +1,85 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+use crate::metrics::Histogram;
+use crate::sync;
+
 // We represent a job as a boxed closure that can be sent across threads. Since the closure is
 // `Send`, it can be sent across threads. Since it is in a box, we have ownership and can transfer
 // it to other threads.
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// What the workers are sent: a job to run, with when it was queued, or, when the pool shrinks,
+// word for one of them to exit.
+enum Message {
+    Job(Job, Instant),
+    Retire,
+}
+
+/// Why a job sent to a `ThreadPool` gave no result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskError {
+    /// The job panicked, with the given message
+    Panicked(String),
+    /// The job hadn't finished when the caller stopped waiting for it; it may still be running
+    TimedOut,
+    /// The job was dropped without being run to the end, so it will never give a result
+    Lost,
+}
+
+/// The result of a job sent to a `ThreadPool`, once it finishes. Dropping the handle doesn't stop
+/// the job; its result is thrown away
+pub struct TaskHandle<T> {
+    result: mpsc::Receiver<Result<T, TaskError>>,
+}
+
+impl<T> TaskHandle<T> {
+    // Wait for the job to finish, and return what it returned or why it didn't return anything.
+    pub fn join(self) -> Result<T, TaskError> {
+        self.result.recv().unwrap_or(Err(TaskError::Lost))
+    }
+
+    // Like `join`, but wait at most `timeout`, failing with `TimedOut` if the job is still queued
+    // or running by then. The handle can be waited on again afterwards.
+    pub fn join_timeout(&self, timeout: Duration) -> Result<T, TaskError> {
+        match self.result.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(TaskError::TimedOut),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(TaskError::Lost),
+        }
+    }
+}
+
+// Wrap `f` as a job that sends its result, or the message it panicked with, to the returned
+// handle. A panic is passed on once it is sent, so the worker still logs and counts it.
+fn job_with_handle<F, T>(f: F) -> (Job, TaskHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, result) = mpsc::channel();
+    let job = Box::new(move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => {
+            let _ = sender.send(Ok(value));
+        }
+        Err(payload) => {
+            let message = panic_message(payload.as_ref()).to_string();
+            let _ = sender.send(Err(TaskError::Panicked(message)));
+            panic::resume_unwind(payload);
+        }
+    });
+    (job, TaskHandle { result })
+}
+
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
@@ -15,78 +87,260 @@ struct Worker {
 impl Worker {
     // Spawn a new thread that will loop forever, receiving jobs from the receiver and executing
     // them. If the `recv()` method returns an error, it means the thread pool has been dropped and
-    // the thread should exit by breaking the loop.
-    // This function should return a `Worker` as a handle to the thread.
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || {
-            loop {
-                let result = receiver.lock().unwrap().recv();
-                match result {
-                    Ok(job) => job(),
-                    Err(_) => break
+    // the thread should exit by breaking the loop, as it should when told to retire. A job that
+    // panics is logged and counted, and the worker goes on to the next one, so the pool never
+    // loses workers to bad jobs. How long each job waited and ran is added to `timings`.
+    // This function should return a `Worker` as a handle to the thread, or the error the thread
+    // couldn't be spawned with.
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+        queued: Arc<AtomicUsize>,
+        active: Arc<AtomicUsize>,
+        panics: Arc<AtomicUsize>,
+        timings: Arc<Mutex<Timings>>,
+    ) -> io::Result<Worker> {
+        let thread = thread::Builder::new().spawn(move || loop {
+            let result = sync::lock(&receiver).recv();
+            match result {
+                Ok(Message::Job(job, queued_at)) => {
+                    let started = Instant::now();
+                    active.fetch_add(1, Ordering::Relaxed);
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    let outcome = panic::catch_unwind(AssertUnwindSafe(job));
+                    let mut times = sync::lock(&timings);
+                    times.queue_wait.observe(started - queued_at);
+                    times.run_time.observe(started.elapsed());
+                    drop(times);
+                    if let Err(payload) = outcome {
+                        panics.fetch_add(1, Ordering::Relaxed);
+                        tracing::error!(
+                            worker = id,
+                            panic = panic_message(payload.as_ref()),
+                            "worker recovered from a panicking job"
+                        );
+                    }
+                    active.fetch_sub(1, Ordering::Relaxed);
                 }
+                Ok(Message::Retire) | Err(_) => break,
             }
-        });
-        Worker {
-            id, 
+        })?;
+        Ok(Worker {
+            id,
             thread: Some(thread),
-        }
+        })
     }
 }
 
+// The message a panic was raised with, if it was raised with one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match (
+        payload.downcast_ref::<&str>(),
+        payload.downcast_ref::<String>(),
+    ) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message,
+        _ => "unknown cause",
+    }
+}
+
+/// How long the jobs a pool has finished spent waiting and running
+#[derive(Debug, Default, Clone)]
+pub struct Timings {
+    /// From being sent to the pool until a worker took them
+    pub queue_wait: Histogram,
+    /// From a worker taking them until they returned or panicked
+    pub run_time: Histogram,
+}
+
 pub struct ThreadPool {
+    /// Every worker started, including any told to retire that may not have exited yet
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    /// The number of workers the pool is meant to have
+    size: usize,
+    /// The id to give the next worker started
+    next_id: usize,
+    sender: Option<mpsc::Sender<Message>>,
+    /// Shared by the workers, each taking the next message when it is free
+    receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+    /// The number of jobs sent that no worker has started yet
+    queued: Arc<AtomicUsize>,
+    /// The number of workers running a job
+    active: Arc<AtomicUsize>,
+    /// The number of jobs that have panicked
+    panics: Arc<AtomicUsize>,
+    /// How long finished jobs waited and ran
+    timings: Arc<Mutex<Timings>>,
+    /// The most jobs `try_execute` lets wait for a free worker, if it is limited
+    queue_capacity: Option<usize>,
 }
 
 impl ThreadPool {
-    // Spawn `size` workers by calling the `Worker::new` function `size` times, each time with a
-    // unique id. You will need to create a channel and wrap the receiver in an `Arc<Mutex<...>>`
-    // in order to share it with the worker threads. Finally, return an instance of `ThreadPool`
-    // that has the workers and the sender.
+    // Create a channel, wrapping the receiver in an `Arc<Mutex<...>>` in order to share it with
+    // the worker threads, and spawn `size` workers on it, each with a unique id, by growing the
+    // pool from none.
     pub fn new(size: usize) -> ThreadPool {
         if size < 1 {
             panic!("Pool with size < 1");
         }
         let (tx, rx) = mpsc::channel();
         let rx = Arc::new(Mutex::new(rx));
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            let rx_clone = Arc::clone(&rx);
-            workers.push(Worker::new(id, rx_clone));
-        }
-        ThreadPool {
-            workers,
+        let queued = Arc::new(AtomicUsize::new(0));
+        let active = Arc::new(AtomicUsize::new(0));
+        let panics = Arc::new(AtomicUsize::new(0));
+        let mut pool = ThreadPool {
+            workers: Vec::with_capacity(size),
+            size: 0,
+            next_id: 0,
             sender: Some(tx),
+            receiver: rx,
+            queued,
+            active,
+            panics,
+            timings: Arc::default(),
+            queue_capacity: None,
+        };
+        pool.resize(size)
+            .expect("failed to spawn the pool's workers");
+        pool
+    }
+
+    // Grow or shrink the pool to `size` workers, which must be at least one. New workers start at
+    // once. Workers are let go by queueing word for them to retire, so each leaves once the jobs
+    // queued ahead of that word are taken, and no job is dropped. If a new worker can't be
+    // spawned, the pool keeps those started before it and the error is returned.
+    pub fn resize(&mut self, size: usize) -> io::Result<()> {
+        assert!(size > 0, "a pool must have at least one worker");
+        // Forget workers that have already retired
+        self.workers.retain(|worker| {
+            worker
+                .thread
+                .as_ref()
+                .is_some_and(|thread| !thread.is_finished())
+        });
+        let sender = self.sender.as_ref().unwrap();
+        for _ in size..self.size {
+            sender.send(Message::Retire).unwrap();
+        }
+        while self.size < size {
+            self.workers.push(Worker::new(
+                self.next_id,
+                Arc::clone(&self.receiver),
+                Arc::clone(&self.queued),
+                Arc::clone(&self.active),
+                Arc::clone(&self.panics),
+                Arc::clone(&self.timings),
+            )?);
+            self.next_id += 1;
+            self.size += 1;
         }
+        self.size = size;
+        Ok(())
+    }
+
+    /// The number of workers the pool is meant to have. Just after it shrinks, some of those let
+    /// go may still be finishing the jobs queued ahead of their word to retire
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Let at most `capacity` jobs sent with `try_execute` wait for a free worker, beyond those that
+    // idle workers are about to pick up. Jobs sent with `execute` always wait, however many there
+    // are, but still count towards the limit.
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = Some(capacity);
+        self
+    }
 
+    /// The most jobs `try_execute` lets wait for a free worker, if it is limited
+    pub fn queue_capacity(&self) -> Option<usize> {
+        self.queue_capacity
     }
 
-    // Send the job `f` to the worker threads via the channel `send` method.
-    pub fn execute<F>(&self, f: F)
+    // Send the job `f` to the worker threads via the channel `send` method. Return a handle to
+    // wait on for what `f` returns, e.g. to give up on it after a deadline.
+    pub fn execute<F, T>(&self, f: F) -> TaskHandle<T>
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
-        let job = Box::new(f);
+        let (job, handle) = job_with_handle(f);
         let sender = self.sender.as_ref().unwrap();
-        sender.send(job).unwrap();
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        sender.send(Message::Job(job, Instant::now())).unwrap();
+        handle
+    }
+
+    // Like `execute`, but if the queue is already at capacity, hand `f` straight back instead of
+    // making it wait, so the caller can shed load rather than let the queue grow without bound.
+    pub fn try_execute<F, T>(&self, f: F) -> Result<TaskHandle<T>, F>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let idle = self.size - self.active.load(Ordering::Relaxed).min(self.size);
+        let capacity = self
+            .queue_capacity
+            .map_or(usize::MAX, |capacity| capacity + idle);
+        // Claim a place in the queue first, so that two callers can't both take the last one
+        let claimed = self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                (queued < capacity).then_some(queued + 1)
+            });
+        if claimed.is_err() {
+            return Err(f);
+        }
+        let (job, handle) = job_with_handle(f);
+        let sender = self.sender.as_ref().unwrap();
+        sender.send(Message::Job(job, Instant::now())).unwrap();
+        Ok(handle)
+    }
+
+    /// The number of jobs that have panicked since the pool was created
+    pub fn panic_count(&self) -> usize {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// The number of jobs waiting for a free worker
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// The number of workers running a job
+    pub fn active_count(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// How long the jobs the pool has finished spent waiting for a worker and running
+    pub fn timings(&self) -> Timings {
+        sync::lock(&self.timings).clone()
+    }
+
+    // Stop accepting jobs and wait for the workers to finish every job already sent, so that none
+    // is cut off partway through.
+    pub fn shutdown(mut self) {
+        self.join_workers();
     }
-}
 
-impl Drop for ThreadPool {
     // First, take ownership of the sender from inside the option, then drop it. This will trigger
     // the worker threads to stop since the channel is closed, so you should then call `join` on
     // each worker thread handle to make sure they finish executing. Calling `join` will also
     // require you to take ownership of the worker thread handle from inside the option
-    fn drop(&mut self) {
+    fn join_workers(&mut self) {
         drop(self.sender.take());
         for worker in &mut self.workers {
-            match worker.thread.take() {
-                Some(thread) => thread.join().unwrap(),
-                None => (),
+            if let Some(thread) = worker.thread.take() {
+                if thread.join().is_err() {
+                    tracing::error!(worker = worker.id, "worker panicked");
+                }
             }
         }
-
     }
 }
 
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.join_workers();
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Boolean queries combine word searches, e.g. `whale AND (ship OR boat) NOT harpoon`. Words next to
+// each other must both match, as if joined by AND. NOT binds tightest, then AND, then OR, and
+// parentheses group. The operators are only recognized in capitals, so that "and", "or" and "not"
+// can still be searched for. Each word is searched for as `Database::search` would.
+//
+// The grammar is:
+//
+//     or    = and ("OR" and)*
+//     and   = unary ("AND"? unary)*
+//     unary = "NOT" unary | "(" or ")" | word
+
+/// The most words, operators and parentheses a query may have. Queries are parsed and evaluated
+/// recursively, so this keeps a hostile query from overflowing the stack
+pub const MAX_TOKENS: usize = 1024;
+
+/// A parsed boolean query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// Documents containing the word
+    Word(String),
+    /// Documents matching both queries
+    And(Box<Query>, Box<Query>),
+    /// Documents matching either query
+    Or(Box<Query>, Box<Query>),
+    /// Searchable documents not matching the query
+    Not(Box<Query>),
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Query::Word(word) => f.write_str(word),
+            Query::And(a, b) => write!(f, "({} AND {})", a, b),
+            Query::Or(a, b) => write!(f, "({} OR {})", a, b),
+            Query::Not(a) => write!(f, "NOT {}", a),
+        }
+    }
+}
+
+// Parse a query written in the grammar above, explaining what is wrong with it if it can't be.
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s);
+        if tokens.len() > MAX_TOKENS {
+            return Err(format!("query has more than {} terms", MAX_TOKENS));
+        }
+        let mut parser = Parser { tokens, next: 0 };
+        let query = parser.or()?;
+        match parser.peek() {
+            None => Ok(query),
+            Some(token) => Err(format!("unexpected {:?} in query", token)),
+        }
+    }
+}
+
+// Split a query into words and parentheses.
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in s.chars() {
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+// A recursive descent parser over a query's tokens, with one method per rule of the grammar.
+struct Parser {
+    tokens: Vec<String>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.next).map(String::as_str)
+    }
+
+    // Take the next token if it is `expected`.
+    fn eat(&mut self, expected: &str) -> bool {
+        let found = self.peek() == Some(expected);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Query, String> {
+        let mut query = self.and()?;
+        while self.eat("OR") {
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query, String> {
+        let mut query = self.unary()?;
+        loop {
+            if !self.eat("AND") && matches!(self.peek(), None | Some("OR" | ")")) {
+                return Ok(query);
+            }
+            query = Query::And(Box::new(query), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Query, String> {
+        match self.peek() {
+            Some("NOT") => {
+                self.next += 1;
+                Ok(Query::Not(Box::new(self.unary()?)))
+            }
+            Some("(") => {
+                self.next += 1;
+                let query = self.or()?;
+                match self.eat(")") {
+                    true => Ok(query),
+                    false => Err("missing ')' in query".to_string()),
+                }
+            }
+            Some(token @ ("AND" | "OR" | ")")) => Err(format!(
+                "expected a word or '(' before {:?} in query",
+                token
+            )),
+            Some(word) => {
+                let query = Query::Word(word.to_string());
+                self.next += 1;
+                Ok(query)
+            }
+            None => Err("expected a word or '(' at the end of the query".to_string()),
+        }
+    }
+}
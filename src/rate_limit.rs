@@ -0,0 +1,84 @@
+use crate::sync;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Each client address gets a bucket of tokens, which refills at a steady rate up to a cap. Every
+// connection takes a token, and a connection that finds the bucket empty is turned away, so a
+// client can make a burst of requests at once but can't go on faster than the rate for long.
+
+/// The most buckets kept before those of clients that have gone quiet are forgotten
+const MAX_TRACKED: usize = 4096;
+
+/// How fast each client may send requests
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// The number of requests a client may make each second, over time
+    pub per_second: f64,
+    /// The most requests a client may make at once, after being quiet
+    pub burst: u32,
+}
+
+/// Tracks how many requests each client address has left
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+// The tokens a client had left when it last made a request
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        assert!(
+            limit.per_second > 0.0 && limit.burst > 0,
+            "rate limits must allow some requests"
+        );
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    // Take a token for a request from `address`, returning false if it has none left.
+    pub fn allow(&self, address: IpAddr) -> bool {
+        self.allow_at(address, Instant::now())
+    }
+
+    // Like `allow`, but as if it were `now`.
+    pub fn allow_at(&self, address: IpAddr, now: Instant) -> bool {
+        let burst = self.limit.burst as f64;
+        let mut buckets = sync::lock(&self.buckets);
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&address) {
+            // A full bucket is the same as no bucket at all
+            buckets.retain(|_, bucket| self.refill(*bucket, now) < burst);
+        }
+        let bucket = buckets.entry(address).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(*bucket, now);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    // The tokens in `bucket` by `now`.
+    fn refill(&self, bucket: Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64)
+    }
+}
@@ -0,0 +1,416 @@
+use crate::client::Client;
+use crate::database::{PublishOptions, SearchOptions};
+use crate::message::{Request, Response};
+use crate::sync;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Requests handled by the server can be recorded to a JSONL file, one request per line, and later
+// replayed against another server with `replay`. Published documents are large, so by default only
+// their length and a hash are recorded; replaying such an entry publishes filler text of the same
+// length, which is enough for load testing.
+
+/// One line of a request log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    /// When the server received the request, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// The request itself
+    #[serde(flatten)]
+    pub request: RecordedKind,
+}
+
+/// The recorded contents of a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordedKind {
+    Publish {
+        length: usize,
+        hash: String,
+        /// The document itself, only present when the log records payloads
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        doc: Option<String>,
+        #[serde(flatten)]
+        options: PublishOptions,
+    },
+    Search {
+        word: String,
+    },
+    Retrieve {
+        id: usize,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        offset: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        length: Option<usize>,
+    },
+    List {
+        preview_chars: usize,
+    },
+    SuggestTerms {
+        prefix: String,
+        limit: usize,
+    },
+    SearchWith {
+        word: String,
+        #[serde(flatten)]
+        options: SearchOptions,
+    },
+    SearchRanked {
+        word: String,
+        k: usize,
+    },
+    Commit {
+        id: usize,
+    },
+    Stats,
+    Update {
+        id: usize,
+        length: usize,
+        hash: String,
+        /// The new text, only present when the log records payloads
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        doc: Option<String>,
+    },
+    SearchPrefix {
+        prefix: String,
+    },
+    SearchSubstring {
+        text: String,
+    },
+    TermStats {
+        after: Option<String>,
+        limit: usize,
+    },
+    Query {
+        expr: String,
+    },
+    Shutdown,
+    Snapshot,
+    Ping,
+    Count {
+        word: String,
+    },
+    Export {
+        path: String,
+    },
+    Import {
+        path: String,
+    },
+    CreateCollection {
+        name: String,
+    },
+    ListCollections,
+    Replicate {
+        session: u64,
+        seq: u64,
+        entry: String,
+    },
+    Subscribe {
+        word: String,
+    },
+    ResizePool {
+        workers: usize,
+    },
+    PublishBatch {
+        lengths: Vec<usize>,
+        hashes: Vec<String>,
+        /// The documents themselves, only present when the log records payloads
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        docs: Option<Vec<String>>,
+        #[serde(flatten)]
+        options: PublishOptions,
+    },
+}
+
+impl RecordedKind {
+    // Record `request`, keeping the full text of published documents only if `include_payloads`
+    // is set.
+    fn new(request: &Request, include_payloads: bool) -> Self {
+        let publish = |doc: &String, options: &PublishOptions| RecordedKind::Publish {
+            length: doc.len(),
+            hash: hash(doc),
+            doc: include_payloads.then(|| doc.clone()),
+            options: options.clone(),
+        };
+        match request {
+            Request::Publish { doc } => publish(doc, &PublishOptions::default()),
+            Request::PublishWith { doc, options } => publish(doc, options),
+            Request::Commit { id } => RecordedKind::Commit { id: *id },
+            Request::Stats => RecordedKind::Stats,
+            Request::Shutdown => RecordedKind::Shutdown,
+            Request::Snapshot => RecordedKind::Snapshot,
+            Request::Ping => RecordedKind::Ping,
+            Request::Count { word } => RecordedKind::Count { word: word.clone() },
+            Request::Export { path } => RecordedKind::Export { path: path.clone() },
+            Request::Import { path } => RecordedKind::Import { path: path.clone() },
+            Request::CreateCollection { name } => {
+                RecordedKind::CreateCollection { name: name.clone() }
+            }
+            Request::ListCollections => RecordedKind::ListCollections,
+            Request::Subscribe { word } => RecordedKind::Subscribe { word: word.clone() },
+            Request::ResizePool { workers } => RecordedKind::ResizePool { workers: *workers },
+            Request::Replicate {
+                session,
+                seq,
+                entry,
+            } => RecordedKind::Replicate {
+                session: *session,
+                seq: *seq,
+                entry: entry.clone(),
+            },
+            Request::SearchPrefix { prefix } => RecordedKind::SearchPrefix {
+                prefix: prefix.clone(),
+            },
+            Request::SearchSubstring { text } => {
+                RecordedKind::SearchSubstring { text: text.clone() }
+            }
+            Request::TermStats { after, limit } => RecordedKind::TermStats {
+                after: after.clone(),
+                limit: *limit,
+            },
+            Request::Query { expr } => RecordedKind::Query { expr: expr.clone() },
+            Request::PublishBatch { docs, options } => RecordedKind::PublishBatch {
+                lengths: docs.iter().map(String::len).collect(),
+                hashes: docs.iter().map(|doc| hash(doc)).collect(),
+                docs: include_payloads.then(|| docs.clone()),
+                options: options.clone(),
+            },
+            Request::Update { id, doc } => RecordedKind::Update {
+                id: *id,
+                length: doc.len(),
+                hash: hash(doc),
+                doc: include_payloads.then(|| doc.clone()),
+            },
+            Request::Search { word } => RecordedKind::Search { word: word.clone() },
+            Request::Retrieve { id, offset, length } => RecordedKind::Retrieve {
+                id: *id,
+                offset: *offset,
+                length: *length,
+            },
+            Request::List { preview_chars } => RecordedKind::List {
+                preview_chars: *preview_chars,
+            },
+            Request::SuggestTerms { prefix, limit } => RecordedKind::SuggestTerms {
+                prefix: prefix.clone(),
+                limit: *limit,
+            },
+            Request::SearchWith { word, options } => RecordedKind::SearchWith {
+                word: word.clone(),
+                options: options.clone(),
+            },
+            Request::SearchRanked { word, k } => RecordedKind::SearchRanked {
+                word: word.clone(),
+                k: *k,
+            },
+        }
+    }
+
+    /// The request to send when replaying this entry
+    pub fn to_request(&self) -> Request {
+        match self {
+            RecordedKind::Publish {
+                length,
+                doc,
+                options,
+                ..
+            } => {
+                let doc = doc.clone().unwrap_or_else(|| filler(*length));
+                if *options == PublishOptions::default() {
+                    Request::Publish { doc }
+                } else {
+                    Request::PublishWith {
+                        doc,
+                        options: options.clone(),
+                    }
+                }
+            }
+            RecordedKind::Commit { id } => Request::Commit { id: *id },
+            RecordedKind::Stats => Request::Stats,
+            RecordedKind::Shutdown => Request::Shutdown,
+            RecordedKind::Snapshot => Request::Snapshot,
+            RecordedKind::Ping => Request::Ping,
+            RecordedKind::Count { word } => Request::Count { word: word.clone() },
+            RecordedKind::Export { path } => Request::Export { path: path.clone() },
+            RecordedKind::Import { path } => Request::Import { path: path.clone() },
+            RecordedKind::CreateCollection { name } => {
+                Request::CreateCollection { name: name.clone() }
+            }
+            RecordedKind::ListCollections => Request::ListCollections,
+            RecordedKind::Subscribe { word } => Request::Subscribe { word: word.clone() },
+            RecordedKind::ResizePool { workers } => Request::ResizePool { workers: *workers },
+            RecordedKind::Replicate {
+                session,
+                seq,
+                entry,
+            } => Request::Replicate {
+                session: *session,
+                seq: *seq,
+                entry: entry.clone(),
+            },
+            RecordedKind::SearchPrefix { prefix } => Request::SearchPrefix {
+                prefix: prefix.clone(),
+            },
+            RecordedKind::SearchSubstring { text } => {
+                Request::SearchSubstring { text: text.clone() }
+            }
+            RecordedKind::TermStats { after, limit } => Request::TermStats {
+                after: after.clone(),
+                limit: *limit,
+            },
+            RecordedKind::Query { expr } => Request::Query { expr: expr.clone() },
+            RecordedKind::PublishBatch {
+                lengths,
+                docs,
+                options,
+                ..
+            } => Request::PublishBatch {
+                docs: docs
+                    .clone()
+                    .unwrap_or_else(|| lengths.iter().map(|length| filler(*length)).collect()),
+                options: options.clone(),
+            },
+            RecordedKind::Update {
+                id, length, doc, ..
+            } => Request::Update {
+                id: *id,
+                doc: doc.clone().unwrap_or_else(|| filler(*length)),
+            },
+            RecordedKind::Search { word } => Request::Search { word: word.clone() },
+            RecordedKind::Retrieve { id, offset, length } => Request::Retrieve {
+                id: *id,
+                offset: *offset,
+                length: *length,
+            },
+            RecordedKind::List { preview_chars } => Request::List {
+                preview_chars: *preview_chars,
+            },
+            RecordedKind::SuggestTerms { prefix, limit } => Request::SuggestTerms {
+                prefix: prefix.clone(),
+                limit: *limit,
+            },
+            RecordedKind::SearchWith { word, options } => Request::SearchWith {
+                word: word.clone(),
+                options: options.clone(),
+            },
+            RecordedKind::SearchRanked { word, k } => Request::SearchRanked {
+                word: word.clone(),
+                k: *k,
+            },
+        }
+    }
+}
+
+// A hash of `doc`, recorded in place of a document's payload.
+fn hash(doc: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    doc.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+// Filler text of exactly `length` bytes, standing in for a document whose payload wasn't recorded.
+fn filler(length: usize) -> String {
+    "lorem ipsum dolor sit amet "
+        .chars()
+        .cycle()
+        .take(length)
+        .collect()
+}
+
+/// An append-only JSONL log of the requests a server handles
+pub struct RequestLog {
+    path: String,
+    writer: Mutex<BufWriter<File>>,
+    include_payloads: bool,
+}
+
+impl RequestLog {
+    // Open the log at `path` for appending, creating it if needed. If `include_payloads` is set,
+    // the full text of published documents is recorded so replays are exact.
+    pub fn open(path: &str, include_payloads: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_string(),
+            writer: Mutex::new(BufWriter::new(file)),
+            include_payloads,
+        })
+    }
+
+    // Close the log and open it again by its path, so that after it has been rotated, new lines
+    // go to a fresh file rather than the rotated one.
+    pub fn reopen(&self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut writer = sync::lock(&self.writer);
+        writer.flush()?;
+        *writer = BufWriter::new(file);
+        Ok(())
+    }
+
+    // Append `request` to the log. Each line is flushed immediately so the log stays complete if
+    // the server is killed.
+    pub fn record(&self, request: &Request) -> io::Result<()> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let entry = RecordedRequest {
+            timestamp_ms,
+            request: RecordedKind::new(request, self.include_payloads),
+        };
+        let line = serde_json::to_string(&entry)?;
+        let mut writer = sync::lock(&self.writer);
+        writeln!(writer, "{}", line)?;
+        writer.flush()
+    }
+}
+
+/// The outcome of replaying a request log
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReplaySummary {
+    /// Requests that got a response
+    pub sent: usize,
+    /// Requests that got no response, or a `Failure`
+    pub failed: usize,
+    /// How long the replay took
+    pub elapsed: Duration,
+}
+
+// Read every request in the log at `path` and send it with `client`, one at a time. If `timed` is
+// set, the original gaps between requests are reproduced; otherwise they are sent back to back.
+pub fn replay(path: &str, client: &Client, timed: bool) -> io::Result<ReplaySummary> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut summary = ReplaySummary::default();
+    let start = std::time::Instant::now();
+    let mut previous_timestamp = None;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: RecordedRequest = serde_json::from_str(&line)?;
+        if let (true, Some(previous)) = (timed, previous_timestamp) {
+            thread::sleep(Duration::from_millis(
+                entry.timestamp_ms.saturating_sub(previous),
+            ));
+        }
+        previous_timestamp = Some(entry.timestamp_ms);
+        match client.send(&entry.request.to_request()) {
+            Some(Response::Failure { .. }) | None => summary.failed += 1,
+            Some(_) => summary.sent += 1,
+        }
+    }
+    summary.elapsed = start.elapsed();
+    Ok(summary)
+}
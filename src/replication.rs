@@ -0,0 +1,230 @@
+use crate::client::{Client, ClientPool};
+use crate::message::{ErrorCode, Request, Response};
+use crate::sync;
+use crate::wal::WalEntry;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+// A primary server sends every change made to its archive to each of its followers, which make
+// the same changes to theirs and serve searches and retrieves from them. Changes are sent as
+// `Replicate` requests holding the change as a write-ahead log entry, in the order the primary
+// made them, so a follower gives every document the same id. Changes to collections aren't sent.
+//
+// Each change is numbered within a session, which starts whenever the primary does. A follower
+// remembers the last change it made, so that one sent again, e.g. because its acknowledgement was
+// lost, is only made once, and one arriving after a gap is refused. A follower must start out
+// holding what the primary did when the session started: empty, or from a copy of its data
+// directory. It should also index documents the same way, e.g. with the same analyzer and
+// duplicates policy.
+
+/// How long to wait before sending a change to a follower again after failing to
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How long to wait for a follower to connect or answer
+const FOLLOWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A change to send to a follower: its number in the session, and the change as JSON
+type Change = (u64, Arc<String>);
+
+/// Sends the changes made to a primary's archive to its followers
+pub struct Replicator {
+    /// The number of the last change sent. Held while a change is made, so changes are numbered
+    /// in the order they are made
+    last_seq: Mutex<u64>,
+    /// Takes the changes to send to each follower, with their numbers. Emptied once the primary
+    /// stops, which closes the connections to the followers once every change has been sent
+    followers: Mutex<Vec<mpsc::Sender<Change>>>,
+    /// How many changes are waiting to be sent, over every follower
+    pending: Arc<AtomicUsize>,
+    /// Set once the primary stops, so that changes that can't be sent aren't retried forever
+    stopped: Arc<AtomicBool>,
+}
+
+impl Replicator {
+    // Start sending changes to the followers at `followers`, each on a thread of its own,
+    // authenticating with `token` if given.
+    pub fn new(followers: &[SocketAddr], token: Option<&str>) -> Self {
+        // Identifies this run of the primary to its followers
+        let session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let pending = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let followers = followers
+            .iter()
+            .map(|&address| {
+                let client = Client::builder(&address.ip().to_string(), address.port())
+                    .with_connect_timeout(FOLLOWER_TIMEOUT)
+                    .with_read_timeout(FOLLOWER_TIMEOUT)
+                    .build();
+                let client = match token {
+                    Some(token) => client.with_token(token),
+                    None => client,
+                };
+                let (sender, receiver) = mpsc::channel();
+                let (pending, stopped) = (Arc::clone(&pending), Arc::clone(&stopped));
+                thread::spawn(move || {
+                    send_changes(
+                        address,
+                        ClientPool::new(client, 1),
+                        session,
+                        receiver,
+                        pending,
+                        stopped,
+                    )
+                });
+                sender
+            })
+            .collect();
+        Self {
+            last_seq: Mutex::new(0),
+            followers: Mutex::new(followers),
+            pending,
+            stopped,
+        }
+    }
+
+    // Make the change `entry` describes with `apply`, and if it succeeds, send it to every
+    // follower. Changes are made one at a time, so that followers make them in the same order.
+    pub fn replicate<F: FnOnce(WalEntry) -> Response>(
+        &self,
+        entry: WalEntry,
+        apply: F,
+    ) -> Response {
+        let encoded = match serde_json::to_string(&entry) {
+            Ok(encoded) => Arc::new(encoded),
+            Err(e) => {
+                error!(error = %e, "failed to encode a change for followers");
+                return Response::failure(ErrorCode::Internal, "failed to encode the change");
+            }
+        };
+        let mut last_seq = sync::lock(&self.last_seq);
+        let response = apply(entry);
+        if let Response::Failure { .. } = response {
+            return response;
+        }
+        *last_seq += 1;
+        for follower in sync::lock(&self.followers).iter() {
+            self.pending.fetch_add(1, Ordering::Relaxed);
+            if follower.send((*last_seq, Arc::clone(&encoded))).is_err() {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        response
+    }
+
+    /// How many changes are waiting to be sent, over every follower
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    // Stop sending changes. Those already made are still sent if they can be at once, but aren't
+    // retried.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        sync::lock(&self.followers).clear();
+    }
+}
+
+// Send each change `changes` receives to the follower at `address` through `pool`, in order,
+// until the primary stops. A change that can't be sent, or that the follower is too busy to make,
+// is sent again until it is made. A follower that refuses a change, e.g. because it has fallen out
+// of step, is given up on, as every later change would be refused too.
+fn send_changes(
+    address: SocketAddr,
+    pool: ClientPool,
+    session: u64,
+    changes: mpsc::Receiver<Change>,
+    pending: Arc<AtomicUsize>,
+    stopped: Arc<AtomicBool>,
+) {
+    info!(%address, "replicating to follower");
+    for (seq, entry) in &changes {
+        let request = Request::Replicate {
+            session,
+            seq,
+            entry: entry.to_string(),
+        };
+        loop {
+            match pool.send(&request) {
+                Some(Response::Replicated(_)) => break,
+                Some(Response::Failure { code, message }) if !is_transient(code) => {
+                    error!(%address, seq, ?code, message, "follower refused a change; no longer replicating to it");
+                    pending.fetch_sub(changes.try_iter().count() + 1, Ordering::Relaxed);
+                    return;
+                }
+                response => {
+                    if stopped.load(Ordering::SeqCst) {
+                        warn!(%address, "primary stopped before the follower took every change");
+                        return;
+                    }
+                    warn!(%address, seq, ?response, "failed to replicate change; retrying");
+                    thread::sleep(RETRY_BACKOFF);
+                }
+            }
+        }
+        pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Whether a follower that refused a change with `code` may make it if sent it again.
+fn is_transient(code: ErrorCode) -> bool {
+    matches!(
+        code,
+        ErrorCode::TimedOut | ErrorCode::Overloaded | ErrorCode::Internal | ErrorCode::RateLimited
+    )
+}
+
+/// Where a follower has got to in the changes its primary sent
+#[derive(Debug, Default)]
+pub struct FollowerPosition {
+    /// The primary's session, once a change has been made
+    session: Option<u64>,
+    /// The number of the last change made in that session
+    seq: u64,
+}
+
+impl FollowerPosition {
+    // Make the change numbered `seq` in the primary's `session`, described by `entry`, with
+    // `apply`, unless it has been made already. A session's first change starts it. Return the
+    // response to send the primary.
+    pub fn follow<F: FnOnce(WalEntry) -> Response>(
+        &mut self,
+        session: u64,
+        seq: u64,
+        entry: &str,
+        apply: F,
+    ) -> Response {
+        let expected = match self.session {
+            Some(current) if current == session && seq <= self.seq => {
+                return Response::Replicated(seq)
+            }
+            Some(current) if current == session => self.seq + 1,
+            _ => 1,
+        };
+        if seq != expected {
+            return Response::failure(
+                ErrorCode::OutOfRange,
+                format!("expected change {} of the session, not {}", expected, seq),
+            );
+        }
+        let entry = match serde_json::from_str(entry) {
+            Ok(entry) => entry,
+            Err(e) => {
+                return Response::failure(ErrorCode::Malformed, format!("invalid change: {}", e))
+            }
+        };
+        let response = apply(entry);
+        if let Response::Failure { .. } = response {
+            return response;
+        }
+        self.session = Some(session);
+        self.seq = seq;
+        Response::Replicated(seq)
+    }
+}
@@ -1,42 +1,1110 @@
-use crate::database::Database;
+use crate::analyzer::Analyzer;
+use crate::audit::{AuditEntry, AuditLog};
+use crate::auth::{self, ApiKeys, Role};
+use crate::cache::LruCache;
+use crate::database::{self, Busy, Database, Duplicates, PublishOptions, BUCKETS};
+#[cfg(feature = "fault-injection")]
+use crate::faults::{self, Fault, FaultConfig, FaultInjector};
+use crate::feed::{Feed, Notification};
+#[cfg(feature = "http")]
+use crate::http;
 use crate::message::*;
+use crate::metrics::{self, Metrics};
 use crate::pool::ThreadPool;
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
+use crate::query::Query;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::record::RequestLog;
+use crate::replication::{FollowerPosition, Replicator};
+use crate::shard::Shards;
+use crate::snapshot::{self, SnapshotPolicy};
+use crate::sync;
+use crate::wal::{WalEntry, WriteAheadLog};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    mpsc, Arc, Mutex, RwLock,
 };
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn, Span};
 
-/// The number of workers in the server's thread pool
-const WORKERS: usize = 16;
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncServer;
+
+/// The number of workers in the server's thread pool unless told otherwise
+pub const WORKERS: usize = 16;
+
+/// The most workers an admin may resize the server's thread pool to unless told otherwise
+pub const MAX_WORKERS: usize = 256;
+
+/// How long a retrieve waits for the blob store before answering `Busy`
+const RETRIEVE_DEADLINE: Duration = Duration::from_millis(250);
+
+/// How often a subscriber's connection checks whether the server has stopped
+const NOTIFICATION_POLL: Duration = Duration::from_millis(500);
+
+/// The most words of context a search snippet may have; searches asking for more get this many
+const MAX_SNIPPET_WORDS: usize = 1000;
+
+/// How often a kept-alive connection waiting for its next request checks whether to give up
+const KEEP_ALIVE_POLL: Duration = Duration::from_millis(100);
+
+/// The longest a kept-alive connection may wait for its next request if reads have no timeout
+const KEEP_ALIVE_IDLE: Duration = Duration::from_secs(60);
+
+/// The address listeners bind to unless told otherwise
+pub const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
 
 // Implement the `process_message` function. This function should take a `ServerState`, a `Request`,
 // and a `TcpStream`. It should process the request and write the response to the stream.
 // Processing the request should simply require calling the appropriate function on the database
 // and then creating the appropriate response and turning it into bytes which are sent to along
 // the stream by calling the `write_all` method.
-fn process_message(state: Arc<ServerState>, request: Request, mut stream: TcpStream) {
-    let response = match request {
-        Request::Publish { doc } => {
-            let index = state.database.publish(doc);
-            Response::PublishSuccess(index)
+//
+// The work is split into `respond`, which answers the request, and `encode_response`, so that the
+// async server can share them. Returns whether the response was sent.
+fn process_message<S: Write>(
+    state: Arc<ServerState>,
+    request: Request,
+    header: &RequestHeader,
+    context: &RequestContext,
+    mut stream: S,
+) -> bool {
+    let kind = request.kind();
+    let start = Instant::now();
+    let response = answer(&state, request, header, context);
+    log_answered(kind, &response, start.elapsed());
+    let Some(encoded) = encode_response(&state, response, header) else {
+        // Dropping the stream closes the connection without a response
+        warn!(kind, "dropped the connection instead of responding");
+        return false;
+    };
+    // Count the request before the client can see the response, so a client that asks for
+    // metrics next always sees its earlier requests counted
+    state.metrics.record(kind, start.elapsed());
+    if let Err(e) = encoded.write_to(&mut stream) {
+        warn!(error = %e, "failed to send response");
+        return false;
+    }
+    true
+}
+
+// Log that a `kind` request was answered with `response`, `latency` after it was read.
+fn log_answered(kind: &str, response: &Response, latency: Duration) {
+    info!(
+        kind,
+        outcome = %outcome(response),
+        latency_us = latency.as_micros() as u64,
+        "answered request"
+    );
+}
+
+// A short description of how a request turned out, for logs: "ok", or why it didn't succeed.
+fn outcome(response: &Response) -> String {
+    match response {
+        Response::Failure { code, .. } => code.to_string(),
+        Response::Busy => "busy".to_string(),
+        Response::TooLarge => "too_large".to_string(),
+        Response::ResponseTooLarge(_) => "response_too_large".to_string(),
+        Response::Truncated(_) => "truncated".to_string(),
+        Response::UnsupportedVersion { .. } => "unsupported_version".to_string(),
+        _ => "ok".to_string(),
+    }
+}
+
+// Stop the server on SIGINT or SIGTERM, letting it finish the requests it has accepted, and reload
+// it on SIGHUP, until the returned handle is closed.
+#[cfg(unix)]
+fn handle_signals(state: Arc<ServerState>) -> signal_hook::iterator::Handle {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM, SIGHUP])
+        .expect("Error setting signal handlers");
+    let handle = signals.handle();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => {
+                    info!("reloading server");
+                    state.reload();
+                }
+                _ => {
+                    info!(signal, "stopping server");
+                    state.is_stopped.store(true, Ordering::SeqCst);
+                }
+            }
         }
-        Request::Search { word } => {
-            let indices = state.database.search(&word);
-            Response::SearchSuccess(indices)
+    });
+    handle
+}
+
+// Other platforms only have Ctrl-C, which stops the server.
+#[cfg(not(unix))]
+fn handle_signals(state: Arc<ServerState>) -> CtrlCHandler {
+    match ctrlc::try_set_handler(move || {
+        info!("stopping server");
+        state.is_stopped.store(true, Ordering::SeqCst);
+    }) {
+        Ok(_) => {}
+        Err(ctrlc::Error::MultipleHandlers) => {}
+        Err(e) => {
+            panic!("Error setting Ctrl-C handler: {}", e);
         }
-        Request::Retrieve { id } => {
-            match state.database.retrieve(id) {
-                Some(doc) => Response::RetrieveSuccess(doc),
-                None => Response::Failure, // Document ID not found
+    }
+    CtrlCHandler
+}
+
+// The Ctrl-C handler stays set for the life of the process, so there is nothing to close.
+#[cfg(not(unix))]
+struct CtrlCHandler;
+#[cfg(not(unix))]
+impl CtrlCHandler {
+    fn close(&self) {}
+}
+
+// Answer `request` as the client asked in `header`: with the metadata of the documents in the
+// response, if it asked for that, and cut down to the size it can accept.
+fn answer(
+    state: &ServerState,
+    request: Request,
+    header: &RequestHeader,
+    context: &RequestContext,
+) -> Response {
+    // The request is answered as whoever its API key belongs to
+    let context = &match authenticate(state, header) {
+        Some((identity, role)) => {
+            Span::current().record("identity", identity.as_str());
+            RequestContext {
+                identity: Some(identity),
+                role: Some(role),
+                ..context.clone()
             }
         }
+        None => context.clone(),
+    };
+    // ...from the collection it names, or the client's own if the server isolates tenants
+    let (collection, refusal) = match state.collection_for(header, context) {
+        Ok(collection) => (collection, None),
+        Err(refusal) => (None, Some(refusal)),
     };
-    let bytes = response.to_bytes();
-    if let Err(e) = stream.write_all(&bytes) {
-        eprintln!("Failed to send response: {}", e);
+    let context = &RequestContext {
+        collection,
+        ..context.clone()
+    };
+    let audit = match &state.audit_log {
+        Some(log) if request.is_mutating() => Some((log, AuditEntry::new(&request, context))),
+        _ => None,
+    };
+    // Retrieve responses don't say which document they hold
+    let retrieved = match request {
+        Request::Retrieve { id, .. } => Some(id),
+        _ => None,
+    };
+    // A request that makes the server panic is answered with a failure rather than dropped, and
+    // the worker lives on to answer the next one
+    sync::take_recovered();
+    let answered = panic::catch_unwind(AssertUnwindSafe(|| {
+        if let Some(refusal) = refusal {
+            return refusal;
+        }
+        let collection = match &context.collection {
+            Some(name) if state.is_tenant(context) => Some(state.tenant_collection(name)),
+            Some(name) => match state.collection(name) {
+                Some(collection) => Some(collection),
+                None => {
+                    return Response::failure(
+                        ErrorCode::NotFound,
+                        format!("no collection named {}", name),
+                    )
+                }
+            },
+            None => None,
+        };
+        let response = respond(state, collection.as_deref(), request, context);
+        match header.include_metadata {
+            true => {
+                let database = collection.as_deref().unwrap_or(&state.database);
+                attach_metadata(database, retrieved, response)
+            }
+            false => response,
+        }
+    }));
+    let response = answered.unwrap_or_else(|_| {
+        error!("panicked while answering request");
+        Response::failure(
+            ErrorCode::Internal,
+            "the server failed while handling the request",
+        )
+    });
+    // ...as is one that ran into data left behind by such a panic, which it may or may not have
+    // changed
+    let response = match sync::take_recovered() {
+        true => Response::failure(
+            ErrorCode::Internal,
+            "the server recovered from an earlier failure while handling the request",
+        ),
+        false => response,
+    };
+    if let Some((log, entry)) = audit {
+        if let Err(e) = log.record(&entry.answered(&response, outcome(&response))) {
+            error!(error = %e, "failed to write to the audit log");
+        }
+    }
+    response.fit(header)
+}
+
+// The name of the client the API key in `header` belongs to and its role, if the server has API
+// keys and the key is one of them, or if the server is a follower and it is its primary's key.
+fn authenticate(state: &ServerState, header: &RequestHeader) -> Option<(String, Role)> {
+    let token = header.token.as_deref()?;
+    if let Some((_, primary_key)) = &state.follower {
+        if auth::constant_time_eq(primary_key.as_bytes(), token.as_bytes()) {
+            return Some(("primary".to_string(), Role::Replicator));
+        }
+    }
+    let keys = sync::read(&state.api_keys);
+    let (name, role) = keys.as_ref()?.identify(token)?;
+    Some((name.to_string(), role))
+}
+
+// Refuse `request` if the client's role doesn't allow it: with `Unauthorized` if the client has no
+// role, and `Forbidden` if its role is too low. Admin requests always need an admin key, so a
+// server without API keys refuses them, and read-only listeners refuse them outright. Changes
+// replicated to a follower need its primary's key. Otherwise, a server without API keys allows
+// anything, and anyone may make requests that only read the archive.
+fn authorize(
+    state: &ServerState,
+    request: &Request,
+    context: &RequestContext,
+) -> Result<(), Response> {
+    if context.read_only && request.is_admin() {
+        return Err(Response::failure(
+            ErrorCode::ReadOnly,
+            "this listener doesn't accept admin requests",
+        ));
+    }
+    let needed = match request {
+        Request::Replicate { .. } => Role::Replicator,
+        request if request.is_admin() => Role::Admin,
+        _ if sync::read(&state.api_keys).is_none() => return Ok(()),
+        request if request.is_mutating() => Role::Publisher,
+        _ => return Ok(()),
+    };
+    match context.role {
+        Some(role) if role.allows(needed) => Ok(()),
+        Some(role) => Err(Response::failure(
+            ErrorCode::Forbidden,
+            format!(
+                "a {} key can't make this request; it needs {}",
+                role, needed
+            ),
+        )),
+        None => Err(Response::failure(
+            ErrorCode::Unauthorized,
+            format!("this request needs a valid {} key", needed),
+        )),
+    }
+}
+
+// Answer a request for the `length` bytes of `doc` from `offset` on, or all of them from `offset`
+// on if `length` is None. The range is cut short at the end of the document, and to end on a whole
+// character, but an offset past the end or inside a character is refused.
+fn byte_range(doc: String, offset: usize, length: Option<usize>) -> Response {
+    if offset > doc.len() {
+        return Response::failure(
+            ErrorCode::OutOfRange,
+            format!(
+                "offset {} is past the end of the document, which is {} bytes long",
+                offset,
+                doc.len()
+            ),
+        );
+    }
+    if !doc.is_char_boundary(offset) {
+        return Response::failure(
+            ErrorCode::OutOfRange,
+            format!("offset {} is inside a character", offset),
+        );
+    }
+    let mut end = length.map_or(doc.len(), |length| {
+        offset.saturating_add(length).min(doc.len())
+    });
+    while !doc.is_char_boundary(end) {
+        end -= 1;
+    }
+    match (offset, end) {
+        (0, end) if end == doc.len() => Response::RetrieveSuccess(doc),
+        _ => Response::RetrieveSuccess(doc[offset..end].to_string()),
+    }
+}
+
+// Wrap `response` in `WithMetadata` with the metadata of every document it lists in `database`,
+// or of `retrieved` for a retrieve response. Other responses are returned as they are.
+fn attach_metadata(database: &Database, retrieved: Option<usize>, response: Response) -> Response {
+    // The metadata goes with the page it describes
+    if let Response::Paged {
+        response,
+        total_hits,
+    } = response
+    {
+        return Response::Paged {
+            response: Box::new(attach_metadata(database, retrieved, *response)),
+            total_hits,
+        };
+    }
+    let ids: Vec<usize> = match &response {
+        Response::SearchSuccess(ids) => ids.clone(),
+        Response::SearchRankedSuccess(results) => results.iter().map(|(id, _)| *id).collect(),
+        Response::SearchSnippetsSuccess(results) => results.iter().map(|(id, _)| *id).collect(),
+        Response::ListSuccess(summaries) => summaries.iter().map(|summary| summary.id).collect(),
+        Response::RetrieveSuccess(_) => retrieved.into_iter().collect(),
+        _ => return response,
+    };
+    Response::WithMetadata {
+        metadata: database.metadata_for(&ids),
+        response: Box::new(response),
+    }
+}
+
+// Answer `request` using `collection`, if the request named one, or else the server's default
+// database. Requests that would modify the archive are refused with a failure response if they
+// arrive on a read-only listener, or at a follower from anyone but its primary.
+fn respond(
+    state: &ServerState,
+    collection: Option<&Database>,
+    request: Request,
+    context: &RequestContext,
+) -> Response {
+    if let Err(refusal) = authorize(state, &request, context) {
+        return refusal;
+    }
+    if let (Some(shards), None) = (&state.shards, collection) {
+        if Shards::forwards(&request) && !(context.read_only && request.is_mutating()) {
+            return shards.respond(request);
+        }
+    }
+    let database = collection.unwrap_or(&state.database);
+    match request {
+        Request::Replicate {
+            session,
+            seq,
+            entry,
+        } => match &state.follower {
+            Some((position, _)) => {
+                sync::lock(position).follow(session, seq, &entry, |entry| write(state, None, entry))
+            }
+            None => Response::failure(ErrorCode::Forbidden, "this server isn't a follower"),
+        },
+        _ if context.read_only && request.is_mutating() => Response::failure(
+            ErrorCode::ReadOnly,
+            "this listener doesn't accept requests that modify the archive",
+        ),
+        _ if state.follower.is_some() && request.is_mutating() => Response::failure(
+            ErrorCode::ReadOnly,
+            "this server is a follower; send changes to its primary",
+        ),
+        Request::Publish { .. }
+        | Request::PublishWith { .. }
+        | Request::PublishBatch { .. }
+        | Request::Update { .. }
+        | Request::Import { .. }
+            if state.is_over_memory_limit() =>
+        {
+            Response::failure(
+                ErrorCode::MemoryLimit,
+                "the archive is using all the memory it is allowed",
+            )
+        }
+        Request::Publish { doc } => write(
+            state,
+            collection,
+            WalEntry::publish(doc, PublishOptions::default()),
+        ),
+        Request::PublishWith { doc, options } => {
+            write(state, collection, WalEntry::publish(doc, options))
+        }
+        Request::Commit { id } => write(state, collection, WalEntry::Commit { id }),
+        Request::PublishBatch { docs, options } => {
+            write(state, collection, WalEntry::publish_batch(docs, options))
+        }
+        Request::Update { id, doc } => write(state, collection, WalEntry::Update { id, doc }),
+        Request::SearchPrefix { prefix } => {
+            Response::SearchSuccess(database.search_prefix(&prefix))
+        }
+        Request::SearchSubstring { text } => {
+            Response::SearchSuccess(database.search_substring(&text))
+        }
+        Request::Query { expr } => match expr.parse::<Query>() {
+            Ok(query) => Response::SearchSuccess(database.search_query(&query)),
+            Err(e) => Response::failure(ErrorCode::Malformed, e),
+        },
+        Request::TermStats { after, limit } => {
+            Response::TermStatsSuccess(database.term_stats(after.as_deref(), limit))
+        }
+        Request::Search { word } => match collection {
+            Some(collection) => Response::SearchSuccess(collection.search(&word)),
+            None => Response::SearchSuccess(state.search(&word)),
+        },
+        Request::Count { word } => Response::CountSuccess(database.count(&word)),
+        Request::Retrieve { id, offset, length } => {
+            match database.try_retrieve(id, RETRIEVE_DEADLINE) {
+                Ok(Some(doc)) => byte_range(doc, offset, length),
+                Ok(None) => Response::not_found(id),
+                Err(Busy) => Response::Busy, // A long publish is holding the blob store
+            }
+        }
+        Request::List { preview_chars } => Response::ListSuccess(database.list(preview_chars)),
+        Request::SearchWith { word, options } => {
+            let (ids, total_hits) = database.search_page(&word, &options);
+            let response = match options.snippet_words {
+                Some(context_words) => Response::SearchSnippetsSuccess(database.snippets(
+                    &ids,
+                    &word,
+                    context_words.min(MAX_SNIPPET_WORDS),
+                )),
+                None => Response::SearchSuccess(ids),
+            };
+            match options.is_paged() {
+                true => Response::Paged {
+                    response: Box::new(response),
+                    total_hits,
+                },
+                false => response,
+            }
+        }
+        Request::SearchRanked { word, k } => {
+            Response::SearchRankedSuccess(database.search_ranked(&word, k))
+        }
+        Request::SuggestTerms { prefix, limit } => {
+            Response::SuggestSuccess(database.suggest(&prefix, limit))
+        }
+        Request::Stats => Response::StatsSuccess(state.render_metrics()),
+        Request::Ping => Response::Pong {
+            uptime_secs: state.started_at.elapsed().as_secs(),
+            documents: database.document_count(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+        Request::Shutdown => {
+            info!("stopping server at a client's request");
+            state.is_stopped.store(true, Ordering::SeqCst);
+            Response::ShuttingDown
+        }
+        Request::Snapshot => match &state.snapshots {
+            Some(snapshots) => match state.take_snapshot(snapshots) {
+                Ok(()) => Response::SnapshotSuccess(state.database.document_count()),
+                Err(e) => {
+                    error!(error = %e, "failed to save snapshot");
+                    Response::failure(ErrorCode::Internal, "failed to save the snapshot")
+                }
+            },
+            None => Response::failure(ErrorCode::NotFound, "the server has no snapshot file"),
+        },
+        Request::Export { path } => match data_file(state, &path) {
+            Ok(path) => export(database, &path),
+            Err(refusal) => refusal,
+        },
+        Request::Import { path } => match data_file(state, &path) {
+            Ok(path) => import(state, collection, &path),
+            Err(refusal) => refusal,
+        },
+        Request::CreateCollection { name } => state.create_collection(name),
+        Request::ResizePool { workers: 0 } => {
+            Response::failure(ErrorCode::Malformed, "a pool needs at least one worker")
+        }
+        Request::ResizePool { workers } if workers > state.max_workers => Response::failure(
+            ErrorCode::OutOfRange,
+            format!("the pool can have at most {} workers", state.max_workers),
+        ),
+        Request::ResizePool { workers } => match sync::lock(&state.pool).as_mut() {
+            Some(pool) => {
+                info!(from = pool.size(), to = workers, "resizing the thread pool");
+                match pool.resize(workers) {
+                    Ok(()) => Response::PoolResized(workers),
+                    Err(e) => {
+                        error!(error = %e, workers = pool.size(), "failed to grow the thread pool");
+                        Response::failure(
+                            ErrorCode::Internal,
+                            format!("only {} workers could be started: {}", pool.size(), e),
+                        )
+                    }
+                }
+            }
+            None => Response::failure(ErrorCode::Unsupported, "this server has no thread pool"),
+        },
+        // Tenants only know of their own collection
+        Request::ListCollections if state.is_tenant(context) => {
+            Response::CollectionsSuccess(context.collection.iter().cloned().collect())
+        }
+        Request::ListCollections => {
+            Response::CollectionsSuccess(sync::read(&state.collections).keys().cloned().collect())
+        }
+        // The connection's handler subscribes once the client is told it may
+        Request::Subscribe { .. } if collection.is_some() => Response::failure(
+            ErrorCode::Unsupported,
+            "only the default archive can be subscribed to",
+        ),
+        Request::Subscribe { word } => match database.search_term(&word) {
+            Some(term) => Response::Subscribed(term),
+            None => Response::failure(
+                ErrorCode::Malformed,
+                format!("{} is never indexed, so can't be subscribed to", word),
+            ),
+        },
+    }
+}
+
+// The file called `name` in the server's data directory, which is the only place exports and
+// imports may touch. A name that is absolute, has directories in it or climbs out with `..` is
+// refused, as is any name if the server has no data directory.
+fn data_file(state: &ServerState, name: &str) -> Result<PathBuf, Response> {
+    let dir = state.data_dir.as_ref().ok_or_else(|| {
+        Response::failure(
+            ErrorCode::Unsupported,
+            "this server has no data directory to export to or import from",
+        )
+    })?;
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(file)), None) => Ok(dir.join(file)),
+        _ => Err(Response::failure(
+            ErrorCode::Malformed,
+            format!("{} isn't the name of a file in the data directory", name),
+        )),
+    }
+}
+
+// Write every document in `database` to a dump at `path`.
+fn export(database: &Database, path: &Path) -> Response {
+    let exported = File::create(path).and_then(|file| database.export(BufWriter::new(file)));
+    match exported {
+        Ok(documents) => Response::ExportSuccess(documents),
+        Err(e) => {
+            error!(error = %e, path = %path.display(), "failed to export the archive");
+            Response::failure(
+                ErrorCode::Internal,
+                format!("failed to export the archive to {}: {}", path.display(), e),
+            )
+        }
+    }
+}
+
+// Publish every document in the dump at `path` to `collection`, or the default archive if None,
+// one at a time as if each were published by a client, so that each is logged and searchable as
+// soon as it is imported. Documents keep the ids they were dumped with, so only an empty archive
+// can be imported into. Importing stops at the first line that isn't a dumped document, and at
+// the first document that can't keep its id or that the server refuses, e.g. as a copy, with a
+// failure saying which document it was.
+fn import(state: &ServerState, collection: Option<&Database>, path: &Path) -> Response {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            return Response::failure(
+                ErrorCode::NotFound,
+                format!("failed to open {}: {}", path.display(), e),
+            )
+        }
+    };
+    let database = collection.unwrap_or(&state.database);
+    if database.document_count() > 0 {
+        return Response::failure(
+            ErrorCode::Unsupported,
+            "dumps can only be imported into an empty archive, so documents keep their ids",
+        );
+    }
+    let mut imported = 0;
+    for dumped in database::read_dump(BufReader::new(file)) {
+        let dumped = match dumped {
+            Ok(dumped) => dumped,
+            Err(e) => {
+                return Response::failure(
+                    ErrorCode::Malformed,
+                    format!(
+                        "{} isn't a valid dump, after {} documents: {}",
+                        path.display(),
+                        imported,
+                        e
+                    ),
+                )
+            }
+        };
+        let id = dumped.id;
+        if database.document_count() != id {
+            return Response::failure(
+                ErrorCode::OutOfRange,
+                format!(
+                    "document {} of {} can't keep its id, after {} documents",
+                    id,
+                    path.display(),
+                    imported
+                ),
+            );
+        }
+        match write(state, collection, dumped.into()) {
+            Response::PublishSuccess(published) if published == id => imported += 1,
+            Response::Failure { code, message } => {
+                return Response::failure(
+                    code,
+                    format!(
+                        "document {} of {} was refused, after {} documents: {}",
+                        id,
+                        path.display(),
+                        imported,
+                        message
+                    ),
+                )
+            }
+            _ => {
+                return Response::failure(
+                    ErrorCode::OutOfRange,
+                    format!(
+                        "document {} of {} didn't keep its id, after {} documents",
+                        id,
+                        path.display(),
+                        imported
+                    ),
+                )
+            }
+        }
+    }
+    info!(path = %path.display(), documents = imported, "imported a dump");
+    Response::ImportSuccess(imported)
+}
+
+// Make the change to the archive that `entry` describes, logging it first if the server keeps a
+// write-ahead log. A change to a collection is made to it directly, as collections are only kept
+// in memory.
+fn write(state: &ServerState, collection: Option<&Database>, entry: WalEntry) -> Response {
+    if let Some(collection) = collection {
+        return entry.apply(collection);
+    }
+    let response = apply(state, entry);
+    // Only once the change has been made, so that no search can cache results from before it
+    if let Some(cache) = &state.search_cache {
+        cache.clear();
+    }
+    match &response {
+        Response::PublishSuccess(id)
+        | Response::CommitSuccess(id)
+        | Response::UpdateSuccess(id) => state.feed.notify(&state.database, &[*id]),
+        Response::PublishBatchSuccess(ids) => state.feed.notify(&state.database, ids),
+        _ => {}
+    }
+    response
+}
+
+// Make the change `entry` describes, logging it first if the server has a write-ahead log, and
+// then sending it to the server's followers if it has any.
+fn apply(state: &ServerState, entry: WalEntry) -> Response {
+    match &state.replicator {
+        Some(replicator) => replicator.replicate(entry, |entry| log_and_apply(state, entry)),
+        None => log_and_apply(state, entry),
+    }
+}
+
+// Make the change `entry` describes, logging it first if the server has a write-ahead log.
+fn log_and_apply(state: &ServerState, entry: WalEntry) -> Response {
+    let Some(wal) = &state.wal else {
+        // Counted once applied, so a snapshot that sees the count also sees the change
+        let response = entry.apply(&state.database);
+        state.writes_since_snapshot.fetch_add(1, Ordering::Relaxed);
+        return response;
+    };
+    match wal.append(entry, &state.database) {
+        Ok(response) => {
+            state.writes_since_snapshot.fetch_add(1, Ordering::Relaxed);
+            response
+        }
+        Err(e) => {
+            error!(error = %e, "failed to write to the write-ahead log");
+            Response::failure(ErrorCode::Internal, "failed to log the change")
+        }
+    }
+}
+
+// A response encoded for sending.
+enum Encoded {
+    // The whole response, in one frame
+    Frame(Vec<u8>),
+    // A retrieved document, to stream in chunks as `document_stream` encodes it
+    Document(String),
+}
+
+impl Encoded {
+    // Send the response on `stream`, a frame at a time.
+    fn write_to<S: Write>(&self, stream: &mut S) -> io::Result<()> {
+        match self {
+            Encoded::Frame(bytes) => stream.write_all(bytes)?,
+            Encoded::Document(doc) => {
+                for frame in document_stream(doc) {
+                    stream.write_all(&frame)?;
+                }
+            }
+        }
+        stream.flush()
+    }
+}
+
+// Encode `response` for the client that sent `header`, tagged with the id it gave the request if
+// any: compressed if it asked for that and the response is large, or as a streamed document if it
+// asked for that, the response is one, and the request has no id. Return None if the connection
+// should be dropped instead. Faults are only injected into whole frames.
+#[cfg_attr(not(feature = "fault-injection"), allow(unused_variables))]
+fn encode_response(
+    state: &ServerState,
+    response: Response,
+    header: &RequestHeader,
+) -> Option<Encoded> {
+    let response = match response {
+        Response::RetrieveSuccess(doc)
+            if header.stream_documents && header.request_id.is_none() =>
+        {
+            return Some(Encoded::Document(doc))
+        }
+        response => response.tagged(header),
+    };
+    let bytes = match header.compression {
+        true => response.to_bytes_compressed(),
+        false => response.to_bytes(),
+    };
+    #[cfg(feature = "fault-injection")]
+    let bytes = inject_faults(state, bytes)?;
+    Some(Encoded::Frame(bytes))
+}
+
+// Log and record a request that was read successfully.
+fn record_request(state: &ServerState, request: &Request) {
+    if let Some(log) = &state.request_log {
+        if let Err(e) = log.record(request) {
+            error!(error = %e, "failed to record request");
+        }
+    }
+}
+
+// Answer the client on `stream` with `response` without reading its request, e.g. because the
+// server is too busy to take it, and hang up. Whatever part of the request has already arrived is
+// read first, since closing a connection with unread data resets it, which can lose the response
+// on its way to the client.
+fn reject(mut stream: TcpStream, response: &Response) {
+    let _ = stream.write_all(&response.to_bytes());
+    let _ = stream.shutdown(Shutdown::Write);
+    if stream.set_nonblocking(true).is_ok() {
+        let mut buffer = [0_u8; 4096];
+        while matches!(stream.read(&mut buffer), Ok(n) if n > 0) {}
+    }
+}
+
+// The response to send when a request couldn't be read.
+fn decode_failure(error: DecodeError) -> Response {
+    match error {
+        DecodeError::Malformed | DecodeError::Closed => {
+            warn!("failed to deserialize request or client disconnected");
+            Response::failure(ErrorCode::Malformed, "failed to read request")
+        }
+        DecodeError::TooLarge => {
+            warn!("rejected request over the size limit");
+            Response::TooLarge
+        }
+        DecodeError::TimedOut => {
+            warn!("timed out waiting for request");
+            Response::failure(ErrorCode::TimedOut, "timed out waiting for request")
+        }
+        DecodeError::UnsupportedVersion(version) => {
+            warn!(version, "rejected request in unsupported protocol version");
+            Response::UnsupportedVersion {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            }
+        }
+    }
+}
+
+// Apply whatever faults the server is configured to inject to the encoded response `bytes`,
+// returning None if the connection should be dropped instead of answered.
+#[cfg(feature = "fault-injection")]
+fn inject_faults(state: &ServerState, mut bytes: Vec<u8>) -> Option<Vec<u8>> {
+    if let Some(injector) = &state.faults {
+        for fault in injector.roll() {
+            match fault {
+                Fault::Delay(delay) => thread::sleep(delay),
+                Fault::Drop => return None,
+                Fault::Corrupt => faults::corrupt(&mut bytes),
+            }
+        }
+    }
+    Some(bytes)
+}
+
+// Deserialize a request from `stream` and process it, replying with a failure response if the
+// request could not be read. A client that asks for the connection to be kept alive may send
+// further requests on it, which are answered the same way until it hangs up, or the connection is
+// closed while it waits for the next one, as `next_request_byte` describes.
+fn handle_connection<S: Connection>(
+    state: Arc<ServerState>,
+    mut stream: S,
+    context: RequestContext,
+) {
+    let _connection = state.metrics.connection();
+    let mut first = true;
+    loop {
+        // Only the first byte of each request is waited for in steps
+        let read = match next_request_byte(&state, &mut stream, !first) {
+            Ok(byte) => {
+                Request::read_with_header(&mut [byte].as_slice().chain(&mut stream), &state.limits)
+            }
+            Err(e) => Err(e),
+        };
+        let (request, header) = match read {
+            Ok(read) => read,
+            Err(DecodeError::Closed) => return,
+            Err(e) => return send_failure(&mut stream, &decode_failure(e)),
+        };
+        // A kept-alive connection was only admitted for its first request
+        if !first {
+            if let Err(response) = state.check_rate(&context) {
+                return send_failure(&mut stream, &response);
+            }
+        }
+        first = false;
+        record_request(&state, &request);
+        if let Request::Subscribe { .. } = request {
+            return subscribe(&state, request, &header, &context, stream);
+        }
+        let answered = process_message(Arc::clone(&state), request, &header, &context, &mut stream);
+        if !answered || !header.keep_alive || state.is_stopped.load(Ordering::SeqCst) {
+            return;
+        }
+    }
+}
+
+// Wait for the first byte of the client's next request on `stream`, checking every
+// `KEEP_ALIVE_POLL` whether the server has stopped, so that an idle client never keeps it from
+// stopping. The first request on a connection times out after the read timeout, if there is one.
+// A kept-alive client's wait is given up, as if it had closed the connection, once it sits idle for
+// longer than the read timeout (or `KEEP_ALIVE_IDLE` without one), or if other connections are
+// waiting for a worker, so that an idle client never keeps them from being answered. The client
+// may reconnect to join them.
+fn next_request_byte<S: Connection>(
+    state: &ServerState,
+    stream: &mut S,
+    kept_alive: bool,
+) -> Result<u8, DecodeError> {
+    let idle_limit = match kept_alive {
+        true => Some(state.timeouts.read.unwrap_or(KEEP_ALIVE_IDLE)),
+        false => state.timeouts.read,
+    };
+    let start = Instant::now();
+    stream
+        .set_read_timeout(Some(KEEP_ALIVE_POLL))
+        .map_err(|_| DecodeError::Closed)?;
+    let mut byte = [0];
+    let read = loop {
+        match stream.read(&mut byte) {
+            Ok(0) => break Err(DecodeError::Closed),
+            Ok(_) => break Ok(byte[0]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                let idle = idle_limit.is_some_and(|limit| start.elapsed() >= limit);
+                if state.is_stopped.load(Ordering::SeqCst)
+                    || (kept_alive && (idle || state.has_waiting_connections()))
+                {
+                    break Err(DecodeError::Closed);
+                }
+                if idle {
+                    break Err(DecodeError::TimedOut);
+                }
+            }
+            Err(_) => break Err(DecodeError::Closed),
+        }
+    };
+    // The rest of the request is read with the configured timeout
+    stream
+        .set_read_timeout(state.timeouts.read)
+        .map_err(|_| DecodeError::Closed)?;
+    read
+}
+
+/// A connection the server reads requests from, whose read timeout can be shortened while it waits
+/// for a kept-alive client's next request
+trait Connection: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Connection for rustls::StreamOwned<rustls::ServerConnection, TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+impl<C: Connection + ?Sized> Connection for &mut C {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+// Answer the `Subscribe` request `request`, and if the client is subscribed, send it a notification
+// on `stream` for each document it subscribed to, until it hangs up or the server stops.
+fn subscribe<S: Write>(
+    state: &ServerState,
+    request: Request,
+    header: &RequestHeader,
+    context: &RequestContext,
+    mut stream: S,
+) {
+    let start = Instant::now();
+    let response = answer(state, request, header, context);
+    log_answered("subscribe", &response, start.elapsed());
+    state.metrics.record("subscribe", start.elapsed());
+    // Subscribe before telling the client, so it misses nothing published after it is told
+    let notifications = match &response {
+        Response::Subscribed(term) => Some(state.feed.subscribe(term.clone())),
+        _ => None,
+    };
+    let mut response = Some(response);
+    while let Some(next) = response.take() {
+        let Some(encoded) = encode_response(state, next, header) else {
+            warn!("dropped a subscriber instead of responding");
+            return;
+        };
+        if let Err(e) = encoded.write_to(&mut stream) {
+            info!(error = %e, "subscriber hung up");
+            return;
+        }
+        let Some(notifications) = &notifications else {
+            return;
+        };
+        response = next_notification(state, notifications)
+            .map(|(id, preview)| Response::Notification { id, preview });
+    }
+}
+
+// Wait for the next notification to send a subscriber from `notifications`, or return None once
+// the server stops.
+fn next_notification(
+    state: &ServerState,
+    notifications: &mpsc::Receiver<Notification>,
+) -> Option<Notification> {
+    loop {
+        match notifications.recv_timeout(NOTIFICATION_POLL) {
+            Ok(notification) => return Some(notification),
+            Err(mpsc::RecvTimeoutError::Timeout) if !state.is_stopped.load(Ordering::SeqCst) => {}
+            Err(_) => return None,
+        }
+    }
+}
+
+// Try to send the failure `response` on `stream`, before it is closed.
+fn send_failure<S: Write>(stream: &mut S, response: &Response) {
+    let _ = stream
+        .write_all(&response.to_bytes())
+        .and_then(|_| stream.flush());
+}
+
+// Read one HTTP request from `stream` and answer it through the gateway, as if the request it maps
+// onto had arrived over the binary protocol.
+#[cfg(feature = "http")]
+fn handle_http(state: Arc<ServerState>, mut stream: TcpStream, context: RequestContext) {
+    let _span = context.span().entered();
+    let _connection = state.metrics.connection();
+    let routed = http::read_request(&mut stream, &state.limits)
+        .map(|request| (http::route(&request), request.token));
+    let response = match routed {
+        Ok((Ok(request), token)) => {
+            record_request(&state, &request);
+            let kind = request.kind();
+            let start = Instant::now();
+            let header = RequestHeader {
+                token,
+                ..RequestHeader::default()
+            };
+            let response = answer(&state, request, &header, &context);
+            state.metrics.record(kind, start.elapsed());
+            log_answered(kind, &response, start.elapsed());
+            response
+        }
+        Ok((Err(response), _)) => response,
+        Err(e) => decode_failure(e),
+    };
+    if let Err(e) = http::write_response(&mut stream, &response) {
+        warn!(error = %e, "failed to send HTTP response");
+    }
+}
+
+/// A connection's place among those the server holds open at once, given up when it is dropped
+struct ConnectionSlot(Arc<ServerState>);
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Metadata about the connection a request arrived on, available to everything that handles the
+/// request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestContext {
+    /// The address of the client, if it could be determined
+    pub peer: Option<SocketAddr>,
+    /// When the server accepted the connection
+    pub received_at: SystemTime,
+    /// Who the client authenticated as, if anyone
+    pub identity: Option<String>,
+    /// What the client's API key allows it to do, if it sent a valid one
+    pub role: Option<Role>,
+    /// Identifies this request in log lines; unique for the lifetime of the server
+    pub trace_id: u64,
+    /// Whether the request arrived on a read-only listener
+    pub read_only: bool,
+    /// The collection the request named, if any, rather than the server's default archive
+    pub collection: Option<String>,
+}
+impl RequestContext {
+    // The span to log everything about the request in, so each line says which request it is
+    // about. The client's identity is filled in once it is known.
+    fn span(&self) -> Span {
+        tracing::info_span!(
+            "request",
+            trace_id = %format_args!("{:016x}", self.trace_id),
+            peer = self.peer.map(tracing::field::display),
+            identity = self.identity.as_deref(),
+            read_only = self.read_only,
+        )
+    }
+}
+impl std::fmt::Display for RequestContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{:016x}", self.trace_id)?;
+        if let Some(peer) = self.peer {
+            write!(f, " {}", peer)?;
+        }
+        if let Some(identity) = &self.identity {
+            write!(f, " as {}", identity)?;
+        }
+        write!(f, "]")
     }
 }
 
@@ -44,39 +1112,727 @@ fn process_message(state: Arc<ServerState>, request: Request, mut stream: TcpStr
 struct ServerState {
     /// The database that the server uses to store documents
     database: Database,
-    /// The thread pool that the server uses to process requests
-    pool: ThreadPool,
+    /// The archives that requests may name instead of `database`, by name. They are kept in
+    /// memory only, without a write-ahead log or snapshots
+    collections: RwLock<BTreeMap<String, Arc<Database>>>,
+    /// The number of buckets in the reverse index of each database, including new collections
+    buckets: usize,
+    /// The thread pool that the server uses to process requests, until the server is stopped
+    pool: Mutex<Option<ThreadPool>>,
     /// A flag that indicates whether the server has been stopped
     is_stopped: AtomicBool,
+    /// When the server was created, to report its uptime
+    started_at: Instant,
+    /// The trace id to give the next request
+    next_trace_id: AtomicU64,
+    /// The addresses the server's listeners are bound to, in the order they were started
+    local_addrs: Mutex<Vec<SocketAddr>>,
+    /// When set, every accepted connection is wrapped in a TLS session using this configuration
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    /// When set, every request received is recorded to this log
+    request_log: Option<RequestLog>,
+    /// When set, every request that would modify the archive is recorded to this log
+    audit_log: Option<AuditLog>,
+    /// When set, only clients with one of these keys may modify the archive
+    api_keys: RwLock<Option<ApiKeys>>,
+    /// Whether every client but an admin is confined to its own collection, named after it
+    tenant_isolation: bool,
+    /// When set, each client address may only make requests as fast as this allows
+    rate_limiter: Option<RateLimiter>,
+    /// The most connections the server holds open at once, counting those waiting for a worker
+    max_connections: Option<usize>,
+    /// The most workers an admin may resize the thread pool to
+    max_workers: usize,
+    /// How many connections the server holds open now
+    open_connections: AtomicUsize,
+    /// When set, documents are refused once the archive takes up roughly this many bytes
+    memory_limit: Option<usize>,
+    /// When set, the results of recent searches, by the term searched for. Emptied by every
+    /// change to the archive
+    search_cache: Option<LruCache<String, Vec<usize>>>,
+    /// When set, every change to the archive is logged here before it is made
+    wal: Option<WriteAheadLog>,
+    /// When set, every change to the archive is also sent to these followers
+    replicator: Option<Replicator>,
+    /// Set on a follower, which takes changes only from its primary. Holds where it has got to in
+    /// them, and the key the primary sends them with
+    follower: Option<(Mutex<FollowerPosition>, String)>,
+    /// When set, the server is a coordinator: the archive is split across these backends, which
+    /// answer requests about it in place of `database`
+    shards: Option<Shards>,
+    /// The clients subscribed to documents published to the default archive
+    feed: Feed,
+    /// When set, the archive is saved to a snapshot as often as this asks
+    snapshots: Option<SnapshotPolicy>,
+    /// When set, the directory clients may export the archive to and import dumps from
+    data_dir: Option<PathBuf>,
+    /// The last write-ahead log entry included in the snapshot the archive was loaded from
+    snapshot_seq: u64,
+    /// How many changes have been made to the archive since the last snapshot
+    writes_since_snapshot: AtomicUsize,
+    /// The largest requests the server will read
+    limits: MessageLimits,
+    /// How long the server waits on each connection before giving up on it
+    timeouts: ConnectionTimeouts,
+    /// Counters and histograms describing the requests the server has handled
+    metrics: Metrics,
+    /// When set, metrics are also served over HTTP at `/metrics` on this address
+    metrics_addr: Option<SocketAddr>,
+    /// When set, the archive is also served over HTTP on this address
+    #[cfg(feature = "http")]
+    http_addr: Option<SocketAddr>,
+    /// When set, requests are also accepted on a Unix domain socket at this path
+    #[cfg(unix)]
+    unix_socket: Option<std::path::PathBuf>,
+    /// When set, responses are delayed, dropped, or corrupted at random
+    #[cfg(feature = "fault-injection")]
+    faults: Option<FaultInjector>,
 }
 impl ServerState {
-    fn new() -> Self {
+    // Describe a connection just accepted from `peer` on a listener that is read-only if
+    // `read_only` is set.
+    fn context(&self, peer: Option<SocketAddr>, read_only: bool) -> RequestContext {
+        RequestContext {
+            peer,
+            received_at: SystemTime::now(),
+            identity: None,
+            role: None,
+            trace_id: self.next_trace_id.fetch_add(1, Ordering::Relaxed),
+            read_only,
+            collection: None,
+        }
+    }
+
+    // Decide whether to take the connection `context` describes, giving it a slot among the
+    // server's open connections if so. A client over its rate limit, or arriving when the server
+    // already has as many connections open as it may, is turned away with the response to send
+    // it instead.
+    fn admit(self: &Arc<Self>, context: &RequestContext) -> Result<ConnectionSlot, Response> {
+        let _span = context.span().entered();
+        self.check_rate(context)?;
+        let max = self.max_connections.unwrap_or(usize::MAX);
+        let opened =
+            self.open_connections
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                    (open < max).then_some(open + 1)
+                });
+        match opened {
+            Ok(_) => Ok(ConnectionSlot(Arc::clone(self))),
+            Err(_) => {
+                warn!("turned away a connection over the limit");
+                Err(Response::failure(
+                    ErrorCode::Overloaded,
+                    "too many connections are open",
+                ))
+            }
+        }
+    }
+
+    // Count a request from the client in `context` against its rate limit, answering with the
+    // response to turn it away with if it is over the limit.
+    fn check_rate(&self, context: &RequestContext) -> Result<(), Response> {
+        if let (Some(limiter), Some(peer)) = (&self.rate_limiter, context.peer) {
+            if !limiter.allow(peer.ip()) {
+                warn!("turned away a client over its rate limit");
+                return Err(Response::failure(
+                    ErrorCode::RateLimited,
+                    "too many requests; slow down",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Render the server's metrics, along with gauges read from the pool and database.
+    fn render_metrics(&self) -> String {
+        let pool = sync::lock(&self.pool);
+        let workers = pool.as_ref().map_or(0, ThreadPool::size);
+        let busy = pool.as_ref().map_or(0, ThreadPool::active_count);
+        let queue_depth = pool.as_ref().map_or(0, ThreadPool::queue_depth);
+        let panics = pool.as_ref().map_or(0, ThreadPool::panic_count);
+        let timings = pool.as_ref().map(ThreadPool::timings);
+        drop(pool);
+        let memory = self.database.memory_usage();
+        let collections = sync::read(&self.collections).len();
+        let mut gauges = vec![
+            ("pool_workers", workers),
+            ("pool_busy_workers", busy),
+            ("pool_queue_depth", queue_depth),
+            ("pool_job_panics", panics),
+            ("documents", self.database.document_count()),
+            ("blob_store_bytes", self.database.stored_bytes()),
+            ("index_segments", self.database.segment_count()),
+            ("memory_bytes", memory.total()),
+            ("blob_store_memory_bytes", memory.blob_store),
+            ("index_memory_bytes", memory.index),
+            ("collections", collections),
+            ("subscriptions", self.feed.len()),
+        ];
+        if let Some(limit) = self.memory_limit {
+            gauges.push(("memory_limit_bytes", limit));
+        }
+        if let Some(replicator) = &self.replicator {
+            gauges.push(("replication_pending", replicator.pending()));
+        }
+        if let Some(cache) = &self.search_cache {
+            gauges.push(("search_cache_entries", cache.len()));
+            gauges.push(("search_cache_hits", cache.hits()));
+            gauges.push(("search_cache_misses", cache.misses()));
+        }
+        let histograms = match &timings {
+            Some(timings) => vec![
+                (
+                    "pool_queue_wait_seconds",
+                    "Time connections waited for a free worker",
+                    &timings.queue_wait,
+                ),
+                (
+                    "pool_task_duration_seconds",
+                    "Time workers spent on each connection",
+                    &timings.run_time,
+                ),
+            ],
+            None => Vec::new(),
+        };
+        self.metrics.render_with(&gauges, &histograms)
+    }
+
+    // The documents containing `word`, from the search cache if the server has one and it holds
+    // them.
+    fn search(&self, word: &str) -> Vec<usize> {
+        let (Some(cache), Some(term)) = (&self.search_cache, self.database.search_term(word))
+        else {
+            return self.database.search(word);
+        };
+        let generation = cache.generation();
+        if let Some(ids) = cache.get(&term) {
+            return ids;
+        }
+        let ids = self.database.search(word);
+        cache.insert(term, ids.clone(), generation);
+        ids
+    }
+
+    // Whether the archive and its collections have grown to the server's memory limit, if it has
+    // one.
+    fn is_over_memory_limit(&self) -> bool {
+        self.memory_limit.is_some_and(|limit| {
+            let collections = sync::read(&self.collections);
+            let used = collections
+                .values()
+                .map(|collection| collection.memory_usage().total())
+                .sum::<usize>();
+            used + self.database.memory_usage().total() >= limit
+        })
+    }
+
+    // The collection called `name`, if there is one.
+    fn collection(&self, name: &str) -> Option<Arc<Database>> {
+        sync::read(&self.collections).get(name).cloned()
+    }
+
+    // The collection of the tenant `name`, created empty the first time the tenant uses it.
+    fn tenant_collection(&self, name: &str) -> Arc<Database> {
+        if let Some(collection) = self.collection(name) {
+            return collection;
+        }
+        let mut collections = sync::write(&self.collections);
+        let collection = collections.entry(name.to_string()).or_insert_with(|| {
+            info!(collection = name, "created collection for tenant");
+            Arc::new(Database::with_buckets(self.buckets))
+        });
+        Arc::clone(collection)
+    }
+
+    // Whether connections are waiting for a worker, so that kept-alive connections sitting idle
+    // should give theirs up.
+    fn has_waiting_connections(&self) -> bool {
+        sync::lock(&self.pool)
+            .as_ref()
+            .is_some_and(|pool| pool.queue_depth() > 0)
+    }
+
+    // Whether the client in `context` is confined to its own collection, because the server
+    // isolates tenants and the client isn't an admin, or a follower's primary.
+    fn is_tenant(&self, context: &RequestContext) -> bool {
+        self.tenant_isolation && !matches!(context.role, Some(Role::Admin | Role::Replicator))
+    }
+
+    // The collection the request sent with `header` by the client in `context` is about, or None
+    // for the default archive. A tenant always gets its own collection, and is refused if it names
+    // another; without a valid key, a client of a server that isolates tenants may do nothing.
+    fn collection_for(
+        &self,
+        header: &RequestHeader,
+        context: &RequestContext,
+    ) -> Result<Option<String>, Response> {
+        if !self.is_tenant(context) {
+            return Ok(header.collection.clone());
+        }
+        let Some(tenant) = &context.identity else {
+            return Err(Response::failure(
+                ErrorCode::Unauthorized,
+                "this server needs a valid key for every request",
+            ));
+        };
+        match &header.collection {
+            Some(name) if name != tenant => Err(Response::failure(
+                ErrorCode::Forbidden,
+                format!("{} may only use its own collection", tenant),
+            )),
+            _ => Ok(Some(tenant.clone())),
+        }
+    }
+
+    // Create an empty collection called `name`. Its index has the server's number of buckets, but
+    // is otherwise a plain `Database`'s, whatever analyzer and stop words the default archive has.
+    // Names are up to 64 letters, digits, dashes and underscores.
+    fn create_collection(&self, name: String) -> Response {
+        let valid = name.len() <= 64
+            && !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Response::failure(
+                ErrorCode::Malformed,
+                "collection names are up to 64 letters, digits, dashes and underscores",
+            );
+        }
+        let mut collections = sync::write(&self.collections);
+        if collections.contains_key(&name) {
+            return Response::failure(
+                ErrorCode::Duplicate,
+                format!("a collection named {} already exists", name),
+            );
+        }
+        info!(collection = name, "created collection");
+        collections.insert(name.clone(), Arc::new(Database::with_buckets(self.buckets)));
+        Response::CollectionCreated(name)
+    }
+
+    // Reopen the server's log files and reload its API keys from their key file, if they have
+    // one. A key file that can no longer be read leaves the old keys in place.
+    fn reload(&self) {
+        if let Some(log) = &self.request_log {
+            if let Err(e) = log.reopen() {
+                error!(error = %e, "failed to reopen request log");
+            }
+        }
+        if let Some(log) = &self.audit_log {
+            if let Err(e) = log.reopen() {
+                error!(error = %e, "failed to reopen audit log");
+            }
+        }
+        let source = match &*sync::read(&self.api_keys) {
+            Some(keys) => keys.source().map(Path::to_path_buf),
+            None => None,
+        };
+        if let Some(path) = source {
+            match ApiKeys::load(&path) {
+                Ok(keys) => {
+                    info!(keys = keys.len(), "reloaded API keys");
+                    *sync::write(&self.api_keys) = Some(keys);
+                }
+                Err(e) => error!(path = %path.display(), error = %e, "failed to reload API keys"),
+            }
+        }
+    }
+
+    // Save the archive to a snapshot as `snapshots` asks, emptying the write-ahead log if there is
+    // one, since the snapshot holds every change in it.
+    fn take_snapshot(&self, snapshots: &SnapshotPolicy) -> io::Result<()> {
+        // Only the writes counted before saving are known to be in the snapshot; any counted
+        // while it is saved stay counted towards the next one
+        let writes = self.writes_since_snapshot.load(Ordering::Relaxed);
+        let save = |seq| snapshot::save(&self.database, seq, &snapshots.path);
+        match &self.wal {
+            Some(wal) => wal.checkpoint(save)?,
+            None => save(0)?,
+        }
+        self.writes_since_snapshot
+            .fetch_sub(writes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // State for a server with `workers` threads in its pool, or no pool at all if `workers` is
+    // None, and `buckets` buckets in its database's reverse index.
+    fn new(workers: Option<usize>, buckets: usize) -> Self {
         Self {
-            database: Database::new(),
-            pool: ThreadPool::new(WORKERS),
+            database: Database::with_buckets(buckets),
+            collections: RwLock::new(BTreeMap::new()),
+            buckets,
+            pool: Mutex::new(workers.map(ThreadPool::new)),
             is_stopped: AtomicBool::new(false),
+            started_at: Instant::now(),
+            next_trace_id: AtomicU64::new(0),
+            local_addrs: Mutex::new(Vec::new()),
+            #[cfg(feature = "tls")]
+            tls: None,
+            request_log: None,
+            audit_log: None,
+            api_keys: RwLock::new(None),
+            tenant_isolation: false,
+            rate_limiter: None,
+            max_connections: None,
+            max_workers: MAX_WORKERS,
+            memory_limit: None,
+            search_cache: None,
+            open_connections: AtomicUsize::new(0),
+            wal: None,
+            replicator: None,
+            follower: None,
+            shards: None,
+            feed: Feed::default(),
+            snapshots: None,
+            data_dir: None,
+            snapshot_seq: 0,
+            writes_since_snapshot: AtomicUsize::new(0),
+            limits: MessageLimits::default(),
+            timeouts: ConnectionTimeouts::default(),
+            metrics: Metrics::new(),
+            metrics_addr: None,
+            #[cfg(feature = "http")]
+            http_addr: None,
+            #[cfg(unix)]
+            unix_socket: None,
+            #[cfg(feature = "fault-injection")]
+            faults: None,
+        }
+    }
+}
+
+/// How long the server waits on a connection before giving up on it. A client that takes longer
+/// to send its request is answered with a `TimedOut` failure, and the connection is closed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionTimeouts {
+    /// The longest to wait for the next bytes of a request, or None to wait forever
+    pub read: Option<Duration>,
+    /// The longest to wait for the client to accept more bytes of a response, or None to wait
+    /// forever
+    pub write: Option<Duration>,
+}
+
+// Bind a TCP listener to `address`. An IPv6 listener only takes IPv6 connections, whatever the
+// platform's default, so that an IPv4 listener can share its port for dual-stack serving.
+fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // As `TcpListener::bind` does, so a restarted server can bind while old connections linger
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// A port that the server accepts connections on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerConfig {
+    /// The local address to bind, e.g. `127.0.0.1`, `0.0.0.0`, or `::1`
+    pub address: IpAddr,
+    /// The port to listen on
+    pub port: u16,
+    /// Whether requests that modify the archive should be refused on this port
+    pub read_only: bool,
+}
+impl ListenerConfig {
+    /// A listener on `port` that accepts every kind of request
+    pub fn new(port: u16) -> Self {
+        Self {
+            address: DEFAULT_BIND,
+            port,
+            read_only: false,
+        }
+    }
+
+    /// A listener on `port` that only accepts requests which don't modify the archive
+    pub fn read_only(port: u16) -> Self {
+        Self {
+            address: DEFAULT_BIND,
+            port,
+            read_only: true,
         }
     }
+
+    /// This listener, bound to `address` instead of the default
+    pub fn bind(self, address: IpAddr) -> Self {
+        Self { address, ..self }
+    }
 }
 
 pub struct Server {
     state: Arc<ServerState>,
 }
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Server {
     // TODO:
     // Create a new server by using the `ServerState::new` function
     pub fn new() -> Self {
+        Self::with_capacity(WORKERS, BUCKETS)
+    }
+
+    // Create a new server with `workers` threads in its pool and `buckets` buckets in its
+    // database's reverse index.
+    pub fn with_capacity(workers: usize, buckets: usize) -> Self {
         Self {
-            state: Arc::new(ServerState::new()),
+            state: Arc::new(ServerState::new(Some(workers), buckets)),
         }
     }
 
+    // Options can only be changed before the server starts running, while nothing else holds a
+    // reference to its state.
+    fn state_mut(&mut self) -> &mut ServerState {
+        Arc::get_mut(&mut self.state).expect("server options must be set before it runs")
+    }
+
+    // Serve every listener over TLS using `config`, e.g. one built by `tls::server_config`.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.state_mut().tls = Some(config);
+        self
+    }
+
+    // Record every request the server receives to `log`.
+    pub fn with_request_log(mut self, log: RequestLog) -> Self {
+        self.state_mut().request_log = Some(log);
+        self
+    }
+
+    // Record who made every request that would modify the archive, and what it did, to `log`.
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.state_mut().audit_log = Some(log);
+        self
+    }
+
+    // Log every change to the archive to `wal` before making it, after making every change
+    // already in the log. Fails if the log can't be read.
+    pub fn with_write_ahead_log(mut self, wal: WriteAheadLog) -> io::Result<Self> {
+        let state = self.state_mut();
+        let replayed = wal.replay(&state.database, state.snapshot_seq)?;
+        info!(replayed, "replayed changes from the write-ahead log");
+        state.wal = Some(wal);
+        Ok(self)
+    }
+
+    // Save the archive to a snapshot as often as `snapshots` asks, after loading the last snapshot
+    // saved there, if any. A write-ahead log must be added after this, so that only the changes
+    // since the snapshot are replayed. Fails if the snapshot can't be read.
+    pub fn with_snapshots(mut self, snapshots: SnapshotPolicy) -> io::Result<Self> {
+        let state = self.state_mut();
+        assert!(
+            state.wal.is_none(),
+            "snapshots must be loaded before the write-ahead log is replayed"
+        );
+        if let Some(seq) = snapshot::load(&state.database, &snapshots.path)? {
+            info!(
+                documents = state.database.document_count(),
+                path = %snapshots.path.display(),
+                "loaded snapshot"
+            );
+            state.snapshot_seq = seq;
+        }
+        state.snapshots = Some(snapshots);
+        Ok(self)
+    }
+
+    // Also serve the server's metrics as plain text over HTTP, at `/metrics` on `address`.
+    pub fn with_metrics_listener(mut self, address: SocketAddr) -> Self {
+        self.state_mut().metrics_addr = Some(address);
+        self
+    }
+
+    // Also serve the archive over HTTP on `address`, as the REST API in `http` describes.
+    #[cfg(feature = "http")]
+    pub fn with_http_listener(mut self, address: SocketAddr) -> Self {
+        self.state_mut().http_addr = Some(address);
+        self
+    }
+
+    // Also accept requests on a Unix domain socket at `path`, for clients on the same host. A stale
+    // socket file left at `path` by an earlier run is replaced.
+    #[cfg(unix)]
+    pub fn with_unix_socket(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.state_mut().unix_socket = Some(path.into());
+        self
+    }
+
+    // Only accept requests that modify the archive from clients sending one of `keys`. Anyone may
+    // still search and retrieve. Admin requests need an admin key whether or not this is set.
+    pub fn with_api_keys(mut self, keys: ApiKeys) -> Self {
+        *self.state_mut().api_keys.get_mut().unwrap() = Some(keys);
+        self
+    }
+
+    // Give every client but an admin a collection of its own, named after it, and confine it
+    // there: it can't search, retrieve or modify anything else. Clients without a valid key are
+    // refused outright, so this needs API keys.
+    pub fn with_tenant_isolation(mut self) -> Self {
+        self.state_mut().tenant_isolation = true;
+        self
+    }
+
+    // Let admins export the archive to, and import dumps from, files in `dir`. Without it, exports
+    // and imports are refused.
+    pub fn with_data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state_mut().data_dir = Some(dir.into());
+        self
+    }
+
+    // Send every change made to the archive to the followers `replicator` sends to. Changes to
+    // collections aren't sent.
+    pub fn with_replication(mut self, replicator: Replicator) -> Self {
+        self.state_mut().replicator = Some(replicator);
+        self
+    }
+
+    // Run as a follower, which serves searches and retrieves but takes changes only from its
+    // primary, which must send them with `primary_key`. Any other request that would modify the
+    // archive is refused with `ReadOnly`.
+    pub fn as_follower(mut self, primary_key: impl Into<String>) -> Self {
+        self.state_mut().follower =
+            Some((Mutex::new(FollowerPosition::default()), primary_key.into()));
+        self
+    }
+
+    // Run as a coordinator, serving an archive split across the backend servers in `shards` rather
+    // than one of its own. Collections aren't split, and stay on the coordinator.
+    pub fn with_shards(mut self, shards: Shards) -> Self {
+        self.state_mut().shards = Some(shards);
+        self
+    }
+
+    // Refuse requests larger than `limits` allows with a `TooLarge` response.
+    pub fn with_limits(mut self, limits: MessageLimits) -> Self {
+        self.state_mut().limits = limits;
+        self
+    }
+
+    // Turn away clients that connect more often than `limit` allows from the same address, with a
+    // `RateLimited` failure.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.state_mut().rate_limiter = Some(RateLimiter::new(limit));
+        self
+    }
+
+    // Hold at most `max` connections open at once, counting those waiting for a worker. More are
+    // answered at once with an `Overloaded` failure and closed.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        assert!(max > 0, "the server must allow at least one connection");
+        self.state_mut().max_connections = Some(max);
+        self
+    }
+
+    // Refuse publishes and updates with a `MemoryLimit` failure once the archive takes up roughly
+    // `bytes` bytes, rather than growing until the process runs out of memory.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.state_mut().memory_limit = Some(bytes);
+        self
+    }
+
+    // Keep the results of the `capacity` most recently used searches, so that popular words
+    // aren't looked up in the index every time. Any change to the archive empties the cache.
+    pub fn with_search_cache(mut self, capacity: usize) -> Self {
+        self.state_mut().search_cache = Some(LruCache::new(capacity));
+        self
+    }
+
+    // Let at most `capacity` requests wait for a free worker. Requests beyond that are turned
+    // away at once with an `Overloaded` failure, rather than queued without bound.
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        let pool = self.state_mut().pool.get_mut().unwrap();
+        *pool = pool.take().map(|pool| pool.with_queue_capacity(capacity));
+        self
+    }
+
+    // Let admins resize the thread pool to at most `max` workers, rather than `MAX_WORKERS`. Larger
+    // sizes are refused with an `OutOfRange` failure.
+    pub fn with_max_workers(mut self, max: usize) -> Self {
+        assert!(max > 0, "the pool must be allowed at least one worker");
+        self.state_mut().max_workers = max;
+        self
+    }
+
+    // Give up on connections that are slower than `timeouts` allows to send a request or take
+    // the response. Neither timeout may be zero.
+    pub fn with_timeouts(mut self, timeouts: ConnectionTimeouts) -> Self {
+        let is_zero = |timeout: Option<Duration>| timeout.is_some_and(|t| t.is_zero());
+        assert!(
+            !is_zero(timeouts.read) && !is_zero(timeouts.write),
+            "connection timeouts must not be zero"
+        );
+        self.state_mut().timeouts = timeouts;
+        self
+    }
+
+    // Turn words into terms with `analyzer` when indexing documents and searching.
+    pub fn with_analyzer<A: Analyzer + 'static>(mut self, analyzer: A) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_analyzer(analyzer);
+        self
+    }
+
+    // Leave `stop_words` out of the index; searching for one finds nothing.
+    pub fn with_stop_words<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        mut self,
+        stop_words: I,
+    ) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_stop_words(stop_words);
+        self
+    }
+
+    // Keep documents compressed with zstd at `level`, from 1, the fastest, to 22, the smallest.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, level: i32) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_compression(level);
+        self
+    }
+
+    // Index the character n-grams of published documents, `n` characters each, to speed up
+    // substring searches.
+    pub fn with_ngram_index(mut self, n: usize) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_ngram_index(n);
+        self
+    }
+
+    // Treat publishes of copies of stored documents as `duplicates` says. Rejected copies get a
+    // `Duplicate` failure.
+    pub fn with_duplicates(mut self, duplicates: Duplicates) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_duplicates(duplicates);
+        self
+    }
+
+    // Misbehave as `config` asks when responding, to test how clients cope with an unreliable
+    // server.
+    #[cfg(feature = "fault-injection")]
+    pub fn with_faults(mut self, config: FaultConfig) -> Self {
+        self.state_mut().faults = Some(FaultInjector::new(config));
+        self
+    }
+
     // Spawn a thread that listens for incoming connections on the given port. When a connection is
     // established, add a task to the thread pool that deserializes the request, and processes it
     // using the `process_message` function.
     //
     // To listen for incoming connections, you can use the `std::net::TcpListener::bind` function.
-    // To listen on the local address, you can call `TcpListener::bind(("127.0.0.1", port))`. The
+    // To listen on the configured address, you can call `TcpListener::bind((address, port))`. The
     // resulting TcpListener can be used to accept incoming connections by calling the `accept`
     // method in a loop. This method blocks until a new connection is established, and then returns
     // a new TcpStream and the address of the remote peer. You should move this stream into the
@@ -85,58 +1841,129 @@ impl Server {
     // While looping to accept connections, you should also check the `is_stopped` flag in the
     // `ServerState` to see if the server has been stopped. If it has, you should break out of the
     // loop and return.
-    fn listen(&self, port: u16) {
-        let listener = match TcpListener::bind(("127.0.0.1", port)) {
+    //
+    // Each listener gets its own accept thread, but all of them share the same `ServerState`, so
+    // documents published on one port are visible on every other.
+    //
+    // Binding to port 0 picks any free port; the port actually bound is printed and can be read
+    // back with `local_addrs`.
+    fn listen(&self, config: ListenerConfig) {
+        let read_only = config.read_only;
+        let listener = match bind((config.address, config.port).into()) {
             Ok(listener) => listener,
             Err(e) => {
-                eprintln!("Failed to bind to port {}: {}", port, e);
+                error!(address = %config.address, port = config.port, error = %e, "failed to bind");
                 return;
             }
         };
+        let port = match listener.local_addr() {
+            Ok(addr) => {
+                sync::lock(&self.state.local_addrs).push(addr);
+                addr.port()
+            }
+            Err(_) => config.port,
+        };
 
         let state = Arc::clone(&self.state);
 
         // Listener thread
         thread::spawn(move || {
-            println!("Blocking listener thread started on port {}", port);
+            info!(port, "blocking listener thread started");
 
             // Block until a new client connects.
             for stream_result in listener.incoming() {
-                
                 // Check the stop flag after a connection is received.
                 if state.is_stopped.load(Ordering::SeqCst) {
-                    println!("Listener thread shutting down.");
+                    info!(port, "listener thread shutting down");
                     break;
                 }
 
                 match stream_result {
                     Ok(stream) => {
+                        if let Err(e) = stream
+                            .set_read_timeout(state.timeouts.read)
+                            .and_then(|_| stream.set_write_timeout(state.timeouts.write))
+                        {
+                            warn!(error = %e, "failed to set connection timeouts");
+                            continue;
+                        }
                         // Connection established, clone state for the worker
                         let state_clone = Arc::clone(&state);
+                        let context = state.context(stream.peer_addr().ok(), read_only);
+                        let slot = match state.admit(&context) {
+                            Ok(slot) => slot,
+                            Err(response) => {
+                                #[cfg(feature = "tls")]
+                                if state.tls.is_some() {
+                                    continue;
+                                }
+                                reject(stream, &response);
+                                continue;
+                            }
+                        };
+                        // With a bounded queue, keep a handle on the connection to tell the
+                        // client if its request is turned away. A TLS client can't be told
+                        // anything before the handshake, so it is just hung up on
+                        #[cfg(feature = "tls")]
+                        let plain = state.tls.is_none();
+                        #[cfg(not(feature = "tls"))]
+                        let plain = true;
+                        let pool = sync::lock(&state.pool);
+                        let bounded = pool
+                            .as_ref()
+                            .is_some_and(|pool| pool.queue_capacity().is_some());
+                        let overflow = (bounded && plain)
+                            .then(|| stream.try_clone().ok())
+                            .flatten();
 
-                        // Execute the task in the thread pool
-                        state.pool.execute(move || {
-                            let mut stream = stream;
+                        let trace = context.clone();
 
-                            // Translate the request
-                            match Request::from_bytes(&mut stream) {
-                                Some(request) => {
-                                    // Process message
-                                    process_message(state_clone, request, stream);
-                                }
-                                None => {
-                                    eprintln!("Failed to deserialize request or client disconnected.");
-                                    // Try to send a failure response
-                                    let response = Response::Failure;
-                                    let _ = stream.write_all(&response.to_bytes());
+                        // Execute the task in the thread pool, boxed so that a job turned away is
+                        // cheap to hand back
+                        let job = move || {
+                            // The connection holds its slot until it is answered
+                            let _slot = slot;
+                            let _span = context.span().entered();
+                            #[cfg(feature = "tls")]
+                            if let Some(config) = &state_clone.tls {
+                                let session =
+                                    match rustls::ServerConnection::new(Arc::clone(config)) {
+                                        Ok(session) => session,
+                                        Err(e) => {
+                                            error!(error = %e, "failed to start TLS session");
+                                            return;
+                                        }
+                                    };
+                                let mut stream = rustls::StreamOwned::new(session, stream);
+                                handle_connection(state_clone, &mut stream, context);
+                                stream.conn.send_close_notify();
+                                let _ = stream.flush();
+                                return;
+                            }
+                            handle_connection(state_clone, stream, context);
+                        };
+                        match pool.as_ref().map(|pool| pool.try_execute(Box::new(job))) {
+                            Some(Ok(_)) => {}
+                            Some(Err(_)) => {
+                                trace.span().in_scope(|| {
+                                    warn!("turned away a request while overloaded");
+                                });
+                                if let Some(stream) = overflow {
+                                    let response = Response::failure(
+                                        ErrorCode::Overloaded,
+                                        "too many requests are queued",
+                                    );
+                                    reject(stream, &response);
                                 }
                             }
-                        });
+                            // The server was stopped since the flag was checked
+                            None => break,
+                        }
                     }
                     Err(e) => {
                         // Only print an error if not shutting down.
                         if !state.is_stopped.load(Ordering::SeqCst) {
-                            eprintln!("Connection failed: {}", e);
+                            warn!(error = %e, "connection failed");
                         }
                     }
                 }
@@ -144,30 +1971,267 @@ impl Server {
         });
     }
 
-    // This function has already been partially completed for you
-    pub fn run(&self, port: u16) {
-        // Set up a signal handler to stop the server when Ctrl-C is pressed
+    // Spawn a thread that answers HTTP requests for metrics on `address`. Rendering the metrics is
+    // cheap, so requests are answered on the listener thread rather than in the pool.
+    fn listen_metrics(&self, address: SocketAddr) {
+        let listener = match bind(address) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(%address, error = %e, "failed to bind metrics listener");
+                return;
+            }
+        };
+        if let Ok(addr) = listener.local_addr() {
+            info!("serving metrics at http://{}/metrics", addr);
+        }
         let state = Arc::clone(&self.state);
-        match ctrlc::try_set_handler(move || {
-            println!("Stopping server...");
-            state.is_stopped.store(true, Ordering::SeqCst);
-        }) {
-            Ok(_) => {}
-            Err(ctrlc::Error::MultipleHandlers) => {}
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if state.is_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    metrics::serve_http(stream, || state.render_metrics());
+                }
+            }
+        });
+    }
+
+    // Spawn a thread that accepts connections on a Unix domain socket at `path`, answering them
+    // just like those on a TCP listener.
+    #[cfg(unix)]
+    fn listen_unix(&self, path: &Path) {
+        use std::os::unix::net::UnixListener;
+        let _ = std::fs::remove_file(path);
+        let listener = match UnixListener::bind(path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!(path = %path.display(), error = %e, "failed to bind Unix socket");
+                return;
+            }
+        };
+        info!(path = %path.display(), "listening on Unix socket");
+        let state = Arc::clone(&self.state);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if state.is_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                if let Err(e) = stream
+                    .set_read_timeout(state.timeouts.read)
+                    .and_then(|_| stream.set_write_timeout(state.timeouts.write))
+                {
+                    warn!(error = %e, "failed to set connection timeouts");
+                    continue;
+                }
+                // Unix socket clients have no address, and are limited only by the connection cap
+                let context = state.context(None, false);
+                let slot = match state.admit(&context) {
+                    Ok(slot) => slot,
+                    Err(response) => {
+                        let _ = stream.write_all(&response.to_bytes());
+                        continue;
+                    }
+                };
+                let overflow = stream.try_clone().ok();
+                let state_clone = Arc::clone(&state);
+                let job = move || {
+                    let _slot = slot;
+                    let _span = context.span().entered();
+                    handle_connection(state_clone, stream, context)
+                };
+                match sync::lock(&state.pool)
+                    .as_ref()
+                    .map(|pool| pool.try_execute(Box::new(job)))
+                {
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) => {
+                        warn!("turned away a request while overloaded");
+                        let response = Response::failure(
+                            ErrorCode::Overloaded,
+                            "too many requests are queued",
+                        );
+                        if let Some(mut stream) = overflow {
+                            let _ = stream.write_all(&response.to_bytes());
+                        }
+                    }
+                    None => break,
+                }
+            }
+        });
+    }
+
+    // Spawn a thread that accepts HTTP connections on `address` and answers each in the pool, just
+    // as `listen` does for the binary protocol.
+    #[cfg(feature = "http")]
+    fn listen_http(&self, address: SocketAddr) {
+        let listener = match bind(address) {
+            Ok(listener) => listener,
             Err(e) => {
-                panic!("Error setting Ctrl-C handler: {}", e);
+                error!(%address, error = %e, "failed to bind HTTP listener");
+                return;
             }
+        };
+        if let Ok(addr) = listener.local_addr() {
+            info!("serving HTTP at http://{}/", addr);
         }
+        let state = Arc::clone(&self.state);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if state.is_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                if let Err(e) = stream
+                    .set_read_timeout(state.timeouts.read)
+                    .and_then(|_| stream.set_write_timeout(state.timeouts.write))
+                {
+                    warn!(error = %e, "failed to set connection timeouts");
+                    continue;
+                }
+                let context = state.context(stream.peer_addr().ok(), false);
+                let slot = match state.admit(&context) {
+                    Ok(slot) => slot,
+                    Err(response) => {
+                        let _ = http::write_response(stream, &response);
+                        continue;
+                    }
+                };
+                let overflow = stream.try_clone().ok();
+                let state_clone = Arc::clone(&state);
+                let job = move || {
+                    let _slot = slot;
+                    handle_http(state_clone, stream, context)
+                };
+                match sync::lock(&state.pool)
+                    .as_ref()
+                    .map(|pool| pool.try_execute(Box::new(job)))
+                {
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) => {
+                        let response = Response::failure(
+                            ErrorCode::Overloaded,
+                            "too many requests are queued",
+                        );
+                        if let Some(stream) = overflow {
+                            let _ = http::write_response(stream, &response);
+                        }
+                    }
+                    None => break,
+                }
+            }
+        });
+    }
+
+    // Spawn a thread that saves a snapshot of the archive whenever the server's snapshot policy
+    // says one is due.
+    fn snapshot_periodically(&self, snapshots: SnapshotPolicy) {
+        let state = Arc::clone(&self.state);
+        thread::spawn(move || {
+            let mut last = Instant::now();
+            while !state.is_stopped.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(100));
+                let writes = state.writes_since_snapshot.load(Ordering::Relaxed);
+                let due = snapshots
+                    .interval
+                    .is_some_and(|every| last.elapsed() >= every)
+                    || snapshots.writes.is_some_and(|every| writes >= every);
+                if !due || writes == 0 {
+                    continue;
+                }
+                last = Instant::now();
+                if let Err(e) = state.take_snapshot(&snapshots) {
+                    error!(error = %e, "failed to save snapshot");
+                }
+            }
+        });
+    }
+
+    // This function has already been partially completed for you
+    pub fn run(&self, port: u16) {
+        self.run_on(DEFAULT_BIND, port);
+    }
+
+    // Like `run`, but binds to `address` instead of the loopback address.
+    pub fn run_on(&self, address: IpAddr, port: u16) {
+        self.run_listeners(&[ListenerConfig::new(port).bind(address)]);
+    }
+
+    // Like `run`, but accepts connections on every listener in `listeners` until the server is
+    // stopped.
+    pub fn run_listeners(&self, listeners: &[ListenerConfig]) {
+        let signals = handle_signals(Arc::clone(&self.state));
 
         // Call the listen function and then loop (doing nothing) until the server has been stopped
-        self.listen(port);
-        println!("Server Running: Interupt with Ctrl-C");
+        for &listener in listeners {
+            self.listen(listener);
+        }
+        if let Some(address) = self.state.metrics_addr {
+            self.listen_metrics(address);
+        }
+        #[cfg(unix)]
+        if let Some(path) = &self.state.unix_socket {
+            self.listen_unix(path);
+        }
+        #[cfg(feature = "http")]
+        if let Some(address) = self.state.http_addr {
+            self.listen_http(address);
+        }
+        if let Some(snapshots) = &self.state.snapshots {
+            self.snapshot_periodically(snapshots.clone());
+        }
+        info!("server running; interrupt with Ctrl-C");
         while !self.state.is_stopped.load(Ordering::SeqCst) {
             thread::sleep(std::time::Duration::from_millis(500)); //sleep rather than busy waiting
         }
-        println!("Exiting");
+        self.shutdown_pool();
+        // Save the changes since the last snapshot, so the next start has nothing to replay
+        if let Some(snapshots) = &self.state.snapshots {
+            if let Err(e) = self.state.take_snapshot(snapshots) {
+                error!(error = %e, "failed to save snapshot");
+            }
+        }
+        signals.close();
+        #[cfg(unix)]
+        if let Some(path) = &self.state.unix_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        info!("exiting");
+    }
+
+    // Reopen the request and audit logs, so they can be rotated, and reload the API keys if they
+    // came from a key file. The server does the same on SIGHUP.
+    pub fn reload(&self) {
+        self.state.reload();
     }
+
+    // The addresses the server is listening on, in the order its listeners were given. Empty until
+    // the server starts running; useful to find out which port was picked for a listener on port
+    // 0.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        sync::lock(&self.state.local_addrs).clone()
+    }
+
+    // Stop accepting connections, then wait for the requests already accepted to be answered.
     pub fn stop(&self) {
         self.state.is_stopped.store(true, Ordering::SeqCst);
+        self.shutdown_pool();
+    }
+
+    // Shut down the thread pool, if it hasn't been already, letting queued requests finish, and
+    // then stop retrying changes that followers haven't taken.
+    fn shutdown_pool(&self) {
+        let pool = sync::lock(&self.state.pool).take();
+        if let Some(pool) = pool {
+            pool.shutdown();
+        }
+        if let Some(replicator) = &self.state.replicator {
+            replicator.stop();
+        }
     }
 }
@@ -0,0 +1,523 @@
+use super::*;
+use std::io;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{watch, Semaphore};
+use tracing::Instrument;
+
+// A server that handles each connection on a tokio task instead of a pool thread, so thousands of
+// slow clients only cost thousands of cheap tasks rather than tying up every worker. Requests are
+// read and responses written asynchronously; answering a request still calls the blocking
+// database, so that part runs on tokio's blocking thread pool.
+//
+// It shares `ServerState`, and the code that answers requests, with the blocking `Server`. TLS is
+// not supported yet.
+
+/// A server that handles connections on tokio tasks
+pub struct AsyncServer {
+    state: Arc<ServerState>,
+    /// Set to true to stop the accept loops
+    stop: watch::Sender<bool>,
+}
+impl Default for AsyncServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsyncServer {
+    // Create a new server whose database's reverse index has `BUCKETS` buckets.
+    pub fn new() -> Self {
+        Self::with_buckets(BUCKETS)
+    }
+
+    // Create a new server whose database's reverse index has `buckets` buckets.
+    pub fn with_buckets(buckets: usize) -> Self {
+        Self {
+            state: Arc::new(ServerState::new(None, buckets)),
+            stop: watch::channel(false).0,
+        }
+    }
+
+    // Options can only be changed before the server starts running, while nothing else holds a
+    // reference to its state.
+    fn state_mut(&mut self) -> &mut ServerState {
+        Arc::get_mut(&mut self.state).expect("server options must be set before it runs")
+    }
+
+    // Record every request the server receives to `log`.
+    pub fn with_request_log(mut self, log: RequestLog) -> Self {
+        self.state_mut().request_log = Some(log);
+        self
+    }
+
+    // Record who made every request that would modify the archive, and what it did, to `log`.
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.state_mut().audit_log = Some(log);
+        self
+    }
+
+    // Log every change to the archive to `wal` before making it, after making every change
+    // already in the log. Fails if the log can't be read.
+    pub fn with_write_ahead_log(mut self, wal: WriteAheadLog) -> io::Result<Self> {
+        let state = self.state_mut();
+        let replayed = wal.replay(&state.database, state.snapshot_seq)?;
+        info!(replayed, "replayed changes from the write-ahead log");
+        state.wal = Some(wal);
+        Ok(self)
+    }
+
+    // Only accept requests that modify the archive from clients sending one of `keys`.
+    pub fn with_api_keys(mut self, keys: ApiKeys) -> Self {
+        *self.state_mut().api_keys.get_mut().unwrap() = Some(keys);
+        self
+    }
+
+    // Give every client but an admin a collection of its own, and confine it there.
+    pub fn with_tenant_isolation(mut self) -> Self {
+        self.state_mut().tenant_isolation = true;
+        self
+    }
+
+    // Let admins export the archive to, and import dumps from, files in `dir`.
+    pub fn with_data_dir(mut self, dir: impl Into<std::path::PathBuf>) -> Self {
+        self.state_mut().data_dir = Some(dir.into());
+        self
+    }
+
+    // Send every change made to the archive to the followers `replicator` sends to.
+    pub fn with_replication(mut self, replicator: Replicator) -> Self {
+        self.state_mut().replicator = Some(replicator);
+        self
+    }
+
+    // Run as a follower, taking changes only from its primary, sent with `primary_key`.
+    pub fn as_follower(mut self, primary_key: impl Into<String>) -> Self {
+        self.state_mut().follower =
+            Some((Mutex::new(FollowerPosition::default()), primary_key.into()));
+        self
+    }
+
+    // Run as a coordinator, serving an archive split across the backend servers in `shards`.
+    pub fn with_shards(mut self, shards: Shards) -> Self {
+        self.state_mut().shards = Some(shards);
+        self
+    }
+
+    // Refuse requests larger than `limits` allows with a `TooLarge` response.
+    pub fn with_limits(mut self, limits: MessageLimits) -> Self {
+        self.state_mut().limits = limits;
+        self
+    }
+
+    // Hold at most `max` connections open at once; more are answered with an `Overloaded` failure.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        assert!(max > 0, "the server must allow at least one connection");
+        self.state_mut().max_connections = Some(max);
+        self
+    }
+
+    // Turn away clients that connect more often than `limit` allows from the same address.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.state_mut().rate_limiter = Some(RateLimiter::new(limit));
+        self
+    }
+
+    // Give up on connections that are slower than `timeouts` allows to send a request or take
+    // the response. Neither timeout may be zero.
+    pub fn with_timeouts(mut self, timeouts: ConnectionTimeouts) -> Self {
+        let is_zero = |timeout: Option<Duration>| timeout.is_some_and(|t| t.is_zero());
+        assert!(
+            !is_zero(timeouts.read) && !is_zero(timeouts.write),
+            "connection timeouts must not be zero"
+        );
+        self.state_mut().timeouts = timeouts;
+        self
+    }
+
+    // Turn words into terms with `analyzer` when indexing documents and searching.
+    pub fn with_analyzer<A: Analyzer + 'static>(mut self, analyzer: A) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_analyzer(analyzer);
+        self
+    }
+
+    // Leave `stop_words` out of the index; searching for one finds nothing.
+    pub fn with_stop_words<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        mut self,
+        stop_words: I,
+    ) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_stop_words(stop_words);
+        self
+    }
+
+    // Keep documents compressed with zstd at `level`, from 1, the fastest, to 22, the smallest.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, level: i32) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_compression(level);
+        self
+    }
+
+    // Index the character n-grams of published documents, `n` characters each, to speed up
+    // substring searches.
+    pub fn with_ngram_index(mut self, n: usize) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_ngram_index(n);
+        self
+    }
+
+    // Refuse publishes and updates with a `MemoryLimit` failure once the archive takes up roughly
+    // `bytes` bytes.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.state_mut().memory_limit = Some(bytes);
+        self
+    }
+
+    // Keep the results of the `capacity` most recently used searches. Any change to the archive
+    // empties the cache.
+    pub fn with_search_cache(mut self, capacity: usize) -> Self {
+        self.state_mut().search_cache = Some(LruCache::new(capacity));
+        self
+    }
+
+    // Treat publishes of copies of stored documents as `duplicates` says. Rejected copies get a
+    // `Duplicate` failure.
+    pub fn with_duplicates(mut self, duplicates: Duplicates) -> Self {
+        let state = self.state_mut();
+        state.database = std::mem::take(&mut state.database).with_duplicates(duplicates);
+        self
+    }
+
+    // Misbehave as `config` asks when responding, to test how clients cope with an unreliable
+    // server.
+    #[cfg(feature = "fault-injection")]
+    pub fn with_faults(mut self, config: FaultConfig) -> Self {
+        self.state_mut().faults = Some(FaultInjector::new(config));
+        self
+    }
+
+    // Accept connections on `port` of the loopback address until the server is stopped.
+    pub async fn run(&self, port: u16) -> io::Result<()> {
+        self.run_listeners(&[ListenerConfig::new(port)]).await
+    }
+
+    // Accept connections on every listener in `listeners` until the server is stopped. Fails if
+    // any of them can't be bound.
+    pub async fn run_listeners(&self, listeners: &[ListenerConfig]) -> io::Result<()> {
+        let mut tasks = Vec::new();
+        for config in listeners {
+            let listener = bind((config.address, config.port).into())?;
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            let addr = listener.local_addr()?;
+            sync::lock(&self.state.local_addrs).push(addr);
+            info!(port = addr.port(), "async listener started");
+            tasks.push(tokio::spawn(accept_loop(
+                Arc::clone(&self.state),
+                listener,
+                config.read_only,
+                self.stop.subscribe(),
+            )));
+        }
+        let signals = handle_signals(Arc::clone(&self.state));
+        // A client's shutdown request only sets the stop flag, so watch it to stop the loops too
+        let watcher = tokio::spawn({
+            let state = Arc::clone(&self.state);
+            let stop = self.stop.clone();
+            async move {
+                while !state.is_stopped.load(Ordering::SeqCst) {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                stop.send_replace(true);
+            }
+        });
+        for task in tasks {
+            let _ = task.await;
+        }
+        watcher.abort();
+        signals.close();
+        Ok(())
+    }
+
+    // Reopen the request and audit logs, so they can be rotated, and reload the API keys if they
+    // came from a key file. The server does the same on SIGHUP.
+    pub fn reload(&self) {
+        self.state.reload();
+    }
+
+    // The addresses the server is listening on, in the order its listeners were given. Empty until
+    // the server starts running.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        sync::lock(&self.state.local_addrs).clone()
+    }
+
+    // Stop accepting connections. Connections already accepted are still answered.
+    pub fn stop(&self) {
+        self.state.is_stopped.store(true, Ordering::SeqCst);
+        self.stop.send_replace(true);
+        if let Some(replicator) = &self.state.replicator {
+            replicator.stop();
+        }
+    }
+}
+
+// Accept connections from `listener`, spawning a task for each, until `stop` becomes true.
+async fn accept_loop(
+    state: Arc<ServerState>,
+    listener: tokio::net::TcpListener,
+    read_only: bool,
+    mut stop: watch::Receiver<bool>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stop.wait_for(|stopped| *stopped) => break,
+        };
+        match accepted {
+            Ok((mut stream, peer)) => {
+                let context = state.context(Some(peer), read_only);
+                let slot = match state.admit(&context) {
+                    Ok(slot) => slot,
+                    Err(response) => {
+                        tokio::spawn(async move {
+                            let _ = stream.write_all(&response.to_bytes()).await;
+                        });
+                        continue;
+                    }
+                };
+                let span = context.span();
+                let connection =
+                    handle_connection_async(Arc::clone(&state), stream, context).instrument(span);
+                tokio::spawn(async move {
+                    // The connection holds its slot until it is answered
+                    let _slot = slot;
+                    connection.await
+                });
+            }
+            Err(e) => warn!(error = %e, "connection failed"),
+        }
+    }
+}
+
+/// The most pipelined requests from one connection answered at once. Reading more from the
+/// connection waits until one of them has been answered
+const MAX_PIPELINED: usize = 32;
+
+/// The notifications for a client that subscribed, and the header of its request
+type Subscription = (mpsc::Receiver<Notification>, RequestHeader);
+
+/// What answering a request gave: its kind, the encoded response or None to hang up without one,
+/// whether to keep the connection open, and the client's notifications if it subscribed
+type Answered = (&'static str, Option<Encoded>, bool, Option<Subscription>);
+
+// Read a request from `stream`, answer it, and write the response back, then do the same for any
+// further requests while the client asks for the connection to be kept alive. Requests the client
+// gave ids are answered concurrently, up to `MAX_PIPELINED` at a time, and each response is sent
+// as soon as it is ready; a request without an id waits for those read before it.
+async fn handle_connection_async(
+    state: Arc<ServerState>,
+    stream: tokio::net::TcpStream,
+    context: RequestContext,
+) {
+    let _connection = state.metrics.connection();
+    let (mut reader, writer) = stream.into_split();
+    let writer = Arc::new(tokio::sync::Mutex::new(writer));
+    let pipelined = Arc::new(Semaphore::new(MAX_PIPELINED));
+    let mut first = true;
+    loop {
+        let start = Instant::now();
+        // Unlike the blocking server's, the read timeout covers the whole request rather than
+        // each read of it
+        let read = Request::read_with_header_async(&mut reader, &state.limits);
+        let decoded = match state.timeouts.read {
+            Some(timeout) => tokio::time::timeout(timeout, read)
+                .await
+                .unwrap_or(Err(DecodeError::TimedOut)),
+            None => read.await,
+        };
+        // A kept-alive connection was only admitted for its first request
+        let admitted = match &decoded {
+            Ok(_) if !first => state.check_rate(&context),
+            _ => Ok(()),
+        };
+        first = false;
+        let (kind, encoded, keep_alive, subscription) = match (decoded, admitted) {
+            (Ok((request, header)), Ok(())) if is_pipelined(&request, &header) => {
+                let Ok(permit) = Arc::clone(&pipelined).acquire_owned().await else {
+                    break;
+                };
+                let (state, context) = (Arc::clone(&state), context.clone());
+                let writer = Arc::clone(&writer);
+                let answering = async move {
+                    let _permit = permit;
+                    let Some((kind, encoded, ..)) =
+                        answer_async(&state, request, header, context, start).await
+                    else {
+                        return;
+                    };
+                    let mut writer = writer.lock().await;
+                    // Hanging up on one request hangs up on the rest
+                    let Some(encoded) = encoded else {
+                        let _ = writer.shutdown().await;
+                        return;
+                    };
+                    state.metrics.record(kind, start.elapsed());
+                    if let Err(e) = write_response(&state, &mut *writer, &encoded).await {
+                        warn!(error = %e, "failed to send response");
+                    }
+                };
+                tokio::spawn(answering.instrument(Span::current()));
+                continue;
+            }
+            (Ok((request, header)), Ok(())) => {
+                // Requests read earlier are answered first
+                let Ok(_answered) = pipelined.acquire_many(MAX_PIPELINED as u32).await else {
+                    break;
+                };
+                match answer_async(&state, request, header, context.clone(), start).await {
+                    Some((kind, encoded, keep_alive, subscription)) => {
+                        (Some(kind), encoded, keep_alive, subscription)
+                    }
+                    None => return,
+                }
+            }
+            (Ok(_), Err(response)) => {
+                let encoded = Some(Encoded::Frame(response.to_bytes()));
+                (None, encoded, false, None)
+            }
+            (Err(DecodeError::Closed), _) => break,
+            (Err(e), _) => {
+                let bytes = decode_failure(e).to_bytes();
+                (None, Some(Encoded::Frame(bytes)), false, None)
+            }
+        };
+        // Dropping the stream without writing closes the connection without a response
+        let Some(encoded) = encoded else {
+            return;
+        };
+        // As with the blocking server, count the request before the client can see the response
+        if let Some(kind) = kind {
+            state.metrics.record(kind, start.elapsed());
+        }
+        let mut writer = writer.lock().await;
+        if let Err(e) = write_response(&state, &mut *writer, &encoded).await {
+            warn!(error = %e, "failed to send response");
+            return;
+        }
+        if let Some((notifications, header)) = subscription {
+            send_notifications(&state, &mut *writer, notifications, &header).await;
+            break;
+        }
+        if !keep_alive || state.is_stopped.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+    // Pipelined requests still being answered are sent their responses before hanging up
+    let _answered = pipelined.acquire_many(MAX_PIPELINED as u32).await;
+    let _ = writer.lock().await.shutdown().await;
+}
+
+// Whether `request`, sent with `header`, may be answered alongside others read on its connection:
+// it must have an id to match it to its response, and be on a connection kept open for more.
+// Subscriptions take over their connection, so they never are.
+fn is_pipelined(request: &Request, header: &RequestHeader) -> bool {
+    header.request_id.is_some()
+        && header.keep_alive
+        && !matches!(request, Request::Subscribe { .. })
+}
+
+// Answer `request` on tokio's blocking thread pool, since answering may block on the database.
+// Return None if answering failed.
+async fn answer_async(
+    state: &Arc<ServerState>,
+    request: Request,
+    header: RequestHeader,
+    context: RequestContext,
+    start: Instant,
+) -> Option<Answered> {
+    let state = Arc::clone(state);
+    let span = Span::current();
+    let answered = tokio::task::spawn_blocking(move || {
+        let _span = span.entered();
+        record_request(&state, &request);
+        let kind = request.kind();
+        let response = answer(&state, request, &header, &context);
+        log_answered(kind, &response, start.elapsed());
+        // Subscribe before telling the client, so it misses nothing published after
+        let subscription = match &response {
+            Response::Subscribed(term) => {
+                Some((state.feed.subscribe(term.clone()), header.clone()))
+            }
+            _ => None,
+        };
+        let encoded = encode_response(&state, response, &header);
+        (kind, encoded, header.keep_alive, subscription)
+    })
+    .await;
+    answered
+        .inspect_err(|e| error!(error = %e, "request handler failed"))
+        .ok()
+}
+
+// Write `encoded` to `stream`, giving up once the server's write timeout passes.
+async fn write_response<W: AsyncWrite + Unpin>(
+    state: &ServerState,
+    stream: &mut W,
+    encoded: &Encoded,
+) -> io::Result<()> {
+    let write = encoded.write_to_async(stream);
+    match state.timeouts.write {
+        Some(timeout) => tokio::time::timeout(timeout, write)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => write.await,
+    }
+}
+
+// Like the blocking server's `subscribe`, send the subscriber on `stream` a notification from
+// `notifications` for each document it subscribed to, until it hangs up or the server stops.
+async fn send_notifications<W: AsyncWrite + Unpin>(
+    state: &Arc<ServerState>,
+    stream: &mut W,
+    mut notifications: mpsc::Receiver<Notification>,
+    header: &RequestHeader,
+) {
+    loop {
+        // Waiting for the next one blocks, so the receiver is handed to a blocking task and back
+        let waiting = {
+            let state = Arc::clone(state);
+            tokio::task::spawn_blocking(move || {
+                let next = next_notification(&state, &notifications);
+                (notifications, next)
+            })
+        };
+        let Ok((receiver, Some((id, preview)))) = waiting.await else {
+            return;
+        };
+        notifications = receiver;
+        let response = Response::Notification { id, preview };
+        let Some(encoded) = encode_response(state, response, header) else {
+            return;
+        };
+        if let Err(e) = encoded.write_to_async(stream).await {
+            info!(error = %e, "subscriber hung up");
+            return;
+        }
+    }
+}
+
+impl Encoded {
+    // Like `write_to`, but without blocking.
+    async fn write_to_async<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> io::Result<()> {
+        match self {
+            Encoded::Frame(bytes) => stream.write_all(bytes).await?,
+            Encoded::Document(doc) => {
+                for frame in document_stream(doc) {
+                    stream.write_all(&frame).await?;
+                }
+            }
+        }
+        stream.flush().await
+    }
+}
@@ -0,0 +1,391 @@
+use crate::client::{Client, ClientPool};
+use crate::database::{PublishOptions, SearchOptions};
+use crate::message::{ErrorCode, Request, Response};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::thread;
+
+// A coordinator serves an archive split across several backend servers, each holding some of its
+// documents, so that it can grow larger than one machine's memory. Clients talk to the coordinator
+// as they would to any server.
+//
+// Each document is published to the backend its text hashes to, so that copies of a document land
+// on the same backend and its duplicates policy still catches them. The coordinator numbers
+// documents by interleaving the backends' ids: the document a backend numbers `id` is document
+// `id * backends + backend`. Requests about one document go to the backend holding it; searches go
+// to every backend at once, and their results are merged.
+//
+// Each backend ranks its own matches using its own statistics, so ranked searches are only as good
+// as the documents are evenly spread. Term suggestions are gathered from each backend's best, so a
+// term common overall but never among any backend's best may be left out.
+
+/// How many connections to keep open to each backend
+const BACKEND_CONNECTIONS: usize = 4;
+
+/// The backend servers a coordinator splits its archive across
+pub struct Shards {
+    /// A pool of connections to each backend, in the order they were given
+    backends: Vec<(SocketAddr, ClientPool)>,
+}
+
+impl Shards {
+    // Split the archive across the servers at `backends`, in this order, authenticating with
+    // `token` if given. The order decides which backend holds which documents, so it must stay the
+    // same for as long as the backends keep their archives.
+    pub fn new(backends: &[SocketAddr], token: Option<&str>) -> Self {
+        assert!(
+            !backends.is_empty(),
+            "a coordinator needs at least one backend"
+        );
+        let backends = backends
+            .iter()
+            .map(|&address| {
+                let client = Client::new(&address.ip().to_string(), address.port());
+                let client = match token {
+                    Some(token) => client.with_token(token),
+                    None => client,
+                };
+                (address, ClientPool::new(client, BACKEND_CONNECTIONS))
+            })
+            .collect();
+        Self { backends }
+    }
+
+    // Whether `request` is answered by the backends, rather than by the coordinator itself, when
+    // it is about the split archive rather than a collection.
+    pub fn forwards(request: &Request) -> bool {
+        !matches!(
+            request,
+            Request::Stats
+                | Request::Ping
+                | Request::Shutdown
+                | Request::CreateCollection { .. }
+                | Request::ListCollections
+                | Request::ResizePool { .. }
+        )
+    }
+
+    // Answer `request` from the backends.
+    pub fn respond(&self, request: Request) -> Response {
+        match request {
+            Request::Publish { ref doc } | Request::PublishWith { ref doc, .. } => {
+                let shard = self.shard_for(doc);
+                self.send(shard, &request, |response| match response {
+                    Response::PublishSuccess(id) => {
+                        Response::PublishSuccess(self.global(shard, id))
+                    }
+                    response => response,
+                })
+            }
+            Request::PublishBatch { docs, options } => self.publish_batch(docs, options),
+            Request::Retrieve { id, offset, length } => {
+                let (shard, local) = self.locate(id);
+                let request = Request::Retrieve {
+                    id: local,
+                    offset,
+                    length,
+                };
+                self.send(shard, &request, |response| with_id(response, id))
+            }
+            Request::Commit { id } => {
+                let (shard, local) = self.locate(id);
+                self.send(shard, &Request::Commit { id: local }, |response| {
+                    with_id(response, id)
+                })
+            }
+            Request::Update { id, doc } => {
+                let (shard, local) = self.locate(id);
+                let request = Request::Update { id: local, doc };
+                self.send(shard, &request, |response| with_id(response, id))
+            }
+            Request::Search { .. }
+            | Request::SearchPrefix { .. }
+            | Request::SearchSubstring { .. }
+            | Request::Query { .. } => self.gather(&request, |responses| {
+                let mut ids = Vec::new();
+                for (shard, response) in responses {
+                    let Response::SearchSuccess(found) = response else {
+                        return Err(response);
+                    };
+                    ids.extend(found.into_iter().map(|id| self.global(shard, id)));
+                }
+                ids.sort_unstable();
+                Ok(Response::SearchSuccess(ids))
+            }),
+            Request::SearchWith { word, options } => self.search_with(word, options),
+            Request::SearchRanked { word, k } => {
+                self.gather(&Request::SearchRanked { word, k }, |responses| {
+                    let mut results = Vec::new();
+                    for (shard, response) in responses {
+                        let Response::SearchRankedSuccess(found) = response else {
+                            return Err(response);
+                        };
+                        results.extend(
+                            found
+                                .into_iter()
+                                .map(|(id, score)| (self.global(shard, id), score)),
+                        );
+                    }
+                    results.sort_by(|(a, a_score), (b, b_score)| {
+                        b_score.total_cmp(a_score).then(a.cmp(b))
+                    });
+                    results.truncate(k);
+                    Ok(Response::SearchRankedSuccess(results))
+                })
+            }
+            Request::Count { .. } => self.gather(&request, |responses| {
+                let mut total = 0;
+                for (_, response) in responses {
+                    let Response::CountSuccess(count) = response else {
+                        return Err(response);
+                    };
+                    total += count;
+                }
+                Ok(Response::CountSuccess(total))
+            }),
+            Request::List { .. } => self.gather(&request, |responses| {
+                let mut summaries = Vec::new();
+                for (shard, response) in responses {
+                    let Response::ListSuccess(listed) = response else {
+                        return Err(response);
+                    };
+                    summaries.extend(listed.into_iter().map(|mut summary| {
+                        summary.id = self.global(shard, summary.id);
+                        summary
+                    }));
+                }
+                summaries.sort_unstable_by_key(|summary| summary.id);
+                Ok(Response::ListSuccess(summaries))
+            }),
+            Request::SuggestTerms { limit, .. } => self.gather(&request, |responses| {
+                let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+                for (_, response) in responses {
+                    let Response::SuggestSuccess(terms) = response else {
+                        return Err(response);
+                    };
+                    for (term, count) in terms {
+                        *counts.entry(term).or_default() += count;
+                    }
+                }
+                let mut terms: Vec<_> = counts.into_iter().collect();
+                terms.sort_by(|(a, a_count), (b, b_count)| {
+                    b_count.cmp(a_count).then_with(|| a.cmp(b))
+                });
+                terms.truncate(limit);
+                Ok(Response::SuggestSuccess(terms))
+            }),
+            // Each backend's first terms include every term among the first overall that it has,
+            // so the merged counts are exact
+            Request::TermStats { limit, .. } => self.gather(&request, |responses| {
+                let mut stats: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+                for (_, response) in responses {
+                    let Response::TermStatsSuccess(terms) = response else {
+                        return Err(response);
+                    };
+                    for (term, documents, occurrences) in terms {
+                        let entry = stats.entry(term).or_default();
+                        entry.0 += documents;
+                        entry.1 += occurrences;
+                    }
+                }
+                let terms = stats
+                    .into_iter()
+                    .take(limit)
+                    .map(|(term, (documents, occurrences))| (term, documents, occurrences))
+                    .collect();
+                Ok(Response::TermStatsSuccess(terms))
+            }),
+            request => Response::failure(
+                ErrorCode::Unsupported,
+                format!("a coordinator can't answer {} requests", request.kind()),
+            ),
+        }
+    }
+
+    // Publish `docs` as `options` asks, each to the backend its text hashes to, as one batch per
+    // backend. The batches are published separately, so if one fails, those before it may still
+    // have been published.
+    fn publish_batch(&self, docs: Vec<String>, options: PublishOptions) -> Response {
+        let mut batches = vec![Vec::new(); self.backends.len()];
+        let shards: Vec<usize> = docs.iter().map(|doc| self.shard_for(doc)).collect();
+        for (doc, &shard) in docs.into_iter().zip(&shards) {
+            batches[shard].push(doc);
+        }
+        let mut published = vec![Vec::new().into_iter(); self.backends.len()];
+        for (shard, docs) in batches.into_iter().enumerate() {
+            if docs.is_empty() {
+                continue;
+            }
+            let request = Request::PublishBatch {
+                docs,
+                options: options.clone(),
+            };
+            match self.send(shard, &request, |response| response) {
+                Response::PublishBatchSuccess(ids) => published[shard] = ids.into_iter(),
+                response => return response,
+            }
+        }
+        // Put the ids back in the order the documents were given
+        let ids = shards
+            .into_iter()
+            .map(|shard| {
+                let id = published[shard].next().unwrap_or_default();
+                self.global(shard, id)
+            })
+            .collect();
+        Response::PublishBatchSuccess(ids)
+    }
+
+    // Answer a search as `options` asks. Each backend is asked for as many matches as could be on
+    // the page, and the page is cut from their merged matches. Ordering by publication time isn't
+    // supported, as backends don't report it.
+    fn search_with(&self, word: String, options: SearchOptions) -> Response {
+        if options.newest_first {
+            return Response::failure(
+                ErrorCode::Unsupported,
+                "a coordinator can't order matches by publication time",
+            );
+        }
+        let page = SearchOptions {
+            offset: 0,
+            limit: options
+                .limit
+                .map(|limit| limit.saturating_add(options.offset)),
+            ..options.clone()
+        };
+        let request = Request::SearchWith {
+            word,
+            options: page,
+        };
+        self.gather(&request, |responses| {
+            let mut hits = Vec::new();
+            let mut total_hits = 0;
+            for (shard, response) in responses {
+                let response = match response {
+                    Response::Paged {
+                        response,
+                        total_hits: hits,
+                    } => {
+                        total_hits += hits;
+                        *response
+                    }
+                    response => response,
+                };
+                match response {
+                    Response::SearchSuccess(ids) => {
+                        hits.extend(ids.into_iter().map(|id| (self.global(shard, id), None)))
+                    }
+                    Response::SearchSnippetsSuccess(results) => hits.extend(
+                        results
+                            .into_iter()
+                            .map(|(id, snippet)| (self.global(shard, id), Some(snippet))),
+                    ),
+                    response => return Err(response),
+                }
+            }
+            hits.sort_unstable_by_key(|(id, _)| *id);
+            let hits = hits
+                .into_iter()
+                .skip(options.offset)
+                .take(options.limit.unwrap_or(usize::MAX));
+            let response = match options.snippet_words {
+                Some(_) => Response::SearchSnippetsSuccess(
+                    hits.map(|(id, snippet)| (id, snippet.unwrap_or_default()))
+                        .collect(),
+                ),
+                None => Response::SearchSuccess(hits.map(|(id, _)| id).collect()),
+            };
+            Ok(match options.is_paged() {
+                true => Response::Paged {
+                    response: Box::new(response),
+                    total_hits,
+                },
+                false => response,
+            })
+        })
+    }
+
+    // Send `request` to the backend `shard`, and answer with what `map` makes of its response. A
+    // backend that doesn't answer is reported as a failure.
+    fn send(
+        &self,
+        shard: usize,
+        request: &Request,
+        map: impl FnOnce(Response) -> Response,
+    ) -> Response {
+        let (address, pool) = &self.backends[shard];
+        match pool.send(request) {
+            Some(response) => map(response),
+            None => unreachable_backend(*address),
+        }
+    }
+
+    // Send `request` to every backend at once, and answer with what `merge` makes of their
+    // responses, each with the backend it came from. `merge` fails with the response to answer
+    // with instead if one of them isn't what it expects, e.g. a failure.
+    fn gather(
+        &self,
+        request: &Request,
+        merge: impl FnOnce(Vec<(usize, Response)>) -> Result<Response, Response>,
+    ) -> Response {
+        let responses: Vec<_> = thread::scope(|scope| {
+            let sent: Vec<_> = self
+                .backends
+                .iter()
+                .map(|(_, pool)| scope.spawn(|| pool.send(request)))
+                .collect();
+            sent.into_iter()
+                .map(|handle| handle.join().ok().flatten())
+                .collect()
+        });
+        let mut answered = Vec::with_capacity(responses.len());
+        for (shard, response) in responses.into_iter().enumerate() {
+            match response {
+                Some(response) => answered.push((shard, response)),
+                None => return unreachable_backend(self.backends[shard].0),
+            }
+        }
+        merge(answered).unwrap_or_else(|response| response)
+    }
+
+    // The backend to publish `doc` to.
+    fn shard_for(&self, doc: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        doc.hash(&mut hasher);
+        (hasher.finish() % self.backends.len() as u64) as usize
+    }
+
+    // The coordinator's id for the document backend `shard` numbers `id`.
+    fn global(&self, shard: usize, id: usize) -> usize {
+        id * self.backends.len() + shard
+    }
+
+    // The backend holding the document the coordinator numbers `id`, and the backend's id for it.
+    fn locate(&self, id: usize) -> (usize, usize) {
+        (id % self.backends.len(), id / self.backends.len())
+    }
+}
+
+// Put the coordinator's id `id` back into `response`, a backend's answer to a request about the
+// document it numbers differently.
+fn with_id(response: Response, id: usize) -> Response {
+    match response {
+        Response::CommitSuccess(_) => Response::CommitSuccess(id),
+        Response::UpdateSuccess(_) => Response::UpdateSuccess(id),
+        Response::Failure {
+            code: ErrorCode::NotFound,
+            ..
+        } => Response::not_found(id),
+        response => response,
+    }
+}
+
+// The failure to answer with when the backend at `address` can't be reached.
+fn unreachable_backend(address: SocketAddr) -> Response {
+    Response::failure(
+        ErrorCode::Internal,
+        format!("the backend at {} didn't answer", address),
+    )
+}
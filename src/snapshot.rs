@@ -0,0 +1,54 @@
+use crate::database::Database;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// A snapshot is the whole archive saved to one file, so that a restarted server can load it rather
+// than rebuild the archive a change at a time. The file holds the number of the last write-ahead
+// log entry it includes, as a big-endian u64, followed by the archive as `Database::save` writes
+// it. A server that keeps no write-ahead log records 0.
+//
+// Snapshots are written to a temporary file beside the real one and renamed over it once
+// complete, so a crash while writing one leaves the previous snapshot in place.
+
+/// When a server saves a snapshot of its archive, and where to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotPolicy {
+    /// The file to save snapshots to, and load one from on startup
+    pub path: PathBuf,
+    /// The longest to go between snapshots, or None to only take them after `writes` changes
+    pub interval: Option<Duration>,
+    /// Take a snapshot after this many changes to the archive, or None to only take them every
+    /// `interval`
+    pub writes: Option<usize>,
+}
+
+// Save `database` to a snapshot at `path`, recording that it includes every write-ahead log
+// entry up to `seq`.
+pub fn save(database: &Database, seq: u64, path: &Path) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let file = File::create(&temporary)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&seq.to_be_bytes())?;
+    database.save(&mut writer)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+// Load the snapshot at `path` into `database`, which should be empty, returning the number of
+// the last write-ahead log entry it includes. Return None if there is no snapshot yet.
+pub fn load(database: &Database, path: &Path) -> io::Result<Option<u64>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
+    let mut seq = [0_u8; 8];
+    reader.read_exact(&mut seq)?;
+    database.restore(reader)?;
+    Ok(Some(u64::from_be_bytes(seq)))
+}
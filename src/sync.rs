@@ -0,0 +1,77 @@
+use std::cell::Cell;
+use std::sync::{
+    Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+use tracing::warn;
+
+// A lock is poisoned when a thread panics while holding it. Rather than letting that panic spread
+// to every later request that touches the same data, these take the lock anyway: the archive's
+// locks only guard collections that are never left half-updated in a way later operations can't
+// cope with. The poison is cleared once it has been logged, so each panic is only reported once.
+// The thread that recovered the lock also notes it, so the server can tell the client whose
+// request ran into it.
+
+thread_local! {
+    /// Whether this thread has recovered a poisoned lock since `take_recovered` last looked
+    static RECOVERED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn recovered() {
+    warn!("recovered a lock poisoned by a thread that panicked while holding it");
+    RECOVERED.set(true);
+}
+
+// Whether this thread has recovered a poisoned lock since the last call.
+pub(crate) fn take_recovered() -> bool {
+    RECOVERED.replace(false)
+}
+
+// Lock `mutex`, recovering it if it is poisoned.
+pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        recovered();
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+// Take a reader lock of `lock`, recovering it if it is poisoned.
+pub(crate) fn read<T: ?Sized>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|poisoned| {
+        recovered();
+        lock.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+// Take a reader lock of `lock` if no one holds a writer lock of it, recovering it if it is
+// poisoned.
+pub(crate) fn try_read<T: ?Sized>(lock: &RwLock<T>) -> Option<RwLockReadGuard<'_, T>> {
+    match lock.try_read() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poisoned)) => {
+            recovered();
+            lock.clear_poison();
+            Some(poisoned.into_inner())
+        }
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+// Take a writer lock of `lock`, recovering it if it is poisoned.
+pub(crate) fn write<T: ?Sized>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|poisoned| {
+        recovered();
+        lock.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+// Wait on `condvar` with the lock `guard` holds, recovering the lock if it is poisoned meanwhile.
+// The poison is cleared the next time the lock is taken.
+pub(crate) fn wait<'a, T>(condvar: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    condvar.wait(guard).unwrap_or_else(|poisoned| {
+        recovered();
+        poisoned.into_inner()
+    })
+}
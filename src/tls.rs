@@ -0,0 +1,48 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::io;
+use std::sync::Arc;
+
+// Helpers for building the rustls configurations used by the client and server. Certificates and
+// keys are read from PEM files, which is the format produced by most tools (openssl, certbot,
+// mkcert, ...).
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Build a server configuration from the PEM certificate chain at `cert_path` and the PEM private
+/// key at `key_path`.
+pub fn server_config(cert_path: &str, key_path: &str) -> io::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(invalid_data)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid_data)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(invalid_data)?;
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+/// Build a client configuration that trusts the certificates in the PEM bundle at `ca_path`.
+pub fn client_config(ca_path: &str) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_path).map_err(invalid_data)? {
+        roots
+            .add(cert.map_err(invalid_data)?)
+            .map_err(invalid_data)?;
+    }
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(Arc::new(config))
+}
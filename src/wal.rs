@@ -0,0 +1,215 @@
+use crate::database::{self, Database, DumpedDocument, Duplicate, PublishOptions};
+use crate::message::Response;
+use crate::sync;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Every request that changes the archive is appended to the write-ahead log, and the log is synced
+// to disk, before the change is made and acknowledged. A server restarted with the same log
+// replays it to get back every change it acknowledged. Entries are JSON, one per line, and hold
+// everything needed to repeat the change exactly, including when documents were published. Each
+// is numbered, so that once a snapshot of the archive is taken the entries it already holds can be
+// told apart from later ones.
+
+/// One line of a write-ahead log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct WalRecord {
+    /// Where the entry comes in the sequence of every change ever logged, starting from 1
+    seq: u64,
+    #[serde(flatten)]
+    entry: WalEntry,
+}
+
+/// One change to the archive, as recorded in a write-ahead log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WalEntry {
+    Publish {
+        doc: String,
+        /// When the document was published, in seconds since the Unix epoch
+        published_at: u64,
+        #[serde(flatten)]
+        options: PublishOptions,
+    },
+    PublishBatch {
+        docs: Vec<String>,
+        /// When the documents were published, in seconds since the Unix epoch
+        published_at: u64,
+        #[serde(flatten)]
+        options: PublishOptions,
+    },
+    Commit {
+        id: usize,
+    },
+    Update {
+        id: usize,
+        doc: String,
+    },
+}
+
+impl WalEntry {
+    /// Publishing `doc` as `options` asks, now
+    pub fn publish(doc: String, options: PublishOptions) -> Self {
+        WalEntry::Publish {
+            doc,
+            published_at: database::now(),
+            options,
+        }
+    }
+
+    /// Publishing every document in `docs` as `options` asks, now
+    pub fn publish_batch(docs: Vec<String>, options: PublishOptions) -> Self {
+        WalEntry::PublishBatch {
+            docs,
+            published_at: database::now(),
+            options,
+        }
+    }
+
+    // Make this change to `database`, returning the response to the request that asked for it.
+    pub fn apply(self, database: &Database) -> Response {
+        match self {
+            WalEntry::Publish {
+                doc,
+                published_at,
+                options,
+            } => match database.try_publish_with_at(doc, &options, published_at) {
+                Ok(id) => Response::PublishSuccess(id),
+                Err(Duplicate(id)) => Response::duplicate(id),
+            },
+            WalEntry::PublishBatch {
+                docs,
+                published_at,
+                options,
+            } => match database.try_publish_batch_at(docs, &options, published_at) {
+                Ok(ids) => Response::PublishBatchSuccess(ids),
+                Err(Duplicate(id)) => Response::duplicate(id),
+            },
+            WalEntry::Commit { id } => match database.commit(id) {
+                true => Response::CommitSuccess(id),
+                false => Response::not_found(id),
+            },
+            WalEntry::Update { id, doc } => match database.update(id, doc) {
+                true => Response::UpdateSuccess(id),
+                false => Response::not_found(id),
+            },
+        }
+    }
+}
+
+// Importing a dumped document publishes it again as it was, including when it was published.
+impl From<DumpedDocument> for WalEntry {
+    fn from(dumped: DumpedDocument) -> Self {
+        WalEntry::Publish {
+            doc: dumped.text,
+            published_at: dumped.published_at,
+            options: dumped.options,
+        }
+    }
+}
+
+/// An append-only log of the changes made to an archive, which can be replayed to rebuild it
+pub struct WriteAheadLog {
+    path: PathBuf,
+    writer: Mutex<Writer>,
+}
+
+// The log file, along with the number to give the next entry appended to it
+struct Writer {
+    file: File,
+    next_seq: u64,
+}
+
+impl WriteAheadLog {
+    // Open the log at `path` for appending, creating it if needed. A last entry that was only
+    // partly written, because the server died while writing it, was never acknowledged and is
+    // cut off.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let complete = contents
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |last| last + 1);
+        if complete < contents.len() {
+            file.set_len(complete as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            path,
+            writer: Mutex::new(Writer { file, next_seq: 1 }),
+        })
+    }
+
+    // Make every change in the log numbered after `after` to `database`, in order, returning how
+    // many there were. Changes up to `after` are already in `database`, e.g. because it was loaded
+    // from a snapshot taken then. Entries appended later are numbered after both.
+    pub fn replay(&self, database: &Database, after: u64) -> io::Result<usize> {
+        let mut writer = sync::lock(&self.writer);
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut replayed = 0;
+        writer.next_seq = writer.next_seq.max(after + 1);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: WalRecord = serde_json::from_str(&line)?;
+            writer.next_seq = writer.next_seq.max(record.seq + 1);
+            if record.seq > after {
+                record.entry.apply(database);
+                replayed += 1;
+            }
+        }
+        Ok(replayed)
+    }
+
+    // Append `entry` to the log and sync it to disk, then make the change to `database`. Changes
+    // are made in the order they are logged, so replaying the log gives every document the same
+    // id. If the entry can't be logged, the change isn't made.
+    pub fn append(&self, entry: WalEntry, database: &Database) -> io::Result<Response> {
+        let mut writer = sync::lock(&self.writer);
+        let record = WalRecord {
+            seq: writer.next_seq,
+            entry,
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        let file = &mut writer.file;
+        let length = file.metadata()?.len();
+        if let Err(e) = file
+            .write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+        {
+            // Don't leave part of an entry for the next one to be appended to
+            let _ = file
+                .set_len(length)
+                .and_then(|_| file.seek(SeekFrom::End(0)));
+            return Err(e);
+        }
+        writer.next_seq += 1;
+        Ok(record.entry.apply(database))
+    }
+
+    // Call `snapshot` with the number of the last entry logged, while no more can be appended,
+    // and once it has saved every change up to there, empty the log. The numbering carries on
+    // from where it was.
+    pub fn checkpoint<F: FnOnce(u64) -> io::Result<()>>(&self, snapshot: F) -> io::Result<()> {
+        let mut writer = sync::lock(&self.writer);
+        snapshot(writer.next_seq - 1)?;
+        writer.file.set_len(0)?;
+        writer.file.seek(SeekFrom::Start(0))?;
+        writer.file.sync_data()
+    }
+}
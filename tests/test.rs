@@ -19,8 +19,8 @@ mod test_multimap {
     fn test_get_after_set_single_5() {
         fn get_after_set_single(k: i32, v: usize) {
             let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
-            map.set(UnCloneable(k), v as usize);
-            assert_eq!(map.get(&UnCloneable(k)), vec![v as usize]);
+            map.set(UnCloneable(k), v);
+            assert_eq!(map.get(&UnCloneable(k)), vec![v]);
         }
         quickcheck(get_after_set_single as fn(i32, usize));
     }
@@ -30,7 +30,7 @@ mod test_multimap {
         fn get_after_set_multi(k: i32, values: HashSet<usize>) {
             let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
             for values in values.iter() {
-                map.set(UnCloneable(k), *values as usize);
+                map.set(UnCloneable(k), *values);
             }
             let result = map.get(&UnCloneable(k));
             println!("+==================+");
@@ -39,7 +39,7 @@ mod test_multimap {
             println!("{:?}", result);
             assert_eq!(result.len(), values.len());
             for values in values.iter() {
-                assert!(result.contains(&(*values as usize)));
+                assert!(result.contains(values));
             }
         }
         quickcheck(get_after_set_multi as fn(i32, HashSet<usize>));
@@ -49,10 +49,10 @@ mod test_multimap {
         fn get_from_large_map(k: i32, v: usize, others: Vec<(i32, usize)>) {
             let map = ConcurrentMultiMap::<UnCloneable, usize>::new(1000);
             for (k, v) in others.iter() {
-                map.set(UnCloneable(*k), *v as usize);
+                map.set(UnCloneable(*k), *v);
             }
-            map.set(UnCloneable(k), v as usize);
-            assert!(map.get(&UnCloneable(k)).contains(&(v as usize)));
+            map.set(UnCloneable(k), v);
+            assert!(map.get(&UnCloneable(k)).contains(&(v)));
         }
         quickcheck(get_from_large_map as fn(i32, usize, Vec<(i32, usize)>));
     }
@@ -60,15 +60,177 @@ mod test_multimap {
     fn test_no_duplicates_5() {
         fn no_duplicates(k: i32, v: usize) {
             let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
-            map.set(UnCloneable(k), v as usize);
-            map.set(UnCloneable(k), v as usize);
-            map.set(UnCloneable(k), v as usize);
-            map.set(UnCloneable(k), v as usize);
-            assert_eq!(map.get(&UnCloneable(k)), vec![v as usize]);
+            map.set(UnCloneable(k), v);
+            map.set(UnCloneable(k), v);
+            map.set(UnCloneable(k), v);
+            map.set(UnCloneable(k), v);
+            assert_eq!(map.get(&UnCloneable(k)), vec![v]);
         }
         quickcheck(no_duplicates as fn(i32, usize));
     }
     #[test]
+    fn test_remove_5() {
+        fn remove(k: i32, v: usize, others: Vec<usize>) {
+            let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
+            map.set(UnCloneable(k), v);
+            for other in others.iter() {
+                map.set(UnCloneable(k.wrapping_add(1)), *other);
+            }
+            assert!(map.remove(&UnCloneable(k), &v));
+            assert!(!map.remove(&UnCloneable(k), &v));
+            assert_eq!(map.get(&UnCloneable(k)).len(), 0);
+            assert_eq!(
+                map.get(&UnCloneable(k.wrapping_add(1))).len(),
+                others
+                    .iter()
+                    .collect::<std::collections::HashSet<_>>()
+                    .len()
+            );
+        }
+        quickcheck(remove as fn(i32, usize, Vec<usize>));
+    }
+    #[test]
+    fn test_remove_all_5() {
+        fn remove_all(k: i32, values: std::collections::HashSet<usize>, other: usize) {
+            let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
+            for value in values.iter() {
+                map.set(UnCloneable(k), *value);
+            }
+            map.set(UnCloneable(k.wrapping_add(1)), other);
+            let mut removed = map.remove_all(&UnCloneable(k));
+            removed.sort();
+            let mut expected = values.into_iter().collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(removed, expected);
+            assert_eq!(map.get(&UnCloneable(k)).len(), 0);
+            assert_eq!(map.get(&UnCloneable(k.wrapping_add(1))), vec![other]);
+        }
+        quickcheck(remove_all as fn(i32, std::collections::HashSet<usize>, usize));
+    }
+    #[test]
+    fn test_set_after_remove_5() {
+        fn set_after_remove(k: i32, v: usize, w: usize) {
+            let map = ConcurrentMultiMap::<UnCloneable, usize>::new(10);
+            map.set(UnCloneable(k), v);
+            assert!(map.remove(&UnCloneable(k), &v));
+            map.set(UnCloneable(k), w);
+            map.set(UnCloneable(k), w);
+            assert_eq!(map.get(&UnCloneable(k)), vec![w]);
+            assert_eq!(map.remove_all(&UnCloneable(k)), vec![w]);
+            assert!(map.remove_all(&UnCloneable(k)).is_empty());
+        }
+        quickcheck(set_after_remove as fn(i32, usize, usize));
+    }
+    #[test]
+    fn test_resizes_when_overloaded_5() {
+        fn resizes_when_overloaded(pairs: Vec<(i32, usize)>) {
+            let map = ConcurrentMultiMap::<UnCloneable, usize>::new(1);
+            for (k, v) in pairs.iter() {
+                map.set(UnCloneable(*k), *v);
+            }
+            let keys = pairs
+                .iter()
+                .map(|(k, _)| *k)
+                .collect::<std::collections::HashSet<_>>();
+            assert_eq!(map.key_count(), keys.len());
+            assert!(map.key_count() <= map.bucket_count() * MAX_LOAD_FACTOR);
+            for (k, v) in pairs.iter() {
+                assert!(map.get(&UnCloneable(*k)).contains(v));
+            }
+        }
+        quickcheck(resizes_when_overloaded as fn(Vec<(i32, usize)>));
+    }
+    #[test]
+    fn test_introspection_5() {
+        use std::collections::HashSet;
+        fn introspection(pairs: Vec<(i32, usize)>, removed: usize) {
+            let map = ConcurrentMultiMap::<i32, usize>::new(4);
+            for (k, v) in pairs.iter() {
+                map.set(*k, *v);
+            }
+            let mut expected = pairs.iter().copied().collect::<HashSet<_>>();
+            if let Some((k, v)) = pairs.get(removed % pairs.len().max(1)) {
+                map.remove(k, v);
+                expected.remove(&(*k, *v));
+            }
+            assert_eq!(map.len(), expected.len());
+            assert_eq!(map.is_empty(), expected.is_empty());
+            assert_eq!(map.iter().collect::<HashSet<_>>(), expected);
+            let keys = expected.iter().map(|(k, _)| *k).collect::<HashSet<_>>();
+            assert_eq!(map.key_count(), keys.len());
+            assert_eq!(map.keys().into_iter().collect::<HashSet<_>>(), keys);
+            for (k, v) in pairs.iter() {
+                assert_eq!(map.contains(k, v), expected.contains(&(*k, *v)));
+                assert_eq!(map.get_count(k), map.get(k).len());
+            }
+        }
+        quickcheck(introspection as fn(Vec<(i32, usize)>, usize));
+    }
+    #[test]
+    fn test_serde_round_trip_5() {
+        fn serde_round_trip(pairs: Vec<(i32, usize)>) {
+            use std::collections::HashSet;
+            let map = ConcurrentMultiMap::<i32, usize>::new(2);
+            for (k, v) in pairs.iter() {
+                map.set(*k, *v);
+            }
+            let json = serde_json::to_string(&map).unwrap();
+            let loaded: ConcurrentMultiMap<i32, usize> = serde_json::from_str(&json).unwrap();
+            assert_eq!(loaded.len(), map.len());
+            assert_eq!(loaded.key_count(), map.key_count());
+            assert_eq!(
+                loaded.iter().collect::<HashSet<_>>(),
+                map.iter().collect::<HashSet<_>>()
+            );
+            for (k, _) in pairs.iter() {
+                assert_eq!(loaded.get(k), map.get(k));
+            }
+        }
+        quickcheck(serde_round_trip as fn(Vec<(i32, usize)>));
+    }
+    #[test]
+    fn test_with_hasher_5() {
+        fn with_hasher(pairs: Vec<(i32, usize)>) {
+            use std::collections::hash_map::RandomState;
+            let map = ConcurrentMultiMap::<UnCloneable, usize, RandomState>::with_hasher(
+                4,
+                RandomState::new(),
+            );
+            for (k, v) in pairs.iter() {
+                map.set(UnCloneable(*k), *v);
+            }
+            for (k, v) in pairs.iter() {
+                assert!(map.get(&UnCloneable(*k)).contains(v));
+            }
+            for (k, v) in pairs.iter() {
+                map.remove(&UnCloneable(*k), v);
+            }
+            assert_eq!(map.key_count(), 0);
+        }
+        quickcheck(with_hasher as fn(Vec<(i32, usize)>));
+    }
+    #[test]
+    fn test_resizes_concurrently_5() {
+        use std::sync::Arc;
+        let map = Arc::new(ConcurrentMultiMap::<UnCloneable, usize>::new(1));
+        let threads = (0..THREADS).map(|t| {
+            let map = Arc::clone(&map);
+            std::thread::spawn(move || {
+                for i in 0..1000 {
+                    map.set(UnCloneable((t * 1000 + i) as i32), i);
+                }
+            })
+        });
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(map.key_count(), THREADS * 1000);
+        assert!(map.bucket_count() >= THREADS * 1000 / MAX_LOAD_FACTOR);
+        for t in 0..THREADS {
+            for i in 0..1000 {
+                assert_eq!(map.get(&UnCloneable((t * 1000 + i) as i32)), vec![i]);
+            }
+        }
+    }
+    #[test]
     fn passes_stress_test_10() {
         fn passes_stress_test(tuples: Vec<(i32, usize, bool)>) {
             use std::sync::Arc;
@@ -85,7 +247,7 @@ mod test_multimap {
                 std::thread::spawn(move || {
                     for (k, v, is_write) in chunk.iter() {
                         if *is_write {
-                            map.set(UnCloneable(*k), *v as usize);
+                            map.set(UnCloneable(*k), *v);
                         } else {
                             map.get(&UnCloneable(*k));
                         }
@@ -96,6 +258,269 @@ mod test_multimap {
         }
         quickcheck(passes_stress_test as fn(Vec<(i32, usize, bool)>));
     }
+
+    #[test]
+    fn test_recovers_from_poisoned_locks_5() {
+        // Comparing a negative value panics, which happens with its bucket locked for writing
+        #[derive(Debug, Clone)]
+        struct Touchy(i32);
+        impl PartialEq for Touchy {
+            fn eq(&self, other: &Self) -> bool {
+                assert!(self.0 >= 0 && other.0 >= 0);
+                self.0 == other.0
+            }
+        }
+        impl Eq for Touchy {}
+        impl PartialOrd for Touchy {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Touchy {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                assert!(self.0 >= 0 && other.0 >= 0);
+                self.0.cmp(&other.0)
+            }
+        }
+        let map = ConcurrentMultiMap::new(1);
+        map.set("key", Touchy(0));
+        let set = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            map.set("key", Touchy(-1));
+        }));
+        assert!(set.is_err());
+
+        assert_eq!(map.get("key"), vec![Touchy(0)]);
+        map.set("key", Touchy(1));
+        map.set("other", Touchy(2));
+        assert_eq!(map.get("key"), vec![Touchy(0), Touchy(1)]);
+        assert_eq!(map.len(), 3);
+    }
+}
+
+// ============================ INDEX ============================
+mod test_index {
+    use super::*;
+    use ngram::index::*;
+    use std::collections::{BTreeMap, BTreeSet};
+    #[test]
+    fn test_matches_model_across_flushes_5() {
+        fn matches_model(postings: Vec<(u8, usize, u8)>) {
+            let index = SegmentedIndex::with_flush_threshold(8, 4);
+            let mut model: BTreeMap<String, BTreeSet<usize>> = BTreeMap::new();
+            for (term, id, action) in postings.iter() {
+                let term = (term % 16).to_string();
+                index.insert(vec![term.clone()], *id);
+                model.entry(term).or_default().insert(*id);
+                match action % 8 {
+                    0 => index.flush(),
+                    1 => index.merge(),
+                    _ => {}
+                }
+            }
+            for (term, ids) in model.iter() {
+                assert_eq!(index.get(term), ids.iter().copied().collect::<Vec<_>>());
+            }
+        }
+        quickcheck(matches_model as fn(Vec<(u8, usize, u8)>));
+    }
+
+    #[test]
+    fn test_remove_matches_model_5() {
+        fn matches_model(operations: Vec<(u8, u8, u8)>) {
+            let index = SegmentedIndex::with_flush_threshold(8, 4);
+            let mut model: BTreeMap<String, BTreeSet<usize>> = BTreeMap::new();
+            for (term, id, action) in operations.iter() {
+                let (term, id) = ((term % 8).to_string(), (id % 8) as usize);
+                let present = model.get(&term).is_some_and(|ids| ids.contains(&id));
+                match action % 8 {
+                    0 => index.flush(),
+                    1 => index.merge(),
+                    2 | 3 => {
+                        index.remove(vec![term.clone()], id);
+                        model.entry(term).or_default().remove(&id);
+                    }
+                    // Postings are only inserted while absent
+                    _ if !present => {
+                        index.insert(vec![term.clone()], id);
+                        model.entry(term).or_default().insert(id);
+                    }
+                    _ => {}
+                }
+            }
+            for (term, ids) in model.iter() {
+                assert_eq!(index.get(term), ids.iter().copied().collect::<Vec<_>>());
+                assert_eq!(index.count(term), ids.len());
+            }
+            let counts = model
+                .into_iter()
+                .filter(|(_, ids)| !ids.is_empty())
+                .map(|(term, ids)| (term, ids.len()))
+                .collect::<Vec<_>>();
+            assert_eq!(index.terms_with_prefix(""), counts);
+        }
+        quickcheck(matches_model as fn(Vec<(u8, u8, u8)>));
+    }
+
+    #[test]
+    fn test_get_prefix_matches_model_5() {
+        fn matches_model(postings: Vec<(u8, usize, u8)>, prefix: u8) {
+            let index = SegmentedIndex::with_flush_threshold(8, 4);
+            let mut model: BTreeMap<String, BTreeSet<usize>> = BTreeMap::new();
+            for (term, id, action) in postings.iter() {
+                let term = (term % 64).to_string();
+                if model.get(&term).is_some_and(|ids| ids.contains(id)) {
+                    continue;
+                }
+                index.insert(vec![term.clone()], *id);
+                model.entry(term).or_default().insert(*id);
+                match action % 8 {
+                    0 => index.flush(),
+                    1 => index.merge(),
+                    _ => {}
+                }
+            }
+            let prefix = (prefix % 8).to_string();
+            let expected: BTreeSet<usize> = model
+                .iter()
+                .filter(|(term, _)| term.starts_with(&prefix))
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect();
+            assert_eq!(
+                index.get_prefix(&prefix),
+                expected.into_iter().collect::<Vec<_>>()
+            );
+        }
+        quickcheck(matches_model as fn(Vec<(u8, usize, u8)>, u8));
+    }
+
+    #[test]
+    fn test_concurrent_writes_survive_flushes_5() {
+        use std::sync::Arc;
+        let index = Arc::new(SegmentedIndex::with_flush_threshold(8, 16));
+        let writers = (0..THREADS)
+            .map(|t| {
+                let index = Arc::clone(&index);
+                std::thread::spawn(move || {
+                    for i in 0..200 {
+                        index.insert(vec![format!("t{}", i % 7), "all".to_string()], t * 200 + i);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..20 {
+            index.flush();
+            index.merge();
+        }
+        writers.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(index.get("all"), (0..THREADS * 200).collect::<Vec<_>>());
+        index.flush();
+        index.merge();
+        assert_eq!(index.segment_count(), 1);
+        assert_eq!(index.get("all").len(), THREADS * 200);
+    }
+}
+
+// ============================ BLOB STORE ============================
+mod test_blob_store {
+    use super::THREADS;
+    use ngram::blob_store::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_insert_get_5() {
+        let store = BlobStore::new(4);
+        assert!(store.is_empty());
+        let ids = (0..10)
+            .map(|i| store.insert_with(|id| (id, i * 10)))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (0..10).collect::<Vec<_>>());
+        assert_eq!(store.len(), 10);
+        assert_eq!(store.get(7, |value| *value), Some((7, 70)));
+        assert_eq!(store.get(10, |value| *value), None);
+        assert_eq!(store.try_get(3, |value| value.1), Ok(Some(30)));
+        assert_eq!(store.get_mut(3, |value| value.1 += 1), Some(()));
+        assert_eq!(store.get(3, |value| value.1), Some(31));
+
+        let batch = store.insert_batch_with(vec![(0, 100), (0, 110)], |ids| {
+            assert_eq!(ids, 10..12);
+        });
+        assert_eq!(batch, 10..12);
+        let all = store.with_all(|values| values.iter().map(|value| value.1).collect::<Vec<_>>());
+        assert_eq!(all.len(), 12);
+        assert_eq!(&all[10..], &[100, 110]);
+    }
+
+    #[test]
+    fn test_concurrent_inserts_5() {
+        let store = Arc::new(BlobStore::new(8));
+        let writers = (0..THREADS)
+            .map(|t| {
+                let store = Arc::clone(&store);
+                std::thread::spawn(move || {
+                    for i in 0..100 {
+                        if i % 10 == 0 {
+                            let ids = store.insert_batch_with(vec![t; 5], |_| {});
+                            assert_eq!(ids.len(), 5);
+                        } else {
+                            let id = store.insert_with(|_| t);
+                            assert_eq!(store.get(id, |value| *value), Some(t));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        // Every visit sees consecutive ids with no gaps, however many inserts are in progress
+        for _ in 0..20 {
+            store.with_all(|values| assert!(values.iter().all(|value| **value < THREADS)));
+        }
+        writers.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(store.len(), THREADS * (90 + 10 * 5));
+        let counts = store.with_all(|values| {
+            let mut counts = vec![0; THREADS];
+            values.iter().for_each(|value| counts[**value] += 1);
+            counts
+        });
+        assert!(counts.iter().all(|count| *count == 140));
+    }
+}
+
+// ============================ CACHE ============================
+mod test_cache {
+    use ngram::cache::*;
+
+    #[test]
+    fn test_evicts_least_recently_used_5() {
+        let cache = LruCache::new(2);
+        let generation = cache.generation();
+        cache.insert("a", 1, generation);
+        cache.insert("b", 2, generation);
+        // Looking "a" up makes "b" the least recently used
+        assert_eq!(cache.get(&"a"), Some(1));
+        cache.insert("c", 3, generation);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+        // Replacing an entry doesn't evict anything
+        cache.insert("c", 4, generation);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(4));
+        assert_eq!((cache.hits(), cache.misses()), (5, 1));
+    }
+
+    #[test]
+    fn test_clear_forgets_stale_results_5() {
+        let cache = LruCache::new(4);
+        let before = cache.generation();
+        cache.insert("word", vec![0], before);
+        cache.clear();
+        assert!(cache.is_empty());
+        // A result worked out before the cache was emptied is out of date
+        cache.insert("word", vec![0], before);
+        assert_eq!(cache.get(&"word"), None);
+        cache.insert("word", vec![0, 1], cache.generation());
+        assert_eq!(cache.get(&"word"), Some(vec![0, 1]));
+    }
 }
 
 // ============================ POOL ============================
@@ -103,6 +528,7 @@ mod test_pool {
     use ngram::pool::*;
     use std::sync::{Arc, Mutex};
     #[test]
+    #[allow(clippy::empty_loop, clippy::assertions_on_constants)]
     fn test_uses_multiple_threads_5() {
         let pool = ThreadPool::new(4);
 
@@ -140,126 +566,2474 @@ mod test_pool {
         drop(pool);
         assert_eq!(*counter.lock().unwrap(), 8);
     }
-}
 
-// ============================ SERIALIZE ============================
-mod test_serialize {
-    use super::*;
-    use ngram::message::*;
     #[test]
-    fn test_round_trip_request_5() {
-        fn round_trip_request(s: String, n: usize) {
-            let pub_request = Request::Publish { doc: s.clone() };
-            let search_request = Request::Search { word: s };
-            let retrieve_request = Request::Retrieve { id: n };
-            assert_eq!(
-                Request::from_bytes(&pub_request.to_bytes()[..]).unwrap(),
-                pub_request
-            );
-            assert_eq!(
-                Request::from_bytes(&search_request.to_bytes()[..]).unwrap(),
-                search_request
-            );
-            assert_eq!(
-                Request::from_bytes(&retrieve_request.to_bytes()[..]).unwrap(),
-                retrieve_request
-            );
+    fn test_shutdown_finishes_queued_jobs_5() {
+        let pool = ThreadPool::new(2);
+        let counter = Arc::new(Mutex::new(0));
+        for _ in 0..8 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                *counter.lock().unwrap() += 1;
+            });
         }
-        quickcheck(round_trip_request as fn(String, usize));
+        pool.shutdown();
+        assert_eq!(*counter.lock().unwrap(), 8);
     }
 
     #[test]
-    fn test_round_trip_response_5() {
-        fn round_trip_response(s: String, n: usize) {
-            let pub_response = Response::PublishSuccess(n);
-            let search_response = Response::SearchSuccess(vec![n]);
-            let retrieve_response = Response::RetrieveSuccess(s.clone());
-            assert_eq!(
-                Response::from_bytes(&pub_response.to_bytes()[..]).unwrap(),
-                pub_response
-            );
-            assert_eq!(
-                Response::from_bytes(&search_response.to_bytes()[..]).unwrap(),
-                search_response
-            );
+    fn test_survives_panicking_job_5() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("job failed"));
+
+        // The only worker should go on to run the next job
+        let (tx, rx) = std::sync::mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(std::time::Duration::from_secs(5))
+            .expect("worker did not survive the panic");
+        assert_eq!(pool.panic_count(), 1);
+    }
+
+    #[test]
+    fn test_execute_returns_result_5() {
+        let pool = ThreadPool::new(1);
+        assert_eq!(
+            pool.execute(|| -> usize { panic!("job failed") }).join(),
+            Err(TaskError::Panicked("job failed".to_string()))
+        );
+        assert_eq!(pool.execute(|| 6 * 7).join(), Ok(42));
+        // The worker still counts the panic, as it does for jobs nobody waits on
+        assert_eq!(pool.panic_count(), 1);
+
+        // A caller can stop waiting on a slow job, and pick up its result later
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let handle = pool.execute(move || blocked.recv().is_err());
+        assert_eq!(
+            handle.join_timeout(std::time::Duration::from_millis(50)),
+            Err(TaskError::TimedOut)
+        );
+        drop(release);
+        assert_eq!(
+            handle.join_timeout(std::time::Duration::from_secs(5)),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_resize_5() {
+        let mut pool = ThreadPool::new(1);
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = blocked.recv();
+        });
+
+        // Jobs stuck behind the only worker run once the pool grows
+        let handles = (0..4).map(|i| pool.execute(move || i)).collect::<Vec<_>>();
+        pool.resize(3).unwrap();
+        assert_eq!(pool.size(), 3);
+        for (i, handle) in handles.into_iter().enumerate() {
             assert_eq!(
-                Response::from_bytes(&retrieve_response.to_bytes()[..]).unwrap(),
-                retrieve_response
+                handle.join_timeout(std::time::Duration::from_secs(5)),
+                Ok(i)
             );
         }
-        quickcheck(round_trip_response as fn(String, usize));
+
+        // Shrinking lets idle workers go without dropping what is queued
+        pool.resize(1).unwrap();
+        let counter = Arc::new(Mutex::new(0));
+        for _ in 0..8 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || *counter.lock().unwrap() += 1);
+        }
+        drop(release);
+        pool.shutdown();
+        assert_eq!(*counter.lock().unwrap(), 8);
     }
-}
 
-// ============================ ARGUMENTS ============================
+    #[test]
+    fn test_timings_5() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let (started, is_started) = std::sync::mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        is_started.recv().unwrap();
+        assert_eq!(pool.active_count(), 1);
 
-// graded manually
+        // The next job waits for the only worker to be let go
+        let waiting = pool.execute(|| ());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        drop(release);
+        waiting.join().unwrap();
+        // Jobs are timed once they finish, which the worker does before taking the next
+        pool.execute(|| ()).join().unwrap();
+        let timings = pool.timings();
+        assert!(timings.queue_wait.count() >= 2);
+        assert!(timings.queue_wait.total_seconds() >= 0.02);
+        assert!(timings.run_time.total_seconds() >= 0.02);
+    }
 
-// ============================ CLIENT + Server============================
+    #[test]
+    fn test_try_execute_when_full_5() {
+        let pool = ThreadPool::new(1).with_queue_capacity(1);
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let (started, is_started) = std::sync::mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        is_started.recv().unwrap();
 
-mod integration {
-    use super::*;
-    use ngram::message::*;
-    use ngram::{client, server};
-    use std::fs;
-    use std::sync::{Arc, Mutex};
-    use std::thread::{self, JoinHandle};
+        // The only worker is busy, so one job may wait, but not a second
+        let counter = Arc::new(Mutex::new(0));
+        let job = |counter: &Arc<Mutex<i32>>| {
+            let counter = Arc::clone(counter);
+            move || *counter.lock().unwrap() += 1
+        };
+        assert!(pool.try_execute(job(&counter)).is_ok());
+        assert!(pool.try_execute(job(&counter)).is_err());
+        assert_eq!(pool.queue_depth(), 1);
+
+        drop(release);
+        pool.shutdown();
+        assert_eq!(*counter.lock().unwrap(), 1);
+    }
+}
+
+// ============================ METRICS ============================
+mod test_metrics {
+    use ngram::metrics::*;
     use std::time::Duration;
+    #[test]
+    fn test_render_5() {
+        let metrics = Metrics::new();
+        metrics.record("search", Duration::from_micros(200));
+        metrics.record("search", Duration::from_millis(20));
+        metrics.record("publish", Duration::from_secs(2));
+        let connection = metrics.connection();
+        assert_eq!(metrics.active_connections(), 1);
+        assert_eq!(metrics.request_count("search"), 2);
 
-    fn start_server(port: u16) -> (Arc<server::Server>, JoinHandle<()>) {
-        let server = Arc::new(server::Server::new());
-        let handle = thread::spawn({
-            let server = Arc::clone(&server);
-            move || server.run(port)
-        });
-        thread::sleep(Duration::from_millis(500));
-        (server, handle)
+        let text = metrics.render(&[("documents", 7)]);
+        for line in [
+            r#"ngram_requests_total{type="search"} 2"#,
+            r#"ngram_requests_total{type="publish"} 1"#,
+            r#"ngram_request_duration_seconds_bucket{type="search",le="0.0005"} 1"#,
+            r#"ngram_request_duration_seconds_bucket{type="search",le="0.025"} 2"#,
+            r#"ngram_request_duration_seconds_bucket{type="publish",le="1"} 0"#,
+            r#"ngram_request_duration_seconds_bucket{type="publish",le="+Inf"} 1"#,
+            "ngram_active_connections 1",
+            "ngram_documents 7",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+        drop(connection);
+        assert_eq!(metrics.active_connections(), 0);
     }
 
     #[test]
-    fn test_start_stop_server_5() {
-        let port = 7880;
-        let (server, _handle) = start_server(port);
-        server.stop();
+    fn test_render_histograms_5() {
+        let mut waits = Histogram::default();
+        waits.observe(Duration::from_micros(200));
+        waits.observe(Duration::from_secs(2));
+        assert_eq!(waits.count(), 2);
+        let text = Metrics::new().render_with(&[], &[("queue_wait_seconds", "Waits", &waits)]);
+        for line in [
+            "# HELP ngram_queue_wait_seconds Waits",
+            "# TYPE ngram_queue_wait_seconds histogram",
+            r#"ngram_queue_wait_seconds_bucket{le="0.0005"} 1"#,
+            r#"ngram_queue_wait_seconds_bucket{le="1"} 1"#,
+            r#"ngram_queue_wait_seconds_bucket{le="+Inf"} 2"#,
+            "ngram_queue_wait_seconds_sum 2.0002",
+            "ngram_queue_wait_seconds_count 2",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
     }
+}
 
+// ============================ DATABASE ============================
+mod test_database {
+    use ngram::database::*;
     #[test]
-    fn test_publish_5() {
-        let port = 7881;
-        let (server, _handle) = start_server(port);
+    fn test_save_load_round_trip_5() {
+        let database = Database::new();
+        let first = database.publish("the quick brown fox".to_string());
+        let second = database.publish("the lazy dog".to_string());
 
-        let client = client::Client::new("127.0.0.1", port);
-        let response = client.publish_from_path("data/austen-emma.txt");
-        assert!(matches!(response, Some(Response::PublishSuccess(_))));
-        server.stop();
+        let mut bytes = Vec::new();
+        database.save(&mut bytes).unwrap();
+        let loaded = Database::load(&bytes[..]).unwrap();
+        assert_eq!(loaded.retrieve(first), database.retrieve(first));
+        assert_eq!(loaded.retrieve(second), database.retrieve(second));
+        assert_eq!(loaded.search("dog"), vec![second]);
+        assert!(Database::load(&bytes[..bytes.len() - 1]).is_err());
     }
 
     #[test]
-    fn test_search_empty_5() {
-        let port = 7882;
-        let (server, _handle) = start_server(port);
+    fn test_serde_round_trip_5() {
+        let database = Database::new();
+        let first = database.publish("the quick brown fox".to_string());
+        let pending = database.publish_with(
+            "fn lazy_dog() {}".to_string(),
+            &PublishOptions {
+                pending: true,
+                metadata: Metadata {
+                    title: Some("Dogs".to_string()),
+                    ..Metadata::default()
+                },
+                content_type: ContentType::Code,
+            },
+        );
 
-        let client = client::Client::new("127.0.0.1", port);
-        let response = client.search("a");
-        assert_eq!(response, Some(Response::SearchSuccess(vec![])));
-        server.stop();
+        let json = serde_json::to_string(&database).unwrap();
+        let loaded: Database = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.retrieve(first), database.retrieve(first));
+        assert_eq!(loaded.retrieve(pending), database.retrieve(pending));
+        assert_eq!(loaded.metadata(pending), database.metadata(pending));
+        assert_eq!(loaded.search("fox"), vec![first]);
+        assert!(loaded.search("dog").is_empty());
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), json);
+        assert!(loaded.commit(pending));
+        assert_eq!(loaded.search("dog"), vec![pending]);
+        assert!(serde_json::from_str::<Database>(&json[..json.len() - 1]).is_err());
     }
 
     #[test]
-    fn test_search_inserted_5() {
-        let port = 7883;
-        let (server, _handle) = start_server(port);
+    fn test_recovers_from_poisoned_locks_5() {
+        use ngram::analyzer::Analyzer;
+        use std::time::Duration;
+        // Panics while publishing a document, before it has been given an id
+        struct Explosive;
+        impl Analyzer for Explosive {
+            fn analyze(&self, word: String) -> Option<String> {
+                assert_ne!(word, "boom");
+                Some(word)
+            }
+            fn name(&self) -> &'static str {
+                "explosive"
+            }
+        }
+        let database = Database::new().with_analyzer(Explosive);
+        let first = database.publish("fine".to_string());
+        let published = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            database.publish("boom".to_string())
+        }));
+        assert!(published.is_err());
 
-        let client = client::Client::new("127.0.0.1", port);
+        let second = database.publish("also fine".to_string());
+        assert_eq!(database.search("fine"), vec![first, second]);
+        assert_eq!(database.retrieve(first), Some("fine".to_string()));
+        assert_eq!(
+            database.try_retrieve(second, Duration::from_millis(100)),
+            Ok(Some("also fine".to_string()))
+        );
+        assert_eq!(database.document_count(), 2);
+    }
 
-        let response = client.publish_from_path("data/austen-emma.txt");
-        let id = match response {
-            Some(Response::PublishSuccess(id)) => id,
-            _ => panic!("Failed to publish data/austen-emma.txt"),
-        };
-        let response = client.search("the");
-        assert_eq!(response, Some(Response::SearchSuccess(vec![id])));
-        server.stop();
+    #[test]
+    fn test_concurrent_publish_and_retrieve_5() {
+        use super::THREADS;
+        use std::sync::Arc;
+        let database = Arc::new(Database::new());
+        let workers = (0..THREADS)
+            .map(|t| {
+                let database = Arc::clone(&database);
+                std::thread::spawn(move || {
+                    let mut ids = Vec::new();
+                    for i in 0..20 {
+                        let id = database.publish(format!("thread{} doc{}", t, i));
+                        assert_eq!(database.retrieve(id), Some(format!("thread{} doc{}", t, i)));
+                        ids.push(id);
+                    }
+                    let batch = database
+                        .publish_batch(vec![format!("batch{}", t); 3], &PublishOptions::default());
+                    assert_eq!(batch.len(), 3);
+                    assert!(batch.windows(2).all(|pair| pair[1] == pair[0] + 1));
+                    ids.extend(batch);
+                    ids
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut ids = workers
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, (0..THREADS * 23).collect::<Vec<_>>());
+        assert_eq!(database.document_count(), THREADS * 23);
+        assert_eq!(database.search("doc0").len(), THREADS);
+        assert_eq!(database.list(0).len(), THREADS * 23);
+    }
+
+    #[test]
+    fn test_tokenizes_without_locking_5() {
+        use ngram::analyzer::Analyzer;
+        use std::sync::{Arc, Barrier};
+        use std::time::Duration;
+        // Stops in the middle of splitting a document into words until the test lets it go on
+        struct Stalling(Arc<Barrier>);
+        impl Analyzer for Stalling {
+            fn analyze(&self, word: String) -> Option<String> {
+                if word == "stall" {
+                    self.0.wait();
+                    self.0.wait();
+                }
+                Some(word)
+            }
+            fn name(&self) -> &'static str {
+                "stalling"
+            }
+        }
+        let barrier = Arc::new(Barrier::new(2));
+        let database = Arc::new(Database::new().with_analyzer(Stalling(Arc::clone(&barrier))));
+        let first = database.publish("quick".to_string());
+        let publisher = {
+            let database = Arc::clone(&database);
+            std::thread::spawn(move || database.publish("a long book that will stall".to_string()))
+        };
+        barrier.wait();
+
+        // Everything else carries on while the book is being split up
+        assert_eq!(database.retrieve(first), Some("quick".to_string()));
+        assert_eq!(
+            database.try_retrieve(first, Duration::ZERO),
+            Ok(Some("quick".to_string()))
+        );
+        let second = database.publish("brief".to_string());
+        assert!(database.update(first, "quicker".to_string()));
+        assert_eq!(database.search("quicker"), vec![first]);
+        assert_eq!(database.list(0).len(), 2);
+
+        barrier.wait();
+        // The book only got its id once it was split up
+        let book = publisher.join().unwrap();
+        assert_eq!(book, second + 1);
+        assert_eq!(database.search("book"), vec![book]);
+    }
+
+    #[test]
+    fn test_duplicates_5() {
+        let allowing = Database::new();
+        assert_eq!(allowing.publish("same".to_string()), 0);
+        assert_eq!(allowing.publish("same".to_string()), 1);
+
+        // Copies already stored are found once duplicates are looked for
+        let reusing = allowing.with_duplicates(Duplicates::Reuse);
+        assert_eq!(reusing.publish("same".to_string()), 0);
+        assert_eq!(reusing.publish("different".to_string()), 2);
+        assert_eq!(reusing.publish("different".to_string()), 2);
+        let code = PublishOptions {
+            content_type: ContentType::Code,
+            ..PublishOptions::default()
+        };
+        assert_eq!(reusing.publish_with("different".to_string(), &code), 3);
+        let batch = vec!["new".to_string(), "same".to_string(), "new".to_string()];
+        assert_eq!(
+            reusing.publish_batch(batch, &PublishOptions::default()),
+            vec![4, 0, 4]
+        );
+        assert_eq!(reusing.document_count(), 5);
+        assert_eq!(reusing.search("new"), vec![4]);
+
+        // An updated document is found by its new text, not its old one
+        assert!(reusing.update(2, "changed".to_string()));
+        assert_eq!(reusing.publish("changed".to_string()), 2);
+        assert_eq!(reusing.publish("different".to_string()), 5);
+
+        let rejecting = Database::new().with_duplicates(Duplicates::Reject);
+        let options = PublishOptions::default();
+        assert_eq!(
+            rejecting.try_publish_with_at("one".to_string(), &options, 0),
+            Ok(0)
+        );
+        assert_eq!(
+            rejecting.try_publish_with_at("one".to_string(), &options, 0),
+            Err(Duplicate(0))
+        );
+        let batch = vec!["two".to_string(), "one".to_string()];
+        assert_eq!(
+            rejecting.try_publish_batch_at(batch, &options, 0),
+            Err(Duplicate(0))
+        );
+        assert_eq!(rejecting.document_count(), 1);
+        // Callers that can't be told about a rejection get the stored document's id
+        assert_eq!(rejecting.publish("one".to_string()), 0);
+
+        // Restoring an archive keeps every copy in it, so that ids don't change
+        let copies = Database::new();
+        copies.publish("copy".to_string());
+        copies.publish("copy".to_string());
+        let mut bytes = Vec::new();
+        copies.save(&mut bytes).unwrap();
+        let restored = Database::new().with_duplicates(Duplicates::Reuse);
+        restored.restore(&bytes[..]).unwrap();
+        assert_eq!(restored.document_count(), 2);
+        assert_eq!(restored.publish("copy".to_string()), 0);
+    }
+
+    #[test]
+    fn test_memory_usage_5() {
+        let database = Database::new().with_ngram_index(3);
+        assert_eq!(database.memory_usage(), MemoryUsage::default());
+        let pending = PublishOptions {
+            pending: true,
+            ..PublishOptions::default()
+        };
+        let id = database.publish_with("a short document".to_string(), &pending);
+        let stored = database.memory_usage();
+        assert!(stored.blob_store > "a short document".len());
+        assert_eq!(stored.index, 0);
+
+        // Postings are only counted once there are any
+        database.commit(id);
+        let committed = database.memory_usage();
+        assert_eq!(committed.blob_store, stored.blob_store);
+        assert!(committed.index > 0);
+        assert_eq!(committed.total(), committed.blob_store + committed.index);
+
+        // Updating a document counts the difference, so changing it back undoes the change
+        database.update(id, "a much longer document than it was before".to_string());
+        let updated = database.memory_usage();
+        assert!(updated.blob_store > committed.blob_store);
+        assert!(updated.index > committed.index);
+        database.update(id, "a short document".to_string());
+        assert_eq!(database.memory_usage(), committed);
+
+        let ids = database.publish_batch(
+            vec!["one".to_string(), "two".to_string()],
+            &PublishOptions::default(),
+        );
+        assert_eq!(ids.len(), 2);
+        assert!(database.memory_usage().total() > committed.total());
+    }
+
+    #[test]
+    fn test_suggest_by_document_frequency_5() {
+        let database = Database::new();
+        database.publish("astronomy astronaut".to_string());
+        database.publish("Astronaut astro".to_string());
+        database.publish("astronaut zebra".to_string());
+        assert_eq!(
+            database.suggest("ASTRO", 10),
+            vec![
+                ("astronaut".to_string(), 3),
+                ("astro".to_string(), 1),
+                ("astronomy".to_string(), 1),
+            ]
+        );
+        assert_eq!(database.suggest("astro", 1).len(), 1);
+        assert!(database.suggest("q", 10).is_empty());
+    }
+
+    #[test]
+    fn test_diff_5() {
+        let old = Database::new();
+        old.publish("the cat sat".to_string());
+        old.publish("the dog ran".to_string());
+        let mut bytes = Vec::new();
+        old.save(&mut bytes).unwrap();
+        let new = Database::load(&bytes[..]).unwrap();
+        assert_eq!(old.diff(&new), SnapshotDiff::default());
+
+        new.publish("the cat ran".to_string());
+        let diff = old.diff(&new);
+        assert_eq!(diff.added, vec![2]);
+        assert!(diff.removed.is_empty() && diff.changed.is_empty());
+        let delta = |term: &str, before, after| TermDelta {
+            term: term.to_string(),
+            before,
+            after,
+        };
+        assert_eq!(
+            diff.terms,
+            vec![delta("cat", 1, 2), delta("ran", 1, 2), delta("the", 2, 3)]
+        );
+        assert_eq!(new.diff(&old).removed, vec![2]);
+        assert_eq!(old.diff(&old), SnapshotDiff::default());
+    }
+
+    #[test]
+    fn test_pending_until_commit_5() {
+        let database = Database::new();
+        let live = database.publish("moderated content".to_string());
+        let pending = database.publish_with(
+            "unmoderated content".to_string(),
+            &PublishOptions {
+                pending: true,
+                ..PublishOptions::default()
+            },
+        );
+        assert_eq!(database.search("content"), vec![live]);
+        assert!(database.search("unmoderated").is_empty());
+        assert!(database.suggest("unmod", 10).is_empty());
+        assert_eq!(
+            database.retrieve(pending),
+            Some("unmoderated content".to_string())
+        );
+
+        // Pending documents stay pending across a save and load
+        let mut bytes = Vec::new();
+        database.save(&mut bytes).unwrap();
+        let loaded = Database::load(&bytes[..]).unwrap();
+        assert!(loaded.search("unmoderated").is_empty());
+
+        assert!(database.commit(pending));
+        assert!(database.commit(pending));
+        assert_eq!(database.search("content"), vec![live, pending]);
+        assert!(!database.commit(pending + 1));
+        assert!(loaded.commit(pending));
+        assert_eq!(loaded.search("unmoderated"), vec![pending]);
+    }
+
+    #[test]
+    fn test_search_prefix_5() {
+        let database = Database::new();
+        let astronomy = database.publish("Astronomy for beginners".to_string());
+        let astronaut = database.publish("the astronaut landed".to_string());
+        database.publish("an asteroid belt".to_string());
+        assert_eq!(database.search_prefix("ASTRO"), vec![astronomy, astronaut]);
+        assert_eq!(database.search_prefix("astronaut"), vec![astronaut]);
+        assert_eq!(database.search_prefix("ast").len(), 3);
+        assert!(database.search_prefix("astronauts").is_empty());
+    }
+
+    #[test]
+    fn test_search_substring_5() {
+        let database = Database::new().with_ngram_index(3);
+        let fox = database.publish("The Quick brown fox".to_string());
+        let dog = database.publish("the lazy dog".to_string());
+        assert_eq!(database.search_substring("UICK BRO"), vec![fox]);
+        assert_eq!(database.search_substring("the"), vec![fox, dog]);
+        assert_eq!(database.search_substring("og"), vec![dog]);
+        assert!(database.search_substring("quick fox").is_empty());
+        assert!(database.update(dog, "the lazy cat".to_string()));
+        assert!(database.search_substring("dog").is_empty());
+        assert_eq!(database.search_substring("y ca"), vec![dog]);
+    }
+
+    #[test]
+    fn test_search_page_5() {
+        let database = Database::new();
+        let ids: Vec<usize> = (0..10)
+            .map(|i| database.publish_at(format!("page {}", i), i))
+            .collect();
+        let page = |offset, limit| SearchOptions {
+            offset,
+            limit,
+            newest_first: true,
+            ..SearchOptions::default()
+        };
+        assert_eq!(
+            database.search_page("page", &page(2, Some(3))),
+            (vec![ids[7], ids[6], ids[5]], 10)
+        );
+        assert_eq!(
+            database.search_page("page", &page(8, None)),
+            (vec![ids[1], ids[0]], 10)
+        );
+        assert_eq!(
+            database.search_page("page", &page(20, Some(3))),
+            (vec![], 10)
+        );
+        assert_eq!(
+            database.search_with("page", &page(9, Some(3))),
+            vec![ids[0]]
+        );
+    }
+
+    #[test]
+    fn test_snippets_5() {
+        let database = Database::new();
+        let long =
+            database.publish("one two three four five Target six seven eight nine".to_string());
+        let short = database.publish("target practice".to_string());
+        let missing = database.publish("nothing to see here".to_string());
+        assert_eq!(
+            database.snippets(&[long, short, missing, 99], "TARGET", 4),
+            vec![
+                (long, "... four five **Target** six seven ...".to_string()),
+                (short, "**target** practice".to_string()),
+                (missing, "nothing to see here".to_string()),
+            ]
+        );
+        // Near either end of the document, the context comes from the other side
+        assert_eq!(
+            database.snippets(&[long], "two", 2),
+            vec![(long, "one **two** three ...".to_string())]
+        );
+        assert_eq!(
+            database.snippets(&[long], "nine", 2),
+            vec![(long, "... seven eight **nine**".to_string())]
+        );
+        // However much context is asked for, a snippet is at most the whole document
+        assert_eq!(
+            database.snippets(&[short], "TARGET", usize::MAX),
+            database.snippets(&[short], "TARGET", 100)
+        );
+    }
+
+    #[test]
+    fn test_term_stats_5() {
+        let database = Database::new();
+        database.publish("the cat saw the dog".to_string());
+        database.publish("The dog".to_string());
+        let pending = PublishOptions {
+            pending: true,
+            ..PublishOptions::default()
+        };
+        database.publish_with("the zebra".to_string(), &pending);
+        let all = database.term_stats(None, usize::MAX);
+        assert_eq!(
+            all,
+            vec![
+                ("cat".to_string(), 1, 1),
+                ("dog".to_string(), 2, 2),
+                ("saw".to_string(), 1, 1),
+                ("the".to_string(), 2, 3),
+            ]
+        );
+        // Pages pick up after the last word of the one before
+        assert_eq!(database.term_stats(None, 2), all[..2]);
+        assert_eq!(database.term_stats(Some("dog"), 2), all[2..]);
+        assert!(database.term_stats(Some("the"), 2).is_empty());
+    }
+
+    #[test]
+    fn test_search_query_5() {
+        use ngram::query::Query;
+        let database = Database::new();
+        let hunt = database.publish("whale ship harpoon".to_string());
+        let sail = database.publish("whale boat".to_string());
+        let dock = database.publish("ship boat".to_string());
+        let pending = PublishOptions {
+            pending: true,
+            ..PublishOptions::default()
+        };
+        database.publish_with("whale boat".to_string(), &pending);
+        let search = |expr: &str| database.search_query(&expr.parse().unwrap());
+        assert_eq!(search("whale AND (ship OR boat) NOT harpoon"), vec![sail]);
+        assert_eq!(search("whale ship"), vec![hunt]);
+        assert_eq!(search("whale OR dock OR boat"), vec![hunt, sail, dock]);
+        assert_eq!(search("NOT whale"), vec![dock]);
+        assert_eq!(search("NOT (whale OR ship)"), Vec::<usize>::new());
+        assert_eq!(search("boat NOT NOT ship"), vec![dock]);
+        // Operators only count in capitals
+        assert_eq!(search("whale and boat"), Vec::<usize>::new());
+
+        assert_eq!(
+            "a OR b c NOT d".parse::<Query>().unwrap().to_string(),
+            "(a OR ((b AND c) AND NOT d))"
+        );
+        for malformed in ["", "a AND", "(a OR b", "a )", "OR b", "NOT"] {
+            assert!(malformed.parse::<Query>().is_err(), "{:?}", malformed);
+        }
+        assert!(vec!["a"; 2000].join(" ").parse::<Query>().is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compression_5() {
+        let database = Database::new().with_compression(3);
+        let poem = "Tyger Tyger, burning bright, In the forests of the night; ".repeat(200);
+        let id = database.publish(poem.clone());
+        let short = database.publish("tiny".to_string());
+        assert!(database.stored_bytes() < poem.len() / 10);
+        assert_eq!(database.retrieve(id), Some(poem.clone()));
+        assert_eq!(database.retrieve(short), Some("tiny".to_string()));
+        assert_eq!(database.search("forests"), vec![id]);
+        assert_eq!(database.search_substring("BURNING BRIGHT"), vec![id]);
+        assert_eq!(database.list(5)[0].length, poem.len());
+        assert_eq!(database.list(5)[0].preview, "Tyger");
+
+        database.update(id, "the lamb".to_string());
+        assert_eq!(database.retrieve(id), Some("the lamb".to_string()));
+        assert_eq!(database.search("forests"), Vec::<usize>::new());
+        let mut saved = Vec::new();
+        database.save(&mut saved).unwrap();
+        let loaded = Database::load(&saved[..]).unwrap();
+        assert_eq!(loaded.retrieve(id), Some("the lamb".to_string()));
+    }
+
+    #[test]
+    fn test_stop_words_5() {
+        let database = Database::new().with_stop_words(["The", "and"]);
+        let tiger = database.publish("The tiger and the lamb".to_string());
+        database.publish("AND then".to_string());
+        assert_eq!(database.search("the"), Vec::<usize>::new());
+        assert_eq!(database.search("And"), Vec::<usize>::new());
+        assert_eq!(database.search("tiger"), vec![tiger]);
+        assert_eq!(
+            database.term_stats(None, usize::MAX),
+            vec![
+                ("lamb".to_string(), 1, 1),
+                ("then".to_string(), 1, 1),
+                ("tiger".to_string(), 1, 1),
+            ]
+        );
+        // Stop words don't count towards a document's length when ranking
+        let idf = (3.0_f32 / 2.0).ln() + 1.0;
+        assert_eq!(database.search_ranked("tiger", 1), vec![(tiger, 0.5 * idf)]);
+        database.update(tiger, "the lamb".to_string());
+        assert_eq!(database.search("tiger"), Vec::<usize>::new());
+        assert_eq!(database.search("the"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_analyzer_5() {
+        use ngram::analyzer::{Analyzer, Pipeline};
+        let standard: Pipeline = "standard".parse().unwrap();
+        assert_eq!(standard.to_string(), "unicode,lowercase,punctuation");
+        assert_eq!(
+            standard.analyze("\"Café,\"".to_string()),
+            Some("cafe".to_string())
+        );
+        assert_eq!(
+            standard.analyze("don't".to_string()),
+            Some("don't".to_string())
+        );
+        assert_eq!(standard.analyze("--".to_string()), None);
+        assert!("lowercase,stemming".parse::<Pipeline>().is_err());
+
+        let database = Database::new().with_analyzer(standard);
+        let book = database.publish("A book, a café.".to_string());
+        database.publish("--- BOOKS".to_string());
+        assert_eq!(database.search("Book"), vec![book]);
+        assert_eq!(database.search("book!"), vec![book]);
+        assert_eq!(database.search("cafe\u{301}"), vec![book]);
+        assert_eq!(database.search("?"), Vec::<usize>::new());
+        assert_eq!(database.search_prefix("BOO").len(), 2);
+        assert_eq!(
+            database.snippets(&[book], "BOOK", 1),
+            vec![(book, "... **book,** a ...".to_string())]
+        );
+
+        // Without a lowercasing stage, case matters
+        let exact = Database::new().with_analyzer(Pipeline::new(vec![]));
+        let id = exact.publish("Tyger tyger".to_string());
+        assert_eq!(exact.search("Tyger"), vec![id]);
+        assert_eq!(exact.search("TYGER"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_stemming_5() {
+        use ngram::analyzer::{Analyzer, Pipeline, PorterStem};
+        for (word, stem) in [
+            ("caresses", "caress"),
+            ("ponies", "poni"),
+            ("cats", "cat"),
+            ("feed", "feed"),
+            ("agreed", "agre"),
+            ("plastered", "plaster"),
+            ("motoring", "motor"),
+            ("sing", "sing"),
+            ("conflated", "conflat"),
+            ("hopping", "hop"),
+            ("filing", "file"),
+            ("happy", "happi"),
+            ("relational", "relat"),
+            ("conditional", "condit"),
+            ("generalization", "gener"),
+            ("adoption", "adopt"),
+            ("controlling", "control"),
+            ("running", "run"),
+            ("runs", "run"),
+        ] {
+            assert_eq!(
+                PorterStem.analyze(word.to_string()).as_deref(),
+                Some(stem),
+                "{}",
+                word
+            );
+        }
+
+        let database = Database::new().with_analyzer(Pipeline::standard().with_stemming());
+        let running = database.publish("Running, he runs".to_string());
+        let connection = database.publish("a connection".to_string());
+        assert_eq!(database.search("run"), vec![running]);
+        assert_eq!(database.search("RUNNING"), vec![running]);
+        assert_eq!(database.search("connected"), vec![connection]);
+        // Prefixes aren't stemmed, since they aren't words
+        assert_eq!(database.search_prefix("connec"), vec![connection]);
+        // Without the stemming stage, only the exact word matches
+        let exact = Database::new();
+        exact.publish("running".to_string());
+        assert_eq!(exact.search("run"), Vec::<usize>::new());
+    }
+
+    // Searching for substrings with an n-gram index of any size should find exactly the
+    // documents a scan does, through publishes, batches, commits and updates.
+    #[test]
+    fn test_search_substring_matches_scan_5() {
+        fn matches_scan(n: u8, docs: Vec<(String, bool)>, updates: Vec<(usize, String)>) -> bool {
+            let indexed = Database::new().with_ngram_index(n as usize % 4 + 1);
+            let scanned = Database::new();
+            let pending = PublishOptions {
+                pending: true,
+                ..PublishOptions::default()
+            };
+            for (doc, is_pending) in docs.iter() {
+                let options = if *is_pending {
+                    &pending
+                } else {
+                    &PublishOptions::default()
+                };
+                indexed.publish_with(doc.clone(), options);
+                scanned.publish_with(doc.clone(), options);
+            }
+            let batch = vec!["ab ba".to_string(), "aab".to_string()];
+            indexed.publish_batch(batch.clone(), &PublishOptions::default());
+            scanned.publish_batch(batch, &PublishOptions::default());
+            indexed.commit(0);
+            scanned.commit(0);
+            for (id, doc) in updates {
+                indexed.update(id % (docs.len() + 2), doc.clone());
+                scanned.update(id % (docs.len() + 2), doc);
+            }
+            let queries = ["", "a", "b", "ab", "ba", "aab", "a b", "abab", "Ab"];
+            let found_every_query = queries
+                .iter()
+                .all(|query| indexed.search_substring(query) == scanned.search_substring(query));
+            // The documents' own text, whole and in pieces, should be found too
+            found_every_query
+                && docs.iter().all(|(doc, _)| {
+                    let query: String = doc.chars().skip(1).take(5).collect();
+                    indexed.search_substring(&query) == scanned.search_substring(&query)
+                })
+        }
+        quickcheck::quickcheck(
+            matches_scan as fn(u8, Vec<(String, bool)>, Vec<(usize, String)>) -> bool,
+        );
+    }
+
+    #[test]
+    fn test_publish_batch_5() {
+        let database = Database::new();
+        let first = database.publish("a preface".to_string());
+        let parts = vec!["volume one".to_string(), "volume two".to_string()];
+        let metadata = Metadata {
+            title: Some("Emma".to_string()),
+            ..Metadata::default()
+        };
+        let options = PublishOptions {
+            metadata: metadata.clone(),
+            ..PublishOptions::default()
+        };
+        let ids = database.publish_batch(parts, &options);
+        assert_eq!(ids, vec![first + 1, first + 2]);
+        assert_eq!(database.search("volume"), ids);
+        assert_eq!(database.retrieve(ids[1]), Some("volume two".to_string()));
+        assert_eq!(database.metadata(ids[0]), Some(metadata));
+        assert!(database.publish_batch(vec![], &options).is_empty());
+
+        // A pending batch stays hidden until each part is committed
+        let pending = PublishOptions {
+            pending: true,
+            ..PublishOptions::default()
+        };
+        let drafts = database.publish_batch(vec!["draft".to_string(); 2], &pending);
+        assert!(database.search("draft").is_empty());
+        assert!(drafts.iter().all(|id| database.commit(*id)));
+        assert_eq!(database.search("draft"), drafts);
+    }
+
+    #[test]
+    fn test_publish_batch_all_or_nothing_5() {
+        use std::sync::Arc;
+        const BATCH: usize = 5;
+        let database = Arc::new(Database::new());
+        let reader = std::thread::spawn({
+            let database = Arc::clone(&database);
+            move || {
+                for _ in 0..2000 {
+                    assert_eq!(database.search("part").len() % BATCH, 0);
+                    assert_eq!(database.document_count() % BATCH, 0);
+                }
+            }
+        });
+        for batch in 0..200 {
+            let docs: Vec<String> = (0..BATCH)
+                .map(|part| format!("batch {} part {}", batch, part))
+                .collect();
+            database.publish_batch(docs, &PublishOptions::default());
+        }
+        reader.join().unwrap();
+        assert_eq!(database.search("part").len(), 200 * BATCH);
+    }
+
+    #[test]
+    fn test_update_5() {
+        let database = Database::new();
+        let id = database.publish("the old text".to_string());
+        let other = database.publish("other text".to_string());
+        assert!(database.update(id, "the NEW Text text".to_string()));
+        assert_eq!(database.retrieve(id), Some("the NEW Text text".to_string()));
+        assert!(database.search("old").is_empty());
+        assert_eq!(database.search("new"), vec![id]);
+        assert_eq!(database.search("text"), vec![id, other]);
+        assert_eq!(database.suggest("ol", 10), vec![]);
+        assert_eq!(
+            database.search_ranked("text", 1),
+            database.search_ranked("text", 2)[..1]
+        );
+        assert_eq!(database.search_ranked("text", 1)[0].0, id);
+        assert!(!database.update(other + 1, "nothing here".to_string()));
+
+        // Removed words can come back
+        assert!(database.update(id, "old again".to_string()));
+        assert_eq!(database.search("old"), vec![id]);
+        assert_eq!(database.search("text"), vec![other]);
+
+        // Pending documents stay unsearchable
+        let pending = database.publish_with(
+            "draft".to_string(),
+            &PublishOptions {
+                pending: true,
+                ..PublishOptions::default()
+            },
+        );
+        assert!(database.update(pending, "final draft".to_string()));
+        assert!(database.search("final").is_empty());
+        assert!(database.commit(pending));
+        assert_eq!(database.search("final"), vec![pending]);
+    }
+
+    #[test]
+    fn test_metadata_5() {
+        let database = Database::new();
+        let metadata = Metadata {
+            title: Some("Emma".to_string()),
+            author: Some("Jane Austen".to_string()),
+            date: None,
+        };
+        let plain = database.publish("no metadata here".to_string());
+        let described = database.publish_with(
+            "a described document".to_string(),
+            &PublishOptions {
+                metadata: metadata.clone(),
+                ..PublishOptions::default()
+            },
+        );
+        assert_eq!(database.metadata(plain), None);
+        assert_eq!(database.metadata(described), Some(metadata.clone()));
+        assert_eq!(database.metadata(described + 1), None);
+        assert_eq!(
+            database.metadata_for(&[described, plain, described]),
+            vec![(described, metadata.clone()), (described, metadata.clone())]
+        );
+
+        // Metadata survives a save and load
+        let mut bytes = Vec::new();
+        database.save(&mut bytes).unwrap();
+        let loaded = Database::load(&bytes[..]).unwrap();
+        assert_eq!(loaded.metadata(plain), None);
+        assert_eq!(loaded.metadata(described), Some(metadata));
+        assert_eq!(loaded.search("described"), vec![described]);
+    }
+
+    #[test]
+    fn test_search_ranked_5() {
+        let database = Database::new();
+        let once = database.publish("a cat and a dog and a bird".to_string());
+        let twice = database.publish("Cat eats cat food".to_string());
+        let mostly = database.publish("cat cat cat dog".to_string());
+        database.publish("no felines here".to_string());
+
+        let ranked = database.search_ranked("cat", 10);
+        let ids = ranked.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![mostly, twice, once]);
+        assert!(ranked.windows(2).all(|pair| pair[0].1 > pair[1].1));
+        // A rarer word is worth more than a common one at the same frequency
+        let dog = database.search_ranked("dog", 10);
+        assert!(dog
+            .iter()
+            .any(|&(id, score)| id == mostly && score > ranked[0].1 / 3.0));
+
+        assert_eq!(database.search_ranked("cat", 1).len(), 1);
+        assert!(database.search_ranked("unicorn", 10).is_empty());
+    }
+
+    #[test]
+    fn test_search_time_range_5() {
+        let database = Database::new();
+        let old = database.publish_at("breaking news".to_string(), 100);
+        let new = database.publish_at("more news".to_string(), 300);
+        let middle = database.publish_at("news again".to_string(), 200);
+        let since = |since| SearchOptions {
+            since: Some(since),
+            ..SearchOptions::default()
+        };
+        assert_eq!(
+            database.search_with("news", &SearchOptions::default()),
+            vec![old, new, middle]
+        );
+        assert_eq!(database.search_with("news", &since(200)), vec![new, middle]);
+        let newest_first = SearchOptions {
+            until: Some(250),
+            newest_first: true,
+            ..SearchOptions::default()
+        };
+        assert_eq!(
+            database.search_with("news", &newest_first),
+            vec![middle, old]
+        );
+    }
+
+    #[test]
+    fn test_search_exclude_5() {
+        let database = Database::new();
+        let rust = database.publish("rust is fast".to_string());
+        let both = database.publish("rust and Python".to_string());
+        let python = database.publish("python is slow".to_string());
+        let exclude = |words: &[&str]| SearchOptions {
+            exclude: words.iter().map(|word| word.to_string()).collect(),
+            ..SearchOptions::default()
+        };
+        assert_eq!(
+            database.search_with("rust", &exclude(&["PYTHON"])),
+            vec![rust]
+        );
+        assert_eq!(
+            database.search_with("is", &exclude(&["fast", "slow"])),
+            Vec::<usize>::new()
+        );
+        assert_eq!(
+            database.search_with("python", &exclude(&["missing"])),
+            vec![both, python]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_5() {
+        assert_eq!(
+            ContentType::Plain.tokenize("The **quick** fox"),
+            vec!["the", "**quick**", "fox"]
+        );
+        assert_eq!(
+            ContentType::Markdown.tokenize("# The **quick** [fox](https://fox.example)"),
+            vec!["the", "quick", "fox", "https://fox.example"]
+        );
+        assert_eq!(
+            ContentType::Code.tokenize("let parsed = parseHTTPRequest(raw_input);"),
+            vec![
+                "let",
+                "parsed",
+                "parsehttprequest",
+                "parse",
+                "http",
+                "request",
+                "raw_input",
+                "raw",
+                "input"
+            ]
+        );
+        assert_eq!(
+            ContentType::Code.tokenize("__init__ x2y"),
+            vec!["__init__", "x2y"]
+        );
+        for content_type in ContentType::ALL {
+            assert_eq!(
+                ContentType::from_code(content_type.code()),
+                Some(content_type)
+            );
+            assert_eq!(content_type.to_string().parse(), Ok(content_type));
+        }
+        assert!("rust".parse::<ContentType>().is_err());
+    }
+
+    #[test]
+    fn test_search_by_content_type_5() {
+        let database = Database::new();
+        let publish = |doc: &str, content_type| {
+            database.publish_with(
+                doc.to_string(),
+                &PublishOptions {
+                    content_type,
+                    ..PublishOptions::default()
+                },
+            )
+        };
+        let prose = publish("how to parse a request", ContentType::Plain);
+        let code = publish("fn parse_request(input: &str)", ContentType::Code);
+        let notes = publish("## Parse the *request*", ContentType::Markdown);
+        assert_eq!(database.search("request"), vec![prose, code, notes]);
+        assert_eq!(database.search("parse_request"), vec![code]);
+        let only = |content_type| SearchOptions {
+            content_type: Some(content_type),
+            ..SearchOptions::default()
+        };
+        assert_eq!(
+            database.search_with("request", &only(ContentType::Code)),
+            vec![code]
+        );
+        assert_eq!(
+            database.search_with("request", &only(ContentType::Markdown)),
+            vec![notes]
+        );
+
+        // Types survive a save and load
+        let mut bytes = Vec::new();
+        database.save(&mut bytes).unwrap();
+        let loaded = Database::load(&bytes[..]).unwrap();
+        assert_eq!(
+            loaded.search_with("parse", &only(ContentType::Code)),
+            vec![code]
+        );
+    }
+
+    #[test]
+    fn test_try_retrieve_uncontended_5() {
+        use std::time::Duration;
+        let database = Database::new();
+        let id = database.publish("a short document".to_string());
+        assert_eq!(
+            database.try_retrieve(id, Duration::ZERO),
+            Ok(Some("a short document".to_string()))
+        );
+        assert_eq!(database.try_retrieve(id + 1, Duration::ZERO), Ok(None));
+    }
+}
+
+// ============================ EMBEDDED ============================
+mod test_embedded {
+    use ngram::embedded::{Archive, Pipeline, PublishOptions};
+    use std::fs;
+
+    #[test]
+    fn test_archive_5() {
+        let archive = Archive::new()
+            .with_analyzer(Pipeline::standard().with_stemming())
+            .with_stop_words(["the"]);
+        assert!(archive.is_empty());
+        let whales = archive.publish("The whales were swimming");
+        let ships = archive.publish("A ship sailed past the whale");
+        let pending = archive.publish_with(
+            "A whale of a draft",
+            &PublishOptions {
+                pending: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(archive.len(), 3);
+        assert_eq!(archive.search("whale"), vec![whales, ships]);
+        assert_eq!(archive.search("the"), Vec::<usize>::new());
+        assert_eq!(archive.query("whale AND NOT ship"), Ok(vec![whales]));
+        assert!(archive.query("whale AND").is_err());
+        assert_eq!(archive.search_ranked("swim", 1)[0].0, whales);
+        assert!(archive.commit(pending));
+        assert_eq!(archive.search("draft"), vec![pending]);
+        assert!(archive.update(ships, "A ship sailed on"));
+        assert_eq!(archive.retrieve(ships).as_deref(), Some("A ship sailed on"));
+
+        // Saved archives load into a new one with the same analyzer
+        let path = std::env::temp_dir().join("ngram-test-archive.snapshot");
+        let _ = fs::remove_file(&path);
+        let loaded = Archive::new().with_analyzer(Pipeline::standard().with_stemming());
+        assert!(!loaded.load(&path).unwrap());
+        archive.save(&path).unwrap();
+        assert!(loaded.load(&path).unwrap());
+        assert_eq!(loaded.search("whales"), vec![whales, pending]);
+        let _ = fs::remove_file(&path);
+    }
+}
+
+// ============================ AUTH ============================
+mod test_auth {
+    use ngram::auth::{ApiKeys, Role};
+    use std::fs;
+
+    #[test]
+    fn test_api_keys_5() {
+        let path = std::env::temp_dir().join("ngram-test-api-keys.txt");
+        fs::write(&path, "# publishers\nalice s3cret\n\nbob hunter2 admin\n").unwrap();
+        let keys = ApiKeys::load(&path).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.identify("s3cret"), Some(("alice", Role::Publisher)));
+        assert_eq!(keys.identify("hunter2"), Some(("bob", Role::Admin)));
+        assert_eq!(keys.identify("s3cre"), None);
+        assert_eq!(keys.identify(""), None);
+        fs::write(&path, "alice\n").unwrap();
+        assert!(ApiKeys::load(&path).is_err());
+        fs::write(&path, "alice s3cret owner\n").unwrap();
+        assert!(ApiKeys::load(&path).is_err());
+        assert!(Role::Reader < Role::Publisher && Role::Publisher < Role::Admin);
+        assert!(Role::Admin.allows(Role::Publisher) && !Role::Reader.allows(Role::Publisher));
+        assert!(Role::Replicator.allows(Role::Replicator) && !Role::Admin.allows(Role::Replicator));
+        assert!(!Role::Replicator.allows(Role::Reader));
+        fs::write(&path, "primary r3plicate replicator\n").unwrap();
+        assert!(ApiKeys::load(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+}
+
+// ============================ RATE LIMIT ============================
+mod test_rate_limit {
+    use ngram::rate_limit::{RateLimit, RateLimiter};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket_5() {
+        let limiter = RateLimiter::new(RateLimit {
+            per_second: 2.0,
+            burst: 3,
+        });
+        let alice: IpAddr = "10.0.0.1".parse().unwrap();
+        let bob: IpAddr = "10.0.0.2".parse().unwrap();
+        let start = Instant::now();
+        // A burst is allowed, then requests come at the rate
+        for _ in 0..3 {
+            assert!(limiter.allow_at(alice, start));
+        }
+        assert!(!limiter.allow_at(alice, start));
+        assert!(limiter.allow_at(bob, start));
+        assert!(!limiter.allow_at(alice, start + Duration::from_millis(400)));
+        assert!(limiter.allow_at(alice, start + Duration::from_millis(500)));
+        assert!(!limiter.allow_at(alice, start + Duration::from_millis(600)));
+        // Waiting doesn't save up more than a burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.allow_at(alice, later));
+        }
+        assert!(!limiter.allow_at(alice, later));
+    }
+}
+
+// ============================ WAL ============================
+mod test_wal {
+    use ngram::database::{Database, PublishOptions};
+    use ngram::message::Response;
+    use ngram::wal::{WalEntry, WriteAheadLog};
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_replay_5() {
+        let path = std::env::temp_dir().join("ngram-test-wal.jsonl");
+        let _ = fs::remove_file(&path);
+        let database = Database::new();
+        let wal = WriteAheadLog::open(&path).unwrap();
+        let pending = PublishOptions {
+            pending: true,
+            ..Default::default()
+        };
+        let entries = vec![
+            WalEntry::publish("the tyger burning bright".to_string(), Default::default()),
+            WalEntry::publish_batch(vec!["a lamb".to_string(), "a rose".to_string()], pending),
+            WalEntry::Commit { id: 1 },
+            WalEntry::Update {
+                id: 0,
+                doc: "the tyger in the night".to_string(),
+            },
+            WalEntry::Commit { id: 9 },
+        ];
+        for entry in entries {
+            wal.append(entry, &database).unwrap();
+        }
+        drop(wal);
+        // The server died while appending another entry
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"type\":\"publish\",\"doc\":\"lost")
+            .unwrap();
+        drop(file);
+
+        let replayed = Database::new();
+        let wal = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(wal.replay(&replayed, 0).unwrap(), 5);
+        assert_eq!(replayed.list(100), database.list(100));
+        assert_eq!(replayed.search("night"), vec![0]);
+        assert_eq!(replayed.search("lamb"), vec![1]);
+        assert_eq!(replayed.search("rose"), Vec::<usize>::new());
+
+        // The cut-off entry is gone, so the next one starts on its own line
+        assert_eq!(
+            wal.append(WalEntry::Commit { id: 2 }, &replayed).unwrap(),
+            Response::CommitSuccess(2)
+        );
+        let again = Database::new();
+        assert_eq!(
+            WriteAheadLog::open(&path)
+                .unwrap()
+                .replay(&again, 0)
+                .unwrap(),
+            6
+        );
+        assert_eq!(again.search("rose"), vec![2]);
+        let _ = fs::remove_file(&path);
+    }
+    #[test]
+    fn test_snapshot_with_wal_5() {
+        use ngram::snapshot;
+        let dir = std::env::temp_dir();
+        let wal_path = dir.join("ngram-test-snapshot-wal.jsonl");
+        let snapshot_path = dir.join("ngram-test-snapshot.bin");
+        let _ = fs::remove_file(&wal_path);
+        let _ = fs::remove_file(&snapshot_path);
+        let database = Database::new();
+        let wal = WriteAheadLog::open(&wal_path).unwrap();
+        let publish = |doc: &str| WalEntry::publish(doc.to_string(), Default::default());
+        wal.append(publish("tyger"), &database).unwrap();
+        wal.checkpoint(|seq| snapshot::save(&database, seq, &snapshot_path))
+            .unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+        wal.append(publish("lamb"), &database).unwrap();
+        // The server died after saving a snapshot, before it could empty the log
+        snapshot::save(&database, 2, &snapshot_path).unwrap();
+        wal.append(publish("rose"), &database).unwrap();
+        drop(wal);
+
+        let restored = Database::new();
+        let seq = snapshot::load(&restored, &snapshot_path).unwrap();
+        assert_eq!(seq, Some(2));
+        let wal = WriteAheadLog::open(&wal_path).unwrap();
+        assert_eq!(wal.replay(&restored, 2).unwrap(), 1);
+        assert_eq!(restored.list(100), database.list(100));
+        // Numbering carries on past both the snapshot and the log
+        wal.checkpoint(|seq| {
+            assert_eq!(seq, 3);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            snapshot::load(&Database::new(), &dir.join("ngram-test-no-snapshot.bin")).unwrap(),
+            None
+        );
+        let _ = fs::remove_file(&wal_path);
+        let _ = fs::remove_file(&snapshot_path);
+    }
+}
+
+// ============================ SERIALIZE ============================
+mod test_serialize {
+    use super::*;
+    use ngram::database::{ContentType, DocumentSummary, Metadata, PublishOptions, SearchOptions};
+    use ngram::message::*;
+    #[test]
+    fn test_round_trip_request_5() {
+        fn round_trip_request(s: String, n: usize) {
+            let pub_request = Request::Publish { doc: s.clone() };
+            let search_request = Request::Search { word: s.clone() };
+            let retrieve_request = Request::Retrieve {
+                id: n,
+                offset: 0,
+                length: None,
+            };
+            let list_request = Request::List { preview_chars: n };
+            let search_with_request = Request::SearchWith {
+                word: s.clone(),
+                options: SearchOptions {
+                    since: Some(n as u64),
+                    until: None,
+                    newest_first: true,
+                    exclude: vec![s.clone(), String::new()],
+                    content_type: Some(ContentType::Markdown),
+                    snippet_words: Some(n),
+                    offset: n,
+                    limit: Some(n / 2),
+                },
+            };
+            let publish_with_request = Request::PublishWith {
+                doc: s.clone(),
+                options: PublishOptions {
+                    pending: true,
+                    metadata: Metadata {
+                        title: Some(s.clone()),
+                        author: None,
+                        date: Some(String::new()),
+                    },
+                    content_type: ContentType::Code,
+                },
+            };
+            let commit_request = Request::Commit { id: n };
+            let update_request = Request::Update {
+                id: n,
+                doc: s.clone(),
+            };
+            let prefix_request = Request::SearchPrefix { prefix: s.clone() };
+            assert_eq!(
+                Request::from_bytes(&prefix_request.to_bytes()[..]).unwrap(),
+                prefix_request
+            );
+            let substring_request = Request::SearchSubstring { text: s.clone() };
+            assert_eq!(
+                Request::from_bytes(&substring_request.to_bytes()[..]).unwrap(),
+                substring_request
+            );
+            let query_request = Request::Query { expr: s.clone() };
+            assert_eq!(
+                Request::from_bytes(&query_request.to_bytes()[..]).unwrap(),
+                query_request
+            );
+            for length in [None, Some(n)] {
+                let range_request = Request::Retrieve {
+                    id: n,
+                    offset: n / 2,
+                    length,
+                };
+                assert_eq!(
+                    Request::from_bytes(&range_request.to_bytes()[..]).unwrap(),
+                    range_request
+                );
+            }
+            for after in [None, Some(s.clone())] {
+                let term_stats_request = Request::TermStats { after, limit: n };
+                assert_eq!(
+                    Request::from_bytes(&term_stats_request.to_bytes()[..]).unwrap(),
+                    term_stats_request
+                );
+            }
+            let batch_request = Request::PublishBatch {
+                docs: vec![s.clone(), String::new()],
+                options: PublishOptions::default(),
+            };
+            assert_eq!(
+                Request::from_bytes(&batch_request.to_bytes()[..]).unwrap(),
+                batch_request
+            );
+            assert_eq!(
+                Request::from_bytes(&update_request.to_bytes()[..]).unwrap(),
+                update_request
+            );
+            assert_eq!(
+                Request::from_bytes(&publish_with_request.to_bytes()[..]).unwrap(),
+                publish_with_request
+            );
+            assert_eq!(
+                Request::from_bytes(&commit_request.to_bytes()[..]).unwrap(),
+                commit_request
+            );
+            let ranked_request = Request::SearchRanked {
+                word: s.clone(),
+                k: n,
+            };
+            let suggest_request = Request::SuggestTerms {
+                prefix: s,
+                limit: n,
+            };
+            assert_eq!(
+                Request::from_bytes(&pub_request.to_bytes()[..]).unwrap(),
+                pub_request
+            );
+            assert_eq!(
+                Request::from_bytes(&search_request.to_bytes()[..]).unwrap(),
+                search_request
+            );
+            assert_eq!(
+                Request::from_bytes(&retrieve_request.to_bytes()[..]).unwrap(),
+                retrieve_request
+            );
+            assert_eq!(
+                Request::from_bytes(&list_request.to_bytes()[..]).unwrap(),
+                list_request
+            );
+            assert_eq!(
+                Request::from_bytes(&suggest_request.to_bytes()[..]).unwrap(),
+                suggest_request
+            );
+            assert_eq!(
+                Request::from_bytes(&search_with_request.to_bytes()[..]).unwrap(),
+                search_with_request
+            );
+            assert_eq!(
+                Request::from_bytes(&ranked_request.to_bytes()[..]).unwrap(),
+                ranked_request
+            );
+        }
+        quickcheck(round_trip_request as fn(String, usize));
+    }
+
+    #[test]
+    fn test_framing_5() {
+        let bytes = Request::Search {
+            word: "hello".to_string(),
+        }
+        .to_bytes();
+        // The header holds the length of the rest of the frame
+        assert_eq!(bytes[..4], ((bytes.len() - 4) as u32).to_be_bytes());
+        // Truncated frames are rejected
+        for len in 0..bytes.len() {
+            assert_eq!(Request::from_bytes(&bytes[..len]), None);
+        }
+        // As are frames with bytes left over after the message
+        let mut padded = bytes.clone();
+        padded.push(0);
+        padded[3] += 1;
+        assert_eq!(Request::from_bytes(&padded[..]), None);
+        // And frames too large to accept, without reading their body
+        let oversized = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes();
+        assert_eq!(Response::from_bytes(&oversized[..]), None);
+    }
+
+    #[test]
+    fn test_read_limited_5() {
+        let limits = MessageLimits {
+            max_message_len: 64,
+            max_document_len: 8,
+        };
+        let publish = |doc: &str| {
+            Request::Publish {
+                doc: doc.to_string(),
+            }
+            .to_bytes()
+        };
+        assert_eq!(
+            Request::read_limited(&publish("short")[..], &limits),
+            Ok(Request::Publish {
+                doc: "short".to_string()
+            })
+        );
+        assert_eq!(
+            Request::read_limited(&publish("not so short")[..], &limits),
+            Err(DecodeError::TooLarge)
+        );
+        assert_eq!(
+            Request::read_limited(&publish(&"x".repeat(100))[..], &limits),
+            Err(DecodeError::TooLarge)
+        );
+        // One oversized document is enough to refuse a whole batch
+        let batch = Request::PublishBatch {
+            docs: vec!["short".to_string(), "not so short".to_string()],
+            options: PublishOptions::default(),
+        };
+        assert_eq!(
+            Request::read_limited(&batch.to_bytes()[..], &limits),
+            Err(DecodeError::TooLarge)
+        );
+        // A string claiming to be enormous is rejected without allocating for it. The frame has
+        // a default header (no response limit, no truncation, no metadata), then the `Search` tag
+        let mut bytes = vec![0, 0, 0, 12, 0, 0, 0, 2];
+        bytes.extend(usize::MAX.to_be_bytes());
+        assert_eq!(
+            Request::read_limited(&bytes[..], &limits),
+            Err(DecodeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_response_json_5() {
+        assert_eq!(
+            Response::SearchSuccess(vec![1, 2]).to_json().to_string(),
+            r#"{"doc_ids":[1,2],"type":"search"}"#
+        );
+        assert_eq!(
+            Response::PublishSuccess(3).to_json()["doc_id"],
+            serde_json::json!(3)
+        );
+        let failure = Response::failure(ErrorCode::NotFound, "no document with id 3").to_json();
+        assert_eq!(failure["type"], "failure");
+        assert_eq!(failure["code"], "not_found");
+        assert_eq!(failure["message"], "no document with id 3");
+    }
+
+    #[test]
+    fn test_round_trip_response_5() {
+        fn round_trip_response(s: String, n: usize) {
+            let pub_response = Response::PublishSuccess(n);
+            let search_response = Response::SearchSuccess(vec![n]);
+            let retrieve_response = Response::RetrieveSuccess(s.clone());
+            let list_response = Response::ListSuccess(vec![DocumentSummary {
+                id: n,
+                length: s.len(),
+                preview: s.clone(),
+                published_at: n as u64,
+            }]);
+            assert_eq!(
+                Response::from_bytes(&pub_response.to_bytes()[..]).unwrap(),
+                pub_response
+            );
+            assert_eq!(
+                Response::from_bytes(&search_response.to_bytes()[..]).unwrap(),
+                search_response
+            );
+            assert_eq!(
+                Response::from_bytes(&retrieve_response.to_bytes()[..]).unwrap(),
+                retrieve_response
+            );
+            assert_eq!(
+                Response::from_bytes(&list_response.to_bytes()[..]).unwrap(),
+                list_response
+            );
+            assert_eq!(
+                Response::from_bytes(&Response::Busy.to_bytes()[..]).unwrap(),
+                Response::Busy
+            );
+            for code in ErrorCode::ALL {
+                let failure_response = Response::failure(code, s.clone());
+                assert_eq!(
+                    Response::from_bytes(&failure_response.to_bytes()[..]).unwrap(),
+                    failure_response
+                );
+            }
+            let paged_response = Response::Paged {
+                response: Box::new(Response::SearchSuccess(vec![n])),
+                total_hits: n,
+            };
+            assert_eq!(
+                Response::from_bytes(&paged_response.to_bytes()[..]).unwrap(),
+                paged_response
+            );
+            let snippets_response = Response::SearchSnippetsSuccess(vec![(n, s.clone())]);
+            assert_eq!(
+                Response::from_bytes(&snippets_response.to_bytes()[..]).unwrap(),
+                snippets_response
+            );
+            let term_stats_response = Response::TermStatsSuccess(vec![(s.clone(), n, n / 2)]);
+            assert_eq!(
+                Response::from_bytes(&term_stats_response.to_bytes()[..]).unwrap(),
+                term_stats_response
+            );
+            let suggest_response = Response::SuggestSuccess(vec![(s.clone(), n)]);
+            assert_eq!(
+                Response::from_bytes(&suggest_response.to_bytes()[..]).unwrap(),
+                suggest_response
+            );
+            assert_eq!(
+                Response::from_bytes(&Response::TooLarge.to_bytes()[..]).unwrap(),
+                Response::TooLarge
+            );
+            assert_eq!(
+                Response::from_bytes(&Response::CommitSuccess(n).to_bytes()[..]).unwrap(),
+                Response::CommitSuccess(n)
+            );
+            assert_eq!(
+                Response::from_bytes(&Response::UpdateSuccess(n).to_bytes()[..]).unwrap(),
+                Response::UpdateSuccess(n)
+            );
+            let batch_response = Response::PublishBatchSuccess(vec![n, n.wrapping_add(1)]);
+            assert_eq!(
+                Response::from_bytes(&batch_response.to_bytes()[..]).unwrap(),
+                batch_response
+            );
+            assert_eq!(
+                Request::from_bytes(&Request::Stats.to_bytes()[..]).unwrap(),
+                Request::Stats
+            );
+            for request in [
+                Request::Shutdown,
+                Request::Snapshot,
+                Request::Ping,
+                Request::Count { word: s.clone() },
+                Request::Export { path: s.clone() },
+                Request::Import { path: s.clone() },
+                Request::CreateCollection { name: s.clone() },
+                Request::ListCollections,
+                Request::Replicate {
+                    session: n as u64,
+                    seq: n as u64,
+                    entry: s.clone(),
+                },
+                Request::Subscribe { word: s.clone() },
+                Request::ResizePool { workers: n },
+            ] {
+                assert_eq!(
+                    Request::from_bytes(&request.to_bytes()[..]).as_ref(),
+                    Some(&request)
+                );
+            }
+            let pong = Response::Pong {
+                uptime_secs: n as u64,
+                documents: n,
+                version: s.clone(),
+            };
+            for response in [
+                Response::ShuttingDown,
+                Response::SnapshotSuccess(n),
+                pong,
+                Response::CountSuccess(n),
+                Response::ExportSuccess(n),
+                Response::ImportSuccess(n),
+                Response::CollectionCreated(s.clone()),
+                Response::CollectionsSuccess(vec![s.clone(), String::new()]),
+                Response::Replicated(n as u64),
+                Response::Subscribed(s.clone()),
+                Response::Notification {
+                    id: n,
+                    preview: s.clone(),
+                },
+                Response::Tagged {
+                    request_id: n as u64,
+                    response: Box::new(Response::SearchSuccess(vec![n])),
+                },
+                Response::PoolResized(n),
+            ] {
+                assert_eq!(
+                    Response::from_bytes(&response.to_bytes()[..]).as_ref(),
+                    Some(&response)
+                );
+            }
+            let stats_response = Response::StatsSuccess(s.clone());
+            assert_eq!(
+                Response::from_bytes(&stats_response.to_bytes()[..]).unwrap(),
+                stats_response
+            );
+            let ranked_response = Response::SearchRankedSuccess(vec![(n, n as f32 / 3.0)]);
+            assert_eq!(
+                Response::from_bytes(&ranked_response.to_bytes()[..]).unwrap(),
+                ranked_response
+            );
+            let truncated_response = Response::Truncated(Box::new(list_response));
+            assert_eq!(
+                Response::from_bytes(&truncated_response.to_bytes()[..]).unwrap(),
+                truncated_response
+            );
+            assert_eq!(
+                Response::from_bytes(&Response::ResponseTooLarge(n).to_bytes()[..]).unwrap(),
+                Response::ResponseTooLarge(n)
+            );
+            let metadata_response = Response::WithMetadata {
+                response: Box::new(Response::SearchSuccess(vec![n])),
+                metadata: vec![(
+                    n,
+                    Metadata {
+                        title: None,
+                        author: Some(s.clone()),
+                        date: Some(s.clone()),
+                    },
+                )],
+            };
+            assert_eq!(
+                Response::from_bytes(&metadata_response.to_bytes()[..]).unwrap(),
+                metadata_response
+            );
+        }
+        quickcheck(round_trip_response as fn(String, usize));
+    }
+
+    #[test]
+    fn test_round_trip_request_header_5() {
+        fn round_trip_header(
+            word: String,
+            max_response_len: Option<usize>,
+            flags: (bool, bool),
+            token: Option<String>,
+            collection: Option<String>,
+            request_id: Option<u64>,
+        ) {
+            let header = RequestHeader {
+                max_response_len,
+                allow_truncation: flags.0,
+                include_metadata: flags.1,
+                token,
+                collection,
+                request_id,
+                ..RequestHeader::default()
+            };
+            let request = Request::Search { word };
+            let bytes = request.to_bytes_with(&header);
+            assert_eq!(
+                Request::read_with_header(&bytes[..], &MessageLimits::default()),
+                Ok((request, header))
+            );
+        }
+        quickcheck(
+            round_trip_header
+                as fn(
+                    String,
+                    Option<usize>,
+                    (bool, bool),
+                    Option<String>,
+                    Option<String>,
+                    Option<u64>,
+                ),
+        );
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_round_trip_compressed_5() {
+        let header = RequestHeader {
+            compression: true,
+            ..RequestHeader::default()
+        };
+        let doc = "the cat sat on the mat ".repeat(1000);
+        // Large messages are compressed, and decode to what was sent
+        let request = Request::Publish { doc: doc.clone() };
+        let bytes = request.to_bytes_with(&header);
+        assert!(bytes.len() < doc.len() / 10);
+        assert_eq!(
+            Request::read_with_header(&bytes[..], &MessageLimits::default()),
+            Ok((request, header.clone()))
+        );
+        let response = Response::RetrieveSuccess(doc.clone());
+        let bytes = response.to_bytes_compressed();
+        assert!(bytes.len() < doc.len() / 10);
+        assert_eq!(Response::from_bytes(&bytes[..]), Some(response));
+        // Small ones are sent as they are
+        let response = Response::RetrieveSuccess("cat".to_string());
+        assert_eq!(response.to_bytes_compressed(), response.to_bytes());
+        // A message that decompresses to more than the limit is refused
+        let limits = MessageLimits {
+            max_message_len: 1000,
+            ..MessageLimits::default()
+        };
+        let bytes = Request::Publish { doc }.to_bytes_with(&header);
+        assert!(Request::read_with_header(&bytes[..], &limits).is_err());
+    }
+
+    #[test]
+    fn test_protocol_version_5() {
+        let bytes = Request::Search {
+            word: "cat".to_string(),
+        }
+        .to_bytes();
+        assert_eq!(&bytes[4..7], &[MAGIC[0], MAGIC[1], PROTOCOL_VERSION]);
+        // Requests in a version the reader doesn't speak are told apart from garbage
+        let mut newer = bytes.clone();
+        newer[6] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Request::read_limited(&newer[..], &MessageLimits::default()),
+            Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+        let mut garbage = bytes.clone();
+        garbage[4] = b'X';
+        assert_eq!(
+            Request::read_limited(&garbage[..], &MessageLimits::default()),
+            Err(DecodeError::Malformed)
+        );
+        // Older headers end before the flags later versions added, which read as unset
+        let header = RequestHeader {
+            keep_alive: true,
+            stream_documents: true,
+            ..RequestHeader::default()
+        };
+        let bytes = Request::Stats.to_bytes_with(&header);
+        assert_eq!(bytes[12..16], [1, 1, 0, 0]);
+        let mut v4 = bytes.clone();
+        v4.remove(15);
+        v4[3] -= 1;
+        v4[6] = 4;
+        assert_eq!(
+            Request::read_with_header(&v4[..], &MessageLimits::default()),
+            Ok((Request::Stats, header.clone()))
+        );
+        let mut v3 = v4.clone();
+        v3.remove(14);
+        v3[3] -= 1;
+        v3[6] = 3;
+        assert_eq!(
+            Request::read_with_header(&v3[..], &MessageLimits::default()),
+            Ok((Request::Stats, header.clone()))
+        );
+        let mut v2 = v3.clone();
+        v2.remove(13);
+        v2[3] -= 1;
+        v2[6] = 2;
+        assert_eq!(
+            Request::read_with_header(&v2[..], &MessageLimits::default()),
+            Ok((
+                Request::Stats,
+                RequestHeader {
+                    keep_alive: true,
+                    ..RequestHeader::default()
+                }
+            ))
+        );
+        let mut v1 = v2.clone();
+        v1.remove(12);
+        v1[3] -= 1;
+        v1[6] = 1;
+        assert_eq!(
+            Request::read_with_header(&v1[..], &MessageLimits::default()),
+            Ok((Request::Stats, RequestHeader::default()))
+        );
+        // A reader that finds the stream closed before a frame starts says so
+        assert_eq!(
+            Request::read_limited(&[][..], &MessageLimits::default()),
+            Err(DecodeError::Closed)
+        );
+        let response = Response::UnsupportedVersion { min: 1, max: 3 };
+        assert_eq!(
+            Response::from_bytes(&response.to_bytes()[..]),
+            Some(response)
+        );
+    }
+
+    #[test]
+    fn test_publish_stream_5() {
+        let options = PublishOptions {
+            pending: true,
+            ..PublishOptions::default()
+        };
+        let stream = |chunks: &[&[u8]]| {
+            let mut bytes = publish_stream_start(&RequestHeader::default(), &options);
+            for chunk in chunks {
+                bytes.extend(stream_chunk(chunk));
+            }
+            bytes
+        };
+        // The chunks are put back together, even where they split a character
+        let bytes = stream(&[b"caf\xc3", b"\xa9 ", b"au lait", b""]);
+        assert_eq!(
+            Request::read_limited(&bytes[..], &MessageLimits::default()),
+            Ok(Request::PublishWith {
+                doc: "caf\u{e9} au lait".to_string(),
+                options: options.clone(),
+            })
+        );
+        // The document may be longer than any one frame, but not than the document limit
+        let limits = MessageLimits {
+            max_message_len: 64,
+            max_document_len: 12,
+        };
+        let bytes = stream(&[b"twelve bytes", b""]);
+        assert!(Request::read_limited(&bytes[..], &limits).is_ok());
+        let bytes = stream(&[b"twelve", b" bytes", b"!", b""]);
+        assert_eq!(
+            Request::read_limited(&bytes[..], &limits),
+            Err(DecodeError::TooLarge)
+        );
+        // A stream without its empty last chunk was cut off
+        let bytes = stream(&[b"cut off"]);
+        assert_eq!(
+            Request::read_limited(&bytes[..], &limits),
+            Err(DecodeError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_document_stream_5() {
+        let doc = "streamed back ".repeat(10_000);
+        let bytes: Vec<u8> = document_stream(&doc).flatten().collect();
+        // Too large for one frame under the limit, but each chunk fits
+        assert!(doc.len() > 2 * STREAM_CHUNK_LEN);
+        assert_eq!(
+            Response::read_limited(&bytes[..], doc.len()),
+            Some(Response::RetrieveSuccess(doc.clone()))
+        );
+        assert_eq!(Response::read_limited(&bytes[..], doc.len() - 1), None);
+        let mut written = Vec::new();
+        assert_eq!(
+            Response::read_streamed(&bytes[..], &mut written, MAX_FRAME_LEN).unwrap(),
+            Ok(doc.len() as u64)
+        );
+        assert_eq!(written, doc.as_bytes());
+        // Other responses are handed back as they are
+        let not_found = Response::not_found(3);
+        assert_eq!(
+            Response::read_streamed(&not_found.to_bytes()[..], &mut written, MAX_FRAME_LEN)
+                .unwrap(),
+            Err(not_found)
+        );
+        // A stream that is cut off is invalid
+        assert!(Response::read_streamed(&bytes[..100], Vec::new(), MAX_FRAME_LEN).is_err());
+    }
+
+    #[test]
+    fn test_fixed_width_encoding_5() {
+        // Ids and lengths are sent as 8-byte u64s whatever the width of usize
+        let bytes = Request::Retrieve {
+            id: 258,
+            offset: 0,
+            length: None,
+        }
+        .to_bytes();
+        assert_eq!(&bytes[bytes.len() - 9..], &[3, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(
+            Response::RetrieveSuccess("hi".to_string()).to_bytes(),
+            vec![0, 0, 0, 11, 3, 0, 0, 0, 0, 0, 0, 0, 2, b'h', b'i']
+        );
+        // An id too large for this machine is malformed rather than wrapped around
+        if usize::BITS < 64 {
+            let mut bytes = Request::Retrieve {
+                id: 0,
+                offset: 0,
+                length: None,
+            }
+            .to_bytes();
+            let len = bytes.len();
+            bytes[len - 8] = 1;
+            assert_eq!(Request::from_bytes(&bytes[..]), None);
+        }
+    }
+
+    #[test]
+    fn test_fit_response_5() {
+        let ids: Vec<usize> = (0..100).collect();
+        let full = Response::SearchSuccess(ids.clone());
+        let size = full.to_bytes().len();
+        let limited = |allow_truncation| RequestHeader {
+            max_response_len: Some(200),
+            allow_truncation,
+            ..RequestHeader::default()
+        };
+        // Small enough responses, and requests without a limit, are left alone
+        assert_eq!(
+            Response::SearchSuccess(vec![1]).fit(&limited(false)),
+            Response::SearchSuccess(vec![1])
+        );
+        assert_eq!(
+            Response::SearchSuccess(ids.clone()).fit(&RequestHeader::default()),
+            full
+        );
+        assert_eq!(
+            Response::SearchSuccess(ids.clone()).fit(&limited(false)),
+            Response::ResponseTooLarge(size)
+        );
+        // The longest prefix that fits is kept
+        let Response::Truncated(inner) = Response::SearchSuccess(ids.clone()).fit(&limited(true))
+        else {
+            panic!("expected a truncated response");
+        };
+        let Response::SearchSuccess(kept) = *inner else {
+            panic!("expected search results");
+        };
+        let fits = |n: usize| {
+            Response::Truncated(Box::new(Response::SearchSuccess(ids[..n].to_vec())))
+                .to_bytes()
+                .len()
+                <= 200
+        };
+        assert_eq!(kept, ids[..kept.len()]);
+        assert!(fits(kept.len()) && !fits(kept.len() + 1));
+        // A page is cut down inside its wrapper, keeping its total
+        let page = Response::Paged {
+            response: Box::new(Response::SearchSuccess(ids.clone())),
+            total_hits: 1000,
+        };
+        let truncated = page.fit(&limited(true));
+        assert!(truncated.to_bytes().len() <= 200);
+        match truncated {
+            Response::Truncated(inner) => match *inner {
+                Response::Paged {
+                    response,
+                    total_hits: 1000,
+                } => match *response {
+                    Response::SearchSuccess(page) => {
+                        assert!(!page.is_empty() && page.len() < kept.len());
+                        assert_eq!(page, ids[..page.len()]);
+                    }
+                    other => panic!("unexpected response {:?}", other),
+                },
+                other => panic!("unexpected response {:?}", other),
+            },
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        // Documents are cut on a character boundary
+        let doc = "é".repeat(100);
+        match Response::RetrieveSuccess(doc.clone()).fit(&limited(true)) {
+            Response::Truncated(inner) => match *inner {
+                Response::RetrieveSuccess(text) => {
+                    assert!(doc.starts_with(&text) && !text.is_empty());
+                    assert!(
+                        Response::Truncated(Box::new(Response::RetrieveSuccess(text)))
+                            .to_bytes()
+                            .len()
+                            <= 200
+                    );
+                }
+                other => panic!("unexpected response {:?}", other),
+            },
+            other => panic!("unexpected response {:?}", other),
+        }
+        // Responses that can't be cut down are refused
+        let failure_limit = RequestHeader {
+            max_response_len: Some(2),
+            allow_truncation: true,
+            ..RequestHeader::default()
+        };
+        assert_eq!(
+            Response::PublishSuccess(1).fit(&failure_limit),
+            Response::ResponseTooLarge(Response::PublishSuccess(1).to_bytes().len())
+        );
+    }
+}
+
+// ============================ FAULTS ============================
+#[cfg(feature = "fault-injection")]
+mod test_faults {
+    use ngram::faults::*;
+    use ngram::message::*;
+    use ngram::{client, server};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_roll_rates_5() {
+        let never = FaultInjector::with_seed(FaultConfig::default(), 7);
+        assert!((0..1000).all(|_| never.roll().is_empty()));
+
+        let config = FaultConfig {
+            delay: Duration::from_millis(5),
+            delay_rate: 1.0,
+            drop_rate: 1.0,
+            corrupt_rate: 1.0,
+        };
+        let always = FaultInjector::with_seed(config, 7);
+        assert!((0..1000).all(|_| {
+            always.roll() == vec![Fault::Delay(Duration::from_millis(5)), Fault::Drop]
+        }));
+
+        let half = FaultInjector::with_seed(
+            FaultConfig {
+                drop_rate: 0.5,
+                ..FaultConfig::default()
+            },
+            7,
+        );
+        let drops = (0..10_000).filter(|_| !half.roll().is_empty()).count();
+        assert!((4_000..6_000).contains(&drops));
+    }
+
+    #[test]
+    fn test_corrupt_5() {
+        let mut bytes = Response::SearchSuccess(vec![1, 2, 3]).to_bytes();
+        corrupt(&mut bytes);
+        assert_eq!(Response::from_bytes(&bytes[..]), None);
+    }
+
+    #[test]
+    fn test_injected_faults_5() {
+        let port = 7898;
+        let config = FaultConfig {
+            delay: Duration::from_millis(300),
+            delay_rate: 1.0,
+            drop_rate: 1.0,
+            corrupt_rate: 0.0,
+        };
+        let server = Arc::new(server::Server::new().with_faults(config));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let client = client::Client::new("127.0.0.1", port);
+        let start = Instant::now();
+        assert_eq!(client.search("anything"), None);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        server.stop();
+    }
+}
+
+// ============================ ASYNC SERVER ============================
+#[cfg(feature = "async")]
+mod test_async_server {
+    use ngram::client::{AsyncClient, Client};
+    use ngram::message::*;
+    use ngram::server::{AsyncServer, ConnectionTimeouts};
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_async_server_many_idle_clients_5() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = Arc::new(AsyncServer::new());
+        let running = runtime.spawn({
+            let server = Arc::clone(&server);
+            async move { server.run(0).await }
+        });
+        let port = loop {
+            match server.local_addrs().first() {
+                Some(addr) => break addr.port(),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // Far more idle connections than a thread pool would have workers
+        let idle = (0..200)
+            .map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap())
+            .collect::<Vec<_>>();
+        let client = Client::new("127.0.0.1", port);
+        let id = match client.send(&Request::Publish {
+            doc: "async tasks are cheap".to_string(),
+        }) {
+            Some(Response::PublishSuccess(id)) => id,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(
+            client.search("cheap"),
+            Some(Response::SearchSuccess(vec![id]))
+        );
+        drop(idle);
+
+        server.stop();
+        runtime.block_on(running).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_async_server_subscribe_5() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = Arc::new(AsyncServer::new());
+        let running = runtime.spawn({
+            let server = Arc::clone(&server);
+            async move { server.run(0).await }
+        });
+        let port = loop {
+            match server.local_addrs().first() {
+                Some(addr) => break addr.port(),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        let client = Client::builder("127.0.0.1", port)
+            .with_read_timeout(Duration::from_secs(5))
+            .build();
+        let mut subscription = client.subscribe("Whales").ok().unwrap();
+        client.send(&Request::Publish {
+            doc: "no fish here".to_string(),
+        });
+        client.send(&Request::Publish {
+            doc: "a pod of whales".to_string(),
+        });
+        assert_eq!(
+            subscription.next(),
+            Some((1, "a pod of whales".to_string()))
+        );
+
+        // Stopping the server ends the subscription
+        server.stop();
+        assert_eq!(subscription.next(), None);
+        runtime.block_on(running).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_async_server_pipeline_5() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = Arc::new(AsyncServer::new());
+        let running = runtime.spawn({
+            let server = Arc::clone(&server);
+            async move { server.run(0).await }
+        });
+        let port = loop {
+            match server.local_addrs().first() {
+                Some(addr) => break addr.port(),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        let client = Client::new("127.0.0.1", port);
+        let published = client.pipeline(
+            &(0..50)
+                .map(|i| Request::Publish {
+                    doc: format!("pipelined {}", i),
+                })
+                .collect::<Vec<_>>(),
+        );
+        // Pipelined requests are answered concurrently, so the documents may get any ids, but
+        // each gets its own
+        let mut ids = published
+            .into_iter()
+            .map(|response| match response {
+                Some(Response::PublishSuccess(id)) => id,
+                other => panic!("unexpected response {:?}", other),
+            })
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, (0..50).collect::<Vec<_>>());
+        let found = client.pipeline(&[
+            Request::Search {
+                word: "pipelined".to_string(),
+            },
+            Request::Ping,
+        ]);
+        assert!(matches!(&found[0], Some(Response::SearchSuccess(ids)) if ids.len() == 50));
+        assert!(matches!(found[1], Some(Response::Pong { .. })));
+
+        server.stop();
+        runtime.block_on(running).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_async_server_read_timeout_5() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = Arc::new(AsyncServer::new().with_timeouts(ConnectionTimeouts {
+            read: Some(Duration::from_millis(200)),
+            write: None,
+        }));
+        let running = runtime.spawn({
+            let server = Arc::clone(&server);
+            async move { server.run(0).await }
+        });
+        let port = loop {
+            match server.local_addrs().first() {
+                Some(addr) => break addr.port(),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // A client that never sends its request is told so, and then hung up on
+        let mut idle = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(matches!(
+            Response::from_bytes(&mut idle),
+            Some(Response::Failure {
+                code: ErrorCode::TimedOut,
+                ..
+            })
+        ));
+        assert_eq!(idle.read(&mut [0_u8; 1]).unwrap(), 0);
+
+        server.stop();
+        runtime.block_on(running).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_async_server_streamed_publish_5() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = Arc::new(AsyncServer::new());
+        let running = runtime.spawn({
+            let server = Arc::clone(&server);
+            async move { server.run(0).await }
+        });
+        let port = loop {
+            match server.local_addrs().first() {
+                Some(addr) => break addr.port(),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        let client = Client::new("127.0.0.1", port);
+        let doc = "streamed words ".repeat(10_000);
+        assert_eq!(
+            client.publish_from_reader(doc.as_bytes()),
+            Some(Response::PublishSuccess(0))
+        );
+        assert_eq!(client.retrieve(0), Some(Response::RetrieveSuccess(doc)));
+
+        server.stop();
+        runtime.block_on(running).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_async_client_5() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = Arc::new(AsyncServer::new());
+        let running = runtime.spawn({
+            let server = Arc::clone(&server);
+            async move { server.run(0).await }
+        });
+        let port = loop {
+            match server.local_addrs().first() {
+                Some(addr) => break addr.port(),
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        let client = Arc::new(AsyncClient::new("127.0.0.1", port));
+        runtime.block_on(async {
+            assert_eq!(
+                client.publish("awaited words").await,
+                Some(Response::PublishSuccess(0))
+            );
+            // Many requests can be in flight from one task
+            let searches = (0..20).map(|_| {
+                let client = Arc::clone(&client);
+                tokio::spawn(async move { client.search("awaited").await })
+            });
+            for search in searches {
+                assert_eq!(
+                    search.await.unwrap(),
+                    Some(Response::SearchSuccess(vec![0]))
+                );
+            }
+            assert_eq!(
+                client.retrieve(0).await,
+                Some(Response::RetrieveSuccess("awaited words".to_string()))
+            );
+        });
+
+        server.stop();
+        runtime.block_on(running).unwrap().unwrap();
+        // With the server gone, requests fail rather than hang
+        let unreachable = Client::builder("127.0.0.1", port)
+            .with_connect_timeout(Duration::from_millis(200))
+            .build_async();
+        assert_eq!(runtime.block_on(unreachable.search("awaited")), None);
+    }
+}
+
+// ============================ ARGUMENTS ============================
+
+// graded manually
+
+// ============================ CLIENT + Server============================
+
+mod integration {
+    use super::*;
+    use ngram::database::{DocumentSummary, Duplicates, Metadata, PublishOptions};
+    use ngram::message::*;
+    use ngram::{client, server};
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    fn start_server(port: u16) -> (Arc<server::Server>, JoinHandle<()>) {
+        let server = Arc::new(server::Server::new());
+        let handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        (server, handle)
+    }
+
+    // The key tests make admin requests with, which `admin_keys` gives the admin role.
+    const ADMIN_KEY: &str = "4dmin";
+
+    // API keys with only `ADMIN_KEY` in them, for servers that tests make admin requests to.
+    fn admin_keys() -> ngram::auth::ApiKeys {
+        ngram::auth::ApiKeys::new().with_key("admin", ADMIN_KEY, ngram::auth::Role::Admin)
+    }
+
+    // The code of `response` if it is a failure.
+    fn failure_code(response: Option<Response>) -> Option<ErrorCode> {
+        match response {
+            Some(Response::Failure { code, .. }) => Some(code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_start_stop_server_5() {
+        let port = 7880;
+        let (server, _handle) = start_server(port);
+        server.stop();
+    }
+
+    #[test]
+    fn test_publish_5() {
+        let port = 7881;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let response = client.publish_from_path("data/austen-emma.txt");
+        assert!(matches!(response, Some(Response::PublishSuccess(_))));
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_empty_5() {
+        let port = 7882;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let response = client.search("a");
+        assert_eq!(response, Some(Response::SearchSuccess(vec![])));
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_inserted_5() {
+        let port = 7883;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+
+        let response = client.publish_from_path("data/austen-emma.txt");
+        let id = match response {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-emma.txt"),
+        };
+        let response = client.search("the");
+        assert_eq!(response, Some(Response::SearchSuccess(vec![id])));
+        server.stop();
     }
 
     #[test]
@@ -268,151 +3042,2523 @@ mod integration {
         let (server, _handle) = start_server(port);
 
         let client = client::Client::new("127.0.0.1", port);
-        let id1 = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
-            _ => panic!("Failed to publish data/austen-emma.txt"),
+        let id1 = match client.publish_from_path("data/austen-emma.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-emma.txt"),
+        };
+        let id2 = match client.publish_from_path("data/austen-persuasion.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-persuasion.txt"),
+        };
+
+        let response = client.search("little");
+        if let Some(Response::SearchSuccess(ids)) = response {
+            assert_eq!(ids.len(), 2);
+            assert!(ids.contains(&id1));
+            assert!(ids.contains(&id2));
+        } else {
+            panic!("Failed to search for 'little'");
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_distractor_5() {
+        let port = 7885;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let _id1 = match client.publish_from_path("data/austen-persuasion.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-persuasion.txt"),
+        };
+        let id2 = match client.publish_from_path("data/austen-emma.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-emma.txt"),
+        };
+
+        let response = client.search("ceased");
+        assert_eq!(response, Some(Response::SearchSuccess(vec![id2])));
+        server.stop();
+    }
+
+    #[test]
+    fn test_retrieve_5() {
+        let port = 7886;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        let id = match client.publish_from_path("data/austen-emma.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-emma.txt"),
+        };
+        let doc = std::fs::read_to_string("data/austen-emma.txt").unwrap();
+        let response = client.retrieve(id);
+        assert_eq!(response, Some(Response::RetrieveSuccess(doc)));
+        server.stop();
+    }
+
+    #[test]
+    fn test_multiple_listeners_5() {
+        let server = Arc::new(server::Server::new());
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                server.run_listeners(&[
+                    server::ListenerConfig::new(7890),
+                    server::ListenerConfig::read_only(7891),
+                ])
+            }
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let writer = client::Client::new("127.0.0.1", 7890);
+        let reader = client::Client::new("127.0.0.1", 7891);
+        let id = match writer.publish_from_path("data/blake-poems.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/blake-poems.txt"),
+        };
+        assert!(matches!(
+            reader.publish_from_path("data/blake-poems.txt"),
+            Some(Response::Failure {
+                code: ErrorCode::ReadOnly,
+                ..
+            })
+        ));
+        assert!(matches!(
+            writer.retrieve(id + 1000),
+            Some(Response::Failure {
+                code: ErrorCode::NotFound,
+                ..
+            })
+        ));
+        assert_eq!(
+            reader.search("tigers"),
+            Some(Response::SearchSuccess(vec![id]))
+        );
+        // Admin requests need an admin key, and are never taken on a read-only listener
+        assert_eq!(
+            failure_code(writer.shutdown()),
+            Some(ErrorCode::Unauthorized)
+        );
+        assert_eq!(failure_code(reader.shutdown()), Some(ErrorCode::ReadOnly));
+        server.stop();
+    }
+
+    #[test]
+    fn test_list_5() {
+        let port = 7894;
+        let (server, _handle) = start_server(port);
+
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(client.list(10), Some(Response::ListSuccess(vec![])));
+        let id = match client.publish_from_path("data/blake-poems.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/blake-poems.txt"),
+        };
+        let doc = fs::read_to_string("data/blake-poems.txt").unwrap();
+        let summaries = match client.list(10) {
+            Some(Response::ListSuccess(summaries)) => summaries,
+            response => panic!("Unexpected list response {:?}", response),
+        };
+        let expected = DocumentSummary {
+            id,
+            length: doc.len(),
+            preview: doc.chars().take(10).collect(),
+            published_at: summaries[0].published_at,
+        };
+        assert_eq!(summaries, vec![expected]);
+        server.stop();
+    }
+
+    #[test]
+    fn test_record_and_replay_5() {
+        use ngram::record::{self, RequestLog};
+        let log_path = std::env::temp_dir().join("ngram-test-requests.jsonl");
+        let _ = fs::remove_file(&log_path);
+        let log_path = log_path.to_str().unwrap();
+
+        let recorded = Arc::new(
+            server::Server::new().with_request_log(RequestLog::open(log_path, true).unwrap()),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&recorded);
+            move || server.run(7895)
+        });
+        let (replayed, _handle) = start_server(7896);
+
+        let client = client::Client::new("127.0.0.1", 7895);
+        client.publish_from_path("data/blake-poems.txt").unwrap();
+        client.search("tigers").unwrap();
+        let summary =
+            record::replay(log_path, &client::Client::new("127.0.0.1", 7896), false).unwrap();
+        assert_eq!(summary.sent, 2);
+        assert_eq!(summary.failed, 0);
+
+        let replayed_client = client::Client::new("127.0.0.1", 7896);
+        assert_eq!(
+            replayed_client.search("tigers"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        recorded.stop();
+        replayed.stop();
+    }
+
+    #[test]
+    fn test_document_limit_5() {
+        let port = 7899;
+        let server = Arc::new(server::Server::new().with_limits(MessageLimits {
+            max_document_len: 1024,
+            ..MessageLimits::default()
+        }));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(
+            client.publish_from_path("data/austen-emma.txt"),
+            Some(Response::TooLarge)
+        );
+        assert_eq!(
+            client.send(&Request::Publish {
+                doc: "small enough".to_string()
+            }),
+            Some(Response::PublishSuccess(0))
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_max_response_len_5() {
+        let port = 7902;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port);
+        for i in 0..50 {
+            client.send(&Request::Publish {
+                doc: format!("shared word {}", i),
+            });
+        }
+        let ids = match client.search("shared") {
+            Some(Response::SearchSuccess(ids)) => ids,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(ids.len(), 50);
+
+        let strict = client::Client::new("127.0.0.1", port).with_max_response_len(100, false);
+        assert!(matches!(
+            strict.search("shared"),
+            Some(Response::ResponseTooLarge(_))
+        ));
+        assert!(matches!(
+            strict.retrieve(0),
+            Some(Response::RetrieveSuccess(_))
+        ));
+
+        let lenient = client::Client::new("127.0.0.1", port).with_max_response_len(100, true);
+        match lenient.search("shared") {
+            Some(Response::Truncated(inner)) => match *inner {
+                Response::SearchSuccess(kept) => {
+                    assert!(!kept.is_empty() && kept.len() < ids.len());
+                    assert_eq!(kept, ids[..kept.len()]);
+                }
+                other => panic!("unexpected response {:?}", other),
+            },
+            other => panic!("unexpected response {:?}", other),
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn test_metadata_5() {
+        let port = 7903;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port);
+        let metadata = Metadata {
+            title: Some("Emma".to_string()),
+            author: Some("Jane Austen".to_string()),
+            date: Some("1815".to_string()),
+        };
+        client.send(&Request::Publish {
+            doc: "an anonymous novel".to_string(),
+        });
+        client.send(&Request::PublishWith {
+            doc: "a novel with a title".to_string(),
+            options: PublishOptions {
+                metadata: metadata.clone(),
+                ..PublishOptions::default()
+            },
+        });
+
+        // Clients that don't ask for metadata get plain responses
+        assert_eq!(
+            client.search("novel"),
+            Some(Response::SearchSuccess(vec![0, 1]))
+        );
+        let client = client.with_metadata();
+        assert_eq!(
+            client.search("novel"),
+            Some(Response::WithMetadata {
+                response: Box::new(Response::SearchSuccess(vec![0, 1])),
+                metadata: vec![(1, metadata.clone())],
+            })
+        );
+        assert_eq!(
+            client.retrieve(1),
+            Some(Response::WithMetadata {
+                response: Box::new(Response::RetrieveSuccess(
+                    "a novel with a title".to_string()
+                )),
+                metadata: vec![(1, metadata)],
+            })
+        );
+        assert_eq!(
+            client.retrieve(0),
+            Some(Response::WithMetadata {
+                response: Box::new(Response::RetrieveSuccess("an anonymous novel".to_string())),
+                metadata: vec![],
+            })
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_failover_to_standby_5() {
+        let standby_port = 7904;
+        let (standby, _handle) = start_server(standby_port);
+        let standby_addr = ([127, 0, 0, 1], standby_port).into();
+        let switches = Arc::new(Mutex::new(Vec::new()));
+        let on_failover: client::FailoverCallback = {
+            let switches = Arc::clone(&switches);
+            Arc::new(move |from, to| switches.lock().unwrap().push((from, to)))
+        };
+
+        // Nothing listens on the primary's port, so nothing was sent to it
+        let primary_addr = ([127, 0, 0, 1], 7905).into();
+        let client = client::Client::new("127.0.0.1", 7905)
+            .with_standby(standby_addr)
+            .with_failover_callback(Arc::clone(&on_failover));
+        assert_eq!(client.active_address(), primary_addr);
+        let publish = Request::Publish {
+            doc: "written to the standby".to_string(),
+        };
+        assert_eq!(client.send(&publish), None);
+        assert_eq!(
+            client.search("standby"),
+            Some(Response::SearchSuccess(vec![]))
+        );
+        assert_eq!(client.active_address(), standby_addr);
+        assert_eq!(
+            *switches.lock().unwrap(),
+            vec![(primary_addr, standby_addr)]
+        );
+        // The client sticks with the standby
+        assert_eq!(client.send(&publish), Some(Response::PublishSuccess(0)));
+        assert_eq!(switches.lock().unwrap().len(), 1);
+
+        let client = client::Client::new("127.0.0.1", 7905)
+            .with_standby(standby_addr)
+            .with_failover_writes()
+            .with_failover_callback(Arc::clone(&on_failover));
+        assert_eq!(client.send(&publish), Some(Response::PublishSuccess(1)));
+
+        // A primary that hangs up without answering may have applied a write, so only reads are
+        // retried on the standby
+        let broken = std::net::TcpListener::bind("127.0.0.1:7906").unwrap();
+        thread::spawn(move || {
+            for stream in broken.incoming() {
+                drop(stream);
+            }
+        });
+        let client = client::Client::new("127.0.0.1", 7906)
+            .with_standby(standby_addr)
+            .with_failover_writes()
+            .with_failover_callback(on_failover);
+        assert_eq!(client.send(&publish), None);
+        assert_eq!(
+            client.search("standby"),
+            Some(Response::SearchSuccess(vec![0, 1]))
+        );
+        assert_eq!(client.active_address(), standby_addr);
+        standby.stop();
+    }
+
+    #[test]
+    fn test_stats_and_metrics_endpoint_5() {
+        use std::io::{Read, Write};
+        let port = 7900;
+        let metrics_port = 7901;
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(admin_keys())
+                .with_metrics_listener(([127, 0, 0, 1], metrics_port).into()),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let client = client::Client::new("127.0.0.1", port).with_token(ADMIN_KEY);
+        client.search("anything");
+        client.search("else");
+        let text = match client.stats() {
+            Some(Response::StatsSuccess(text)) => text,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert!(text.contains(r#"ngram_requests_total{type="search"} 2"#));
+        assert!(text.contains("ngram_documents 0"));
+
+        // A client that never sends its request is given up on, rather than blocking the next
+        let _silent = std::net::TcpStream::connect(("127.0.0.1", metrics_port)).unwrap();
+        let mut http = std::net::TcpStream::connect(("127.0.0.1", metrics_port)).unwrap();
+        http.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut reply = String::new();
+        http.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200 OK"));
+        assert!(reply.contains(r#"ngram_requests_total{type="stats"} 1"#));
+        server.stop();
+    }
+
+    #[test]
+    fn test_port_zero_5() {
+        let server = Arc::new(server::Server::new());
+        assert!(server.local_addrs().is_empty());
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(0)
+        });
+        let addr = loop {
+            match server.local_addrs().first() {
+                Some(addr) => break *addr,
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert_ne!(addr.port(), 0);
+
+        let client = client::Client::new("127.0.0.1", addr.port());
+        assert_eq!(
+            client.search("anything"),
+            Some(Response::SearchSuccess(vec![]))
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_request_context_display_5() {
+        let mut context = server::RequestContext {
+            peer: Some("127.0.0.1:5000".parse().unwrap()),
+            received_at: std::time::SystemTime::now(),
+            identity: None,
+            role: None,
+            trace_id: 42,
+            read_only: false,
+            collection: None,
+        };
+        assert_eq!(context.to_string(), "[000000000000002a 127.0.0.1:5000]");
+        context.identity = Some("alice".to_string());
+        assert_eq!(
+            context.to_string(),
+            "[000000000000002a 127.0.0.1:5000 as alice]"
+        );
+    }
+
+    #[test]
+    fn test_with_capacity_5() {
+        let port = 7897;
+        let server = Arc::new(server::Server::with_capacity(2, 4));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let client = client::Client::new("127.0.0.1", port);
+        let id = match client.publish_from_path("data/austen-emma.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/austen-emma.txt"),
+        };
+        assert_eq!(
+            client.search("ceased"),
+            Some(Response::SearchSuccess(vec![id]))
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_run_on_bind_address_5() {
+        let port = 7893;
+        let server = Arc::new(server::Server::new());
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run_on("0.0.0.0".parse().unwrap(), port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(client.search("a"), Some(Response::SearchSuccess(vec![])));
+        server.stop();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_round_trip_5() {
+        use ngram::tls;
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join("ngram-test-cert.pem");
+        let key_path = dir.join("ngram-test-key.pem");
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        let cert_path = cert_path.to_str().unwrap();
+        let key_path = key_path.to_str().unwrap();
+
+        let port = 7892;
+        let server = Arc::new(
+            server::Server::new().with_tls(tls::server_config(cert_path, key_path).unwrap()),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        let client =
+            client::Client::new("127.0.0.1", port).with_tls(tls::client_config(cert_path).unwrap());
+        let id = match client.publish_from_path("data/blake-poems.txt") {
+            Some(Response::PublishSuccess(id)) => id,
+            _ => panic!("Failed to publish data/blake-poems.txt over TLS"),
+        };
+        let doc = fs::read_to_string("data/blake-poems.txt").unwrap();
+        assert_eq!(client.retrieve(id), Some(Response::RetrieveSuccess(doc)));
+
+        // A plaintext client can't talk to a TLS server
+        let plain = client::Client::new("127.0.0.1", port);
+        assert_eq!(plain.search("tigers"), None);
+        server.stop();
+    }
+
+    #[test]
+    fn test_server_stress_test_10() {
+        let port = 7889;
+        let (server, _handle) = start_server(port);
+
+        let paths = vec![
+            "data/austen-emma.txt",
+            "data/austen-persuasion.txt",
+            "data/austen-sense.txt",
+            "data/bible-kjv.txt",
+            "data/blake-poems.txt",
+            "data/bryant-stories.txt",
+            "data/burgess-busterbrown.txt",
+            "data/carroll-alice.txt",
+            "data/chesterton-ball.txt",
+            "data/chesterton-brown.txt",
+            "data/chesterton-thursday.txt",
+            "data/edgeworth-parents.txt",
+            "data/melville-moby_dick.txt",
+            "data/milton-paradise.txt",
+            "data/shakespeare-caesar.txt",
+            "data/shakespeare-hamlet.txt",
+            "data/shakespeare-macbeth.txt",
+            "data/whitman-leaves.txt",
+        ];
+
+        let queue = Arc::new(Mutex::new(paths));
+        println!("Adding docs...");
+        let _now = std::time::Instant::now();
+        let handles = (0..THREADS)
+            .map(|i| {
+                thread::spawn({
+                    let queue = Arc::clone(&queue);
+                    move || loop {
+                        let client = client::Client::new("127.0.0.1", port);
+                        let path = queue.lock().unwrap().pop();
+                        match path {
+                            Some(path) => {
+                                println!("Thread {}: processing {}", i, path);
+                                client.publish_from_path(path);
+                            }
+                            None => return,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+
+        let words = fs::read_to_string("data/words.txt").unwrap();
+        let words = words
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+
+        // Search docs sequentially
+        let _now = std::time::Instant::now();
+        let client = client::Client::new("127.0.0.1", port);
+        for word in words.iter() {
+            let response = client.search(word);
+            assert!(matches!(response, Some(Response::SearchSuccess(_))));
+        }
+        // println!("Sequential search took {:?}", _now.elapsed);
+
+        // Search docs in parallel
+        let word_queue = Arc::new(Mutex::new(words));
+        let _now = std::time::Instant::now();
+        let handles = (0..THREADS)
+            .map(|_| {
+                thread::spawn({
+                    let word_queue = Arc::clone(&word_queue);
+                    let client = client::Client::new("127.0.0.1", port);
+                    move || loop {
+                        let word = word_queue.lock().unwrap().pop().clone();
+                        match word {
+                            Some(word) => {
+                                let response = client.search(&word);
+                                assert!(matches!(response, Some(Response::SearchSuccess(_))));
+                                //println!("Found {} in {:?}", word, indices);
+                            }
+                            None => return,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        // println!("Parallel search took {:?}", _now.elapsed());
+
+        server.stop();
+    }
+
+    #[test]
+    fn test_export_term_stats_5() {
+        let port = 7907;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port);
+        for i in 0..30 {
+            client.send(&Request::Publish {
+                doc: format!("word{} shared shared", i),
+            });
+        }
+        let mut exported = Vec::new();
+        let count = client
+            .export_term_stats(7, |term, doc_frequency, occurrences| {
+                exported.push((term.to_string(), doc_frequency, occurrences))
+            })
+            .unwrap();
+        assert_eq!(count, 31);
+        assert!(exported.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(exported.contains(&("shared".to_string(), 30, 60)));
+        assert!(exported.contains(&("word17".to_string(), 1, 1)));
+
+        // Pages cut down to fit the client's limit are continued from where they stop
+        let limited = client::Client::new("127.0.0.1", port).with_max_response_len(100, true);
+        let mut terms = Vec::new();
+        limited
+            .export_term_stats(1000, |term, _, _| terms.push(term.to_string()))
+            .unwrap();
+        assert_eq!(terms.len(), 31);
+        server.stop();
+    }
+
+    #[test]
+    fn test_read_timeout_5() {
+        let port = 7908;
+        let server = Arc::new(
+            server::Server::with_capacity(1, ngram::database::BUCKETS).with_timeouts(
+                server::ConnectionTimeouts {
+                    read: Some(Duration::from_millis(200)),
+                    write: Some(Duration::from_millis(200)),
+                },
+            ),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        // A client that never sends its request ties up the only worker, but only until it times
+        // out; then it is answered with a failure and hung up on
+        let mut idle = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let client = client::Client::new("127.0.0.1", port);
+        assert!(matches!(
+            client.send(&Request::Publish {
+                doc: "patience".to_string(),
+            }),
+            Some(Response::PublishSuccess(_))
+        ));
+        assert!(matches!(
+            Response::from_bytes(&mut idle),
+            Some(Response::Failure {
+                code: ErrorCode::TimedOut,
+                ..
+            })
+        ));
+        assert_eq!(idle.read(&mut [0_u8; 1]).unwrap(), 0);
+        server.stop();
+    }
+
+    #[test]
+    fn test_client_read_timeout_5() {
+        // A server that accepts connections but never answers
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 7909)).unwrap();
+        let hung = thread::spawn(move || listener.accept().map(|(stream, _)| stream));
+        let client = client::Client::builder("127.0.0.1", 7909)
+            .with_read_timeout(Duration::from_millis(200))
+            .build();
+        let start = std::time::Instant::now();
+        assert_eq!(client.search("anything"), None);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(hung.join().unwrap());
+    }
+
+    #[test]
+    fn test_client_retry_5() {
+        let port = 7910;
+        let policy = client::RetryPolicy {
+            max_retries: 8,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(200),
+        };
+        let client = client::Client::builder("127.0.0.1", port)
+            .with_retry(policy)
+            .build();
+        // Nothing is listening yet, so a client that doesn't retry gives up at once
+        assert_eq!(client::Client::new("127.0.0.1", port).search("word"), None);
+
+        let late = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            let server = Arc::new(server::Server::new());
+            thread::spawn({
+                let server = Arc::clone(&server);
+                move || server.run(port)
+            });
+            server
+        });
+        // A write that never reached the server is safe to retry
+        assert!(matches!(
+            client.send(&Request::Publish {
+                doc: "worth the wait".to_string(),
+            }),
+            Some(Response::PublishSuccess(_))
+        ));
+        assert_eq!(
+            client.search("wait"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        late.join().unwrap().stop();
+    }
+
+    #[test]
+    fn test_publish_dir_5() {
+        let port = 7911;
+        let (server, _handle) = start_server(port);
+        let dir = std::env::temp_dir().join("ngram-test-publish-dir");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+        fs::write(dir.join("b.txt"), "second book").unwrap();
+        fs::write(dir.join("a.txt"), "first book").unwrap();
+        fs::write(dir.join("notes.md"), "book notes").unwrap();
+        fs::write(dir.join("nested/deeper/c.txt"), "third book").unwrap();
+        fs::write(dir.join("cover.png"), [0xff_u8, 0xd8, 0xff]).unwrap();
+
+        let client = client::Client::new("127.0.0.1", port);
+        let published = client.publish_dir_matching(&dir, "*.txt").unwrap();
+        let paths: Vec<_> = published.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                dir.join("a.txt"),
+                dir.join("b.txt"),
+                dir.join("nested/deeper/c.txt")
+            ]
+        );
+        for (path, id) in published {
+            let doc = fs::read_to_string(path).unwrap();
+            assert_eq!(
+                client.retrieve(id.unwrap()),
+                Some(Response::RetrieveSuccess(doc))
+            );
+        }
+
+        // Without a pattern every text file is published, but not the image
+        let published = client.publish_dir(&dir).unwrap();
+        assert_eq!(published.len(), 4);
+        assert!(published
+            .iter()
+            .all(|(path, id)| id.is_some() && !path.ends_with("cover.png")));
+        assert!(client.publish_dir(dir.join("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_snippets_5() {
+        let port = 7912;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port);
+        for doc in ["a needle in a haystack", "no match here"] {
+            client.send(&Request::Publish {
+                doc: doc.to_string(),
+            });
+        }
+        let options = ngram::database::SearchOptions {
+            snippet_words: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            client.search_with("needle", options),
+            Some(Response::SearchSnippetsSuccess(vec![(
+                0,
+                "a **needle** in ...".to_string()
+            )]))
+        );
+        let options = ngram::database::SearchOptions {
+            snippet_words: Some(usize::MAX),
+            ..Default::default()
+        };
+        assert_eq!(
+            client.search_with("needle", options),
+            Some(Response::SearchSnippetsSuccess(vec![(
+                0,
+                "a **needle** in a haystack".to_string()
+            )]))
+        );
+        // Paged results say how many matches there are in all
+        let options = ngram::database::SearchOptions {
+            limit: Some(1),
+            ..Default::default()
+        };
+        client.send(&Request::Publish {
+            doc: "another needle".to_string(),
+        });
+        assert_eq!(
+            client.search_with("needle", options),
+            Some(Response::Paged {
+                response: Box::new(Response::SearchSuccess(vec![0])),
+                total_hits: 2,
+            })
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_queue_capacity_5() {
+        let port = 7913;
+        let server = Arc::new(
+            server::Server::with_capacity(1, ngram::database::BUCKETS).with_queue_capacity(0),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+
+        // An idle client holds the only worker, and no request may wait for it
+        let idle = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let client = client::Client::new("127.0.0.1", port);
+        assert!(matches!(
+            client.search("anything"),
+            Some(Response::Failure {
+                code: ErrorCode::Overloaded,
+                ..
+            })
+        ));
+        drop(idle);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            client.search("anything"),
+            Some(Response::SearchSuccess(vec![]))
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_write_ahead_log_5() {
+        use ngram::wal::WriteAheadLog;
+        let path = std::env::temp_dir().join("ngram-test-server-wal.jsonl");
+        let _ = fs::remove_file(&path);
+        let start = |port| {
+            let server = server::Server::new()
+                .with_write_ahead_log(WriteAheadLog::open(&path).unwrap())
+                .unwrap();
+            let server = Arc::new(server);
+            let _handle = thread::spawn({
+                let server = Arc::clone(&server);
+                move || server.run(port)
+            });
+            thread::sleep(Duration::from_millis(500));
+            server
+        };
+
+        let server = start(7914);
+        let client = client::Client::new("127.0.0.1", 7914);
+        assert_eq!(
+            client.send(&Request::Publish {
+                doc: "tyger tyger".to_string(),
+            }),
+            Some(Response::PublishSuccess(0))
+        );
+        assert_eq!(
+            client.send(&Request::Publish {
+                doc: "little lamb".to_string(),
+            }),
+            Some(Response::PublishSuccess(1))
+        );
+        server.stop();
+
+        // A new server with the same log has every acknowledged document
+        let server = start(7915);
+        let client = client::Client::new("127.0.0.1", 7915);
+        assert_eq!(
+            client.search("lamb"),
+            Some(Response::SearchSuccess(vec![1]))
+        );
+        assert_eq!(
+            client.send(&Request::Publish {
+                doc: "a rose".to_string(),
+            }),
+            Some(Response::PublishSuccess(2))
+        );
+        server.stop();
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_snapshots_5() {
+        use ngram::database::Database;
+        use ngram::snapshot::{self, SnapshotPolicy};
+        let path = std::env::temp_dir().join("ngram-test-server-snapshot.bin");
+        let _ = fs::remove_file(&path);
+        let start = |port| {
+            let policy = SnapshotPolicy {
+                path: path.clone(),
+                interval: None,
+                writes: Some(2),
+            };
+            let server = Arc::new(server::Server::new().with_snapshots(policy).unwrap());
+            let _handle = thread::spawn({
+                let server = Arc::clone(&server);
+                move || server.run(port)
+            });
+            thread::sleep(Duration::from_millis(500));
+            server
+        };
+
+        let server = start(7916);
+        let client = client::Client::new("127.0.0.1", 7916);
+        client.send(&Request::Publish {
+            doc: "tyger tyger".to_string(),
+        });
+        thread::sleep(Duration::from_millis(300));
+        assert!(!path.exists());
+        client.send(&Request::Publish {
+            doc: "little lamb".to_string(),
+        });
+        // Wait for a snapshot with both documents in it
+        let saved = (0..50).any(|_| {
+            thread::sleep(Duration::from_millis(100));
+            let database = Database::new();
+            snapshot::load(&database, &path).is_ok_and(|seq| seq.is_some())
+                && database.search("tyger") == vec![0]
+                && database.search("lamb") == vec![1]
+        });
+        assert!(saved, "no snapshot with both documents was saved");
+
+        // A new server loads the snapshot
+        let restarted = start(7917);
+        let client = client::Client::new("127.0.0.1", 7917);
+        assert_eq!(
+            client.search("lamb"),
+            Some(Response::SearchSuccess(vec![1]))
+        );
+        server.stop();
+        restarted.stop();
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_query_5() {
+        let port = 7918;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port);
+        for doc in ["whale ship harpoon", "whale boat", "ship boat"] {
+            client.send(&Request::Publish {
+                doc: doc.to_string(),
+            });
+        }
+        assert_eq!(
+            client.query("whale AND (ship OR boat) NOT harpoon"),
+            Some(Response::SearchSuccess(vec![1]))
+        );
+        assert!(matches!(
+            client.query("whale AND (ship"),
+            Some(Response::Failure {
+                code: ErrorCode::Malformed,
+                ..
+            })
+        ));
+        server.stop();
+    }
+
+    #[test]
+    fn test_unsupported_version_5() {
+        let port = 7920;
+        let (server, _handle) = start_server(port);
+        let mut bytes = Request::Stats.to_bytes();
+        bytes[6] = PROTOCOL_VERSION + 1;
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(&bytes).unwrap();
+        assert_eq!(
+            Response::from_bytes(&mut stream),
+            Some(Response::UnsupportedVersion {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            })
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_api_keys_5() {
+        use ngram::auth::{ApiKeys, Role};
+        let port = 7923;
+        let server = Arc::new(
+            server::Server::new().with_api_keys(
+                ApiKeys::new()
+                    .with_key("alice", "s3cret", Role::Publisher)
+                    .with_key("rita", "r3ader", Role::Reader)
+                    .with_key("ada", "4dmin", Role::Admin),
+            ),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let publish = Request::Publish {
+            doc: "open to all readers".to_string(),
+        };
+
+        // Writes need a valid key, but reads don't
+        for client in [
+            client::Client::new("127.0.0.1", port),
+            client::Client::new("127.0.0.1", port).with_token("wrong"),
+        ] {
+            assert!(matches!(
+                client.send(&publish),
+                Some(Response::Failure {
+                    code: ErrorCode::Unauthorized,
+                    ..
+                })
+            ));
+        }
+        let publisher = client::Client::new("127.0.0.1", port).with_token("s3cret");
+        assert_eq!(publisher.send(&publish), Some(Response::PublishSuccess(0)));
+        let reader = client::Client::new("127.0.0.1", port);
+        assert_eq!(
+            reader.search("readers"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+
+        // Keys can only do what their roles allow
+        let failure_code = |response| match response {
+            Some(Response::Failure { code, .. }) => Some(code),
+            _ => None,
+        };
+        let reader = client::Client::new("127.0.0.1", port).with_token("r3ader");
+        assert_eq!(
+            failure_code(reader.send(&publish)),
+            Some(ErrorCode::Forbidden)
+        );
+        assert_eq!(failure_code(publisher.stats()), Some(ErrorCode::Forbidden));
+        let anonymous = client::Client::new("127.0.0.1", port);
+        assert_eq!(
+            failure_code(anonymous.stats()),
+            Some(ErrorCode::Unauthorized)
+        );
+        let admin = client::Client::new("127.0.0.1", port).with_token("4dmin");
+        assert!(matches!(admin.stats(), Some(Response::StatsSuccess(_))));
+        assert_eq!(admin.send(&publish), Some(Response::PublishSuccess(1)));
+        server.stop();
+    }
+
+    #[test]
+    fn test_rate_limit_5() {
+        let port = 7924;
+        let server = Arc::new(server::Server::new().with_rate_limit(
+            ngram::rate_limit::RateLimit {
+                per_second: 0.1,
+                burst: 2,
+            },
+        ));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(client.search("a"), Some(Response::SearchSuccess(vec![])));
+        assert_eq!(client.search("b"), Some(Response::SearchSuccess(vec![])));
+        assert!(matches!(
+            client.search("c"),
+            Some(Response::Failure {
+                code: ErrorCode::RateLimited,
+                ..
+            })
+        ));
+        server.stop();
+    }
+
+    #[test]
+    fn test_max_connections_5() {
+        let port = 7925;
+        let server = Arc::new(server::Server::new().with_max_connections(1));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port);
+        // A client that connects and sends nothing holds the only slot
+        let idle = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(matches!(
+            client.search("full"),
+            Some(Response::Failure {
+                code: ErrorCode::Overloaded,
+                ..
+            })
+        ));
+        // Its slot is given back once it hangs up
+        drop(idle);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(client.search("free"), Some(Response::SearchSuccess(vec![])));
+        server.stop();
+    }
+
+    #[test]
+    fn test_ping_5() {
+        let port = 7929;
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(client.ping(), None);
+        let server = Arc::new(server::Server::new());
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        client.send(&Request::Publish {
+            doc: "still alive".to_string(),
+        });
+        match client.ping() {
+            Some(Response::Pong {
+                documents, version, ..
+            }) => {
+                assert_eq!(documents, 1);
+                assert_eq!(version, env!("CARGO_PKG_VERSION"));
+            }
+            other => panic!("expected a pong, got {:?}", other),
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn test_count_5() {
+        let port = 7932;
+        let server = Arc::new(server::Server::new());
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port);
+        for doc in ["the whale", "a whale and a ship", "the ship"] {
+            client.send(&Request::Publish {
+                doc: doc.to_string(),
+            });
+        }
+        assert_eq!(client.count("WHALE"), Some(Response::CountSuccess(2)));
+        assert_eq!(client.count("ship"), Some(Response::CountSuccess(2)));
+        assert_eq!(client.count("harpoon"), Some(Response::CountSuccess(0)));
+        assert_eq!(client.count("the"), Some(Response::CountSuccess(2)));
+        server.stop();
+    }
+
+    #[test]
+    fn test_duplicates_5() {
+        let port = 7933;
+        let server = Arc::new(server::Server::new().with_duplicates(Duplicates::Reject));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port);
+        let publish = Request::Publish {
+            doc: "retried".to_string(),
+        };
+        assert_eq!(client.send(&publish), Some(Response::PublishSuccess(0)));
+        assert_eq!(client.send(&publish), Some(Response::duplicate(0)));
+        // Nothing was stored for the rejected copy
+        let other = Request::Publish {
+            doc: "another".to_string(),
+        };
+        assert_eq!(client.send(&other), Some(Response::PublishSuccess(1)));
+        server.stop();
+    }
+
+    #[test]
+    fn test_memory_limit_5() {
+        let port = 7934;
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(admin_keys())
+                .with_memory_limit(1000),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port).with_token(ADMIN_KEY);
+        let publish = |doc: &str| {
+            client.send(&Request::Publish {
+                doc: doc.to_string(),
+            })
+        };
+        assert_eq!(
+            publish("words words words"),
+            Some(Response::PublishSuccess(0))
+        );
+        let mut published = 1;
+        while let Some(Response::PublishSuccess(id)) = publish("more words to fill the archive") {
+            assert_eq!(id, published);
+            published += 1;
+        }
+        assert!(matches!(
+            publish("one too many"),
+            Some(Response::Failure {
+                code: ErrorCode::MemoryLimit,
+                ..
+            })
+        ));
+        // Reading the archive still works
+        assert_eq!(
+            client.search("words"),
+            Some(Response::SearchSuccess((0..published).collect()))
+        );
+        let Some(Response::StatsSuccess(stats)) = client.stats() else {
+            panic!("expected stats");
+        };
+        assert!(stats
+            .lines()
+            .any(|line| line == "ngram_memory_limit_bytes 1000"));
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_cache_5() {
+        let port = 7935;
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(admin_keys())
+                .with_search_cache(16),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port).with_token(ADMIN_KEY);
+        let publish = |doc: &str| {
+            client.send(&Request::Publish {
+                doc: doc.to_string(),
+            })
+        };
+        publish("cached words");
+        assert_eq!(
+            client.search("words"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        // Searches for the same term share an entry
+        assert_eq!(
+            client.search("WORDS"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        // Publishing empties the cache, so the new document is found
+        publish("more words");
+        assert_eq!(
+            client.search("words"),
+            Some(Response::SearchSuccess(vec![0, 1]))
+        );
+        let Some(Response::StatsSuccess(stats)) = client.stats() else {
+            panic!("expected stats");
+        };
+        for line in [
+            "ngram_search_cache_hits 1",
+            "ngram_search_cache_misses 2",
+            "ngram_search_cache_entries 1",
+        ] {
+            assert!(stats.lines().any(|l| l == line), "missing {}", line);
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn test_client_pool_5() {
+        let port = 7936;
+        let timeouts = server::ConnectionTimeouts {
+            read: Some(Duration::from_millis(300)),
+            write: None,
+        };
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(admin_keys())
+                .with_timeouts(timeouts),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let pool = Arc::new(client::ClientPool::new(
+            client::Client::new("127.0.0.1", port).with_token(ADMIN_KEY),
+            2,
+        ));
+        for doc in ["pooled words", "more pooled words", "other"] {
+            assert!(matches!(
+                pool.send(&Request::Publish {
+                    doc: doc.to_string()
+                }),
+                Some(Response::PublishSuccess(_))
+            ));
+        }
+        let searchers: Vec<_> = (0..6)
+            .map(|_| {
+                let pool = Arc::clone(&pool);
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        assert_eq!(
+                            pool.search("pooled"),
+                            Some(Response::SearchSuccess(vec![0, 1]))
+                        );
+                    }
+                })
+            })
+            .collect();
+        for searcher in searchers {
+            searcher.join().unwrap();
+        }
+        // Every request shared the pool's connections, which are still open
+        let Some(Response::StatsSuccess(stats)) = pool.send(&Request::Stats) else {
+            panic!("expected stats");
+        };
+        let open = stats
+            .lines()
+            .find_map(|line| line.strip_prefix("ngram_active_connections "))
+            .and_then(|open| open.parse::<usize>().ok())
+            .unwrap();
+        assert!((1..=2).contains(&open), "{} connections open", open);
+        // Connections the server closed while they sat idle are replaced, even for writes
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(
+            pool.retrieve(2),
+            Some(Response::RetrieveSuccess("other".to_string()))
+        );
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(
+            pool.send(&Request::Publish {
+                doc: "late words".to_string()
+            }),
+            Some(Response::PublishSuccess(3))
+        );
+        let path = std::env::temp_dir().join(format!("ngram-pool-{}", port));
+        fs::write(&path, "pooled from a file").unwrap();
+        assert_eq!(
+            pool.publish_from_path(path.to_str().unwrap()),
+            Some(Response::PublishSuccess(4))
+        );
+        fs::remove_file(&path).unwrap();
+        server.stop();
+    }
+
+    #[test]
+    fn test_publish_from_reader_5() {
+        let port = 7937;
+        // Documents far larger than any one frame the server accepts
+        let limits = MessageLimits {
+            max_message_len: 1 << 17,
+            max_document_len: 4 << 20,
+        };
+        let server = Arc::new(server::Server::new().with_limits(limits));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port);
+        let doc = "streamed words ".repeat(200_000);
+        assert_eq!(
+            client.publish_from_reader(doc.as_bytes()),
+            Some(Response::PublishSuccess(0))
+        );
+        assert_eq!(
+            client.search("streamed"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        let Some(Response::RetrieveSuccess(retrieved)) = client.retrieve(0) else {
+            panic!("expected the document");
+        };
+        assert_eq!(retrieved, doc);
+        // One over the document limit is refused
+        let reader = std::io::repeat(b'a').take(4 << 20 | 1);
+        assert_eq!(client.publish_from_reader(reader), Some(Response::TooLarge));
+        server.stop();
+    }
+
+    #[test]
+    fn test_retrieve_to_writer_5() {
+        let port = 7938;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port);
+        let doc = "written straight to disk ".repeat(20_000);
+        assert_eq!(
+            client.publish_from_reader(doc.as_bytes()),
+            Some(Response::PublishSuccess(0))
+        );
+        let path = std::env::temp_dir().join(format!("ngram-retrieve-{}", port));
+        let file = fs::File::create(&path).unwrap();
+        assert_eq!(
+            client.retrieve_to_writer(0, file).unwrap(),
+            Ok(doc.len() as u64)
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), doc);
+        fs::remove_file(&path).unwrap();
+        // A missing document is reported rather than written
+        let mut written = Vec::new();
+        assert!(matches!(
+            client.retrieve_to_writer(1, &mut written).unwrap(),
+            Err(Response::Failure {
+                code: ErrorCode::NotFound,
+                ..
+            })
+        ));
+        assert!(written.is_empty());
+        // A range of the document is streamed just the same
+        let mut written = Vec::new();
+        assert_eq!(
+            client
+                .retrieve_range_to_writer(0, 8, Some(8), &mut written)
+                .unwrap(),
+            Ok(8)
+        );
+        assert_eq!(written, b"straight");
+        // Clients that don't ask for a stream still get the document whole
+        assert_eq!(client.retrieve(0), Some(Response::RetrieveSuccess(doc)));
+        server.stop();
+    }
+
+    #[test]
+    fn test_retrieve_range_5() {
+        let port = 7939;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port);
+        client.send(&Request::Publish {
+            doc: "caf\u{e9} society".to_string(),
+        });
+        let range = |offset, length| match client.retrieve_range(0, offset, length) {
+            Some(Response::RetrieveSuccess(text)) => Ok(text),
+            Some(Response::Failure { code, .. }) => Err(code),
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(range(0, Some(3)), Ok("caf".to_string()));
+        assert_eq!(range(6, None), Ok("society".to_string()));
+        // Ranges past the end are cut short there
+        assert_eq!(range(6, Some(100)), Ok("society".to_string()));
+        assert_eq!(range(13, Some(1)), Ok(String::new()));
+        // and so that they end on a whole character
+        assert_eq!(range(0, Some(4)), Ok("caf".to_string()));
+        assert_eq!(range(0, Some(5)), Ok("caf\u{e9}".to_string()));
+        // but can't start past the end or inside a character
+        assert_eq!(range(14, None), Err(ErrorCode::OutOfRange));
+        assert_eq!(range(4, None), Err(ErrorCode::OutOfRange));
+        assert_eq!(
+            client.retrieve_range(1, 0, Some(1)),
+            Some(Response::not_found(1))
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_search_and_retrieve_5() {
+        let port = 7940;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port).with_metadata();
+        for doc in ["the whale swam", "no match", "a whale again", "whale three"] {
+            client.send(&Request::Publish {
+                doc: doc.to_string(),
+            });
+        }
+        assert_eq!(
+            client.search_and_retrieve("whale", 2, None).unwrap(),
+            vec![
+                (0, "the whale swam".to_string()),
+                (2, "a whale again".to_string())
+            ]
+        );
+        let snippets = client.search_and_retrieve("whale", 5, Some(1)).unwrap();
+        assert_eq!(
+            snippets.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![0, 2, 3]
+        );
+        assert!(snippets
+            .iter()
+            .all(|(_, snippet)| snippet.contains("whale")));
+        assert_eq!(
+            client.search_and_retrieve("kraken", 5, None).unwrap(),
+            vec![]
+        );
+        server.stop();
+    }
+
+    #[test]
+    fn test_bench_5() {
+        let port = 7941;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port);
+        let workload = ngram::bench::Workload {
+            concurrency: 4,
+            duration: Duration::from_millis(300),
+            doc_words: 20,
+            vocabulary: 50,
+            ..Default::default()
+        };
+        let report = ngram::bench::run(|request| client.send(request), &workload);
+        let kinds = report
+            .kinds
+            .iter()
+            .map(|latencies| latencies.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["publish", "search", "retrieve"]);
+        assert!(report.requests() > 0);
+        assert_eq!(report.failed(), 0);
+        for latencies in &report.kinds {
+            assert!(latencies.p50 <= latencies.p90);
+            assert!(latencies.p90 <= latencies.p99);
+            assert!(latencies.p99 <= latencies.max);
+        }
+        // Only the weighted kinds are sent
+        let workload = ngram::bench::Workload {
+            publish_weight: 0,
+            search_weight: 1,
+            retrieve_weight: 0,
+            ..workload
+        };
+        let report = ngram::bench::run(|request| client.send(request), &workload);
+        assert_eq!(report.kinds.len(), 1);
+        assert_eq!(report.kinds[0].kind, "search");
+        server.stop();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_5() {
+        let port = 7930;
+        let path = std::env::temp_dir().join("ngram-test.sock");
+        let server = Arc::new(server::Server::new().with_unix_socket(&path));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let local = client::Client::unix(&path);
+        assert_eq!(
+            local.send(&Request::Publish {
+                doc: "over a unix socket".to_string(),
+            }),
+            Some(Response::PublishSuccess(0))
+        );
+        // TCP clients see the same archive
+        let remote = client::Client::new("127.0.0.1", port);
+        assert_eq!(
+            remote.search("unix"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        assert!(matches!(
+            local.ping(),
+            Some(Response::Pong { documents: 1, .. })
+        ));
+        server.stop();
+    }
+
+    #[test]
+    fn test_dual_stack_5() {
+        let port = 7931;
+        let server = Arc::new(server::Server::new());
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                server.run_listeners(&[
+                    server::ListenerConfig::new(port),
+                    server::ListenerConfig::new(port).bind("::1".parse().unwrap()),
+                ])
+            }
+        });
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(server.local_addrs().len(), 2);
+        client::Client::new("::1", port).send(&Request::Publish {
+            doc: "over ipv6".to_string(),
+        });
+        // Both listeners feed the same archive, and addresses may be written with brackets
+        for address in ["127.0.0.1", "[::1]", "localhost"] {
+            assert_eq!(
+                client::Client::new(address, port).search("ipv6"),
+                Some(Response::SearchSuccess(vec![0]))
+            );
+        }
+        server.stop();
+    }
+
+    #[test]
+    fn test_admin_requests_5() {
+        use ngram::auth::{ApiKeys, Role};
+        use ngram::snapshot::SnapshotPolicy;
+        let port = 7927;
+        let path = std::env::temp_dir().join("ngram-test-admin-snapshot.bin");
+        let _ = fs::remove_file(&path);
+        let policy = SnapshotPolicy {
+            path: path.clone(),
+            interval: None,
+            writes: None,
+        };
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(
+                    ApiKeys::new()
+                        .with_key("alice", "s3cret", Role::Publisher)
+                        .with_key("ada", "4dmin", Role::Admin),
+                )
+                .with_snapshots(policy)
+                .unwrap(),
+        );
+        let handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let publisher = client::Client::new("127.0.0.1", port).with_token("s3cret");
+        let admin = client::Client::new("127.0.0.1", port).with_token("4dmin");
+        publisher.send(&Request::Publish {
+            doc: "saved on demand".to_string(),
+        });
+
+        // Only an admin may take a snapshot or stop the server
+        for request in [Request::Snapshot, Request::Shutdown] {
+            assert!(matches!(
+                publisher.send(&request),
+                Some(Response::Failure {
+                    code: ErrorCode::Forbidden,
+                    ..
+                })
+            ));
+        }
+        assert!(!path.exists());
+        assert_eq!(admin.snapshot(), Some(Response::SnapshotSuccess(1)));
+        assert!(path.exists());
+        assert_eq!(admin.shutdown(), Some(Response::ShuttingDown));
+        handle.join().unwrap();
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_export_import_5() {
+        use ngram::auth::{ApiKeys, Role};
+        use ngram::database;
+        use std::io::{self, BufReader};
+        let port = 7942;
+        let dir = std::env::temp_dir().join("ngram-test-export");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.jsonl");
+        let start = |port| {
+            let server = Arc::new(
+                server::Server::new()
+                    .with_api_keys(ApiKeys::new().with_key("ada", "4dmin", Role::Admin))
+                    .with_data_dir(&dir),
+            );
+            let handle = thread::spawn({
+                let server = Arc::clone(&server);
+                move || server.run(port)
+            });
+            thread::sleep(Duration::from_millis(500));
+            (server, handle)
         };
-        let id2 = match client.publish_from_path("data/austen-persuasion.txt") {
-            Some(Response::PublishSuccess(id)) => id,
-            _ => panic!("Failed to publish data/austen-persuasion.txt"),
+        let (server, _handle) = start(port);
+        let client = client::Client::new("127.0.0.1", port).with_token("4dmin");
+        let metadata = Metadata {
+            title: Some("Moby-Dick".to_string()),
+            author: Some("Herman Melville".to_string()),
+            date: None,
         };
+        client.send(&Request::Publish {
+            doc: "the whale surfaced".to_string(),
+        });
+        client.send(&Request::PublishWith {
+            doc: "the ship sailed".to_string(),
+            options: PublishOptions {
+                metadata: metadata.clone(),
+                ..PublishOptions::default()
+            },
+        });
+        // Only an admin may export, and only to a file in the data directory
+        assert_eq!(
+            failure_code(client::Client::new("127.0.0.1", port).export("dump.jsonl")),
+            Some(ErrorCode::Unauthorized)
+        );
+        for outside in ["../dump.jsonl", "/tmp/dump.jsonl", "nested/dump.jsonl", ""] {
+            assert_eq!(
+                failure_code(client.export(outside)),
+                Some(ErrorCode::Malformed)
+            );
+        }
+        assert_eq!(
+            client.export("dump.jsonl"),
+            Some(Response::ExportSuccess(2))
+        );
+        let dumped = database::read_dump(BufReader::new(fs::File::open(&path).unwrap()))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(dumped.len(), 2);
+        assert_eq!(dumped[0].id, 0);
+        assert_eq!(dumped[0].text, "the whale surfaced");
+        assert_eq!(dumped[1].options.metadata, metadata);
+        server.stop();
 
-        let response = client.search("little");
-        if let Some(Response::SearchSuccess(ids)) = response {
-            assert_eq!(ids.len(), 2);
-            assert!(ids.contains(&id1));
-            assert!(ids.contains(&id2));
-        } else {
-            panic!("Failed to search for 'little'");
+        // A fresh server rebuilds the index from the dump
+        let port = 7943;
+        let (server, _handle) = start(port);
+        let client = client::Client::new("127.0.0.1", port).with_token("4dmin");
+        assert_eq!(
+            failure_code(client.import("../ngram-test-export/dump.jsonl")),
+            Some(ErrorCode::Malformed)
+        );
+        assert_eq!(
+            client.import("dump.jsonl"),
+            Some(Response::ImportSuccess(2))
+        );
+        assert_eq!(
+            client.search("ship"),
+            Some(Response::SearchSuccess(vec![1]))
+        );
+        assert_eq!(
+            client.retrieve(0),
+            Some(Response::RetrieveSuccess("the whale surfaced".to_string()))
+        );
+        assert!(matches!(
+            client.import("missing.jsonl"),
+            Some(Response::Failure {
+                code: ErrorCode::NotFound,
+                ..
+            })
+        ));
+        // Documents keep their ids, so an archive that has some takes no dump...
+        assert_eq!(
+            failure_code(client.import("dump.jsonl")),
+            Some(ErrorCode::Unsupported)
+        );
+        // ...and importing stops at a document that can't keep its id
+        let mut gapped = dumped.clone();
+        gapped[1].id = 3;
+        let lines = gapped
+            .iter()
+            .map(|dumped| serde_json::to_string(dumped).unwrap() + "\n")
+            .collect::<String>();
+        fs::write(dir.join("gapped.jsonl"), lines).unwrap();
+        client.create_collection("gaps");
+        let gaps = client::Client::new("127.0.0.1", port)
+            .with_token("4dmin")
+            .with_collection("gaps");
+        assert_eq!(
+            failure_code(gaps.import("gapped.jsonl")),
+            Some(ErrorCode::OutOfRange)
+        );
+        assert_eq!(gaps.search("whale"), Some(Response::SearchSuccess(vec![0])));
+        assert_eq!(gaps.search("ship"), Some(Response::SearchSuccess(vec![])));
+        server.stop();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_collections_5() {
+        let port = 7944;
+        let server = Arc::new(server::Server::new().with_api_keys(admin_keys()));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port).with_token(ADMIN_KEY);
+        assert_eq!(
+            client.create_collection("fiction"),
+            Some(Response::CollectionCreated("fiction".to_string()))
+        );
+        client.create_collection("papers");
+        assert_eq!(
+            client.collections(),
+            Some(Response::CollectionsSuccess(vec![
+                "fiction".to_string(),
+                "papers".to_string()
+            ]))
+        );
+        for (name, code) in [
+            ("fiction", ErrorCode::Duplicate),
+            ("no/slash", ErrorCode::Malformed),
+        ] {
+            assert!(matches!(
+                client.create_collection(name),
+                Some(Response::Failure { code: c, .. }) if c == code
+            ));
         }
+
+        // Each collection is an archive of its own, with its own ids
+        let fiction = client::Client::new("127.0.0.1", port)
+            .with_token(ADMIN_KEY)
+            .with_collection("fiction");
+        let papers = client::Client::new("127.0.0.1", port)
+            .with_token(ADMIN_KEY)
+            .with_collection("papers");
+        client.send(&Request::Publish {
+            doc: "the default archive".to_string(),
+        });
+        fiction.send(&Request::Publish {
+            doc: "a whale of a tale".to_string(),
+        });
+        assert_eq!(
+            papers.send(&Request::Publish {
+                doc: "on the anatomy of the whale".to_string(),
+            }),
+            Some(Response::PublishSuccess(0))
+        );
+        assert_eq!(
+            fiction.search("whale"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        assert_eq!(
+            client.search("whale"),
+            Some(Response::SearchSuccess(vec![]))
+        );
+        assert_eq!(
+            papers.retrieve(0),
+            Some(Response::RetrieveSuccess(
+                "on the anatomy of the whale".to_string()
+            ))
+        );
+        let missing = client::Client::new("127.0.0.1", port).with_collection("poetry");
+        assert!(matches!(
+            missing.search("whale"),
+            Some(Response::Failure {
+                code: ErrorCode::NotFound,
+                ..
+            })
+        ));
         server.stop();
     }
 
     #[test]
-    fn test_search_distractor_5() {
-        let port = 7885;
-        let (server, _handle) = start_server(port);
+    fn test_tenant_isolation_5() {
+        use ngram::auth::{ApiKeys, Role};
+        let port = 7945;
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(
+                    ApiKeys::new()
+                        .with_key("acme", "acme-key", Role::Publisher)
+                        .with_key("globex", "globex-key", Role::Publisher)
+                        .with_key("ops", "ops-key", Role::Admin),
+                )
+                .with_tenant_isolation(),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let acme = client::Client::new("127.0.0.1", port).with_token("acme-key");
+        let globex = client::Client::new("127.0.0.1", port).with_token("globex-key");
+        let ops = client::Client::new("127.0.0.1", port).with_token("ops-key");
 
-        let client = client::Client::new("127.0.0.1", port);
-        let _id1 = match client.publish_from_path("data/austen-persuasion.txt") {
-            Some(Response::PublishSuccess(id)) => id,
-            _ => panic!("Failed to publish data/austen-persuasion.txt"),
-        };
-        let id2 = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
-            _ => panic!("Failed to publish data/austen-emma.txt"),
+        // Each tenant has its own ids and sees only its own documents
+        for (tenant, doc) in [(&acme, "acme quarterly report"), (&globex, "globex report")] {
+            assert_eq!(
+                tenant.send(&Request::Publish {
+                    doc: doc.to_string()
+                }),
+                Some(Response::PublishSuccess(0))
+            );
+        }
+        assert_eq!(
+            acme.search("report"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        assert_eq!(
+            globex.retrieve(0),
+            Some(Response::RetrieveSuccess("globex report".to_string()))
+        );
+        assert_eq!(
+            acme.collections(),
+            Some(Response::CollectionsSuccess(vec!["acme".to_string()]))
+        );
+
+        // Naming another tenant's collection, or sending no key, is refused
+        let snooping = client::Client::new("127.0.0.1", port)
+            .with_token("acme-key")
+            .with_collection("globex");
+        assert!(matches!(
+            snooping.search("report"),
+            Some(Response::Failure {
+                code: ErrorCode::Forbidden,
+                ..
+            })
+        ));
+        assert!(matches!(
+            client::Client::new("127.0.0.1", port).search("report"),
+            Some(Response::Failure {
+                code: ErrorCode::Unauthorized,
+                ..
+            })
+        ));
+
+        // Admins see every tenant's collection, and the default archive, which no tenant reaches
+        assert_eq!(
+            ops.collections(),
+            Some(Response::CollectionsSuccess(vec![
+                "acme".to_string(),
+                "globex".to_string()
+            ]))
+        );
+        let ops_in_globex = client::Client::new("127.0.0.1", port)
+            .with_token("ops-key")
+            .with_collection("globex");
+        assert_eq!(
+            ops_in_globex.search("report"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        assert_eq!(ops.search("report"), Some(Response::SearchSuccess(vec![])));
+        server.stop();
+    }
+
+    #[test]
+    fn test_reload_5() {
+        use ngram::audit::AuditLog;
+        use ngram::auth::ApiKeys;
+        let port = 7928;
+        let dir = std::env::temp_dir();
+        let keys_path = dir.join("ngram-test-reload-keys.txt");
+        let audit_path = dir.join("ngram-test-reload-audit.jsonl");
+        let rotated_path = dir.join("ngram-test-reload-audit.jsonl.1");
+        let audit = audit_path.to_str().unwrap();
+        for path in [&audit_path, &rotated_path] {
+            let _ = fs::remove_file(path);
+        }
+        fs::write(&keys_path, "alice s3cret\n").unwrap();
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(ApiKeys::load(&keys_path).unwrap())
+                .with_audit_log(AuditLog::open(audit).unwrap()),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let publish = Request::Publish {
+            doc: "before the rotation".to_string(),
         };
+        let bob = client::Client::new("127.0.0.1", port).with_token("hunter2");
+        assert!(matches!(
+            bob.send(&publish),
+            Some(Response::Failure {
+                code: ErrorCode::Unauthorized,
+                ..
+            })
+        ));
 
-        let response = client.search("ceased");
-        assert_eq!(response, Some(Response::SearchSuccess(vec![id2])));
+        // Rotate the audit log and give bob a key, then reload
+        fs::rename(&audit_path, &rotated_path).unwrap();
+        fs::write(&keys_path, "alice s3cret\nbob hunter2\n").unwrap();
+        server.reload();
+        assert_eq!(bob.send(&publish), Some(Response::PublishSuccess(0)));
         server.stop();
+
+        assert_eq!(
+            AuditLog::read(rotated_path.to_str().unwrap())
+                .unwrap()
+                .len(),
+            1
+        );
+        let entries = AuditLog::read(audit).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].principal.as_deref(), Some("bob"));
+        for path in [&keys_path, &audit_path, &rotated_path] {
+            let _ = fs::remove_file(path);
+        }
     }
 
     #[test]
-    fn test_retrieve_5() {
-        let port = 7886;
+    fn test_audit_log_5() {
+        use ngram::audit::AuditLog;
+        use ngram::auth::{ApiKeys, Role};
+        let port = 7926;
+        let path = std::env::temp_dir().join("ngram-test-audit.jsonl");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(ApiKeys::new().with_key("alice", "s3cret", Role::Publisher))
+                .with_audit_log(AuditLog::open(path).unwrap()),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let alice = client::Client::new("127.0.0.1", port).with_token("s3cret");
+        let anonymous = client::Client::new("127.0.0.1", port);
+        let publish = |doc: &str| Request::Publish {
+            doc: doc.to_string(),
+        };
+        assert_eq!(
+            alice.send(&publish("call me ishmael")),
+            Some(Response::PublishSuccess(0))
+        );
+        assert!(matches!(
+            anonymous.send(&publish("not allowed")),
+            Some(Response::Failure { .. })
+        ));
+        // Reads aren't audited
+        assert_eq!(
+            anonymous.search("ishmael"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        server.stop();
+
+        let entries = AuditLog::read(path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].principal.as_deref(), Some("alice"));
+        assert_eq!(entries[0].action, "publish");
+        assert_eq!(entries[0].doc_ids, vec![0]);
+        assert_eq!(entries[0].doc_sizes, vec![15]);
+        assert_eq!(entries[0].outcome, "ok");
+        assert!(entries[0].peer.is_some_and(|peer| peer.ip().is_loopback()));
+        assert_eq!(entries[1].principal, None);
+        assert_eq!(entries[1].doc_ids, Vec::<usize>::new());
+        assert_eq!(entries[1].outcome, "unauthorized");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compression_5() {
+        let port = 7919;
         let (server, _handle) = start_server(port);
+        let client = client::Client::new("127.0.0.1", port).with_compression();
+        let doc = "call me ishmael ".repeat(10_000);
+        assert_eq!(
+            client.send(&Request::Publish { doc: doc.clone() }),
+            Some(Response::PublishSuccess(0))
+        );
+        assert_eq!(client.retrieve(0), Some(Response::RetrieveSuccess(doc)));
+        // Clients that don't ask for compression still get plain responses
+        let plain = client::Client::new("127.0.0.1", port);
+        assert_eq!(
+            plain.search("ishmael"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        server.stop();
+    }
 
-        let client = client::Client::new("127.0.0.1", port);
-        let id = match client.publish_from_path("data/austen-emma.txt") {
-            Some(Response::PublishSuccess(id)) => id,
-            _ => panic!("Failed to publish data/austen-emma.txt"),
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_http_gateway_5() {
+        let port = 7921;
+        let http_port = 7922;
+        let server =
+            Arc::new(server::Server::new().with_http_listener(([127, 0, 0, 1], http_port).into()));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let http = |request: &str| {
+            let mut stream = std::net::TcpStream::connect(("127.0.0.1", http_port)).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
         };
-        let doc = std::fs::read_to_string("data/austen-emma.txt").unwrap();
-        let response = client.retrieve(id);
-        assert_eq!(response, Some(Response::RetrieveSuccess(doc)));
+
+        let reply = http("POST /documents HTTP/1.1\r\nContent-Length: 15\r\n\r\nhello over http");
+        assert!(reply.starts_with("HTTP/1.1 201 Created"));
+        assert!(reply.ends_with(r#"{"doc_id":0,"type":"publish"}"#));
+        let reply = http("GET /search?q=HELLO HTTP/1.1\r\n\r\n");
+        assert!(reply.starts_with("HTTP/1.1 200 OK"));
+        assert!(reply.ends_with(r#"{"doc_ids":[0],"type":"search"}"#));
+        let reply = http("GET /documents/0 HTTP/1.1\r\n\r\n");
+        assert!(reply.ends_with(r#"{"doc":"hello over http","type":"retrieve"}"#));
+        assert!(http("GET /documents/7 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(http("GET /search HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 400"));
+        let reply = http("GET /count?q=hello HTTP/1.1\r\n\r\n");
+        assert!(reply.ends_with(r#"{"count":1,"type":"count"}"#));
+        assert!(http("DELETE /documents/0 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        let reply = http("GET /health HTTP/1.1\r\n\r\n");
+        assert!(reply.starts_with("HTTP/1.1 200 OK"));
+        assert!(reply.contains(r#""documents":1"#));
+
+        // The gateway shares the archive with the binary protocol
+        let client = client::Client::new("127.0.0.1", port);
+        assert_eq!(
+            client.search("http"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
         server.stop();
     }
 
     #[test]
-    fn test_server_stress_test_10() {
-        let port = 7889;
-        let (server, _handle) = start_server(port);
+    fn test_replication_5() {
+        use ngram::replication::Replicator;
+        let (primary_port, follower_port) = (7946, 7947);
+        let follower = Arc::new(
+            server::Server::new()
+                .with_api_keys(admin_keys())
+                .as_follower("r3plicate"),
+        );
+        let _follower_handle = thread::spawn({
+            let follower = Arc::clone(&follower);
+            move || follower.run(follower_port)
+        });
+        let replicator =
+            Replicator::new(&[([127, 0, 0, 1], follower_port).into()], Some("r3plicate"));
+        let primary = Arc::new(server::Server::new().with_replication(replicator));
+        let _primary_handle = thread::spawn({
+            let primary = Arc::clone(&primary);
+            move || primary.run(primary_port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", primary_port);
+        let reader = client::Client::new("127.0.0.1", follower_port);
+        for doc in ["call me ishmael", "the whale surfaced"] {
+            client.send(&Request::Publish {
+                doc: doc.to_string(),
+            });
+        }
+        client.send(&Request::Update {
+            id: 0,
+            doc: "call me ishmael, a whaler".to_string(),
+        });
 
-        let paths = vec![
-            "data/austen-emma.txt",
-            "data/austen-persuasion.txt",
-            "data/austen-sense.txt",
-            "data/bible-kjv.txt",
-            "data/blake-poems.txt",
-            "data/bryant-stories.txt",
-            "data/burgess-busterbrown.txt",
-            "data/carroll-alice.txt",
-            "data/chesterton-ball.txt",
-            "data/chesterton-brown.txt",
-            "data/chesterton-thursday.txt",
-            "data/edgeworth-parents.txt",
-            "data/melville-moby_dick.txt",
-            "data/milton-paradise.txt",
-            "data/shakespeare-caesar.txt",
-            "data/shakespeare-hamlet.txt",
-            "data/shakespeare-macbeth.txt",
-            "data/whitman-leaves.txt",
+        // The follower makes the same changes, giving documents the same ids
+        let mut found = None;
+        for _ in 0..50 {
+            found = reader.search("whale");
+            if found == Some(Response::SearchSuccess(vec![1])) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(found, Some(Response::SearchSuccess(vec![1])));
+        let mut retrieved = None;
+        for _ in 0..50 {
+            retrieved = reader.retrieve(0);
+            if retrieved
+                == Some(Response::RetrieveSuccess(
+                    "call me ishmael, a whaler".to_string(),
+                ))
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(
+            retrieved,
+            Some(Response::RetrieveSuccess(
+                "call me ishmael, a whaler".to_string()
+            ))
+        );
+
+        // Only the primary may change the follower's archive
+        let admin = client::Client::new("127.0.0.1", follower_port).with_token(ADMIN_KEY);
+        assert!(matches!(
+            admin.send(&Request::Publish {
+                doc: "not here".to_string()
+            }),
+            Some(Response::Failure {
+                code: ErrorCode::ReadOnly,
+                ..
+            })
+        ));
+        assert!(matches!(
+            reader.send(&Request::Replicate {
+                session: 1,
+                seq: 1,
+                entry: String::new(),
+            }),
+            Some(Response::Failure {
+                code: ErrorCode::Unauthorized,
+                ..
+            })
+        ));
+        assert!(matches!(
+            admin.send(&Request::Replicate {
+                session: 1,
+                seq: 1,
+                entry: String::new(),
+            }),
+            Some(Response::Failure {
+                code: ErrorCode::Forbidden,
+                ..
+            })
+        ));
+
+        // A change out of order is refused, as the follower would fall out of step with it
+        let from_primary = client::Client::new("127.0.0.1", follower_port).with_token("r3plicate");
+        assert!(matches!(
+            from_primary.send(&Request::Replicate {
+                session: 1,
+                seq: 5,
+                entry: String::new(),
+            }),
+            Some(Response::Failure {
+                code: ErrorCode::OutOfRange,
+                ..
+            })
+        ));
+        primary.stop();
+        follower.stop();
+    }
+
+    #[test]
+    fn test_sharding_5() {
+        use ngram::shard::Shards;
+        let (_first, _first_handle) = start_server(7948);
+        let (_second, _second_handle) = start_server(7949);
+        let port = 7950;
+        let shards = Shards::new(
+            &[([127, 0, 0, 1], 7948).into(), ([127, 0, 0, 1], 7949).into()],
+            None,
+        );
+        let coordinator = Arc::new(
+            server::Server::new()
+                .with_api_keys(admin_keys())
+                .with_shards(shards),
+        );
+        let _handle = thread::spawn({
+            let coordinator = Arc::clone(&coordinator);
+            move || coordinator.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port).with_token(ADMIN_KEY);
+        let docs = [
+            "the whale surfaced",
+            "call me ishmael",
+            "a white whale",
+            "the sea was calm",
+            "whale oil lamps",
+            "the captain paced",
         ];
+        let mut ids = Vec::new();
+        for doc in docs {
+            match client.send(&Request::Publish {
+                doc: doc.to_string(),
+            }) {
+                Some(Response::PublishSuccess(id)) => ids.push(id),
+                response => panic!("unexpected response {:?}", response),
+            }
+        }
 
-        let queue = Arc::new(Mutex::new(paths));
-        println!("Adding docs...");
-        let now = std::time::Instant::now();
-        let handles = (0..THREADS)
-            .map(|i| {
-                thread::spawn({
-                    let queue = Arc::clone(&queue);
-                    move || loop {
-                        let client = client::Client::new("127.0.0.1", port);
-                        let path = queue.lock().unwrap().pop().clone();
-                        match path {
-                            Some(path) => {
-                                println!("Thread {}: processing {}", i, path);
-                                client.publish_from_path(path);
-                            }
-                            None => return,
-                        }
-                    }
-                })
+        // Every document gets its own id, and is found through the coordinator wherever it is
+        let mut sorted = ids.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), docs.len());
+        for (id, doc) in ids.iter().zip(docs) {
+            assert_eq!(
+                client.retrieve(*id),
+                Some(Response::RetrieveSuccess(doc.to_string()))
+            );
+        }
+        let mut whales = vec![ids[0], ids[2], ids[4]];
+        whales.sort_unstable();
+        assert_eq!(
+            client.search("whale"),
+            Some(Response::SearchSuccess(whales))
+        );
+        assert_eq!(
+            client.send(&Request::Count {
+                word: "the".to_string()
+            }),
+            Some(Response::CountSuccess(3))
+        );
+        match client.send(&Request::List { preview_chars: 4 }) {
+            Some(Response::ListSuccess(summaries)) => assert_eq!(
+                summaries
+                    .iter()
+                    .map(|summary| summary.id)
+                    .collect::<Vec<_>>(),
+                sorted
+            ),
+            response => panic!("unexpected response {:?}", response),
+        }
+        let batch = vec!["moby dick".to_string(), "dick moby".to_string()];
+        match client.send(&Request::PublishBatch {
+            docs: batch.clone(),
+            options: Default::default(),
+        }) {
+            Some(Response::PublishBatchSuccess(batch_ids)) => {
+                for (id, doc) in batch_ids.into_iter().zip(batch) {
+                    assert_eq!(client.retrieve(id), Some(Response::RetrieveSuccess(doc)));
+                }
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+        assert!(matches!(
+            client.send(&Request::Snapshot),
+            Some(Response::Failure {
+                code: ErrorCode::Unsupported,
+                ..
             })
-            .collect::<Vec<_>>();
-        handles.into_iter().for_each(|h| h.join().unwrap());
+        ));
+        coordinator.stop();
+    }
 
-        let words = fs::read_to_string("data/words.txt").unwrap();
-        let words = words
-            .split_whitespace()
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
+    #[test]
+    fn test_subscribe_5() {
+        let port = 7951;
+        let (server, _handle) = start_server(port);
+        let client = client::Client::builder("127.0.0.1", port)
+            .with_read_timeout(Duration::from_secs(5))
+            .build();
+        let mut subscription = client.subscribe("whale").ok().unwrap();
+        client.send(&Request::Publish {
+            doc: "call me ishmael".to_string(),
+        });
+        let long = format!("the whale {}", "surfaced ".repeat(20));
+        client.send(&Request::Publish { doc: long.clone() });
+        assert_eq!(subscription.next(), Some((1, long[..100].to_string())));
 
-        // Search docs sequentially
-        let _now = std::time::Instant::now();
+        // Committing a pending document or updating one into a match also notifies subscribers
+        let pending = client.publish_from_reader_with(
+            "a white whale".as_bytes(),
+            PublishOptions {
+                pending: true,
+                ..PublishOptions::default()
+            },
+        );
+        assert_eq!(pending, Some(Response::PublishSuccess(2)));
+        client.commit(2);
+        assert_eq!(subscription.next(), Some((2, "a white whale".to_string())));
+        client.send(&Request::Update {
+            id: 0,
+            doc: "call me ishmael, a whaler after the whale".to_string(),
+        });
+        assert_eq!(
+            subscription.next(),
+            Some((0, "call me ishmael, a whaler after the whale".to_string()))
+        );
+        server.stop();
+        assert_eq!(subscription.next(), None);
+    }
+
+    #[test]
+    fn test_resize_pool_5() {
+        let port = 7953;
+        let server = Arc::new(
+            server::Server::new()
+                .with_api_keys(admin_keys())
+                .with_max_workers(4),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let client = client::Client::new("127.0.0.1", port).with_token(ADMIN_KEY);
+        assert_eq!(client.resize_pool(2), Some(Response::PoolResized(2)));
+        assert!(matches!(
+            client.resize_pool(0),
+            Some(Response::Failure {
+                code: ErrorCode::Malformed,
+                ..
+            })
+        ));
+        assert_eq!(
+            failure_code(client.resize_pool(5)),
+            Some(ErrorCode::OutOfRange)
+        );
+        // The smaller pool still answers requests
+        client.send(&Request::Publish {
+            doc: "still running".to_string(),
+        });
+        assert_eq!(
+            client.search("still"),
+            Some(Response::SearchSuccess(vec![0]))
+        );
+        let text = match client.stats() {
+            Some(Response::StatsSuccess(text)) => text,
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert!(text.contains("ngram_pool_workers 2"));
+        // At least the worker answering is busy, with this connection
+        let busy = text
+            .lines()
+            .find_map(|line| line.strip_prefix("ngram_pool_busy_workers "))
+            .and_then(|busy| busy.parse::<usize>().ok());
+        assert!(busy.is_some_and(|busy| busy >= 1));
+        assert!(text.contains("ngram_pool_queue_wait_seconds_count"));
+        assert!(text.contains("ngram_pool_task_duration_seconds_count"));
+        server.stop();
+    }
+
+    #[test]
+    fn test_idle_keep_alive_5() {
+        let port = 7954;
+        let server = Arc::new(server::Server::with_capacity(1, ngram::database::BUCKETS));
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        // The pool's connection is kept alive, but doesn't keep the only worker from others
+        let pool = client::ClientPool::new(client::Client::new("127.0.0.1", port), 1);
+        assert_eq!(pool.search("idle"), Some(Response::SearchSuccess(vec![])));
+        let client = client::Client::builder("127.0.0.1", port)
+            .with_read_timeout(Duration::from_secs(5))
+            .build();
+        assert_eq!(client.search("idle"), Some(Response::SearchSuccess(vec![])));
+        assert_eq!(pool.search("idle"), Some(Response::SearchSuccess(vec![])));
+        // Nor does it keep the server from stopping
+        let start = std::time::Instant::now();
+        server.stop();
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_stop_with_idle_client_5() {
+        let port = 7956;
+        let (server, _handle) = start_server(port);
+        // A client that connects and never sends a request holds a worker, but not forever
+        let _idle = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        let start = std::time::Instant::now();
+        server.stop();
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_pipeline_5() {
+        let port = 7952;
+        let (server, _handle) = start_server(port);
         let client = client::Client::new("127.0.0.1", port);
-        for word in words.iter() {
-            let response = client.search(word);
-            assert!(matches!(response, Some(Response::SearchSuccess(_))));
+        for i in 0..10 {
+            client.send(&Request::Publish {
+                doc: format!("word{} common", i),
+            });
         }
-        // println!("Sequential search took {:?}", _now.elapsed);
 
-        // Search docs in parallel
-        let word_queue = Arc::new(Mutex::new(words));
-        let _now = std::time::Instant::now();
-        let handles = (0..THREADS)
-            .map(|_| {
-                thread::spawn({
-                    let word_queue = Arc::clone(&word_queue);
-                    let client = client::Client::new("127.0.0.1", port);
-                    move || loop {
-                        let word = word_queue.lock().unwrap().pop().clone();
-                        match word {
-                            Some(word) => {
-                                let response = client.search(&word);
-                                assert!(matches!(response, Some(Response::SearchSuccess(_))));
-                                //println!("Found {} in {:?}", word, indices);
-                            }
-                            None => return,
-                        }
-                    }
-                })
+        // More requests than the client sends ahead of the responses it has read
+        let requests = (0..100)
+            .map(|i| Request::Search {
+                word: format!("word{}", i % 20),
             })
+            .chain([Request::Count {
+                word: "common".to_string(),
+            }])
             .collect::<Vec<_>>();
-        handles.into_iter().for_each(|h| h.join().unwrap());
-        // println!("Parallel search took {:?}", _now.elapsed());
+        let responses = client.pipeline(&requests);
+        assert_eq!(responses.len(), 101);
+        for (i, response) in responses[..100].iter().enumerate() {
+            let expected = match i % 20 {
+                n if n < 10 => vec![n],
+                _ => vec![],
+            };
+            assert_eq!(response, &Some(Response::SearchSuccess(expected)));
+        }
+        assert_eq!(responses[100], Some(Response::CountSuccess(10)));
+        assert!(client.pipeline(&[]).is_empty());
+        server.stop();
+    }
+
+    #[test]
+    fn test_recovered_lock_is_reported_5() {
+        use ngram::analyzer::Analyzer;
+        use ngram::wal::WalEntry;
+        // Panics while publishing a document
+        struct Explosive;
+        impl Analyzer for Explosive {
+            fn analyze(&self, word: String) -> Option<String> {
+                assert_ne!(word, "boom");
+                Some(word)
+            }
+            fn name(&self) -> &'static str {
+                "explosive"
+            }
+        }
+        fn publish(doc: &str) -> String {
+            serde_json::to_string(&WalEntry::Publish {
+                doc: doc.to_string(),
+                published_at: 0,
+                options: PublishOptions::default(),
+            })
+            .unwrap()
+        }
+        // A follower applies changes with its position locked, so a panic poisons that lock
+        let port = 7955;
+        let server = Arc::new(
+            server::Server::new()
+                .with_analyzer(Explosive)
+                .as_follower("r3plicate"),
+        );
+        let _handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || server.run(port)
+        });
+        thread::sleep(Duration::from_millis(500));
+        let primary = client::Client::new("127.0.0.1", port).with_token("r3plicate");
+        let replicate = |seq, doc| {
+            primary.send(&Request::Replicate {
+                session: 1,
+                seq,
+                entry: publish(doc),
+            })
+        };
 
+        assert_eq!(
+            failure_code(replicate(1, "boom")),
+            Some(ErrorCode::Internal)
+        );
+        // The next request to take the lock is told it was recovered, and the one after is fine
+        match replicate(1, "fine") {
+            Some(Response::Failure { code, message }) => {
+                assert_eq!(code, ErrorCode::Internal);
+                assert!(message.contains("recovered"));
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+        assert_eq!(replicate(1, "fine"), Some(Response::Replicated(1)));
+        assert_eq!(replicate(2, "also fine"), Some(Response::Replicated(2)));
+        let reader = client::Client::new("127.0.0.1", port);
+        assert_eq!(
+            reader.search("fine"),
+            Some(Response::SearchSuccess(vec![0, 1]))
+        );
         server.stop();
     }
 }